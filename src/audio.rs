@@ -62,7 +62,8 @@ impl Addressable for AudioInterface {
             },
 
             _ => {
-                todo!();
+                warn!(target: "AI", "unhandled read from AI register ${:05X}", offset);
+                Ok(open_bus(offset))
             },
         }
     }
@@ -111,7 +112,7 @@ impl Addressable for AudioInterface {
             },
 
             _ => {
-                todo!();
+                warn!(target: "AI", "unhandled write to AI register ${:05X} value=${:08X}", offset, value);
             },
         }
 
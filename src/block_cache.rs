@@ -0,0 +1,288 @@
+// Pre-decoded instruction blocks
+// Interpreters decode a run of instructions once and keep the result until the memory it came
+// from changes. Blocks are keyed by physical address so every virtual alias shares the same
+// decode. Writes the owning processor doesn't see itself (DMA, other bus masters) are reported
+// through CodePages
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+#[allow(unused_imports)]
+use tracing::{debug,error,trace,warn,info};
+
+const PAGE_SHIFT: u64 = 12;
+pub const PAGE_SIZE: u64 = 1 << PAGE_SHIFT;
+
+pub struct Block<T> {
+    pub physical_address: u64,
+    pub cached: bool,
+    pub instructions: Vec<T>,
+}
+
+impl<T> Block<T> {
+    fn end(&self) -> u64 {
+        self.physical_address + ((self.instructions.len() as u64) << 2)
+    }
+}
+
+pub struct BlockCache<T> {
+    blocks: HashMap<u64, Rc<Block<T>>>,
+
+    // keys of the blocks starting in each page, for invalidation
+    pages: HashMap<u64, Vec<u64>>,
+
+    // incremented whenever a block is removed, so holders of an Rc<Block> know to look it up again
+    generation: u64,
+}
+
+impl<T> BlockCache<T> {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            pages: HashMap::new(),
+            generation: 0,
+        }
+    }
+
+    // the same physical address can be decoded through the cache or not, and the two are
+    // invalidated differently
+    #[inline(always)]
+    fn key(physical_address: u64, cached: bool) -> u64 {
+        (physical_address << 1) | (cached as u64)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    #[inline(always)]
+    pub fn get(&self, physical_address: u64, cached: bool) -> Option<Rc<Block<T>>> {
+        self.blocks.get(&Self::key(physical_address, cached)).cloned()
+    }
+
+    // blocks must not cross a page boundary
+    pub fn insert(&mut self, block: Block<T>) -> Rc<Block<T>> {
+        assert!(!block.instructions.is_empty() && ((block.end() - 1) >> PAGE_SHIFT) == (block.physical_address >> PAGE_SHIFT));
+
+        let key = Self::key(block.physical_address, block.cached);
+        let block = Rc::new(block);
+        self.pages.entry(block.physical_address >> PAGE_SHIFT).or_default().push(key);
+        if self.blocks.insert(key, block.clone()).is_some() {
+            self.generation += 1;
+        }
+        block
+    }
+
+    // remove every block overlapping [start, start+length)
+    pub fn invalidate_range(&mut self, start: u64, length: u64) {
+        if length == 0 { return; }
+
+        let end = start + length;
+        let mut removed = 0;
+        for page in (start >> PAGE_SHIFT)..=((end - 1) >> PAGE_SHIFT) {
+            let keys = match self.pages.get_mut(&page) {
+                Some(keys) => keys,
+                None => continue,
+            };
+
+            let blocks = &mut self.blocks;
+            keys.retain(|key| {
+                let overlaps = match blocks.get(key) {
+                    Some(block) => block.physical_address < end && block.end() > start,
+                    None => true, // replaced by a later insert; drop the stale key
+                };
+                if overlaps && blocks.remove(key).is_some() {
+                    removed += 1;
+                }
+                !overlaps
+            });
+
+            if keys.is_empty() {
+                self.pages.remove(&page);
+            }
+        }
+
+        if removed != 0 {
+            trace!(target: "BLOCKS", "invalidated {} block(s) in ${:08X}..${:08X}", removed, start, end);
+            self.generation += 1;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.pages.clear();
+        self.generation += 1;
+    }
+}
+
+// Pages of RDRAM holding decoded code. Anything that writes RDRAM calls notify_write(), and the
+// processor that owns the blocks collects the pages with take_writes() before its next fetch
+pub struct CodePages {
+    pages: usize,
+    code: Vec<AtomicU64>,
+    written: Vec<AtomicU64>,
+    any_written: AtomicBool,
+}
+
+impl CodePages {
+    // track `size` bytes of RDRAM starting at physical address 0
+    pub fn new(size: u64) -> Self {
+        let pages = (size >> PAGE_SHIFT) as usize;
+        let words = (pages + 63) / 64;
+        Self {
+            pages: pages,
+            code: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            written: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            any_written: AtomicBool::new(false),
+        }
+    }
+
+    // size of the tracked address space
+    #[inline(always)]
+    pub fn size(&self) -> u64 {
+        (self.pages as u64) << PAGE_SHIFT
+    }
+
+    pub fn mark_code(&self, physical_address: u64) {
+        let page = (physical_address >> PAGE_SHIFT) as usize;
+        if page < self.pages {
+            self.code[page >> 6].fetch_or(1 << (page & 63), Ordering::Relaxed);
+        }
+    }
+
+    #[inline(always)]
+    pub fn notify_write(&self, physical_address: usize, length: usize) {
+        if length == 0 || self.pages == 0 { return; }
+
+        let first = physical_address >> PAGE_SHIFT;
+        let last = ((physical_address + length - 1) >> PAGE_SHIFT).min(self.pages - 1);
+        for page in first..=last {
+            let bit = 1u64 << (page & 63);
+            if (self.code[page >> 6].load(Ordering::Relaxed) & bit) != 0 {
+                // the page stops being code until something decodes from it again
+                self.code[page >> 6].fetch_and(!bit, Ordering::Relaxed);
+                self.written[page >> 6].fetch_or(bit, Ordering::Relaxed);
+                self.any_written.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub fn has_writes(&self) -> bool {
+        self.any_written.load(Ordering::Relaxed)
+    }
+
+    // physical addresses of the code pages written since the last call
+    pub fn take_writes(&self) -> Vec<u64> {
+        self.any_written.store(false, Ordering::Relaxed);
+
+        let mut pages = Vec::new();
+        for (i, written) in self.written.iter().enumerate() {
+            let mut bits = written.swap(0, Ordering::Acquire);
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as u64;
+                pages.push((((i as u64) << 6) | bit) << PAGE_SHIFT);
+                bits &= bits - 1;
+            }
+        }
+        pages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(physical_address: u64, cached: bool, length: usize) -> Block<u32> {
+        Block { physical_address, cached, instructions: vec![0; length] }
+    }
+
+    #[test]
+    fn invalidate_removes_only_overlapping_blocks() {
+        let mut blocks = BlockCache::new();
+        blocks.insert(block(0x1000, false, 4)); // 0x1000..0x1010
+        blocks.insert(block(0x1010, false, 4)); // 0x1010..0x1020
+        blocks.insert(block(0x2000, false, 4));
+
+        let generation = blocks.generation();
+        blocks.invalidate_range(0x100C, 4);
+        assert!(blocks.get(0x1000, false).is_none());
+        assert!(blocks.get(0x1010, false).is_some());
+        assert!(blocks.get(0x2000, false).is_some());
+        assert!(blocks.generation() != generation);
+
+        // nothing there, so holders of a block don't need to look it up again
+        let generation = blocks.generation();
+        blocks.invalidate_range(0x1800, 0x100);
+        assert_eq!(blocks.generation(), generation);
+    }
+
+    #[test]
+    fn cached_and_uncached_blocks_are_separate() {
+        let mut blocks = BlockCache::new();
+        blocks.insert(block(0x1000, false, 2));
+        blocks.insert(block(0x1000, true, 8));
+        assert_eq!(blocks.get(0x1000, false).unwrap().instructions.len(), 2);
+        assert_eq!(blocks.get(0x1000, true).unwrap().instructions.len(), 8);
+
+        blocks.invalidate_range(0x1000, 4);
+        assert!(blocks.get(0x1000, false).is_none() && blocks.get(0x1000, true).is_none());
+    }
+
+    #[test]
+    fn invalidate_spans_pages() {
+        let mut blocks = BlockCache::new();
+        blocks.insert(block(0x0FF0, false, 4));
+        blocks.insert(block(0x1000, false, 4));
+        blocks.insert(block(0x2000, false, 4));
+
+        blocks.invalidate_range(0x0FFC, 0x1004);
+        assert!(blocks.get(0x0FF0, false).is_none());
+        assert!(blocks.get(0x1000, false).is_none());
+        assert!(blocks.get(0x2000, false).is_some());
+    }
+
+    #[test]
+    #[should_panic]
+    fn blocks_cannot_cross_pages() {
+        BlockCache::new().insert(block(0x0FF8, false, 4));
+    }
+
+    #[test]
+    fn only_code_pages_report_writes() {
+        let pages = CodePages::new(4 * 1024 * 1024);
+        assert_eq!(pages.size(), 4 * 1024 * 1024);
+
+        pages.notify_write(0x3000, 4);
+        assert!(!pages.has_writes());
+
+        pages.mark_code(0x3010);
+        pages.mark_code(0x5000);
+        pages.notify_write(0x2FFC, 8);
+        pages.notify_write(0x5000, 0);
+        assert!(pages.has_writes());
+        assert_eq!(pages.take_writes(), vec![0x3000]);
+        assert!(!pages.has_writes());
+
+        // the page stops being code until it's decoded again
+        pages.notify_write(0x3000, 4);
+        assert!(!pages.has_writes());
+    }
+
+    #[test]
+    fn code_pages_follow_rdram_size() {
+        let pages = CodePages::new(4 * 1024 * 1024);
+
+        // the last page of the 4MiB and the first page of the Expansion Pak
+        pages.mark_code(0x003F_F000);
+        pages.mark_code(0x0040_0000);
+        pages.notify_write(0x003F_FFFC, 8);
+        assert_eq!(pages.take_writes(), vec![0x003F_F000]);
+
+        let pages = CodePages::new(8 * 1024 * 1024);
+        assert_eq!(pages.size(), 8 * 1024 * 1024);
+        pages.mark_code(0x007F_F000);
+        pages.notify_write(0x007F_F000, 4);
+        assert_eq!(pages.take_writes(), vec![0x007F_F000]);
+    }
+}
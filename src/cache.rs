@@ -0,0 +1,279 @@
+// VR4300 primary caches
+// Both caches are direct mapped, virtually indexed and physically tagged. The I-cache is 16KiB
+// with 32-byte lines and the D-cache is 8KiB write-back with 16-byte lines
+#[allow(unused_imports)]
+use tracing::{debug,error,trace,warn,info};
+
+use crate::*;
+
+// Approximate cost of a cache line transfer over the SysAD bus, in PCycles
+const ICACHE_FILL_CYCLES     : u64 = 40;
+const ICACHE_WRITEBACK_CYCLES: u64 = 32;
+const DCACHE_FILL_CYCLES     : u64 = 32;
+const DCACHE_WRITEBACK_CYCLES: u64 = 24;
+
+#[derive(Copy, Clone, Debug)]
+pub struct CacheLine<const WORDS: usize> {
+    // physical address bits 31:12
+    pub tag: u32,
+    pub valid: bool,
+    pub dirty: bool,
+    pub data: [u32; WORDS],
+}
+
+impl<const WORDS: usize> Default for CacheLine<WORDS> {
+    fn default() -> Self {
+        Self { tag: 0, valid: false, dirty: false, data: [0; WORDS] }
+    }
+}
+
+impl<const WORDS: usize> CacheLine<WORDS> {
+    // physical address of the start of the line, given the index it's stored at
+    pub fn physical_address(&self, index: usize) -> u64 {
+        ((self.tag as u64) << 12) | (((index * WORDS * 4) as u64) & 0xFFF)
+    }
+
+    // TagLo representation: PTagLo in bits 27:8, valid in bit 7 and dirty in bit 6
+    pub fn tag_lo(&self) -> u64 {
+        ((self.tag as u64) << 8) | (if self.valid { 0x80 } else { 0 }) | (if self.dirty { 0x40 } else { 0 })
+    }
+
+    pub fn set_tag_lo(&mut self, tag_lo: u64) {
+        self.tag   = ((tag_lo >> 8) & 0x000F_FFFF) as u32;
+        self.valid = (tag_lo & 0x80) != 0;
+        self.dirty = (tag_lo & 0x40) != 0;
+    }
+}
+
+#[derive(Clone)]
+pub struct Cache<const LINES: usize, const WORDS: usize> {
+    lines: Vec<CacheLine<WORDS>>,
+    fill_cycles: u64,
+    writeback_cycles: u64,
+}
+
+pub type ICache = Cache<512, 8>;
+pub type DCache = Cache<512, 4>;
+
+impl ICache {
+    pub fn new() -> Self {
+        Self::with_cycles(ICACHE_FILL_CYCLES, ICACHE_WRITEBACK_CYCLES)
+    }
+}
+
+impl DCache {
+    pub fn new() -> Self {
+        Self::with_cycles(DCACHE_FILL_CYCLES, DCACHE_WRITEBACK_CYCLES)
+    }
+}
+
+impl<const LINES: usize, const WORDS: usize> Cache<LINES, WORDS> {
+    const LINE_BYTES: u64 = (WORDS * 4) as u64;
+
+    fn with_cycles(fill_cycles: u64, writeback_cycles: u64) -> Self {
+        Self {
+            lines: vec![CacheLine::default(); LINES],
+            fill_cycles: fill_cycles,
+            writeback_cycles: writeback_cycles,
+        }
+    }
+
+    // cache contents are undefined at power on, and the boot code invalidates every line
+    pub fn reset(&mut self) {
+        self.lines.fill(CacheLine::default());
+    }
+
+    #[inline(always)]
+    pub fn index(virtual_address: u64) -> usize {
+        ((virtual_address / Self::LINE_BYTES) as usize) & (LINES - 1)
+    }
+
+    #[inline(always)]
+    fn tag(physical_address: u64) -> u32 {
+        ((physical_address >> 12) & 0x000F_FFFF) as u32
+    }
+
+    // word offset into a line
+    #[inline(always)]
+    pub fn word(physical_address: u64) -> usize {
+        ((physical_address >> 2) as usize) & (WORDS - 1)
+    }
+
+    pub fn line(&self, index: usize) -> &CacheLine<WORDS> {
+        &self.lines[index]
+    }
+
+    pub fn line_mut(&mut self, index: usize) -> &mut CacheLine<WORDS> {
+        &mut self.lines[index]
+    }
+
+    pub fn is_hit(&self, virtual_address: u64, physical_address: u64) -> bool {
+        let line = &self.lines[Self::index(virtual_address)];
+        line.valid && line.tag == Self::tag(physical_address)
+    }
+
+    // Return the line holding physical_address, filling it from the bus on a miss.
+    // A dirty line being replaced is written back first
+    pub fn fetch(&mut self, bus: &mut dyn Addressable, virtual_address: u64, physical_address: u64, cycles: &mut u64) -> Result<&mut CacheLine<WORDS>, ReadWriteFault> {
+        let index = Self::index(virtual_address);
+        if !self.is_hit(virtual_address, physical_address) {
+            self.write_back(bus, index, cycles)?;
+            self.fill(bus, index, physical_address, cycles)?;
+        }
+        Ok(&mut self.lines[index])
+    }
+
+    pub fn fill(&mut self, bus: &mut dyn Addressable, index: usize, physical_address: u64, cycles: &mut u64) -> Result<(), ReadWriteFault> {
+        let base = physical_address & !(Self::LINE_BYTES - 1);
+        let line = &mut self.lines[index];
+
+        // the line holds nothing useful if the bus faults part way through
+        line.valid = false;
+        for i in 0..WORDS {
+            line.data[i] = bus.read_u32((base as usize) + (i << 2))?;
+        }
+        line.tag   = Self::tag(physical_address);
+        line.valid = true;
+        line.dirty = false;
+        *cycles += self.fill_cycles;
+        Ok(())
+    }
+
+    // write the line at index back to memory if it's valid and dirty, and mark it clean
+    pub fn write_back(&mut self, bus: &mut dyn Addressable, index: usize, cycles: &mut u64) -> Result<(), ReadWriteFault> {
+        let line = &mut self.lines[index];
+        if !(line.valid && line.dirty) { return Ok(()); }
+
+        let base = line.physical_address(index);
+        trace!(target: "CACHE", "writing back line {} to ${:08X}", index, base);
+        for i in 0..WORDS {
+            bus.write_u32(line.data[i], (base as usize) + (i << 2))?;
+        }
+        line.dirty = false;
+        *cycles += self.writeback_cycles;
+        Ok(())
+    }
+
+    pub fn invalidate(&mut self, index: usize) {
+        self.lines[index].valid = false;
+        self.lines[index].dirty = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // word addressed memory that counts the words moved
+    struct TestBus {
+        memory: Vec<u32>,
+        reads: usize,
+        writes: usize,
+    }
+
+    impl TestBus {
+        fn new() -> Self {
+            Self { memory: (0..0x2000).map(|i| i * 4).collect(), reads: 0, writes: 0 }
+        }
+    }
+
+    impl Addressable for TestBus {
+        fn read_u32(&mut self, offset: usize) -> Result<u32, ReadWriteFault> {
+            self.reads += 1;
+            Ok(self.memory[offset >> 2])
+        }
+
+        fn write_u32(&mut self, value: u32, offset: usize) -> Result<WriteReturnSignal, ReadWriteFault> {
+            self.writes += 1;
+            self.memory[offset >> 2] = value;
+            Ok(WriteReturnSignal::None)
+        }
+    }
+
+    #[test]
+    fn fetch_fills_on_miss_and_hits_after() {
+        let mut bus = TestBus::new();
+        let mut dcache = DCache::new();
+        let mut cycles = 0;
+
+        assert!(!dcache.is_hit(0x8000_1234, 0x1234));
+        let line = dcache.fetch(&mut bus, 0x8000_1234, 0x1234, &mut cycles).unwrap();
+        assert_eq!(line.data, [0x1230, 0x1234, 0x1238, 0x123C]);
+        assert!(line.valid && !line.dirty);
+        assert_eq!((bus.reads, cycles), (4, DCACHE_FILL_CYCLES));
+
+        assert!(dcache.is_hit(0x8000_1238, 0x1238));
+        dcache.fetch(&mut bus, 0x8000_1238, 0x1238, &mut cycles).unwrap();
+        assert_eq!((bus.reads, cycles), (4, DCACHE_FILL_CYCLES));
+
+        // same index, different tag
+        assert!(!dcache.is_hit(0x8000_3234, 0x3234));
+    }
+
+    #[test]
+    fn dirty_line_is_written_back_when_replaced() {
+        let mut bus = TestBus::new();
+        let mut dcache = DCache::new();
+        let mut cycles = 0;
+
+        let line = dcache.fetch(&mut bus, 0x8000_0010, 0x0010, &mut cycles).unwrap();
+        line.data[1] = 0xDEAD_BEEF;
+        line.dirty = true;
+
+        // 0x2010 shares the index of 0x0010
+        dcache.fetch(&mut bus, 0x8000_2010, 0x2010, &mut cycles).unwrap();
+        assert_eq!(bus.memory[0x0014 >> 2], 0xDEAD_BEEF);
+        assert_eq!(bus.writes, 4);
+        assert_eq!(cycles, 2 * DCACHE_FILL_CYCLES + DCACHE_WRITEBACK_CYCLES);
+
+        let index = DCache::index(0x8000_2010);
+        assert_eq!(dcache.line(index).physical_address(index), 0x2010);
+        assert!(!dcache.line(index).dirty);
+    }
+
+    #[test]
+    fn clean_and_invalid_lines_are_not_written_back() {
+        let mut bus = TestBus::new();
+        let mut dcache = DCache::new();
+        let mut cycles = 0;
+
+        dcache.fetch(&mut bus, 0x8000_0040, 0x0040, &mut cycles).unwrap();
+        let index = DCache::index(0x8000_0040);
+        dcache.write_back(&mut bus, index, &mut cycles).unwrap();
+        assert_eq!(bus.writes, 0);
+
+        dcache.line_mut(index).dirty = true;
+        dcache.invalidate(index);
+        assert!(!dcache.is_hit(0x8000_0040, 0x0040));
+        dcache.write_back(&mut bus, index, &mut cycles).unwrap();
+        assert_eq!((bus.writes, cycles), (0, DCACHE_FILL_CYCLES));
+    }
+
+    #[test]
+    fn icache_uses_its_own_line_size_and_timing() {
+        let mut bus = TestBus::new();
+        let mut icache = ICache::new();
+        let mut cycles = 0;
+
+        let index = ICache::index(0x8000_0024);
+        icache.fill(&mut bus, index, 0x0024, &mut cycles).unwrap();
+        assert_eq!(icache.line(index).data[0], 0x0020);
+        assert_eq!((bus.reads, cycles), (8, ICACHE_FILL_CYCLES));
+
+        icache.line_mut(index).dirty = true;
+        icache.write_back(&mut bus, index, &mut cycles).unwrap();
+        assert_eq!((bus.writes, cycles), (8, ICACHE_FILL_CYCLES + ICACHE_WRITEBACK_CYCLES));
+    }
+
+    #[test]
+    fn tag_lo_round_trips() {
+        let mut line = CacheLine::<4>::default();
+        line.set_tag_lo(0x0123_45C0);
+        assert_eq!((line.tag, line.valid, line.dirty), (0x12345, true, true));
+        assert_eq!(line.tag_lo(), 0x0123_45C0);
+        assert_eq!(line.physical_address(3), 0x1234_5030);
+
+        line.set_tag_lo(0x0123_4580);
+        assert!(line.valid && !line.dirty);
+    }
+}
@@ -9,6 +9,9 @@ use num_traits::{Float, Zero};
 
 use crate::cpu::InstructionFault;
 
+mod softfloat;
+use softfloat::Rounding;
+
 const _Cop1_Revision     : usize = 0;
 const _Cop1_ControlStatus: usize = 31;
 
@@ -24,6 +27,12 @@ const FpeCause_DivByZero    : u64 = 0b001000;
 const FpeCause_Invalid      : u64 = 0b010000;
 const FpeCause_Unimplemented: u64 = 0b100000;
 
+// the RM field of FCR31
+const RoundingMode_Nearest: u64 = 0b00;
+const RoundingMode_Zero   : u64 = 0b01;
+const RoundingMode_Up     : u64 = 0b10;
+const RoundingMode_Down   : u64 = 0b11;
+
 #[allow(dead_code)]
 extern "C" {
     pub static c_fe_upward: i32;
@@ -94,6 +103,35 @@ impl SignallingNan for f64 {
     }
 }
 
+// arithmetic on the host FPU, which reports exceptions through the C fenv shim
+trait HostArithmetic: softfloat::Format {
+    fn host_add(self, b: Self) -> Self;
+    fn host_sub(self, b: Self) -> Self;
+    fn host_mul(self, b: Self) -> Self;
+    fn host_div(self, b: Self) -> Self;
+    fn host_sqrt(self) -> Self;
+    fn host_from_int(value: i64) -> Self;
+}
+
+impl HostArithmetic for f32 {
+    fn host_add(self, b: Self) -> Self { unsafe { c_f32_add(self, b) } }
+    fn host_sub(self, b: Self) -> Self { unsafe { c_f32_sub(self, b) } }
+    fn host_mul(self, b: Self) -> Self { self * b }
+    fn host_div(self, b: Self) -> Self { unsafe { c_f32_div(self, b) } }
+    fn host_sqrt(self) -> Self { self.sqrt() }
+    fn host_from_int(value: i64) -> Self { value as f32 }
+}
+
+impl HostArithmetic for f64 {
+    fn host_add(self, b: Self) -> Self { unsafe { c_f64_add(self, b) } }
+    fn host_sub(self, b: Self) -> Self { unsafe { c_f64_sub(self, b) } }
+    fn host_mul(self, b: Self) -> Self { self * b }
+    fn host_div(self, b: Self) -> Self { unsafe { c_f64_div(self, b) } }
+    fn host_sqrt(self) -> Self { self.sqrt() }
+    fn host_from_int(value: i64) -> Self { value as f64 }
+}
+
+#[derive(Copy, Clone)]
 struct InstructionDecode {
     v: u32,
     special: u32,
@@ -116,6 +154,7 @@ union Fgr {
     as_f64: f64,
 }
 
+#[derive(Clone)]
 pub struct Cop1 {
     // The FPU only has two control registers, 0 (Implementation/Revision) and 31 (Control/Status)
     // and instead of a sparse array, we maintain two separate variables
@@ -137,6 +176,14 @@ pub struct Cop1 {
     // system rounding mode
     system_rounding_mode: i32,
 
+    // when set, arithmetic is done by softfloat instead of the host FPU so results are the
+    // same on every machine. soft_flags collects the exceptions raised by the current operation
+    soft_float: bool,
+    soft_flags: u64,
+
+    // rounding mode of the current operation
+    rounding: Rounding,
+
     // function pointerse to the cop1 functions
     function_table: [Cop1Instruction; 64],
 }
@@ -144,7 +191,7 @@ pub struct Cop1 {
 type Cop1Instruction = fn(&mut Cop1) -> Result<(), InstructionFault>;
 
 impl Cop1 {
-    pub fn new() -> Cop1 {
+    pub fn new(soft_float: bool) -> Cop1 {
         Cop1 {
             fcr_implementation_revision: 0xA00,
             fcr_control_status: 0,
@@ -159,6 +206,10 @@ impl Cop1 {
 
             system_rounding_mode: fegetround(),
 
+            soft_float: soft_float,
+            soft_flags: 0,
+            rounding: Rounding::Nearest,
+
             function_table: [
                 //  _000               _001              _010               _011                _100                _101                _110                _111
     /* 000_ */  Cop1::op_add, Cop1::op_sub, Cop1::op_mul, Cop1::op_div, Cop1::op_sqrt, Cop1::op_abs, Cop1::op_mov, Cop1::op_neg,
@@ -182,6 +233,58 @@ impl Cop1 {
         self.condition_signal
     }
 
+    // raw 64-bit contents of an FGR
+    pub fn fgr(&self, index: usize) -> u64 {
+        unsafe { self.fgr[index].as_u64 }
+    }
+
+    pub fn set_fgr(&mut self, index: usize, value: u64) {
+        self.fgr[index].as_u64 = value;
+    }
+
+    pub fn control_status(&self) -> u64 {
+        self.fcr_control_status
+    }
+
+    // set FCR31 without raising exceptions for any cause bits being set
+    pub fn set_control_status(&mut self, value: u64) {
+        self.fcr_control_status = value & 0x0183_FFFF;
+        self.condition_signal = ((value >> 23) & 0x01) != 0;
+    }
+
+    pub fn fr(&self) -> bool {
+        self.fr_bit
+    }
+
+    // the word swc1 would store from ft
+    pub fn fpr_u32(&self, ft: usize) -> u32 {
+        if !self.fr_bit {
+            // Get value from either the low word or high word based on bit 0 of ft
+            let shift = (ft & 0x01) << 5; // 0 or 32
+            let old = unsafe { self.fgr[ft & !0x01].as_u64 };
+            ((old >> shift) & 0xFFFF_FFFF) as u32
+        } else { 
+            // The datasheet says that resulting value of the high order 32-bits of the register are undefined
+            // but the n64-systemtests program requires us to preserve the value
+            let old = unsafe { self.fgr[ft].as_u64 };
+            (old & 0xFFFF_FFFF) as u32
+        }
+    }
+
+    // the double word sdc1 would store from ft
+    pub fn fpr_u64(&self, ft: usize) -> u64 {
+        if !self.fr_bit {
+            // Get double word from ft and ft+1. The operation is undefined if bit 0 of ft is not 0
+            unsafe { self.fgr[ft & !0x01].as_u64 }
+        } else { 
+            unsafe { self.fgr[ft].as_u64 }
+        }
+    }
+
+    pub fn implementation_revision(&self) -> u64 {
+        self.fcr_implementation_revision
+    }
+
     // Update the cause bits in fcr_control_status and if the corresponding enable bit is set,
     // raise an exception. The unimplemented instruction bit (E) always generates an exception
     fn update_cause(&mut self, cause: u64, update_flag: bool) -> Result<(), InstructionFault> {
@@ -189,7 +292,7 @@ impl Cop1 {
         let unimplemented_instruction = (cause & 0x20) != 0;
         let enable_bits = (self.fcr_control_status >> 7) & 0x1f;
         if unimplemented_instruction || (cause & enable_bits) != 0 {
-            fesetround(&self.system_rounding_mode); // restore system rounding mode
+            self.restore_rounding();
             // this Fpe will propagate up to Cpu::step, where the cpu will enter an exception
             Err(InstructionFault::FloatingPointException)
         } else {
@@ -237,30 +340,11 @@ impl Cop1 {
     }
 
     pub fn sdc(&mut self, ft: usize) -> Result<u64, InstructionFault> {
-        let result = if !self.fr_bit {
-            // Get double word from ft and ft+1. The operation is undefined if bit 0 of ft is not 0
-            unsafe { self.fgr[ft & !0x01].as_u64 }
-        } else { 
-            unsafe { self.fgr[ft].as_u64 }
-        };
-
-        Ok(result)
+        Ok(self.fpr_u64(ft))
     }
 
     pub fn swc(&mut self, ft: usize) -> Result<u32, InstructionFault> {
-        let result = if !self.fr_bit {
-            // Get value from either the low word or high word based on bit 0 of ft
-            let shift = (ft & 0x01) << 5; // 0 or 32
-            let old = unsafe { self.fgr[ft & !0x01].as_u64 };
-            (old >> shift) & 0xFFFF_FFFF
-        } else { 
-            // The datasheet says that resulting value of the high order 32-bits of the register are undefined
-            // but the n64-systemtests program requires us to preserve the value
-            let old = unsafe { self.fgr[ft].as_u64 };
-            old & 0xFFFF_FFFF
-        };
-
-        Ok(result as u32)
+        Ok(self.fpr_u32(ft))
     }
 
     // Move Control Word from Coprocessor
@@ -349,18 +433,79 @@ impl Cop1 {
     }
 
     fn begin_fpu_op(&mut self) {
-        let round_mode = match self.fcr_control_status & 0x03 {
-            0b00 => fe_tonearest,
-            0b01 => fe_towardzero,
-            0b10 => fe_upward,
-            0b11 => fe_downward,
+        self.set_rounding(self.fcr_control_status & 0x03);
+        if self.soft_float {
+            self.soft_flags = 0;
+        } else {
+            feclearexcept(fe_all_except);
+        }
+
+        self.fcr_control_status &= !0x0001F000;
+    }
+
+    // set the rounding mode for the current operation, using the encoding of the RM field
+    fn set_rounding(&mut self, rounding_mode: u64) {
+        self.rounding = Rounding::from_rm(rounding_mode);
+        if self.soft_float { return; }
+
+        let round_mode = match rounding_mode {
+            RoundingMode_Nearest => fe_tonearest,
+            RoundingMode_Zero    => fe_towardzero,
+            RoundingMode_Up      => fe_upward,
+            RoundingMode_Down    => fe_downward,
             _ => panic!("not valid"),
         };
-
         fesetround(round_mode);
-        feclearexcept(fe_all_except);
+    }
 
-        self.fcr_control_status &= !0x0001F000;
+    // restore the system rounding mode after an operation
+    fn restore_rounding(&self) {
+        if !self.soft_float {
+            fesetround(&self.system_rounding_mode);
+        }
+    }
+
+    // record the exceptions raised by a softfloat operation and return its result
+    fn soft<T>(&mut self, (result, flags): (T, u64)) -> T {
+        self.soft_flags |= flags;
+        result
+    }
+
+    fn add<T: HostArithmetic>(&mut self, a: T, b: T) -> T {
+        if self.soft_float { self.soft(softfloat::add(a, b, self.rounding)) } else { a.host_add(b) }
+    }
+
+    fn sub<T: HostArithmetic>(&mut self, a: T, b: T) -> T {
+        if self.soft_float { self.soft(softfloat::sub(a, b, self.rounding)) } else { a.host_sub(b) }
+    }
+
+    fn mul<T: HostArithmetic>(&mut self, a: T, b: T) -> T {
+        if self.soft_float { self.soft(softfloat::mul(a, b, self.rounding)) } else { a.host_mul(b) }
+    }
+
+    fn div<T: HostArithmetic>(&mut self, a: T, b: T) -> T {
+        if self.soft_float { self.soft(softfloat::div(a, b, self.rounding)) } else { a.host_div(b) }
+    }
+
+    fn sqrt<T: HostArithmetic>(&mut self, a: T) -> T {
+        if self.soft_float { self.soft(softfloat::sqrt(a, self.rounding)) } else { a.host_sqrt() }
+    }
+
+    fn int_to_float<T: HostArithmetic>(&mut self, value: i64) -> T {
+        if self.soft_float { self.soft(softfloat::from_int(value, self.rounding)) } else { T::host_from_int(value) }
+    }
+
+    fn f64_to_f32(&mut self, a: f64) -> f32 {
+        if self.soft_float { self.soft(softfloat::convert(a, self.rounding)) } else { a as f32 }
+    }
+
+    fn f32_to_f64(&mut self, a: f32) -> f64 {
+        if self.soft_float { self.soft(softfloat::convert(a, self.rounding)) } else { a as f64 }
+    }
+
+    // round to an integral value using the current rounding mode
+    fn rint(&mut self, a: f64) -> f64 {
+        if self.soft_float { self.soft(softfloat::round_to_integral(a, self.rounding)) } else { unsafe { c_rint_f64(a) } }
     }
 
     fn check_input<T: Float + Zero + SignallingNan>(&mut self, value: T, check_inf: bool) -> Result<T, InstructionFault> {
@@ -387,7 +532,11 @@ impl Cop1 {
         Ok(value)
     }
 
-    fn check_fpu_exceptions() -> (u64, bool) {
+    fn check_fpu_exceptions(&self) -> (u64, bool) {
+        if self.soft_float {
+            return (self.soft_flags & !FpeCause_Underflow, (self.soft_flags & FpeCause_Underflow) != 0);
+        }
+
         let mut cause = 0;
         let excepts = fetestexcept(fe_all_except);
         //error!(target: "CPU", "got excepts={}", excepts);
@@ -416,7 +565,7 @@ impl Cop1 {
     // don't like that this function is duplicated
     fn end_fpu_op_f32(&mut self, result: f32) -> Result<f32, InstructionFault> {
         let mut retval = Ok(result);
-        let (mut cause, is_underflow) = Self::check_fpu_exceptions();
+        let (mut cause, is_underflow) = self.check_fpu_exceptions();
 
         if result.is_nan() {
             retval = Ok(f32::from_bits(0x7FBFFFFF));
@@ -454,7 +603,7 @@ impl Cop1 {
             retval = Ok(f32::from_bits(0x7FBFFFFF));
         }
 
-        fesetround(&self.system_rounding_mode);
+        self.restore_rounding();
 
         retval
     }
@@ -464,7 +613,7 @@ impl Cop1 {
     // THEY SHOULD BE IDENTICAL EXCEPT FOR F32->F64 CHANGES
     fn end_fpu_op_f64(&mut self, result: f64) -> Result<f64, InstructionFault> {
         let mut retval = Ok(result);
-        let (mut cause, is_underflow) = Self::check_fpu_exceptions();
+        let (mut cause, is_underflow) = self.check_fpu_exceptions();
 
         if result.is_nan() {
             retval = Ok(f64::from_bits(0x7FF7_FFFF_FFFF_FFFF));
@@ -506,13 +655,13 @@ impl Cop1 {
             retval = Ok(f64::from_bits(0x7FF7_FFFF_FFFF_FFFF));
         }
 
-        fesetround(&self.system_rounding_mode);
+        self.restore_rounding();
 
         retval
     }
 
     fn end_fpu_op_convert_word(&mut self, rounded_value: f64) -> Result<u32, InstructionFault> {
-        let (mut cause, _) = Self::check_fpu_exceptions();
+        let (mut cause, _) = self.check_fpu_exceptions();
 
         // this conversion has to happen after check_fpu_exceptions
         let mut value = rounded_value as i64;
@@ -527,13 +676,13 @@ impl Cop1 {
             self.update_cause(cause, true)?;
         }
 
-        fesetround(&self.system_rounding_mode);
+        self.restore_rounding();
 
         Ok(value as u32)
     }
 
     fn end_fpu_op_convert_long(&mut self, rounded_value: f64) -> Result<u64, InstructionFault> {
-        let (mut cause, _) = Self::check_fpu_exceptions();
+        let (mut cause, _) = self.check_fpu_exceptions();
 
         // this conversion has to happen after check_fpu_exceptions
         let mut value = rounded_value as i64;
@@ -556,7 +705,7 @@ impl Cop1 {
             self.update_cause(cause, true)?;
         }
 
-        fesetround(&self.system_rounding_mode);
+        self.restore_rounding();
 
         Ok(value as u64)
     }
@@ -574,14 +723,16 @@ impl Cop1 {
             Format_Single => { // .S
                 let input_a = self.check_input(unsafe { self.fgr[self.inst.fs].as_f32 }, false)?;
                 let input_b = self.check_input(unsafe { self.fgr[self.inst.ft].as_f32 }, false)?;
-                let result = self.end_fpu_op_f32(unsafe { c_f32_add(input_a, input_b) })?;
+                let sum = self.add(input_a, input_b);
+                let result = self.end_fpu_op_f32(sum)?;
                 self.fgr[self.inst.fd].as_u64 = result.to_bits() as u64; // clear upper bits
             },
 
             Format_Double => { // .D
                 let input_a = self.check_input(unsafe { self.fgr[self.inst.fs].as_f64 }, false)?;
                 let input_b = self.check_input(unsafe { self.fgr[self.inst.ft].as_f64 }, false)?;
-                let result  = self.end_fpu_op_f64(unsafe { c_f64_add(input_a, input_b) })?;
+                let sum = self.add(input_a, input_b);
+                let result = self.end_fpu_op_f64(sum)?;
                 self.fgr[self.inst.fd].as_f64 = result;
             },
 
@@ -597,14 +748,16 @@ impl Cop1 {
             Format_Single => { // .S
                 let input_a = self.check_input(unsafe { self.fgr[self.inst.fs].as_f32 }, false)?;
                 let input_b = self.check_input(unsafe { self.fgr[self.inst.ft].as_f32 }, false)?;
-                let result  = self.end_fpu_op_f32(unsafe { c_f32_sub(input_a, input_b) })?;
+                let difference = self.sub(input_a, input_b);
+                let result = self.end_fpu_op_f32(difference)?;
                 self.fgr[self.inst.fd].as_u64 = result.to_bits() as u64; // clear upper bits
             },
 
             Format_Double => { // .D
                 let input_a = self.check_input(unsafe { self.fgr[self.inst.fs].as_f64 }, false)?;
                 let input_b = self.check_input(unsafe { self.fgr[self.inst.ft].as_f64 }, false)?;
-                let result  = self.end_fpu_op_f64(unsafe { c_f64_sub(input_a, input_b) })?;
+                let difference = self.sub(input_a, input_b);
+                let result = self.end_fpu_op_f64(difference)?;
                 self.fgr[self.inst.fd].as_f64 = result;
             },
 
@@ -621,8 +774,8 @@ impl Cop1 {
                 let input_a = self.check_input(unsafe { self.fgr[self.inst.fs].as_f32 }, false)?;
                 let input_b = self.check_input(unsafe { self.fgr[self.inst.ft].as_f32 }, false)?;
 
-                //let result = self.end_fpu_op_f32(unsafe { c_f32_mul(input_a, input_b) })?;
-                let result = self.end_fpu_op_f32(input_a * input_b)?;
+                let product = self.mul(input_a, input_b);
+                let result = self.end_fpu_op_f32(product)?;
                 self.fgr[self.inst.fd].as_u64 = result.to_bits() as u64; // clear upper bits
             },
 
@@ -631,8 +784,8 @@ impl Cop1 {
                 let input_b = self.check_input(unsafe { self.fgr[self.inst.ft].as_f64 }, false)?;
 
                 //if input_a.is_infinite() || input_b.is_infinite() { info!(target:"CPU", "an input is infinite without an exception"); }
-                //let result = self.end_fpu_op_f64(unsafe { c_f64_mul(input_a, input_b) })?;
-                let result = self.end_fpu_op_f64(input_a * input_b)?;
+                let product = self.mul(input_a, input_b);
+                let result = self.end_fpu_op_f64(product)?;
                 self.fgr[self.inst.fd].as_f64 = result;
             },
 
@@ -648,14 +801,16 @@ impl Cop1 {
             Format_Single => { // .S
                 let input_a = self.check_input(unsafe { self.fgr[self.inst.fs].as_f32 }, false)?;
                 let input_b = self.check_input(unsafe { self.fgr[self.inst.ft].as_f32 }, false)?;
-                let result = self.end_fpu_op_f32(unsafe { c_f32_div(input_a, input_b) })?;
+                let quotient = self.div(input_a, input_b);
+                let result = self.end_fpu_op_f32(quotient)?;
                 self.fgr[self.inst.fd].as_u64 = result.to_bits() as u64; // clear upper bits
             },
 
             Format_Double => { // .D
                 let input_a = self.check_input(unsafe { self.fgr[self.inst.fs].as_f64 }, false)?;
                 let input_b = self.check_input(unsafe { self.fgr[self.inst.ft].as_f64 }, false)?;
-                let result = self.end_fpu_op_f64(unsafe { c_f64_div(input_a, input_b) })?;
+                let quotient = self.div(input_a, input_b);
+                let result = self.end_fpu_op_f64(quotient)?;
                 self.fgr[self.inst.fd].as_f64 = result;
             },
 
@@ -670,13 +825,15 @@ impl Cop1 {
         match self.inst.fmt {
             Format_Single => { // .S
                 let input_a = self.check_input(unsafe { self.fgr[self.inst.fs].as_f32 }, false)?;
-                let result = self.end_fpu_op_f32(input_a.sqrt())?;
+                let root = self.sqrt(input_a);
+                let result = self.end_fpu_op_f32(root)?;
                 self.fgr[self.inst.fd].as_u64 = result.to_bits() as u64; // clear upper bits
             },
 
             Format_Double => { // .D
                 let input_a = self.check_input(unsafe { self.fgr[self.inst.fs].as_f64 }, false)?;
-                let result = self.end_fpu_op_f64(input_a.sqrt())?;
+                let root = self.sqrt(input_a);
+                let result = self.end_fpu_op_f64(root)?;
                 self.fgr[self.inst.fd].as_f64 = result;
             },
 
@@ -744,65 +901,70 @@ impl Cop1 {
     // ROUND.L.fmt
     pub fn op_round_L(&mut self) -> Result<(), InstructionFault> {
         self.begin_fpu_op();
-        fesetround(fe_tonearest); // ROUND always rounds to nearest
+        self.set_rounding(RoundingMode_Nearest); // ROUND always rounds to nearest
         let input_a = match self.inst.fmt {
             Format_Single => self.check_input(unsafe { self.fgr[self.inst.fs].as_f32 }, true)? as f64, // .S
             Format_Double => self.check_input(unsafe { self.fgr[self.inst.fs].as_f64 }, true)? as f64, // .D
             _ => { self.update_cause(FpeCause_Unimplemented, true)?; 0.0f64 },
         };
-        self.fgr[self.inst.fd].as_u64 = self.end_fpu_op_convert_long(unsafe { c_rint_f64(input_a) })?;
+        let rounded = self.rint(input_a);
+        self.fgr[self.inst.fd].as_u64 = self.end_fpu_op_convert_long(rounded)?;
         Ok(())
     }
 
     // TRUNC.L.fmt
     pub fn op_trunc_L(&mut self) -> Result<(), InstructionFault> {
         self.begin_fpu_op();
-        fesetround(fe_towardzero); // TRUNC always rounds to zero
+        self.set_rounding(RoundingMode_Zero); // TRUNC always rounds to zero
         let input_a = match self.inst.fmt {
             Format_Single => self.check_input(unsafe { self.fgr[self.inst.fs].as_f32 }, true)? as f64, // .S
             Format_Double => self.check_input(unsafe { self.fgr[self.inst.fs].as_f64 }, true)? as f64, // .D
             _ => { self.update_cause(FpeCause_Unimplemented, true)?; 0.0f64 },
         };
-        self.fgr[self.inst.fd].as_u64 = self.end_fpu_op_convert_long(unsafe { c_rint_f64(input_a) })?;
+        let rounded = self.rint(input_a);
+        self.fgr[self.inst.fd].as_u64 = self.end_fpu_op_convert_long(rounded)?;
         Ok(())
     }
 
     // CEIL.L.fmt
     pub fn op_ceil_L(&mut self) -> Result<(), InstructionFault> {
         self.begin_fpu_op();
-        fesetround(fe_upward); // CEIL rounds up
+        self.set_rounding(RoundingMode_Up); // CEIL rounds up
         let input_a = match self.inst.fmt {
             Format_Single => self.check_input(unsafe { self.fgr[self.inst.fs].as_f32 }, true)? as f64, // .S
             Format_Double => self.check_input(unsafe { self.fgr[self.inst.fs].as_f64 }, true)? as f64, // .D
             _ => { self.update_cause(FpeCause_Unimplemented, true)?; 0.0f64 },
         };
-        self.fgr[self.inst.fd].as_u64 = self.end_fpu_op_convert_long(unsafe { c_rint_f64(input_a) })?;
+        let rounded = self.rint(input_a);
+        self.fgr[self.inst.fd].as_u64 = self.end_fpu_op_convert_long(rounded)?;
         Ok(())
     }
 
     // FLOOR.L.fmt
     pub fn op_floor_L(&mut self) -> Result<(), InstructionFault> {
         self.begin_fpu_op();
-        fesetround(fe_downward); // FLOOR rounds down
+        self.set_rounding(RoundingMode_Down); // FLOOR rounds down
         let input_a = match self.inst.fmt {
             Format_Single => self.check_input(unsafe { self.fgr[self.inst.fs].as_f32 }, true)? as f64, // .S
             Format_Double => self.check_input(unsafe { self.fgr[self.inst.fs].as_f64 }, true)? as f64, // .D
             _ => { self.update_cause(FpeCause_Unimplemented, true)?; 0.0f64 },
         };
-        self.fgr[self.inst.fd].as_u64 = self.end_fpu_op_convert_long(unsafe { c_rint_f64(input_a) })?;
+        let rounded = self.rint(input_a);
+        self.fgr[self.inst.fd].as_u64 = self.end_fpu_op_convert_long(rounded)?;
         Ok(())
     }
 
     // ROUND.W.fmt
     pub fn op_round_W(&mut self) -> Result<(), InstructionFault> {
         self.begin_fpu_op();
-        fesetround(fe_tonearest); // ROUND always rounds to nearest
+        self.set_rounding(RoundingMode_Nearest); // ROUND always rounds to nearest
         let input_a = match self.inst.fmt {
             Format_Single => self.check_input(unsafe { self.fgr[self.inst.fs].as_f32 }, true)? as f64, // .S
             Format_Double => self.check_input(unsafe { self.fgr[self.inst.fs].as_f64 }, true)? as f64, // .D
             _ => { self.update_cause(FpeCause_Unimplemented, true)?; 0.0f64 },
         };
-        let result = self.end_fpu_op_convert_word(unsafe { c_rint_f64(input_a) })?;
+        let rounded = self.rint(input_a);
+        let result = self.end_fpu_op_convert_word(rounded)?;
         self.fgr[self.inst.fd].as_u64 = result as u64; // clear upper bits
         Ok(())
     }
@@ -810,13 +972,14 @@ impl Cop1 {
     // TRUNC.W.fmt
     pub fn op_trunc_W(&mut self) -> Result<(), InstructionFault> {
         self.begin_fpu_op();
-        fesetround(fe_towardzero); // TRUNC always rounds to zero
+        self.set_rounding(RoundingMode_Zero); // TRUNC always rounds to zero
         let input_a = match self.inst.fmt {
             Format_Single => self.check_input(unsafe { self.fgr[self.inst.fs].as_f32 }, true)? as f64, // .S
             Format_Double => self.check_input(unsafe { self.fgr[self.inst.fs].as_f64 }, true)? as f64, // .D
             _ => { self.update_cause(FpeCause_Unimplemented, true)?; 0.0f64 },
         };
-        let result = self.end_fpu_op_convert_word(unsafe { c_rint_f64(input_a) })?;
+        let rounded = self.rint(input_a);
+        let result = self.end_fpu_op_convert_word(rounded)?;
         self.fgr[self.inst.fd].as_u64 = result as u64; // clear upper bits
         Ok(())
     }
@@ -824,13 +987,14 @@ impl Cop1 {
     // CEIL.W.fmt
     pub fn op_ceil_W(&mut self) -> Result<(), InstructionFault> {
         self.begin_fpu_op();
-        fesetround(fe_upward); // CEIL rounds up
+        self.set_rounding(RoundingMode_Up); // CEIL rounds up
         let input_a = match self.inst.fmt {
             Format_Single => self.check_input(unsafe { self.fgr[self.inst.fs].as_f32 }, true)? as f64, // .S
             Format_Double => self.check_input(unsafe { self.fgr[self.inst.fs].as_f64 }, true)? as f64, // .D
             _ => { self.update_cause(FpeCause_Unimplemented, true)?; 0.0f64 },
         };
-        let result = self.end_fpu_op_convert_word(unsafe { c_rint_f64(input_a) })?;
+        let rounded = self.rint(input_a);
+        let result = self.end_fpu_op_convert_word(rounded)?;
         self.fgr[self.inst.fd].as_u64 = result as u64; // clear upper bits
         Ok(())
     }
@@ -838,13 +1002,14 @@ impl Cop1 {
     // FLOOR.W.fmt
     pub fn op_floor_W(&mut self) -> Result<(), InstructionFault> {
         self.begin_fpu_op();
-        fesetround(fe_downward); // FLOOR rounds down
+        self.set_rounding(RoundingMode_Down); // FLOOR rounds down
         let input_a = match self.inst.fmt {
             Format_Single => self.check_input(unsafe { self.fgr[self.inst.fs].as_f32 }, true)? as f64, // .S
             Format_Double => self.check_input(unsafe { self.fgr[self.inst.fs].as_f64 }, true)? as f64, // .D
             _ => { self.update_cause(FpeCause_Unimplemented, true)?; 0.0f64 },
         };
-        let result = self.end_fpu_op_convert_word(unsafe { c_rint_f64(input_a) })?;
+        let rounded = self.rint(input_a);
+        let result = self.end_fpu_op_convert_word(rounded)?;
         self.fgr[self.inst.fd].as_u64 = result as u64; // clear upper bits
         Ok(())
     }
@@ -855,13 +1020,15 @@ impl Cop1 {
         match self.inst.fmt {
             Format_Double => { // .D
                 let input_a = self.check_input(unsafe { self.fgr[self.inst.fs].as_f64 }, false)?;
-                let result = self.end_fpu_op_f32(input_a as f32)?;
+                let converted = self.f64_to_f32(input_a);
+                let result = self.end_fpu_op_f32(converted)?;
                 self.fgr[self.inst.fd].as_u64 = result.to_bits() as u64;
             },
 
             Format_Word => { // .W
                 let input_a = unsafe { self.fgr[self.inst.fs].as_u32 as i32 };
-                let result = self.end_fpu_op_f32(input_a as f32)?;
+                let converted = self.int_to_float(input_a as i64);
+                let result = self.end_fpu_op_f32(converted)?;
                 self.fgr[self.inst.fd].as_u64 = result.to_bits() as u64;
             },
 
@@ -876,7 +1043,8 @@ impl Cop1 {
                     let _ = self.update_cause(FpeCause_Unimplemented, true)?;
                 }
 
-                let result = self.end_fpu_op_f32(input_a as f32)?;
+                let converted = self.int_to_float(input_a);
+                let result = self.end_fpu_op_f32(converted)?;
                 self.fgr[self.inst.fd].as_u64 = result.to_bits() as u64;
             },
 
@@ -893,14 +1061,16 @@ impl Cop1 {
         match self.inst.fmt {
             Format_Single => { // .S
                 let input_a = self.check_input(unsafe { self.fgr[self.inst.fs].as_f32 }, false)?;
-                let result = self.end_fpu_op_f64(input_a as f64)?;
+                let converted = self.f32_to_f64(input_a);
+                let result = self.end_fpu_op_f64(converted)?;
                 self.fgr[self.inst.fd].as_f64 = result;
             },
 
             Format_Word => { // .W
                 let input_a = unsafe { self.fgr[self.inst.fs].as_u32 as i32 };
-                let result = self.end_fpu_op_f64(input_a as f64)?;
-                self.fgr[self.inst.fd].as_f64 = result as f64;
+                let converted = self.int_to_float(input_a as i64);
+                let result = self.end_fpu_op_f64(converted)?;
+                self.fgr[self.inst.fd].as_f64 = result;
             },
 
             Format_Long => { // .L
@@ -914,7 +1084,8 @@ impl Cop1 {
                     let _ = self.update_cause(FpeCause_Unimplemented, true)?;
                 }
 
-                let result = self.end_fpu_op_f64(input_a as f64)?;
+                let converted = self.int_to_float(input_a);
+                let result = self.end_fpu_op_f64(converted)?;
                 self.fgr[self.inst.fd].as_f64 = result;
             },
 
@@ -933,7 +1104,8 @@ impl Cop1 {
             Format_Double => self.check_input(unsafe { self.fgr[self.inst.fs].as_f64 }, true)? as f64, // .D
             _ => { self.update_cause(FpeCause_Unimplemented, true)?; 0.0f64 },
         };
-        let result = self.end_fpu_op_convert_word(unsafe { c_rint_f64(input_a) })?;
+        let rounded = self.rint(input_a);
+        let result = self.end_fpu_op_convert_word(rounded)?;
         self.fgr[self.inst.fd].as_u64 = result as u64; // clear upper bits
         Ok(())
     }
@@ -946,7 +1118,8 @@ impl Cop1 {
             Format_Double => self.check_input(unsafe { self.fgr[self.inst.fs].as_f64 }, true)? as f64, // .D
             _ => { self.update_cause(FpeCause_Unimplemented, true)?; 0.0f64 },
         };
-        self.fgr[self.inst.fd].as_u64 = self.end_fpu_op_convert_long(unsafe { c_rint_f64(input_a) })?;
+        let rounded = self.rint(input_a);
+        self.fgr[self.inst.fd].as_u64 = self.end_fpu_op_convert_long(rounded)?;
         Ok(())
     }
 
@@ -1025,3 +1198,80 @@ impl Cop1 {
         self.function_table[self.inst.special as usize](self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CVT_W_D: u32 = 0x4620_0024 | (2 << 11); // cvt.w.d $f0, $f2
+    const CVT_L_D: u32 = 0x4620_0025 | (2 << 11); // cvt.l.d $f0, $f2
+    const CVT_W_S: u32 = 0x4600_0024 | (2 << 11); // cvt.w.s $f0, $f2
+
+    // run one op on both the host and the soft-float paths with the given FCR31, and return the
+    // result and FCR31 from each
+    fn run(inst: u32, input: u64, control_status: u64) -> [Result<(u64, u64), u64>; 2] {
+        [false, true].map(|soft_float| {
+            let mut cop1 = Cop1::new(soft_float);
+            cop1.set_control_status(control_status);
+            cop1.set_fgr(2, input);
+            match cop1.special(inst) {
+                Ok(()) => Ok((cop1.fgr(0), cop1.control_status())),
+                Err(InstructionFault::FloatingPointException) => Err(cop1.control_status()),
+                Err(_) => panic!("unexpected fault"),
+            }
+        })
+    }
+
+    fn check(inst: u32, input: u64, control_status: u64, expected: Result<(u64, u64), u64>) {
+        for (result, soft_float) in run(inst, input, control_status).iter().zip([false, true]) {
+            assert_eq!(*result, expected, "${:08X} input ${:016X} soft_float={}", inst, input, soft_float);
+        }
+    }
+
+    const INEXACT: u64 = (FpeCause_Inexact << 12) | (FpeCause_Inexact << 2);
+    const UNIMPLEMENTED: u64 = FpeCause_Unimplemented << 12;
+
+    #[test]
+    fn convert_word_limits() {
+        check(CVT_W_D, 2147483647.0f64.to_bits(), 0, Ok((0x7FFF_FFFF, 0)));
+        check(CVT_W_D, (-2147483648.0f64).to_bits(), 0, Ok((0x8000_0000, 0)));
+        check(CVT_W_D, 2147483648.0f64.to_bits(), 0, Err(UNIMPLEMENTED));
+        check(CVT_W_D, (-2147483649.0f64).to_bits(), 0, Err(UNIMPLEMENTED));
+
+        // in range only after rounding, in each mode
+        check(CVT_W_D, 2147483647.4f64.to_bits(), 0, Ok((0x7FFF_FFFF, INEXACT)));
+        check(CVT_W_D, 2147483647.4f64.to_bits(), 1, Ok((0x7FFF_FFFF, 1 | INEXACT)));
+        check(CVT_W_D, 2147483647.4f64.to_bits(), 2, Err(2 | UNIMPLEMENTED));
+        check(CVT_W_D, 2147483647.4f64.to_bits(), 3, Ok((0x7FFF_FFFF, 3 | INEXACT)));
+        check(CVT_W_D, (-2147483648.6f64).to_bits(), 1, Ok((0x8000_0000, 1 | INEXACT)));
+        check(CVT_W_D, (-2147483648.6f64).to_bits(), 3, Err(3 | UNIMPLEMENTED));
+
+        // ties to even
+        check(CVT_W_D, 2.5f64.to_bits(), 0, Ok((2, INEXACT)));
+        check(CVT_W_D, 3.5f64.to_bits(), 0, Ok((4, INEXACT)));
+        check(CVT_W_D, (-2.5f64).to_bits(), 2, Ok((0xFFFF_FFFE, 2 | INEXACT)));
+        check(CVT_W_D, (-2.5f64).to_bits(), 3, Ok((0xFFFF_FFFD, 3 | INEXACT)));
+
+        // the largest single below 2^31
+        check(CVT_W_S, 2147483520.0f32.to_bits() as u64, 0, Ok((0x7FFF_FF80, 0)));
+        check(CVT_W_S, 2147483648.0f32.to_bits() as u64, 0, Err(UNIMPLEMENTED));
+
+        // an enabled inexact exception traps instead of writing
+        check(CVT_W_D, 2.5f64.to_bits(), 1 << 7, Err((1 << 7) | (FpeCause_Inexact << 12)));
+    }
+
+    #[test]
+    fn convert_long_limits() {
+        let limit = (1u64 << 53) as f64;
+        check(CVT_L_D, (limit - 1.0).to_bits(), 0, Ok(((1 << 53) - 1, 0)));
+        check(CVT_L_D, (1.0 - limit).to_bits(), 0, Ok(((1 - (1i64 << 53)) as u64, 0)));
+        check(CVT_L_D, limit.to_bits(), 0, Err(UNIMPLEMENTED));
+        check(CVT_L_D, (-limit).to_bits(), 0, Err(UNIMPLEMENTED));
+        check(CVT_L_D, 9.3e18f64.to_bits(), 0, Err(UNIMPLEMENTED));
+
+        // 2^52 - 0.5 is the only kind of value near the limit that still rounds
+        let value = (1u64 << 52) as f64 - 0.5;
+        check(CVT_L_D, value.to_bits(), 0, Ok((1 << 52, INEXACT)));
+        check(CVT_L_D, value.to_bits(), 1, Ok(((1 << 52) - 1, 1 | INEXACT)));
+    }
+}
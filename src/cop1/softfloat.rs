@@ -0,0 +1,537 @@
+// Software IEEE 754 arithmetic for COP1
+//
+// Every operation returns its result along with the FpeCause bits it raised, so nothing depends
+// on the host FPU, its rounding mode or its exception flags. Results are IEEE 754 results in the
+// four VR4300 rounding modes, and the flags follow the VR4300 rules for them: tininess is
+// detected after rounding, Underflow is raised for a tiny result only when it is also inexact,
+// and Overflow always comes with Inexact. Cop1 then applies what the VR4300 doesn't do in
+// hardware: subnormal results (exact or not) and NaN operands trap as unimplemented operations
+// or are flushed, and NaN results are replaced with the VR4300's own.
+use super::{FpeCause_Inexact, FpeCause_Underflow, FpeCause_Overflow, FpeCause_DivByZero, FpeCause_Invalid};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Rounding {
+    Nearest,
+    Zero,
+    Up,
+    Down,
+}
+
+impl Rounding {
+    // from the RM field of FCR31
+    pub fn from_rm(rm: u64) -> Self {
+        match rm & 0x03 {
+            0b00 => Rounding::Nearest,
+            0b01 => Rounding::Zero,
+            0b10 => Rounding::Up,
+            _    => Rounding::Down,
+        }
+    }
+}
+
+// Layout of a binary floating point format
+pub trait Format: Copy {
+    const MANTISSA_BITS: u32;
+    const EXPONENT_BITS: u32;
+
+    fn to_raw(self) -> u64;
+    fn from_raw(bits: u64) -> Self;
+}
+
+impl Format for f32 {
+    const MANTISSA_BITS: u32 = 23;
+    const EXPONENT_BITS: u32 = 8;
+
+    fn to_raw(self) -> u64 { self.to_bits() as u64 }
+    fn from_raw(bits: u64) -> Self { f32::from_bits(bits as u32) }
+}
+
+impl Format for f64 {
+    const MANTISSA_BITS: u32 = 52;
+    const EXPONENT_BITS: u32 = 11;
+
+    fn to_raw(self) -> u64 { self.to_bits() }
+    fn from_raw(bits: u64) -> Self { f64::from_bits(bits) }
+}
+
+enum Value {
+    Nan(bool),                // true when signalling
+    Infinity(bool),           // sign
+    Zero(bool),               // sign
+    Finite(bool, i32, u128),  // sign, exponent and significand, for significand * 2^exponent
+}
+
+use Value::*;
+
+fn bias<F: Format>() -> i32 {
+    (1 << (F::EXPONENT_BITS - 1)) - 1
+}
+
+fn max_biased_exponent<F: Format>() -> u64 {
+    (1 << F::EXPONENT_BITS) - 1
+}
+
+fn mantissa_mask<F: Format>() -> u64 {
+    (1 << F::MANTISSA_BITS) - 1
+}
+
+fn sign_bit<F: Format>(sign: bool) -> u64 {
+    (sign as u64) << (F::MANTISSA_BITS + F::EXPONENT_BITS)
+}
+
+fn zero<F: Format>(sign: bool) -> F {
+    F::from_raw(sign_bit::<F>(sign))
+}
+
+fn infinity<F: Format>(sign: bool) -> F {
+    F::from_raw(sign_bit::<F>(sign) | (max_biased_exponent::<F>() << F::MANTISSA_BITS))
+}
+
+fn largest<F: Format>(sign: bool) -> F {
+    F::from_raw(sign_bit::<F>(sign) | ((max_biased_exponent::<F>() - 1) << F::MANTISSA_BITS) | mantissa_mask::<F>())
+}
+
+// Cop1 replaces NaN results with its own, so the payload doesn't matter
+fn default_nan<F: Format>() -> F {
+    F::from_raw((max_biased_exponent::<F>() << F::MANTISSA_BITS) | (1 << (F::MANTISSA_BITS - 1)))
+}
+
+fn unpack<F: Format>(value: F) -> Value {
+    let bits = value.to_raw();
+    let sign = (bits >> (F::MANTISSA_BITS + F::EXPONENT_BITS)) != 0;
+    let biased_exponent = (bits >> F::MANTISSA_BITS) & max_biased_exponent::<F>();
+    let mantissa = bits & mantissa_mask::<F>();
+
+    // the exponent of the lowest significand bit of subnormals and the smallest normals
+    let min_exponent = 1 - bias::<F>() - (F::MANTISSA_BITS as i32);
+
+    if biased_exponent == max_biased_exponent::<F>() {
+        if mantissa == 0 {
+            Infinity(sign)
+        } else {
+            Nan((mantissa >> (F::MANTISSA_BITS - 1)) == 0)
+        }
+    } else if biased_exponent == 0 {
+        if mantissa == 0 {
+            Zero(sign)
+        } else {
+            Finite(sign, min_exponent, mantissa as u128)
+        }
+    } else {
+        let implicit_one = 1 << F::MANTISSA_BITS;
+        Finite(sign, min_exponent + (biased_exponent as i32) - 1, (mantissa | implicit_one) as u128)
+    }
+}
+
+// NaN inputs give a NaN, and only signalling NaNs are invalid
+fn propagate_nan<F: Format>(a: &Value, b: &Value) -> (F, u64) {
+    let signalling = matches!(a, Nan(true)) || matches!(b, Nan(true));
+    (default_nan(), if signalling { FpeCause_Invalid } else { 0 })
+}
+
+fn invalid<F: Format>() -> (F, u64) {
+    (default_nan(), FpeCause_Invalid)
+}
+
+// Drop the lowest `shift` bits of significand, rounding what's kept. Returns whether any of the
+// dropped bits were set
+fn round_bits(significand: u128, shift: u32, rounding: Rounding, sign: bool) -> (u128, bool) {
+    let kept = significand >> shift;
+    let rest = significand & ((1 << shift) - 1);
+    let half = 1 << (shift - 1);
+    let inexact = rest != 0;
+
+    let round_up = match rounding {
+        Rounding::Nearest => rest > half || (rest == half && (kept & 1) != 0),
+        Rounding::Zero    => false,
+        Rounding::Up      => inexact && !sign,
+        Rounding::Down    => inexact && sign,
+    };
+
+    (kept + (round_up as u128), inexact)
+}
+
+// Round sign * significand * 2^exponent to the format. The lowest bit of significand may be a
+// sticky bit standing in for anything below it, as long as there are plenty of bits above it
+fn round_pack<F: Format>(sign: bool, exponent: i32, significand: u128, rounding: Rounding) -> (F, u64) {
+    if significand == 0 {
+        return (zero(sign), 0);
+    }
+
+    // move the leading one to bit 125, which leaves room for the carry out of rounding
+    let msb = 127 - (significand.leading_zeros() as i32);
+    let (significand, exponent) = if msb > 125 {
+        let shift = msb - 125;
+        let sticky = (significand & ((1 << shift) - 1)) != 0;
+        ((significand >> shift) | (sticky as u128), exponent + shift)
+    } else {
+        (significand << (125 - msb), exponent - (125 - msb))
+    };
+
+    let precision = F::MANTISSA_BITS + 1;
+    let min_normal_exponent = 1 - bias::<F>();
+    let max_exponent = bias::<F>();
+
+    // the exponent of the leading one
+    let mut leading_exponent = exponent + 125;
+
+    // tininess is decided by rounding to full precision as if the exponent range were unbounded
+    let (unbounded, _) = round_bits(significand, 126 - precision, rounding, sign);
+    let tiny = leading_exponent + ((unbounded >> precision) as i32) < min_normal_exponent;
+
+    // subnormal results have fewer significant bits
+    let shift = if leading_exponent < min_normal_exponent {
+        (126 - precision) as i32 + (min_normal_exponent - leading_exponent)
+    } else {
+        (126 - precision) as i32
+    };
+    let (mut kept, inexact) = round_bits(significand, shift.min(127) as u32, rounding, sign);
+
+    let mut flags = if inexact { FpeCause_Inexact } else { 0 };
+    if tiny && inexact {
+        flags |= FpeCause_Underflow;
+    }
+
+    if leading_exponent < min_normal_exponent {
+        // a subnormal that rounds up to the smallest normal carries into the exponent field
+        return (F::from_raw(sign_bit::<F>(sign) | (kept as u64)), flags);
+    }
+
+    if (kept >> precision) != 0 {
+        kept >>= 1;
+        leading_exponent += 1;
+    }
+
+    if leading_exponent > max_exponent {
+        let to_infinity = match rounding {
+            Rounding::Nearest => true,
+            Rounding::Zero    => false,
+            Rounding::Up      => !sign,
+            Rounding::Down    => sign,
+        };
+        let result = if to_infinity { infinity(sign) } else { largest(sign) };
+        return (result, flags | FpeCause_Overflow | FpeCause_Inexact);
+    }
+
+    let biased_exponent = (leading_exponent + bias::<F>()) as u64;
+    (F::from_raw(sign_bit::<F>(sign) | (biased_exponent << F::MANTISSA_BITS) | ((kept as u64) & mantissa_mask::<F>())), flags)
+}
+
+fn add_finite<F: Format>(a: (bool, i32, u128), b: (bool, i32, u128), rounding: Rounding) -> (F, u64) {
+    let (a, b) = if a.1 >= b.1 { (a, b) } else { (b, a) };
+    let (sign_a, exponent_a, significand_a) = a;
+    let (sign_b, exponent_b, significand_b) = b;
+
+    // line both up at the smaller exponent. an operand that's much smaller can only affect
+    // rounding, so it's replaced by a sticky bit well below the result's rounding position
+    let difference = exponent_a - exponent_b;
+    let (exponent, significand_a, significand_b) = if difference <= 72 {
+        (exponent_b, significand_a << difference, significand_b)
+    } else {
+        (exponent_a - 20, significand_a << 20, 1)
+    };
+
+    let value_a = if sign_a { -(significand_a as i128) } else { significand_a as i128 };
+    let value_b = if sign_b { -(significand_b as i128) } else { significand_b as i128 };
+    let sum = value_a + value_b;
+
+    // exact cancellation is +0, except when rounding down
+    if sum == 0 {
+        return (zero(rounding == Rounding::Down), 0);
+    }
+
+    round_pack(sum < 0, exponent, sum.unsigned_abs(), rounding)
+}
+
+pub fn add<F: Format>(a: F, b: F, rounding: Rounding) -> (F, u64) {
+    let (a_bits, b_bits) = (a, b);
+    match (unpack(a), unpack(b)) {
+        (a @ Nan(_), b) | (a, b @ Nan(_)) => propagate_nan(&a, &b),
+        (Infinity(sign_a), Infinity(sign_b)) => if sign_a == sign_b { (infinity(sign_a), 0) } else { invalid() },
+        (Infinity(sign), _) | (_, Infinity(sign)) => (infinity(sign), 0),
+        (Zero(sign_a), Zero(sign_b)) => (zero(if sign_a == sign_b { sign_a } else { rounding == Rounding::Down }), 0),
+        (Zero(_), _) => (b_bits, 0),
+        (_, Zero(_)) => (a_bits, 0),
+        (Finite(sa, ea, ma), Finite(sb, eb, mb)) => add_finite((sa, ea, ma), (sb, eb, mb), rounding),
+    }
+}
+
+pub fn sub<F: Format>(a: F, b: F, rounding: Rounding) -> (F, u64) {
+    add(a, F::from_raw(b.to_raw() ^ sign_bit::<F>(true)), rounding)
+}
+
+pub fn mul<F: Format>(a: F, b: F, rounding: Rounding) -> (F, u64) {
+    match (unpack(a), unpack(b)) {
+        (a @ Nan(_), b) | (a, b @ Nan(_)) => propagate_nan(&a, &b),
+        (Infinity(_), Zero(_)) | (Zero(_), Infinity(_)) => invalid(),
+        (Infinity(sign_a), Infinity(sign_b)) | (Infinity(sign_a), Finite(sign_b, _, _)) | (Finite(sign_a, _, _), Infinity(sign_b))
+            => (infinity(sign_a ^ sign_b), 0),
+        (Zero(sign_a), Zero(sign_b)) | (Zero(sign_a), Finite(sign_b, _, _)) | (Finite(sign_a, _, _), Zero(sign_b))
+            => (zero(sign_a ^ sign_b), 0),
+        (Finite(sa, ea, ma), Finite(sb, eb, mb)) => round_pack(sa ^ sb, ea + eb, ma * mb, rounding),
+    }
+}
+
+pub fn div<F: Format>(a: F, b: F, rounding: Rounding) -> (F, u64) {
+    match (unpack(a), unpack(b)) {
+        (a @ Nan(_), b) | (a, b @ Nan(_)) => propagate_nan(&a, &b),
+        (Infinity(_), Infinity(_)) | (Zero(_), Zero(_)) => invalid(),
+        (Infinity(sign_a), Zero(sign_b)) | (Infinity(sign_a), Finite(sign_b, _, _)) => (infinity(sign_a ^ sign_b), 0),
+        (Zero(sign_a), Infinity(sign_b)) | (Finite(sign_a, _, _), Infinity(sign_b)) => (zero(sign_a ^ sign_b), 0),
+        (Zero(sign_a), Finite(sign_b, _, _)) => (zero(sign_a ^ sign_b), 0),
+        (Finite(sign_a, _, _), Zero(sign_b)) => (infinity(sign_a ^ sign_b), FpeCause_DivByZero),
+        (Finite(sa, ea, ma), Finite(sb, eb, mb)) => {
+            // widen the dividend so the quotient has far more bits than needed, and keep
+            // any remainder as a sticky bit
+            let shift = 125 - (127 - (ma.leading_zeros() as i32));
+            let dividend = ma << shift;
+            let quotient = (dividend / mb) | (((dividend % mb) != 0) as u128);
+            round_pack(sa ^ sb, ea - shift - eb, quotient, rounding)
+        },
+    }
+}
+
+fn isqrt(value: u128) -> u128 {
+    let mut remainder = value;
+    let mut result = 0u128;
+    let mut bit = 1u128 << 126;
+    while bit > value {
+        bit >>= 2;
+    }
+
+    while bit != 0 {
+        if remainder >= result + bit {
+            remainder -= result + bit;
+            result = (result >> 1) + bit;
+        } else {
+            result >>= 1;
+        }
+        bit >>= 2;
+    }
+
+    result
+}
+
+pub fn sqrt<F: Format>(a: F, rounding: Rounding) -> (F, u64) {
+    match unpack(a) {
+        a @ Nan(_) => propagate_nan(&a, &Zero(false)),
+        Zero(sign) => (zero(sign), 0),
+        Infinity(false) => (infinity(false), 0),
+        Infinity(true) | Finite(true, _, _) => invalid(),
+        Finite(false, exponent, significand) => {
+            // widen to about 124 bits, keeping the exponent even so it can be halved
+            let msb = 127 - (significand.leading_zeros() as i32);
+            let mut shift = 124 - msb;
+            if ((exponent - shift) & 1) != 0 {
+                shift += 1;
+            }
+
+            let widened = significand << shift;
+            let root = isqrt(widened);
+            let sticky = (root * root) != widened;
+            round_pack(false, (exponent - shift) / 2, root | (sticky as u128), rounding)
+        },
+    }
+}
+
+// Convert between formats, like CVT.S.D and CVT.D.S
+pub fn convert<F: Format, G: Format>(a: F, rounding: Rounding) -> (G, u64) {
+    match unpack(a) {
+        a @ Nan(_) => propagate_nan(&a, &Zero(false)),
+        Infinity(sign) => (infinity(sign), 0),
+        Zero(sign) => (zero(sign), 0),
+        Finite(sign, exponent, significand) => round_pack(sign, exponent, significand, rounding),
+    }
+}
+
+// Convert a signed integer, like CVT.S.W and CVT.D.L
+pub fn from_int<F: Format>(value: i64, rounding: Rounding) -> (F, u64) {
+    round_pack(value < 0, 0, value.unsigned_abs() as u128, rounding)
+}
+
+// Round to an integral value in the same format, like C's rint()
+pub fn round_to_integral(a: f64, rounding: Rounding) -> (f64, u64) {
+    match unpack(a) {
+        a @ Nan(_) => propagate_nan(&a, &Zero(false)),
+        Infinity(_) | Zero(_) => (a, 0),
+        Finite(_, exponent, _) if exponent >= 0 => (a, 0),
+        Finite(sign, exponent, significand) => {
+            let (integer, inexact) = round_bits(significand, (-exponent).min(127) as u32, rounding, sign);
+            // integer has no more than 53 bits, so it converts exactly
+            let magnitude = integer as f64;
+            (if sign { -magnitude } else { magnitude }, if inexact { FpeCause_Inexact } else { 0 })
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Rounding; 4] = [Rounding::Nearest, Rounding::Zero, Rounding::Up, Rounding::Down];
+
+    fn f32_op(op: fn(f32, f32, Rounding) -> (f32, u64), a: u32, b: u32, rounding: Rounding) -> (u32, u64) {
+        let (result, flags) = op(f32::from_bits(a), f32::from_bits(b), rounding);
+        (result.to_bits(), flags)
+    }
+
+    fn f64_op(op: fn(f64, f64, Rounding) -> (f64, u64), a: u64, b: u64, rounding: Rounding) -> (u64, u64) {
+        let (result, flags) = op(f64::from_bits(a), f64::from_bits(b), rounding);
+        (result.to_bits(), flags)
+    }
+
+    // results of a and b in Nearest, Zero, Up and Down
+    fn check_f32(op: fn(f32, f32, Rounding) -> (f32, u64), a: u32, b: u32, expected: [(u32, u64); 4]) {
+        for (rounding, expected) in ALL.iter().zip(expected) {
+            assert_eq!(f32_op(op, a, b, *rounding), expected, "${:08X}, ${:08X} rounding {:?}", a, b, rounding);
+        }
+    }
+
+    fn check_f64(op: fn(f64, f64, Rounding) -> (f64, u64), a: u64, b: u64, expected: [(u64, u64); 4]) {
+        for (rounding, expected) in ALL.iter().zip(expected) {
+            assert_eq!(f64_op(op, a, b, *rounding), expected, "${:016X}, ${:016X} rounding {:?}", a, b, rounding);
+        }
+    }
+
+    const I: u64 = FpeCause_Inexact;
+    const U: u64 = FpeCause_Underflow;
+    const O: u64 = FpeCause_Overflow;
+    const Z: u64 = FpeCause_DivByZero;
+    const V: u64 = FpeCause_Invalid;
+
+    #[test]
+    fn rounding_modes() {
+        // 1 + half an ulp ties to even, and -1 - half an ulp
+        check_f32(add, 0x3F80_0000, 0x3380_0000, [(0x3F80_0000, I), (0x3F80_0000, I), (0x3F80_0001, I), (0x3F80_0000, I)]);
+        check_f32(add, 0xBF80_0000, 0xB380_0000, [(0xBF80_0000, I), (0xBF80_0000, I), (0xBF80_0000, I), (0xBF80_0001, I)]);
+
+        // an odd last bit ties upwards, and more than half an ulp rounds up
+        check_f32(add, 0x3F80_0001, 0x3380_0000, [(0x3F80_0002, I), (0x3F80_0001, I), (0x3F80_0002, I), (0x3F80_0001, I)]);
+        check_f32(add, 0x3F80_0000, 0x33C0_0000, [(0x3F80_0001, I), (0x3F80_0000, I), (0x3F80_0001, I), (0x3F80_0000, I)]);
+
+        // 1/3 and -1/3
+        check_f32(div, 0x3F80_0000, 0x4040_0000, [(0x3EAA_AAAB, I), (0x3EAA_AAAA, I), (0x3EAA_AAAB, I), (0x3EAA_AAAA, I)]);
+        check_f32(div, 0xBF80_0000, 0x4040_0000, [(0xBEAA_AAAB, I), (0xBEAA_AAAA, I), (0xBEAA_AAAA, I), (0xBEAA_AAAB, I)]);
+        check_f64(div, 0x3FF0_0000_0000_0000, 0x4008_0000_0000_0000,
+                  [(0x3FD5_5555_5555_5555, I), (0x3FD5_5555_5555_5555, I), (0x3FD5_5555_5555_5556, I), (0x3FD5_5555_5555_5555, I)]);
+
+        // sqrt(2)
+        check_f32(|a, _, rounding| sqrt(a, rounding), 0x4000_0000, 0,
+                  [(0x3FB5_04F3, I), (0x3FB5_04F3, I), (0x3FB5_04F4, I), (0x3FB5_04F3, I)]);
+
+        // exact results raise nothing
+        check_f32(mul, 0x4040_0000, 0x4080_0000, [(0x4140_0000, 0); 4]);
+
+        // exact cancellation is -0 only when rounding down
+        check_f32(sub, 0x3F80_0000, 0x3F80_0000, [(0x0000_0000, 0), (0x0000_0000, 0), (0x0000_0000, 0), (0x8000_0000, 0)]);
+    }
+
+    #[test]
+    fn overflow() {
+        // the largest finite value doubled goes to infinity or stays at the largest finite value
+        check_f32(mul, 0x7F7F_FFFF, 0x4000_0000, [(0x7F80_0000, O | I), (0x7F7F_FFFF, O | I), (0x7F80_0000, O | I), (0x7F7F_FFFF, O | I)]);
+        check_f32(mul, 0xFF7F_FFFF, 0x4000_0000, [(0xFF80_0000, O | I), (0xFF7F_FFFF, O | I), (0xFF7F_FFFF, O | I), (0xFF80_0000, O | I)]);
+        check_f64(add, 0x7FEF_FFFF_FFFF_FFFF, 0x7FEF_FFFF_FFFF_FFFF,
+                  [(0x7FF0_0000_0000_0000, O | I), (0x7FEF_FFFF_FFFF_FFFF, O | I), (0x7FF0_0000_0000_0000, O | I), (0x7FEF_FFFF_FFFF_FFFF, O | I)]);
+
+        // narrowing a double that's too large for a single
+        let (result, flags) = convert::<f64, f32>(1e300, Rounding::Nearest);
+        assert_eq!((result.to_bits(), flags), (0x7F80_0000, O | I));
+    }
+
+    #[test]
+    fn subnormals_and_tininess() {
+        // exact subnormal results are tiny but not inexact, so no Underflow
+        check_f32(mul, 0x0080_0000, 0x3F00_0000, [(0x0040_0000, 0); 4]);
+        check_f64(mul, 0x0010_0000_0000_0000, 0x3FE0_0000_0000_0000, [(0x0008_0000_0000_0000, 0); 4]);
+
+        // half the smallest subnormal ties to zero
+        check_f32(mul, 0x0000_0001, 0x3F00_0000, [(0x0000_0000, U | I), (0x0000_0000, U | I), (0x0000_0001, U | I), (0x0000_0000, U | I)]);
+        check_f32(mul, 0x8000_0001, 0x3F00_0000, [(0x8000_0000, U | I), (0x8000_0000, U | I), (0x8000_0000, U | I), (0x8000_0001, U | I)]);
+        check_f64(mul, 0x0000_0000_0000_0001, 0x3FE0_0000_0000_0000,
+                  [(0, U | I), (0, U | I), (1, U | I), (0, U | I)]);
+
+        // the smallest normal times 1-2^-24 is exact with unbounded exponent, so it's tiny even when
+        // it rounds up to the smallest normal
+        check_f32(mul, 0x0080_0000, 0x3F7F_FFFF, [(0x0080_0000, U | I), (0x007F_FFFF, U | I), (0x0080_0000, U | I), (0x007F_FFFF, U | I)]);
+
+        // 2^-126 * (1 - 2^-25) rounds to 2^-126 at full precision, so it isn't tiny after rounding
+        // unless rounding towards zero
+        let value = f64::from_bits(0x380F_FFFF_F000_0000);
+        let results = ALL.map(|rounding| {
+            let (result, flags): (f32, u64) = convert(value, rounding);
+            (result.to_bits(), flags)
+        });
+        assert_eq!(results, [(0x0080_0000, I), (0x007F_FFFF, U | I), (0x0080_0000, I), (0x007F_FFFF, U | I)]);
+
+        // subnormal operands are used as they are
+        check_f32(add, 0x0000_0001, 0x0000_0001, [(0x0000_0002, 0); 4]);
+        check_f32(mul, 0x0040_0000, 0x4000_0000, [(0x0080_0000, 0); 4]);
+    }
+
+    #[test]
+    fn invalid_and_division_by_zero() {
+        let is_nan = |bits: u32| (bits & 0x7F80_0000) == 0x7F80_0000 && (bits & 0x007F_FFFF) != 0;
+
+        for (op, a, b) in [(sub as fn(f32, f32, Rounding) -> (f32, u64), 0x7F80_0000, 0x7F80_0000), // inf - inf
+                           (mul, 0x0000_0000, 0x7F80_0000), // 0 * inf
+                           (div, 0x0000_0000, 0x8000_0000), // 0 / -0
+                           (div, 0xFF80_0000, 0x7F80_0000), // -inf / inf
+                           (add, 0x7F80_0001, 0x3F80_0000)] { // signalling NaN
+            let (result, flags) = f32_op(op, a, b, Rounding::Nearest);
+            assert!(is_nan(result) && flags == V, "${:08X}, ${:08X}: ${:08X} {:X}", a, b, result, flags);
+        }
+
+        // quiet NaNs pass through without raising anything
+        let (result, flags) = f32_op(add, 0x7FC0_0000, 0x3F80_0000, Rounding::Nearest);
+        assert!(is_nan(result) && flags == 0);
+
+        let (result, flags) = sqrt(-1.0f64, Rounding::Nearest);
+        assert!(result.is_nan() && flags == V);
+        assert_eq!(sqrt(-0.0f64, Rounding::Nearest).0.to_bits(), 0x8000_0000_0000_0000);
+
+        check_f32(div, 0x3F80_0000, 0x0000_0000, [(0x7F80_0000, Z); 4]);
+        check_f32(div, 0x3F80_0000, 0x8000_0000, [(0xFF80_0000, Z); 4]);
+        check_f64(div, 0xBFF0_0000_0000_0000, 0, [(0xFFF0_0000_0000_0000, Z); 4]);
+    }
+
+    #[test]
+    fn integer_limits() {
+        let from = |value: i64, rounding: Rounding| -> ((u32, u64), (u64, u64)) {
+            let (single, single_flags): (f32, u64) = from_int(value, rounding);
+            let (double, double_flags): (f64, u64) = from_int(value, rounding);
+            ((single.to_bits(), single_flags), (double.to_bits(), double_flags))
+        };
+
+        // i32 limits, where only the single is inexact
+        assert_eq!(from(i32::MAX as i64, Rounding::Nearest), ((0x4F00_0000, I), (0x41DF_FFFF_FFC0_0000, 0)));
+        assert_eq!(from(i32::MAX as i64, Rounding::Zero), ((0x4EFF_FFFF, I), (0x41DF_FFFF_FFC0_0000, 0)));
+        assert_eq!(from(i32::MIN as i64, Rounding::Nearest), ((0xCF00_0000, 0), (0xC1E0_0000_0000_0000, 0)));
+
+        // i64 limits
+        assert_eq!(from(i64::MAX, Rounding::Nearest), ((0x5F00_0000, I), (0x43E0_0000_0000_0000, I)));
+        assert_eq!(from(i64::MAX, Rounding::Down), ((0x5EFF_FFFF, I), (0x43DF_FFFF_FFFF_FFFF, I)));
+        assert_eq!(from(i64::MIN, Rounding::Nearest), ((0xDF00_0000, 0), (0xC3E0_0000_0000_0000, 0)));
+        assert_eq!(from(i64::MIN + 1, Rounding::Up), ((0xDEFF_FFFF, I), (0xC3DF_FFFF_FFFF_FFFF, I)));
+
+        // 2^53 + 1 is the first integer a double can't hold
+        assert_eq!(from((1 << 53) + 1, Rounding::Nearest).1, (0x4340_0000_0000_0000, I));
+        assert_eq!(from((1 << 53) + 1, Rounding::Up).1, (0x4340_0000_0000_0001, I));
+
+        // rounding to integral values around the i32 and 2^52 limits
+        let rint = |value: f64, rounding: Rounding| {
+            let (result, flags) = round_to_integral(value, rounding);
+            (result, flags)
+        };
+        assert_eq!(rint(2147483647.5, Rounding::Nearest), (2147483648.0, I));
+        assert_eq!(rint(2147483647.5, Rounding::Zero), (2147483647.0, I));
+        assert_eq!(rint(-2147483648.5, Rounding::Nearest), (-2147483648.0, I));
+        assert_eq!(rint(-2147483648.5, Rounding::Down), (-2147483649.0, I));
+        assert_eq!(rint(4503599627370495.5, Rounding::Nearest), (4503599627370496.0, I));
+        assert_eq!(rint(4503599627370497.0, Rounding::Nearest), (4503599627370497.0, 0));
+        assert_eq!(rint(-2.5, Rounding::Nearest), (-2.0, I));
+        assert_eq!(rint(-2.5, Rounding::Up), (-2.0, I));
+        assert_eq!(rint(-2.5, Rounding::Down), (-3.0, I));
+        assert_eq!(rint(3.5, Rounding::Nearest), (4.0, I));
+    }
+}
@@ -4,35 +4,44 @@ use std::cell::RefCell;
 use std::rc::Rc;
 
 #[allow(unused_imports)]
-use tracing::{debug, error, warn, info};
+use tracing::{debug, error, warn, info, trace};
 
 use crate::*;
+use block_cache::{Block, BlockCache, CodePages};
+use cache::{DCache, ICache};
+
+#[cfg(feature = "jit")]
+mod jit;
+#[cfg(feature = "jit")]
+pub use jit::JitMode;
 
 // Exception handling registers
-const Cop0_Index   : usize = 0;
-const Cop0_Random  : usize = 1;
-const Cop0_EntryLo0: usize = 2;
-const Cop0_EntryLo1: usize = 3;
-const Cop0_Context : usize = 4;
-const Cop0_PageMask: usize = 5;
-const Cop0_Wired   : usize = 6;
-const Cop0_BadVAddr: usize = 8; // Bad Virtual Address
-const Cop0_Count   : usize = 9;
-const Cop0_EntryHi : usize = 10;
-const Cop0_Compare : usize = 11;
-const Cop0_Status  : usize = 12;
-const Cop0_Cause   : usize = 13;
-const Cop0_EPC     : usize = 14; // Exception Program Counter
-const Cop0_PRId    : usize = 15; // Processor Revision Identifier
-const _COP0_WATCHLO : usize = 18;
-const _COP0_WATCHHI : usize = 19;
-const Cop0_XContext: usize = 20;
-const Cop0_PErr    : usize = 26; // Parity Error
-const Cop0_CacheErr: usize = 27;
+pub const Cop0_Index   : usize = 0;
+pub const Cop0_Random  : usize = 1;
+pub const Cop0_EntryLo0: usize = 2;
+pub const Cop0_EntryLo1: usize = 3;
+pub const Cop0_Context : usize = 4;
+pub const Cop0_PageMask: usize = 5;
+pub const Cop0_Wired   : usize = 6;
+pub const Cop0_BadVAddr: usize = 8; // Bad Virtual Address
+pub const Cop0_Count   : usize = 9;
+pub const Cop0_EntryHi : usize = 10;
+pub const Cop0_Compare : usize = 11;
+pub const Cop0_Status  : usize = 12;
+pub const Cop0_Cause   : usize = 13;
+pub const Cop0_EPC     : usize = 14; // Exception Program Counter
+pub const Cop0_PRId    : usize = 15; // Processor Revision Identifier
+pub const Cop0_WatchLo : usize = 18;
+pub const Cop0_WatchHi : usize = 19;
+pub const Cop0_XContext: usize = 20;
+pub const Cop0_PErr    : usize = 26; // Parity Error
+pub const Cop0_CacheErr: usize = 27;
+pub const Cop0_TagLo   : usize = 28;
+pub const Cop0_TagHi   : usize = 29;
 const _COP0_ERROREPC: usize = 30; // Error Exception Program Counter
 
-const Cop0_Config: usize = 16;
-const Cop0_LLAddr: usize = 17;
+pub const Cop0_Config: usize = 16;
+pub const Cop0_LLAddr: usize = 17;
 
 const STATUS_IM_TIMER_INTERRUPT_ENABLE_FLAG: u64 = 7;
 
@@ -42,8 +51,8 @@ const ExceptionCode_TLBL : u64 = 2;  // TLB Miss exception (load or instruction
 const ExceptionCode_TLBS : u64 = 3;  // TLB Miss exception (store)
 const ExceptionCode_AdEL : u64 = 4;  // Address Error exception (load or instruction fetch)
 const ExceptionCode_AdES : u64 = 5;  // Address Error exception (store)
-const _ExceptionCode_IBE  : u64 = 6;  // Bus Error exception (instruction fetch)
-const _ExceptionCode_DBE  : u64 = 7;  // Bus Error exception (data reference: load or store)
+const ExceptionCode_IBE  : u64 = 6;  // Bus Error exception (instruction fetch)
+const ExceptionCode_DBE  : u64 = 7;  // Bus Error exception (data reference: load or store)
 const ExceptionCode_Sys  : u64 = 8;  // Syscall exception
 const ExceptionCode_Bp   : u64 = 9;  // Breakpoint exception
 const ExceptionCode_RI   : u64 = 10; // Reserved Instruction exception
@@ -51,12 +60,20 @@ const ExceptionCode_CpU  : u64 = 11; // Coprocessor Unusable exception
 const ExceptionCode_Ov   : u64 = 12; // Arithmetic Overflow exception
 const ExceptionCode_Tr   : u64 = 13; // Trap instruction
 const ExceptionCode_FPE  : u64 = 15; // Floating-Point exception
-const _ExceptionCode_WATCH: u64 = 23; // Watch exception
+const ExceptionCode_WATCH: u64 = 23; // Watch exception
 
 const InterruptCode_RPC: u64 = 0x04;
 const InterruptCode_Timer: u64 = 0x80;
 
-#[derive(Debug, Default)]
+// Approximate cost of an uncached access over the SysAD bus, in PCycles. Cached accesses are
+// assumed to hit and complete inside the pipeline
+const UNCACHED_RDRAM_CYCLES: u64 = 31;
+const UNCACHED_RCP_CYCLES  : u64 = 20;
+
+// longest run of uncached instructions decoded at once. cached blocks end at the I-cache line
+const MAX_BLOCK_INSTRUCTIONS: u64 = 64;
+
+#[derive(Debug, Default, Copy, Clone)]
 pub struct InstructionDecode {
     pub v : u32,      // full 32-bit instruction
     pub op: u32,      // 6-bit opcode field
@@ -81,11 +98,18 @@ pub struct InstructionDecode {
     pub target: u32,
 }
 
+// an instruction decoded ahead of time along with the handler that executes it
+#[derive(Copy, Clone)]
+struct DecodedInstruction {
+    decode: InstructionDecode,
+    handler: CpuInstruction,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 enum MemorySpace {
     User,
     Supervisor,
-    XKPhys, // TODO
+    XKPhys,
     Kernel
 }
 
@@ -100,12 +124,33 @@ struct Address {
                               // in a mapped address
 }
 
+// a call seen by the shadow call stack
+#[derive(Copy, Clone, Debug)]
+pub struct CallFrame {
+    pub call_pc: u64,        // address of the jal/jalr
+    pub target: u64,
+    pub return_address: u64,
+    pub sp: u64,             // sp at the time of the call
+}
+
+// calls deeper than this drop the oldest frames, so code that never returns doesn't grow the stack forever
+const CALL_STACK_LIMIT: usize = 1024;
+
 #[derive(Copy, Clone, Debug, Default)]
-struct TlbEntry {
-    page_mask: u64,
-    entry_hi: u64,
-    entry_lo1: u64,
-    entry_lo0: u64
+pub struct TlbEntry {
+    pub page_mask: u64,
+    pub entry_hi: u64,
+    pub entry_lo1: u64,
+    pub entry_lo0: u64
+}
+
+// how a virtual address translates, for the debugger
+#[derive(Copy, Clone, Debug)]
+pub struct AddressTranslation {
+    pub physical_address: u64,
+    pub cached: bool,
+    pub space: &'static str,
+    pub tlb_index: Option<usize>,
 }
 
 pub struct Cpu {
@@ -114,6 +159,7 @@ pub struct Cpu {
     current_instruction_pc: u64, // actual PC of the currently executing instruction
                                  // only valid inside step()
     next_instruction: u32,       // emulates delay slot (prefetch, next instruction)
+    next_decoded: DecodedInstruction,
     next_instruction_pc: u64,    // for printing correct delay slot addresses
     is_delay_slot: bool,         // true if the currently executing instruction is in a delay slot
     next_is_delay_slot: bool,    // set to true on branching instructions
@@ -127,14 +173,50 @@ pub struct Cpu {
     tlb: [TlbEntry; 32],
     cp2gpr_latch: u64,
 
-    // 32/64-bit addressing modes, set by Cop0_Status bits
-    kernel_64bit_addressing: bool,
-
     half_clock: u32,
     llbit: bool,
 
+    // a load or store matched WatchLo/WatchHi while EXL was set. the Watch exception is taken
+    // once EXL clears
+    watch_pending: bool,
+
+    // pipeline cycles used by the current instruction, including stalls
+    cycles: u64,
+
+    // destination of the previous instruction if it was a load, for the load interlock
+    load_delay_reg: usize,
+
+    // set when the current instruction accessed a device that can stall the bus
+    external_access: bool,
+
     cop1: cop1::Cop1,
 
+    icache: ICache,
+    dcache: DCache,
+
+    // decoded code, and the block sequential fetches are coming from
+    blocks: BlockCache<DecodedInstruction>,
+    code_pages: Arc<CodePages>,
+    block: Option<Rc<Block<DecodedInstruction>>>,
+    block_generation: u64,
+    block_index: usize,
+    block_pc: u64,
+
+    #[cfg(feature = "jit")]
+    jit: Option<Box<jit::Jit>>,
+
+    // set on any uncached access, so the JIT self-check knows a block can't be replayed
+    #[cfg(feature = "jit")]
+    uncached_access: bool,
+
+    // virtual address of the block compiled code is running, which it computes branch targets from
+    #[cfg(feature = "jit")]
+    jit_base_pc: u64,
+
+    // nonzero while something needs every instruction to be a step of its own
+    #[cfg(feature = "jit")]
+    jit_paused: u32,
+
     instruction_table: [CpuInstruction; 64],
     special_table: [CpuInstruction; 64],
     regimm_table: [CpuInstruction; 32],
@@ -142,6 +224,12 @@ pub struct Cpu {
     // instruction decode values
     inst: InstructionDecode,
 
+    // calls made and not yet returned from, when the debugger is keeping a shadow call stack
+    call_stack: Option<Vec<CallFrame>>,
+
+    // sees every load and store, for the debugger's read and write breakpoints
+    data_watch: Option<Rc<RefCell<dyn DataWatch>>>,
+
     num_steps: u64,
 }
 
@@ -167,8 +255,15 @@ impl From<ReadWriteFault> for InstructionFault {
 
 type CpuInstruction = fn(&mut Cpu) -> Result<(), InstructionFault>;
 
+// Loads and stores by physical address, before they reach the D-cache or the bus. Cached accesses
+// only reach the bus as line fills and write backs, so watching the bus would miss them. An error
+// stops the instruction before it changes anything
+pub trait DataWatch {
+    fn check(&mut self, physical_address: u64, size: u64, is_write: bool, value: Option<u32>) -> Result<(), ReadWriteFault>;
+}
+
 impl Cpu {
-    pub fn new(bus: Rc<RefCell<dyn Addressable>>) -> Cpu {
+    pub fn new(bus: Rc<RefCell<dyn Addressable>>, comms: SystemCommunication) -> Cpu {
         let mut cpu = Cpu {
             num_steps: 0,
 
@@ -176,6 +271,7 @@ impl Cpu {
             pc  : 0,
             current_instruction_pc: 0,
             next_instruction: 0,
+            next_decoded: DecodedInstruction { decode: InstructionDecode::default(), handler: Cpu::inst_special },
             next_instruction_pc: 0,
             is_delay_slot: false,
             next_is_delay_slot: false,
@@ -188,11 +284,33 @@ impl Cpu {
             cp0gpr_latch: 0,
             tlb: [TlbEntry::default(); 32],
             cp2gpr_latch: 0,
-            kernel_64bit_addressing: false,
             half_clock: 0,
             llbit: false,
-
-            cop1: cop1::Cop1::new(),
+            watch_pending: false,
+            cycles: 0,
+            load_delay_reg: 0,
+            external_access: false,
+
+            cop1: cop1::Cop1::new(comms.settings.read().unwrap().soft_float),
+
+            icache: ICache::new(),
+            dcache: DCache::new(),
+
+            blocks: BlockCache::new(),
+            code_pages: comms.code_pages.clone(),
+            block: None,
+            block_generation: 0,
+            block_index: 0,
+            block_pc: 0,
+
+            #[cfg(feature = "jit")]
+            jit: jit::Jit::new(comms.settings.read().unwrap().jit),
+            #[cfg(feature = "jit")]
+            uncached_access: false,
+            #[cfg(feature = "jit")]
+            jit_base_pc: 0,
+            #[cfg(feature = "jit")]
+            jit_paused: 0,
 
             // Sorry for making these so wide, but it maps to the instruction decode table in the datasheet better!
             instruction_table: [
@@ -223,14 +341,17 @@ impl Cpu {
                //   _000                 _001                 _010                 _011                 _100                 _101                 _110                 _111
    /* 00_ */    Cpu::regimm_bltz   , Cpu::regimm_bgez   , Cpu::regimm_bltzl  , Cpu::regimm_bgezl  , Cpu::inst_reserved , Cpu::inst_reserved , Cpu::inst_reserved , Cpu::inst_reserved ,
    /* 01_ */    Cpu::regimm_tgei   , Cpu::regimm_tgeiu  , Cpu::regimm_tlti   , Cpu::regimm_tltiu  , Cpu::regimm_teqi   , Cpu::inst_reserved , Cpu::regimm_tnei   , Cpu::inst_reserved ,
-   /* 10_ */    Cpu::regimm_unknown, Cpu::regimm_bgezal , Cpu::regimm_unknown, Cpu::regimm_bgezall, Cpu::inst_reserved , Cpu::inst_reserved , Cpu::inst_reserved , Cpu::inst_reserved ,
+   /* 10_ */    Cpu::regimm_bltzal , Cpu::regimm_bgezal , Cpu::regimm_bltzall, Cpu::regimm_bgezall, Cpu::inst_reserved , Cpu::inst_reserved , Cpu::inst_reserved , Cpu::inst_reserved ,
    /* 11_ */    Cpu::inst_reserved , Cpu::inst_reserved , Cpu::inst_reserved , Cpu::inst_reserved , Cpu::inst_reserved , Cpu::inst_reserved , Cpu::inst_reserved , Cpu::inst_reserved ,
             ],
 
             inst: InstructionDecode {
                 v: 0, op: 0, regimm: 0, special: 0, rd: 0, rs: 0, rt: 0,
                 imm: 0, signed_imm: 0, sa: 0, target: 0,
-            }
+            },
+
+            call_stack: None,
+            data_watch: None,
         };
         
         let _ = cpu.reset(false);
@@ -251,6 +372,19 @@ impl Cpu {
         self.cp0gpr[Cop0_PRId] = 0x0B22;
         self.cp0gpr[Cop0_Config] = 0x7006E463; // EC=1:15, EP=0, BE=1 (big endian), CU=0 (RFU?), K0=3 (kseg0 cache enabled)
         self.cp0gpr[Cop0_Status] = (1 << 22) | (1 << 2) | if is_soft { 1 << 20 } else { 0 }; // BEV=1, ERL=1, SR=0 (SR will be 1 on soft reset), IE=0
+        self.watch_pending = false;
+
+        // cache contents survive a soft reset
+        if !is_soft {
+            self.icache.reset();
+            self.dcache.reset();
+            self.blocks.clear();
+        }
+        self.block = None;
+
+        if let Some(call_stack) = &mut self.call_stack {
+            call_stack.clear();
+        }
 
         // fetch next_instruction before starting the loop
         self.prefetch()?;
@@ -267,6 +401,12 @@ impl Cpu {
         &self.num_steps
     }
 
+    // PCycles taken by the last instruction executed
+    pub fn cycles(&self) -> u64 {
+        // instructions that fault before issue still occupy the pipeline
+        std::cmp::max(1, self.cycles)
+    }
+
     pub fn current_instruction_pc(&self) -> &u64 {
         &self.current_instruction_pc
     }
@@ -287,6 +427,199 @@ impl Cpu {
         &self.gpr
     }
 
+    // writes to r0 are ignored
+    pub fn set_reg(&mut self, index: usize, value: u64) {
+        if index != 0 {
+            self.gpr[index] = value;
+        }
+    }
+
+    pub fn lo(&self) -> u64 {
+        self.lo
+    }
+
+    pub fn hi(&self) -> u64 {
+        self.hi
+    }
+
+    pub fn set_lo(&mut self, value: u64) {
+        self.lo = value;
+    }
+
+    pub fn set_hi(&mut self, value: u64) {
+        self.hi = value;
+    }
+
+    pub fn cp0_register(&self, index: usize) -> u64 {
+        self.cp0gpr[index]
+    }
+
+    // raw write to a COP0 register, bypassing the masking MTC0 does
+    pub fn set_cp0_register(&mut self, index: usize, value: u64) {
+        self.cp0gpr[index] = value;
+        if index == Cop0_Status {
+            self.cop1.set_fr(((value >> 26) & 0x01) != 0);
+        }
+    }
+
+    pub fn cop1(&self) -> &cop1::Cop1 {
+        &self.cop1
+    }
+
+    pub fn cop1_mut(&mut self) -> &mut cop1::Cop1 {
+        &mut self.cop1
+    }
+
+    // start or stop keeping the shadow call stack. It starts out empty
+    pub fn set_call_stack_tracking(&mut self, enable: bool) {
+        self.call_stack = if enable { Some(Vec::new()) } else { None };
+    }
+
+    pub fn set_data_watch(&mut self, data_watch: Option<Rc<RefCell<dyn DataWatch>>>) {
+        self.data_watch = data_watch;
+    }
+
+    // the shadow call stack, outermost call first, if it's being kept
+    pub fn call_stack(&self) -> Option<&Vec<CallFrame>> {
+        self.call_stack.as_ref()
+    }
+
+    // called on every linking jump or branch that's taken. self.pc already points after the delay slot
+    #[inline(always)]
+    fn push_call(&mut self, target: u64) {
+        if let Some(call_stack) = &mut self.call_stack {
+            if call_stack.len() == CALL_STACK_LIMIT {
+                call_stack.remove(0);
+            }
+
+            call_stack.push(CallFrame {
+                call_pc       : self.pc.wrapping_sub(8),
+                target        : target,
+                return_address: self.pc,
+                sp            : self.gpr[29],
+            });
+        }
+    }
+
+    // called on jr ra. Frames above the one returned to are dropped too, in case they returned
+    // some other way. Returns to addresses that were never called are ignored
+    #[inline(always)]
+    fn pop_call(&mut self, return_address: u64) {
+        if let Some(call_stack) = &mut self.call_stack {
+            if let Some(index) = call_stack.iter().rposition(|frame| frame.return_address == return_address) {
+                call_stack.truncate(index);
+            }
+        }
+    }
+
+    // compiled blocks run to the end once entered, so nothing can stop between their instructions.
+    // While paused, every step goes through the interpreter instead. Pauses nest, so every
+    // pause_jit(true) needs a pause_jit(false) to match
+    #[cfg(feature = "jit")]
+    pub fn pause_jit(&mut self, paused: bool) {
+        self.jit_paused = if paused { self.jit_paused + 1 } else { self.jit_paused.saturating_sub(1) };
+    }
+
+    // Continue execution at pc, outside of any delay slot. The CPU is left as it was if pc isn't
+    // word aligned or doesn't map to anything, rather than taking an exception for it
+    pub fn set_pc(&mut self, pc: u64) -> Result<(), InstructionFault> {
+        if (pc & 0x03) != 0 || self.debug_translate_address(pc).is_none() {
+            return Err(InstructionFault::Invalid);
+        }
+
+        self.block = None;
+        self.next_decoded = self.fetch_instruction(pc)?;
+        self.next_instruction = self.next_decoded.decode.v;
+        self.next_instruction_pc = pc;
+        self.pc = pc + 4;
+        self.next_is_delay_slot = false;
+        self.current_instruction_pc = self.next_instruction_pc;
+        self.is_delay_slot = false;
+        Ok(())
+    }
+
+    // translate a virtual address in the current mode without raising exceptions or touching
+    // any CPU state. None if the address isn't valid or misses the TLB
+    pub fn debug_translate_address(&mut self, virtual_address: u64) -> Option<u64> {
+        match self.translate_address(virtual_address, false, false) {
+            Ok(Some(address)) => Some(address.physical_address),
+            _ => None,
+        }
+    }
+
+    // translate a virtual address like debug_translate_address, with the details of how it
+    // mapped or why it didn't
+    pub fn debug_translation(&mut self, virtual_address: u64) -> Result<AddressTranslation, &'static str> {
+        match self.classify_address(virtual_address, false, false) {
+            Ok(Some(_)) => {},
+            _ => return Err("isn't accessible in the current operating mode"),
+        }
+
+        let address = match self.translate_address(virtual_address, false, false) {
+            Ok(Some(address)) => address,
+            _ => return Err("misses the TLB or hits an invalid entry"),
+        };
+
+        Ok(AddressTranslation {
+            physical_address: address.physical_address,
+            cached          : address.cached,
+            space           : match address.space {
+                MemorySpace::User       => "user",
+                MemorySpace::Supervisor => "supervisor",
+                MemorySpace::XKPhys     => "xkphys",
+                MemorySpace::Kernel     => "kernel",
+            },
+            tlb_index       : address.tlb_index,
+        })
+    }
+
+    pub fn tlb_entry(&self, index: usize) -> &TlbEntry {
+        &self.tlb[index]
+    }
+
+    // Read a word the way a load would see it, including data that's only in the D-cache, but
+    // without filling lines or raising exceptions. `bus` is the physical bus, so debugger
+    // accesses don't trip breakpoints
+    pub fn debug_read_u32(&mut self, bus: &mut dyn Addressable, virtual_address: u64) -> Result<u32, ReadWriteFault> {
+        let address = match self.translate_address(virtual_address, false, false) {
+            Ok(Some(address)) => address,
+            _ => return Err(ReadWriteFault::Invalid),
+        };
+
+        if address.cached && self.dcache.is_hit(address.virtual_address, address.physical_address) {
+            return Ok(self.dcache.line(DCache::index(address.virtual_address)).data[DCache::word(address.physical_address)]);
+        }
+
+        bus.read_u32((address.physical_address & !0x03) as usize)
+    }
+
+    // Write the bits of a word selected by mask. Both the D-cache and memory are updated so the
+    // value sticks whether or not the line is written back, and I-cache lines holding the word
+    // are dropped so patched code is fetched again
+    pub fn debug_write_u32(&mut self, bus: &mut dyn Addressable, virtual_address: u64, value: u32, mask: u32) -> Result<(), ReadWriteFault> {
+        let address = match self.translate_address(virtual_address, false, false) {
+            Ok(Some(address)) => address,
+            _ => return Err(ReadWriteFault::Invalid),
+        };
+
+        if address.cached && self.dcache.is_hit(address.virtual_address, address.physical_address) {
+            let line = self.dcache.line_mut(DCache::index(address.virtual_address));
+            let word = DCache::word(address.physical_address);
+            line.data[word] = (line.data[word] & !mask) | (value & mask);
+        }
+
+        if address.cached && self.icache.is_hit(address.virtual_address, address.physical_address) {
+            let index = ICache::index(address.virtual_address);
+            self.forget_icache_line(index);
+            self.icache.invalidate(index);
+        }
+
+        let physical_address = (address.physical_address & !0x03) as usize;
+        let word = if mask == 0xFFFF_FFFF { value } else { (bus.read_u32(physical_address)? & !mask) | (value & mask) };
+        bus.write_u32(word, physical_address)?;
+        Ok(())
+    }
+
     pub fn abi_name(i: usize) -> &'static str {
         const NAMES: [&str; 32] = [
             "r0", "at", "v0", "v1", "a0", "a1", "a2", "a3", "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
@@ -314,7 +647,7 @@ impl Cpu {
 
     #[inline(always)]
     fn read_u8(&mut self, virtual_address: usize) -> Result<u8, InstructionFault> {
-        if let Some(address) = self.translate_address(virtual_address as u64, true, false)? {
+        if let Some(address) = self.translate_data_address(virtual_address as u64, false)? {
             self.read_u8_phys(address)
         } else {
             // invalid TLB translation, no physical address present to read and no exception
@@ -324,7 +657,7 @@ impl Cpu {
 
     #[inline(always)]
     fn read_u16(&mut self, virtual_address: usize) -> Result<u16, InstructionFault> {
-        if let Some(address) = self.translate_address(virtual_address as u64, true, false)? {
+        if let Some(address) = self.translate_data_address(virtual_address as u64, false)? {
             self.read_u16_phys(address)
         } else {
             // invalid TLB translation, no physical address present to read and no exception
@@ -334,7 +667,7 @@ impl Cpu {
 
     #[inline(always)]
     fn read_u32(&mut self, virtual_address: usize) -> Result<u32, InstructionFault> {
-        if let Some(address) = self.translate_address(virtual_address as u64, true, false)? {
+        if let Some(address) = self.translate_data_address(virtual_address as u64, false)? {
             self.read_u32_phys(address)
         } else {
             // invalid TLB translation, no physical address present to read and no exception
@@ -345,36 +678,72 @@ impl Cpu {
     // The VR4300 has to do two reads to get a doubleword
     #[inline(always)]
     fn read_u64(&mut self, virtual_address: usize) -> Result<u64, InstructionFault> {
-        if let Some(address) = self.translate_address(virtual_address as u64, true, false)? {
+        if let Some(address) = self.translate_data_address(virtual_address as u64, false)? {
             self.read_u64_phys(address)
         } else {
             Ok(0)
         }
     }
 
+    #[inline(always)]
+    fn watch(&mut self, address: &Address, size: u64, is_write: bool, value: Option<u32>) -> Result<(), InstructionFault> {
+        if let Some(data_watch) = &self.data_watch {
+            data_watch.borrow_mut().check(address.physical_address, size, is_write, value)?;
+        }
+        Ok(())
+    }
+
     #[inline(always)]
     fn read_u8_phys(&mut self, address: Address) -> Result<u8, InstructionFault> {
+        self.watch(&address, 1, false, None)?;
+        if address.cached {
+            let word = self.dcache_line(address)?.data[DCache::word(address.physical_address)];
+            return Ok((word >> (24 - ((address.physical_address & 0x03) << 3))) as u8);
+        }
+
+        self.bus_cycles(address, 1);
         Ok(self.bus.borrow_mut().read_u8(address.physical_address as usize)?)
     }
 
     #[inline(always)]
     fn read_u16_phys(&mut self, address: Address) -> Result<u16, InstructionFault> {
+        self.watch(&address, 2, false, None)?;
+        if address.cached {
+            let word = self.dcache_line(address)?.data[DCache::word(address.physical_address)];
+            return Ok((word >> (16 - ((address.physical_address & 0x02) << 3))) as u16);
+        }
+
+        self.bus_cycles(address, 1);
         Ok(self.bus.borrow_mut().read_u16(address.physical_address as usize)?)
     }
 
     #[inline(always)]
     fn read_u32_phys(&mut self, address: Address) -> Result<u32, InstructionFault> {
+        self.watch(&address, 4, false, None)?;
+        if address.cached {
+            return Ok(self.dcache_line(address)?.data[DCache::word(address.physical_address)]);
+        }
+
+        self.bus_cycles(address, 1);
         Ok(self.bus.borrow_mut().read_u32(address.physical_address as usize)?)
     }
 
     #[inline(always)]
     fn read_u64_phys(&mut self, address: Address) -> Result<u64, InstructionFault> {
+        self.watch(&address, 8, false, None)?;
+        if address.cached {
+            let word = DCache::word(address.physical_address);
+            let line = self.dcache_line(address)?;
+            return Ok(((line.data[word] as u64) << 32) | (line.data[word + 1] as u64));
+        }
+
+        self.bus_cycles(address, 2);
         Ok(self.bus.borrow_mut().read_u64(address.physical_address as usize)?)
     }
 
     #[inline(always)]
     fn write_u8(&mut self, value: u32, virtual_address: usize) -> Result<WriteReturnSignal, InstructionFault> {
-        if let Some(address) = self.translate_address(virtual_address as u64, true, true)? {
+        if let Some(address) = self.translate_data_address(virtual_address as u64, true)? {
             self.write_u8_phys(value, address)
         } else {
             // invalid TLB translation, no physical address present to read
@@ -384,7 +753,7 @@ impl Cpu {
 
     #[inline(always)]
     fn write_u16(&mut self, value: u32, virtual_address: usize) -> Result<WriteReturnSignal, InstructionFault> {
-        if let Some(address) = self.translate_address(virtual_address as u64, true, true)? {
+        if let Some(address) = self.translate_data_address(virtual_address as u64, true)? {
             self.write_u16_phys(value, address)
         } else {
             // invalid TLB translation, no physical address present to read
@@ -394,7 +763,7 @@ impl Cpu {
 
     #[inline(always)]
     fn write_u32(&mut self, value: u32, virtual_address: usize) -> Result<WriteReturnSignal, InstructionFault> {
-        if let Some(address) = self.translate_address(virtual_address as u64, true, true)? {
+        if let Some(address) = self.translate_data_address(virtual_address as u64, true)? {
             self.write_u32_phys(value, address)
         } else {
             // invalid TLB translation, no physical address present to read
@@ -404,7 +773,7 @@ impl Cpu {
 
     #[inline(always)]
     fn write_u64(&mut self, value: u64, virtual_address: usize) -> Result<WriteReturnSignal, InstructionFault> {
-        if let Some(address) = self.translate_address(virtual_address as u64, true, false)? {
+        if let Some(address) = self.translate_data_address(virtual_address as u64, true)? {
             self.write_u64_phys(value, address)
         } else {
             Ok(WriteReturnSignal::None)
@@ -413,27 +782,318 @@ impl Cpu {
 
     #[inline(always)]
     fn write_u8_phys(&mut self, value: u32, address: Address) -> Result<WriteReturnSignal, InstructionFault> {
+        self.watch(&address, 1, true, Some(value & 0xFF))?;
+        if address.cached {
+            let shift = 24 - ((address.physical_address & 0x03) << 3);
+            self.dcache_write(address, (value & 0xFF) << shift, 0xFF << shift)?;
+            return Ok(WriteReturnSignal::None);
+        }
+
+        self.bus_cycles(address, 1);
         Ok(self.bus.borrow_mut().write_u8(value, address.physical_address as usize)?)
     }
 
     #[inline(always)]
     fn write_u16_phys(&mut self, value: u32, address: Address) -> Result<WriteReturnSignal, InstructionFault> {
+        self.watch(&address, 2, true, Some(value & 0xFFFF))?;
+        if address.cached {
+            let shift = 16 - ((address.physical_address & 0x02) << 3);
+            self.dcache_write(address, (value & 0xFFFF) << shift, 0xFFFF << shift)?;
+            return Ok(WriteReturnSignal::None);
+        }
+
+        self.bus_cycles(address, 1);
         Ok(self.bus.borrow_mut().write_u16(value, address.physical_address as usize)?)
     }
 
     #[inline(always)]
     fn write_u32_phys(&mut self, value: u32, address: Address) -> Result<WriteReturnSignal, InstructionFault> {
+        self.watch(&address, 4, true, Some(value))?;
+        if address.cached {
+            self.dcache_write(address, value, 0xFFFF_FFFF)?;
+            return Ok(WriteReturnSignal::None);
+        }
+
+        self.bus_cycles(address, 1);
         Ok(self.bus.borrow_mut().write_u32(value, address.physical_address as usize)?)
     }
 
     #[inline(always)]
     fn write_u64_phys(&mut self, value: u64, address: Address) -> Result<WriteReturnSignal, InstructionFault> {
+        // the two words are watched separately, so value= breakpoints can match either of them
+        let mut low = address;
+        low.physical_address += 4;
+        self.watch(&address, 4, true, Some((value >> 32) as u32))?;
+        self.watch(&low, 4, true, Some(value as u32))?;
+
+        if address.cached {
+            self.dcache_write(address, (value >> 32) as u32, 0xFFFF_FFFF)?;
+            self.dcache_write(low, value as u32, 0xFFFF_FFFF)?;
+            return Ok(WriteReturnSignal::None);
+        }
+
+        self.bus_cycles(address, 2);
         Ok(self.bus.borrow_mut().write_u64(value, address.physical_address as usize)?)
     }
 
+    // the D-cache line holding address, filled on a miss
+    #[inline(always)]
+    fn dcache_line(&mut self, address: Address) -> Result<&mut cache::CacheLine<4>, InstructionFault> {
+        // skip borrowing the bus on hits
+        if self.dcache.is_hit(address.virtual_address, address.physical_address) {
+            return Ok(self.dcache.line_mut(DCache::index(address.virtual_address)));
+        }
+
+        let mut bus = self.bus.borrow_mut();
+        Ok(self.dcache.fetch(&mut *bus, address.virtual_address, address.physical_address, &mut self.cycles)?)
+    }
+
+    // stores allocate a line on a miss and only update the cache
+    #[inline(always)]
+    fn dcache_write(&mut self, address: Address, value: u32, mask: u32) -> Result<(), InstructionFault> {
+        let word = DCache::word(address.physical_address);
+        let line = self.dcache_line(address)?;
+        line.data[word] = (line.data[word] & !mask) | (value & mask);
+        line.dirty = true;
+        Ok(())
+    }
+
+    // Instruction fetches come out of pre-decoded blocks. Sequential fetches inside the current
+    // block skip address translation and the lookup entirely. Cached code is decoded from the
+    // I-cache line and uncached code from RDRAM; anything else is decoded on every fetch
+    #[inline(always)]
+    fn fetch_instruction(&mut self, virtual_address: u64) -> Result<DecodedInstruction, InstructionFault> {
+        if self.code_pages.has_writes() {
+            for page in self.code_pages.take_writes() {
+                self.blocks.invalidate_range(page, block_cache::PAGE_SIZE);
+            }
+        }
+
+        if virtual_address == self.block_pc && self.block_generation == self.blocks.generation() {
+            if let Some(block) = &self.block {
+                if let Some(decoded) = block.instructions.get(self.block_index) {
+                    if !block.cached {
+                        self.cycles += UNCACHED_RDRAM_CYCLES;
+                    }
+                    self.block_index += 1;
+                    self.block_pc += 4;
+                    return Ok(*decoded);
+                }
+            }
+        }
+
+        let address = match self.translate_address(virtual_address, true, false)? {
+            Some(address) => address,
+            None => return Ok(self.decode(0)),
+        };
+
+        match self.fetch_physical(virtual_address, address) {
+            // the bus error is raised only if the instruction gets executed
+            Err(InstructionFault::ReadWrite(ReadWriteFault::BusError)) => {
+                self.block = None;
+                let decode = self.decode(0).decode;
+                Ok(DecodedInstruction { decode, handler: Cpu::inst_bus_error })
+            },
+
+            result => result,
+        }
+    }
+
+    fn fetch_physical(&mut self, virtual_address: u64, address: Address) -> Result<DecodedInstruction, InstructionFault> {
+        if address.cached {
+            self.icache_fetch(address)?;
+        } else if address.physical_address < self.code_pages.size() {
+            self.bus_cycles(address, 1);
+        } else {
+            self.block = None;
+            let inst = self.read_u32_phys(address)?;
+            return Ok(self.decode(inst));
+        }
+
+        let block = match self.blocks.get(address.physical_address, address.cached) {
+            Some(block) => block,
+            None => self.build_block(address)?,
+        };
+
+        let decoded = block.instructions[0];
+        self.block = Some(block);
+        self.block_generation = self.blocks.generation();
+        self.block_index = 1;
+        self.block_pc = virtual_address.wrapping_add(4);
+        Ok(decoded)
+    }
+
+    // decode a block starting at address, up to and including the delay slot of the first branch.
+    // COP0 instructions can change the address mapping, so the block stops after them too
+    fn build_block(&mut self, address: Address) -> Result<Rc<Block<DecodedInstruction>>, InstructionFault> {
+        let start = address.physical_address;
+        let end = if address.cached {
+            (start | 0x1F) + 1
+        } else {
+            ((start | (block_cache::PAGE_SIZE - 1)) + 1).min(start + (MAX_BLOCK_INSTRUCTIONS << 2))
+        };
+
+        let mut instructions = Vec::new();
+        let mut delay_slot = false;
+        for physical_address in (start..end).step_by(4) {
+            let inst = if address.cached {
+                self.icache.line(ICache::index(address.virtual_address)).data[ICache::word(physical_address)]
+            } else {
+                self.bus.borrow_mut().read_u32(physical_address as usize)?
+            };
+
+            let decoded = self.decode(inst);
+            instructions.push(decoded);
+            if delay_slot || decoded.decode.op == 0b010_000 { // COP0
+                break;
+            }
+            delay_slot = Self::is_branch(&decoded.decode);
+        }
+
+        // only writes to RDRAM are tracked. cached blocks are dropped when their I-cache line changes
+        if !address.cached {
+            self.code_pages.mark_code(start);
+        }
+
+        trace!(target: "BLOCKS", "decoded {} instruction(s) at ${:08X} cached={}", instructions.len(), start, address.cached);
+        Ok(self.blocks.insert(Block {
+            physical_address: start,
+            cached: address.cached,
+            instructions: instructions,
+        }))
+    }
+
+    fn decode(&self, inst: u32) -> DecodedInstruction {
+        let decode = InstructionDecode {
+            v         : inst,
+            op        : inst >> 26,
+            regimm    : (inst >> 16) & 0x1F,
+            special   : inst & 0x3F,
+            rs        : ((inst >> 21) & 0x1F) as usize,
+            rt        : ((inst >> 16) & 0x1F) as usize,
+            rd        : ((inst >> 11) & 0x1F) as usize,
+            imm       : (inst & 0xFFFF) as u64,
+            signed_imm: ((inst & 0xFFFF) as i16) as u64,
+            sa        : (inst >> 6) & 0x1F,
+            target    : inst & 0x3FFFFFF,
+        };
+
+        // resolve SPECIAL and REGIMM now so execution goes straight to the handler
+        let handler = match decode.op {
+            0b000_000 => self.special_table[decode.special as usize],
+            0b000_001 => self.regimm_table[decode.regimm as usize],
+            op        => self.instruction_table[op as usize],
+        };
+
+        DecodedInstruction { decode, handler }
+    }
+
+    // true for jumps and branches, which are followed by a delay slot
+    fn is_branch(inst: &InstructionDecode) -> bool {
+        match inst.op {
+            0b000_000 => matches!(inst.special, 0b001_000 | 0b001_001),             // JR, JALR
+            0b000_001 => matches!(inst.regimm, 0b00_000..=0b00_011 | 0b10_000..=0b10_011), // BLTZ.., BLTZAL..
+            0b000_010..=0b000_111 | 0b010_100..=0b010_111 => true,                   // J, JAL, B*, B*L
+            0b010_001 | 0b010_010 => inst.rs == 0b01_000,                            // BC1*, BC2*
+            _ => false,
+        }
+    }
+
+    // make sure the I-cache holds address, dropping blocks decoded from the line it replaces
+    #[inline(always)]
+    fn icache_fetch(&mut self, address: Address) -> Result<(), InstructionFault> {
+        if !self.icache.is_hit(address.virtual_address, address.physical_address) {
+            self.forget_icache_line(ICache::index(address.virtual_address));
+            let mut bus = self.bus.borrow_mut();
+            self.icache.fetch(&mut *bus, address.virtual_address, address.physical_address, &mut self.cycles)?;
+        }
+        Ok(())
+    }
+
+    fn forget_icache_line(&mut self, index: usize) {
+        let line = self.icache.line(index);
+        if line.valid {
+            self.blocks.invalidate_range(line.physical_address(index), 32);
+        }
+    }
+
+    // add the SysAD bus cost of `words` uncached 32-bit transfers to the current instruction
+    #[inline(always)]
+    fn bus_cycles(&mut self, address: Address, words: u64) {
+        if !address.cached {
+            let cost = if address.physical_address < 0x0400_0000 { UNCACHED_RDRAM_CYCLES } else { UNCACHED_RCP_CYCLES };
+            self.cycles += cost * words;
+
+            #[cfg(feature = "jit")]
+            { self.uncached_access = true; }
+
+            // the PI external bus adds its own wait time, collected after the instruction
+            self.external_access |= (address.physical_address & 0x1FFF_FFFF) >= 0x0500_0000;
+        }
+    }
+
+    // pipeline cost of the instruction in self.inst, not counting memory accesses.
+    // multiply and divide results go to HI/LO and the FPU isn't pipelined, so their full
+    // latency is charged when they're issued
+    fn instruction_cycles(inst: &InstructionDecode, load_delay_reg: usize) -> u64 {
+        let mut cycles = 1;
+
+        // a load followed immediately by a use of the loaded register stalls for one cycle
+        if load_delay_reg != 0 {
+            let reads_rt = match inst.op {
+                0b000_000 | 0b000_100 | 0b000_101 | 0b010_100 | 0b010_101 => true, // SPECIAL, BEQ, BNE, BEQL, BNEL
+                0b101_000..=0b101_111 | 0b111_000 | 0b111_100 => true,             // stores, SC, SD
+                _ => false,
+            };
+            if inst.rs == load_delay_reg || (reads_rt && inst.rt == load_delay_reg) {
+                cycles += 1;
+            }
+        }
+
+        match inst.op {
+            0b000_000 => { // SPECIAL
+                cycles += match inst.v & 0x3F {
+                    0b011_000 | 0b011_001 => 4,  // MULT, MULTU
+                    0b011_100 | 0b011_101 => 7,  // DMULT, DMULTU
+                    0b011_010 | 0b011_011 => 36, // DIV, DIVU
+                    0b011_110 | 0b011_111 => 68, // DDIV, DDIVU
+                    _ => 0,
+                };
+            },
+
+            0b010_001 if (inst.v & (1 << 25)) != 0 => { // COP1 arithmetic
+                let double = ((inst.v >> 21) & 0x1F) == 17; // fmt D
+                cycles += match inst.v & 0x3F {
+                    0b000_000 | 0b000_001 => 2,                           // ADD, SUB
+                    0b000_010 => if double { 7 } else { 4 },              // MUL
+                    0b000_011 | 0b000_100 => if double { 57 } else { 28 }, // DIV, SQRT
+                    0b001_000..=0b001_111 => 4,                           // ROUND, TRUNC, CEIL, FLOOR
+                    0b100_000 => if double { 1 } else { 4 },              // CVT.S
+                    0b100_001 => if ((inst.v >> 21) & 0x1F) == 16 { 0 } else { 4 }, // CVT.D
+                    0b100_100 | 0b100_101 => 4,                           // CVT.W, CVT.L
+                    _ => 0,                                               // ABS, MOV, NEG, C.cond
+                };
+            },
+
+            _ => {},
+        }
+
+        cycles
+    }
+
+    // register written by a load instruction, or 0 for anything else
+    #[inline(always)]
+    fn load_destination(inst: &InstructionDecode) -> usize {
+        match inst.op {
+            0b011_010 | 0b011_011 | 0b100_000..=0b100_111 | 0b110_000 | 0b110_100 | 0b110_111 => inst.rt, // LDL, LDR, LB..LWU, LL, LLD, LD
+            _ => 0,
+        }
+    }
+
     // prefetch the next instruction
     fn prefetch(&mut self) -> Result<(), InstructionFault> {
-        self.next_instruction = self.read_u32(self.pc as usize)?; 
+        self.next_decoded = self.fetch_instruction(self.pc)?;
+        self.next_instruction = self.next_decoded.decode.v;
         self.next_instruction_pc = self.pc;
         self.pc += 4;
 
@@ -441,6 +1101,9 @@ impl Cpu {
     }
 
     fn exception(&mut self, exception_code: u64, use_special: bool) -> Result<(), InstructionFault> {
+        // the XTLB vector is chosen by the addressing mode that caused the miss, so check before setting EXL
+        let xtlb = self.is_64bit_addressing();
+
         // clear BD, exception code and bits that should be 0
         self.cp0gpr[Cop0_Cause] &= !0xC0FF_00FF;
 
@@ -451,14 +1114,15 @@ impl Cpu {
         let previous_exl = self.cp0gpr[Cop0_Status] & 0x02;
         self.cp0gpr[Cop0_Status] |= 0x02;
 
-        // set the Exception Program Counter to the currently executing instruction
-        self.cp0gpr[Cop0_EPC] = (if self.is_delay_slot {
+        // set the Exception Program Counter to the currently executing instruction. The pc is kept
+        // sign extended in 32-bit mode, so the full 64 bits are stored for ERET to return to
+        self.cp0gpr[Cop0_EPC] = if self.is_delay_slot {
             // also set the BD flag indicating the exception was in a delay slot
             self.cp0gpr[Cop0_Cause] |= 0x8000_0000;
-            self.current_instruction_pc - 4
+            self.current_instruction_pc.wrapping_sub(4)
         } else {
             self.current_instruction_pc
-        } as i32) as u64;
+        };
 
         // set PC and discard the delay slot. PC is set to the vector base determined by the BEV bit in Cop0_Status.
         // TLB and XTLB miss are vectored to offsets 0 and 0x80, respectively
@@ -468,7 +1132,7 @@ impl Cpu {
         } else { 
             0xFFFF_FFFF_BFC0_0200
         }) + (if use_special && previous_exl == 0 { // check EXL==0 and tlb miss
-            if self.kernel_64bit_addressing { 0x080 } else { 0x000 }
+            if xtlb { 0x080 } else { 0x000 }
         } else {
             0x180
         });
@@ -481,41 +1145,35 @@ impl Cpu {
         Err(InstructionFault::OtherException(exception_code))
     }
 
-    fn address_exception(&mut self, virtual_address: u64, is_write: bool) -> Result<(), InstructionFault> {
-        //println!("CPU: address exception!");
-
-        // set EntryHi to the vpn that caused the fault, preserving ASID and R
-        // the datasheet says the VPN value of EntryHi is undefined here, but the systemtests
-        // require it
-        self.cp0gpr[Cop0_EntryHi] = (virtual_address & 0xFF_FFFF_E000) | (self.cp0gpr[Cop0_EntryHi] & 0xFF) | (virtual_address & 0xC000_0000_0000_0000);
+    // set BadVAddr, Context, XContext and EntryHi for an address, TLB miss or TLB mod exception
+    fn set_fault_address(&mut self, virtual_address: u64) {
+        // set EntryHi to the region and vpn that caused the fault, preserving ASID
+        // the datasheet says the VPN value of EntryHi is undefined for address errors, but the
+        // systemtests require it
+        self.cp0gpr[Cop0_EntryHi] = (virtual_address & 0xC000_00FF_FFFF_E000) | (self.cp0gpr[Cop0_EntryHi] & 0xFF);
 
         self.cp0gpr[Cop0_BadVAddr] = virtual_address;
         let bad_vpn2 = virtual_address >> 13;
         self.cp0gpr[Cop0_Context] = (self.cp0gpr[Cop0_Context] & 0xFFFF_FFFF_FF80_0000) | ((bad_vpn2 & 0x7FFFF) << 4);
 
-        // TODO the XContext needs the page table
-        self.cp0gpr[Cop0_XContext] = ((self.cp0gpr[Cop0_Context] & 0xFFFF_FFFF_FF80_0000) << 10) 
+        // XContext keeps its own PTEBase in bits 63:33, followed by R in bits 32:31 and
+        // BadVPN2 (virtual address bits 39:13) in bits 30:4
+        self.cp0gpr[Cop0_XContext] = (self.cp0gpr[Cop0_XContext] & 0xFFFF_FFFE_0000_0000)
                                      | ((virtual_address >> 31) & 0x1_8000_0000) 
                                      | ((bad_vpn2 & 0x7FF_FFFF) << 4);
+    }
+
+    fn address_exception(&mut self, virtual_address: u64, is_write: bool) -> Result<(), InstructionFault> {
+        //println!("CPU: address exception!");
+        self.set_fault_address(virtual_address);
 
         let exception_code = if is_write { ExceptionCode_AdES } else { ExceptionCode_AdEL };
         self.exception(exception_code, false)
     }
 
     fn tlb_miss(&mut self, address: Address, is_write: bool) -> Result<(), InstructionFault> {
-        //info!(target: "CPU", "TLB miss exception virtual_address=${:016X} is_write={} 32-bits={}!", address.virtual_address, is_write, !self.kernel_64bit_addressing);
-
-        // set EntryHi to the vpn that caused the fault, preserving ASID and R
-        self.cp0gpr[Cop0_EntryHi] = (address.virtual_address & 0xFF_FFFF_E000) | (self.cp0gpr[Cop0_EntryHi] & 0xFF) | (address.virtual_address & 0xC000_0000_0000_0000);
-
-        self.cp0gpr[Cop0_BadVAddr] = address.virtual_address;
-        let bad_vpn2 = address.virtual_address >> 13;
-        self.cp0gpr[Cop0_Context] = (self.cp0gpr[Cop0_Context] & 0xFFFF_FFFF_FF80_0000) | ((bad_vpn2 & 0x7FFFF) << 4);
-
-        // TODO the XContext needs the page table
-        self.cp0gpr[Cop0_XContext] = ((self.cp0gpr[Cop0_Context] & 0xFFFF_FFFF_FF80_0000) << 10) 
-                                     | ((address.virtual_address >> 31) & 0x1_8000_0000) 
-                                     | ((bad_vpn2 & 0x7FF_FFFF) << 4);
+        //info!(target: "CPU", "TLB miss exception virtual_address=${:016X} is_write={} 64-bits={}!", address.virtual_address, is_write, self.is_64bit_addressing());
+        self.set_fault_address(address.virtual_address);
 
         // TLB miss that doesn't hit an EntryHi gets vectored to a different exception handler
         // than a TLB miss that hits an EntryHi but has an invalid entry
@@ -527,19 +1185,7 @@ impl Cpu {
 
     fn tlb_mod(&mut self, virtual_address: u64) -> Result<(), InstructionFault> {
         //info!(target: "CPU", "TLB mod exception virtual_address=${:016X}", virtual_address);
-
-        // set EntryHi to the page that caused the fault, preserving ASID
-        self.cp0gpr[Cop0_EntryHi] = (virtual_address & 0xFF_FFFF_E000) | (self.cp0gpr[Cop0_EntryHi] & 0xFF);
-
-        self.cp0gpr[Cop0_BadVAddr] = virtual_address;
-        let bad_vpn2 = virtual_address >> 13;
-        self.cp0gpr[Cop0_Context] = (self.cp0gpr[Cop0_Context] & 0xFFFF_FFFF_FF80_0000) | ((bad_vpn2 & 0x7FFFF) << 4);
-
-        // TODO the XContext needs the page table
-        self.cp0gpr[Cop0_XContext] = ((self.cp0gpr[Cop0_Context] & 0xFFFF_FFFF_FF80_0000) << 10) 
-                                     | ((virtual_address >> 31) & 0x1_8000_0000) 
-                                     | ((bad_vpn2 & 0x7FF_FFFF) << 4);
-
+        self.set_fault_address(virtual_address);
         self.exception(ExceptionCode_Mod, false)
     }
 
@@ -574,6 +1220,16 @@ impl Cpu {
         self.exception(ExceptionCode_FPE, false)
     }
 
+    fn watch_exception(&mut self) -> Result<(), InstructionFault> {
+        self.cp0gpr[Cop0_Cause] &= !0x3000_0000; // clear coprocessor number
+        self.exception(ExceptionCode_WATCH, false)
+    }
+
+    fn bus_error_exception(&mut self, exception_code: u64) -> Result<(), InstructionFault> {
+        self.cp0gpr[Cop0_Cause] &= !0x3000_0000; // clear coprocessor number
+        self.exception(exception_code, false)
+    }
+
     fn coprocessor_unusable_exception(&mut self, coprocessor_number: u64) -> Result<(), InstructionFault> {
         //info!("CPU: coprocessor unusable exception (Cop0_Status = ${:08X})!", self.cp0gpr[Cop0_Status]);
         self.cp0gpr[Cop0_Cause] = (self.cp0gpr[Cop0_Cause] & !0x3000_0000) | (coprocessor_number << 28);
@@ -651,6 +1307,42 @@ impl Cpu {
         };
     }
 
+    // the operating mode is kernel whenever EXL or ERL is set, otherwise it's selected by KSU
+    fn operating_mode(&self) -> MemorySpace {
+        let status = self.cp0gpr[Cop0_Status];
+        if (status & 0x06) != 0 { return MemorySpace::Kernel; }
+
+        match (status >> 3) & 0x03 {
+            0b01 => MemorySpace::Supervisor,
+            0b10 => MemorySpace::User,
+            _    => MemorySpace::Kernel, // 0b11 is undefined
+        }
+    }
+
+    // KX, SX and UX in Cop0_Status enable 64-bit addressing for kernel, supervisor and user mode
+    fn is_64bit_addressing(&self) -> bool {
+        let enable_bit = match self.operating_mode() {
+            MemorySpace::User       => 0x20,
+            MemorySpace::Supervisor => 0x40,
+            _                       => 0x80,
+        };
+        (self.cp0gpr[Cop0_Status] & enable_bit) != 0
+    }
+
+    // accessing an address outside the segments available to the current mode is an address error
+    fn address_error(&mut self, virtual_address: u64, generate_exceptions: bool, is_write: bool) -> Result<Option<Address>, InstructionFault> {
+        if generate_exceptions {
+            self.address_exception(virtual_address, is_write)?;
+        }
+        Ok(None)
+    }
+
+    // Config.K0 selects the cache algorithm for kseg0 like the C field of a TLB entry, 2 being uncached
+    #[inline(always)]
+    fn kseg0_cached(&self) -> bool {
+        (self.cp0gpr[Cop0_Config] & 0x07) != 2
+    }
+
     fn classify_address(&mut self, virtual_address: u64, generate_exceptions: bool, is_write: bool) -> Result<Option<Address>, InstructionFault> {
         let mut address = Address {
             virtual_address: virtual_address,
@@ -661,99 +1353,102 @@ impl Cpu {
             tlb_index: None,
         };
 
-        if self.kernel_64bit_addressing {
-            let space_bits = virtual_address >> 62;
-            address.space = match space_bits {
-                0b00 => MemorySpace::User,
-                0b01 => MemorySpace::Supervisor,
-                0b10 => MemorySpace::XKPhys,
-                0b11 => MemorySpace::Kernel,
-                _ => panic!("not possible"),
-            };
-
-            match space_bits {
+        let mode = self.operating_mode();
+        if self.is_64bit_addressing() {
+            match virtual_address >> 62 {
                 0b00 => { // user
                     address.space = MemorySpace::User;
-                    if virtual_address < 0x0000_0100_0000_0000 { // xkuseg, user segment, TLB mapped, cached
+                    if virtual_address < 0x0000_0100_0000_0000 { // xuseg, user segment, TLB mapped, cached
                         address.mapped = true;
                     } else {
-                        self.address_exception(virtual_address, is_write)?;
-                        return Ok(None);
+                        return self.address_error(virtual_address, generate_exceptions, is_write);
                     }
                 },
 
                 0b01 => { // supervisor
                     address.space = MemorySpace::Supervisor;
-                    if virtual_address < 0x4000_0100_0000_0000 { // xksseg, supervisor segment, TLB mapped, cached
+                    if mode != MemorySpace::User && virtual_address < 0x4000_0100_0000_0000 { // xsseg, supervisor segment, TLB mapped, cached
                         address.mapped = true;
                     } else {
-                        self.address_exception(virtual_address, is_write)?;
-                        return Ok(None);
+                        return self.address_error(virtual_address, generate_exceptions, is_write);
                     }
                 },
 
                 0b10 => { // xkphys
                     address.space = MemorySpace::XKPhys;
-                    // addresses with bits 53:32 including 1 cause an address error
-                    if ((virtual_address >> 32) & 0x3FFFFF) != 0 {
-                        self.address_exception(virtual_address, is_write)?;
-                        return Ok(None);
-                    } else {
-                        // all addresses are unmapped in this segment
-                        // the physical_address is the lower 32 bits of the virtual_address
-                        address.physical_address = virtual_address & 0xFFFF_FFFF;
-
-                        // bits 61:59 indicate the use of cache. interestingly enough, only one
-                        // region is uncached (0x9000_0000_0000_0000-0x9000_0000_FFFF_FFFF)
-                        address.cached = ((virtual_address >> 59) & 0x07) != 2;
+                    // only the kernel can use xkphys, and addresses with bits 53:32 including 1 cause an address error
+                    if mode != MemorySpace::Kernel || ((virtual_address >> 32) & 0x3FFFFF) != 0 {
+                        return self.address_error(virtual_address, generate_exceptions, is_write);
                     }
+
+                    // all addresses are unmapped in this segment
+                    // the physical_address is the lower 32 bits of the virtual_address
+                    address.physical_address = virtual_address & 0xFFFF_FFFF;
+
+                    // bits 61:59 select the cache algorithm like the C field of a TLB entry,
+                    // so the window at 0x9000_0000_0000_0000-0x9000_0000_FFFF_FFFF is uncached
+                    address.cached = ((virtual_address >> 59) & 0x07) != 2;
                 },
 
-                0b11 => { // kernel
+                _ => { // kernel
                     address.space = MemorySpace::Kernel;
-                    if virtual_address < 0xC000_00FF_8000_0000 { // xkseg, TLB mapped, cached
+                    if mode == MemorySpace::Supervisor && virtual_address >= 0xFFFF_FFFF_C000_0000 && virtual_address < 0xFFFF_FFFF_E000_0000 {
+                        // csseg is the only part of this region available to supervisor mode
+                        address.space = MemorySpace::Supervisor;
+                        address.mapped = true;
+                    } else if mode != MemorySpace::Kernel {
+                        return self.address_error(virtual_address, generate_exceptions, is_write);
+                    } else if virtual_address < 0xC000_00FF_8000_0000 { // xkseg, TLB mapped, cached
                         address.mapped = true;
                     } else if virtual_address < 0xFFFF_FFFF_8000_0000 { // invalid
-                        self.address_exception(virtual_address, is_write)?;
-                        return Ok(None);
+                        return self.address_error(virtual_address, generate_exceptions, is_write);
                     // the address range from FFFF_FFFF_8000_0000-FFFF_FFFF_FFFF_FFFF is the 32-bit compatibility range
                     } else if virtual_address < 0xFFFF_FFFF_A000_0000 { // ckseg0, segment 0, directly mapped, cached
+                        address.cached = self.kseg0_cached();
                         address.physical_address = virtual_address & 0x1FFF_FFFF;
                     } else if virtual_address < 0xFFFF_FFFF_C000_0000 { // ckseg1, segment 0, directly mapped, uncached
                         address.cached = false;
                         address.physical_address = virtual_address & 0x1FFF_FFFF;
-                    } else if virtual_address < 0xFFFF_FFFF_E000_0000 { // cksseg, supervisor segment (unused), TLB mapped
+                    } else if virtual_address < 0xFFFF_FFFF_E000_0000 { // cksseg, supervisor segment, TLB mapped
+                        address.space = MemorySpace::Supervisor;
                         address.mapped = true;
                     } else { // ckseg3, kernel segment 3, TLB mapped, cached
                         address.mapped = true;
                     }
                 },
-
-                _ => panic!("Not possible"),
             };
         } else {
-            if (virtual_address & 0x8000_0000) != 0 && ((virtual_address >> 32) as i32) != -1 && generate_exceptions {
-                self.address_exception(virtual_address, is_write)?;
-                return Ok(None);
+            // in 32-bit mode the address must be a sign extended 32-bit value
+            if ((virtual_address as i32) as u64) != virtual_address {
+                return self.address_error(virtual_address, generate_exceptions, is_write);
             }
 
             let word_address = virtual_address as u32;
             if word_address < 0x8000_0000 { // kuseg, TLB mapped, cached
                 address.space = MemorySpace::User;
                 address.mapped = true; 
-            } else if word_address < 0xA000_0000 { // kseg0, unmapped, cached
-                address.space = MemorySpace::Kernel;
-                address.physical_address = virtual_address & 0x1FFF_FFFF;
-            } else if word_address < 0xC000_0000 { // kseg1, unmapped, uncached
-                address.space = MemorySpace::Kernel;
-                address.cached = false;
-                address.physical_address = virtual_address & 0x1FFF_FFFF;
-            } else if word_address < 0xE000_0000 { // ksseg, mapped, cached
+            } else if word_address >= 0xC000_0000 && word_address < 0xE000_0000 { // ksseg, mapped, cached
                 address.space = MemorySpace::Supervisor;
+                if mode == MemorySpace::User {
+                    return self.address_error(virtual_address, generate_exceptions, is_write);
+                }
                 address.mapped = true;
-            } else { // kseg3, mapped, cached
+            } else {
+                // the remaining segments are only available to the kernel
                 address.space = MemorySpace::Kernel;
-                address.mapped = true;
+                if mode != MemorySpace::Kernel {
+                    return self.address_error(virtual_address, generate_exceptions, is_write);
+                }
+
+                if word_address < 0xA000_0000 { // kseg0, unmapped, cached
+                    address.cached = self.kseg0_cached();
+                    address.physical_address = virtual_address & 0x1FFF_FFFF;
+                } else if word_address < 0xC000_0000 { // kseg1, unmapped, uncached
+                    address.cached = false;
+                    address.physical_address = virtual_address & 0x1FFF_FFFF;
+                } else { // kseg3, mapped, cached
+                    address.mapped = true;
+                }
             }
         }
 
@@ -769,12 +1464,6 @@ impl Cpu {
             }
         };
 
-        // TODO check address.space and make sure current execution mode allows access to that space
-        // i.e., if we're running in supervisor mode and try to access kernel address, we need to
-        // generate an address_exception(). But as far as I can tell games are always running in
-        // kernel mode.  We may need to support it in the future to support demos or other demo
-        // programs, though.
-
         // if no further address translation is needed, return the Address
         if !address.mapped { return Ok(Some(address)); }
 
@@ -783,8 +1472,11 @@ impl Cpu {
 
         //info!(target: "CPU", "TLB: translating virtual address ${:016X}!", virtual_address);
 
-        // virtual addresses are 40 bits wide
-        let virtual_address = virtual_address & 0xFF_FFFF_FFFF;
+        // virtual addresses are 40 bits wide and entries also match on the region (R) in 64-bit
+        // mode, while 32-bit mode only compares the lower 32 bits
+        let is_64bit = self.is_64bit_addressing();
+        let address_mask = if is_64bit { 0xFF_FFFF_FFFF } else { 0xFFFF_FFFF };
+        let virtual_address = virtual_address & address_mask;
 
         // get the current ASID from EntryHi
         let asid = self.cp0gpr[Cop0_EntryHi] & 0xFF;
@@ -797,7 +1489,7 @@ impl Cpu {
             if (tlb.entry_hi & 0xFF) != asid && (tlb.entry_hi & 0x1000) == 0 { continue; }
 
             // if the region (R) doesn't match, this entry isn't valid
-            if ((tlb.entry_hi ^ address.virtual_address) >> 62) != 0 { continue; }
+            if is_64bit && ((tlb.entry_hi ^ address.virtual_address) >> 62) != 0 { continue; }
 
             //info!(target: "CPU", "checking TLB entry {}", tlb_index);
 
@@ -811,7 +1503,7 @@ impl Cpu {
 
             // If the virtual address is referencing one of the two pages, it's a hit
             // notice that we keep PageMask in bit 13 rather shift it down
-            let vpn_mask = !(tlb.page_mask | 0x1FFF) & 0xFF_FFFF_FFFF;
+            let vpn_mask = !(tlb.page_mask | 0x1FFF) & address_mask;
 
             // VPN2 is the virtual page number (divided by 2) this TLB entry covers
             let vpn2 = tlb.entry_hi & vpn_mask;
@@ -834,10 +1526,13 @@ impl Cpu {
 
             // if D (dirty) is not set and this is a write, a Mod exception occurs
             if generate_exceptions && (entry_lo & 0x04) == 0 && is_write {
-                self.tlb_mod(virtual_address)?;
+                self.tlb_mod(address.virtual_address)?;
                 return Ok(None);
             }
 
+            // the C field selects the cache algorithm, 2 being uncached
+            address.cached = ((entry_lo >> 3) & 0x07) != 2;
+
             // get the page frame number of the corresponding EntryLo field
             let pfn = entry_lo & 0x03FF_FFC0;
 
@@ -858,14 +1553,50 @@ impl Cpu {
         Ok(None)
     }
 
+    // translate the address of a load or store, raising a Watch exception when the physical
+    // address matches WatchLo/WatchHi. Matches while EXL is set let the access complete and
+    // raise the exception after EXL clears
+    fn translate_data_address(&mut self, virtual_address: u64, is_write: bool) -> Result<Option<Address>, InstructionFault> {
+        let address = match self.translate_address(virtual_address, true, is_write)? {
+            Some(address) => address,
+            None => return Ok(None),
+        };
+
+        // R (bit 1) enables the watch on loads and W (bit 0) on stores
+        let watch_lo = self.cp0gpr[Cop0_WatchLo];
+        let enabled = if is_write { watch_lo & 0x01 } else { watch_lo & 0x02 };
+        if enabled != 0 {
+            // the watch covers a doubleword, with physical address bits 35:32 in WatchHi
+            let watch_address = ((self.cp0gpr[Cop0_WatchHi] & 0x0F) << 32) | (watch_lo & 0xFFFF_FFF8);
+            if (address.physical_address & !0x07) == watch_address {
+                if (self.cp0gpr[Cop0_Status] & 0x02) != 0 {
+                    self.watch_pending = true;
+                } else {
+                    self.watch_exception()?;
+                    return Ok(None);
+                }
+            }
+        }
+
+        Ok(Some(address))
+    }
+
     pub fn step(&mut self) -> Result<(), InstructionFault> {
         self.num_steps += 1;
 
-        // increment Cop0_Count at half PClock and trigger exception
-        self.half_clock ^= 1;
-        self.cp0gpr[Cop0_Count] = ((self.cp0gpr[Cop0_Count] as u32) + self.half_clock) as u64;
+        // Cop0_Count increments at half PClock, so advance it by the cycles the previous
+        // instruction took. the timer interrupt triggers when Count passes Compare
+        let half_cycles = (self.half_clock as u64) + self.cycles;
+        self.half_clock = (half_cycles & 0x01) as u32;
+        let old_count = self.cp0gpr[Cop0_Count] as u32;
+        let new_count = old_count.wrapping_add((half_cycles >> 1) as u32);
+        self.cp0gpr[Cop0_Count] = new_count as u64;
+        self.cycles = 0;
         //println!("Count=${:08X} Compare=${:08X}", self.cp0gpr[Cop0_Count], self.cp0gpr[Cop0_Compare]);
-        if self.cp0gpr[Cop0_Compare] == self.cp0gpr[Cop0_Count] {
+        if new_count != old_count && (self.cp0gpr[Cop0_Compare] as u32).wrapping_sub(old_count).wrapping_sub(1) < new_count.wrapping_sub(old_count) {
+            // IP7 stays pending until Compare is written
+            self.cp0gpr[Cop0_Cause] |= InterruptCode_Timer << 8;
+
             // Timer interrupt enable, bit 7 of IM field 
             if self.interrupts_enabled(STATUS_IM_TIMER_INTERRUPT_ENABLE_FLAG) { // TODO move to self.timer_interrupt()
                 debug!(target: "CPU", "COP0: timer interrupt");
@@ -873,8 +1604,28 @@ impl Cpu {
             }
         }
 
-        // decrement Random every cycle
-        // when Wired bit 5 is set, Random runs 0..63 with no wired limit
+        // a Watch exception held back by EXL is taken before the next instruction once it clears
+        if self.watch_pending && (self.cp0gpr[Cop0_Status] & 0x02) == 0 {
+            debug!(target: "CPU", "COP0: deferred watch exception");
+            self.watch_pending = false;
+            self.watch_exception()?;
+        }
+
+        self.advance_random();
+
+        // compiled code runs whole blocks at a time
+        #[cfg(feature = "jit")]
+        if let Some(result) = self.run_jit() {
+            return result;
+        }
+
+        self.execute()
+    }
+
+    // decrement Random every cycle
+    // when Wired bit 5 is set, Random runs 0..63 with no wired limit
+    #[inline(always)]
+    fn advance_random(&mut self) {
         self.cp0gpr[Cop0_Random] = if (self.cp0gpr[Cop0_Wired] & 0x20) != 0 || ((self.cp0gpr[Cop0_Random] ^ self.cp0gpr[Cop0_Wired]) & 0x1F) != 0 {
             (self.cp0gpr[Cop0_Random] & 0x3F).wrapping_sub(1) & 0x3F
         } else {
@@ -884,7 +1635,11 @@ impl Cpu {
                 0x1F
             }
         };
+    }
 
+    // execute the prefetched instruction and fetch the one after it
+    #[inline(always)]
+    fn execute(&mut self) -> Result<(), InstructionFault> {
         // For address and TLB exceptions, the current_instruction_pc needs to point to
         // the address being fetched. This lets Cop0_EPC be set to the correct PC
         self.current_instruction_pc = self.pc; // For Cop0_EPC in case of a TLB miss in a delay
@@ -897,22 +1652,12 @@ impl Cpu {
             return self.address_exception(self.pc, false);
         }
 
-        // current instruction
-        let inst = self.next_instruction;
-        self.inst.v = inst;
-
-        // instruction decode
-        self.inst.op         = inst >> 26;
-        self.inst.rs         = ((inst >> 21) & 0x1F) as usize;
-        self.inst.rt         = ((inst >> 16) & 0x1F) as usize;
-        self.inst.rd         = ((inst >> 11) & 0x1F) as usize;
-        self.inst.imm        = (inst & 0xFFFF) as u64;
-        self.inst.signed_imm = (self.inst.imm as i16) as u64;
-        self.inst.target     = inst & 0x3FFFFFF;
-        self.inst.sa         = (inst >> 6) & 0x1F;
+        // current instruction, already decoded
+        let current = self.next_decoded;
+        self.inst = current.decode;
 
         // next instruction prefetch. we need to catch TLB misses
-        self.next_instruction = match self.read_u32(self.pc as usize) {
+        self.next_decoded = match self.fetch_instruction(self.pc) {
             // most common situation
             Ok(x) => x,
 
@@ -992,6 +1737,7 @@ impl Cpu {
 
             Err(x) => { return Err(x); },
         };
+        self.next_instruction = self.next_decoded.decode.v;
 
         // update and increment PC
         // current_instruction_pc is set twice, once at the beginning in case an external factor
@@ -1005,8 +1751,20 @@ impl Cpu {
         self.is_delay_slot = self.next_is_delay_slot;
         self.next_is_delay_slot = false;
 
+        // pipeline cost of this instruction. memory accesses add to self.cycles as they happen
+        self.cycles += Self::instruction_cycles(&self.inst, self.load_delay_reg);
+        self.load_delay_reg = Self::load_destination(&self.inst);
+
         // execute instruction and check result
-        let result = match self.instruction_table[self.inst.op as usize](self) {
+        let result = (current.handler)(self);
+        self.finish_instruction(current, result)
+    }
+
+    // Turn the result of an instruction handler into CPU exceptions, and leave the CPU ready for
+    // the next instruction. Faults that stop emulation put `current` back so it runs again
+    #[inline(always)]
+    fn finish_instruction(&mut self, current: DecodedInstruction, result: Result<(), InstructionFault>) -> Result<(), InstructionFault> {
+        let result = match result {
             // Most common situation
             result @ Ok(_) => result,
 
@@ -1025,7 +1783,15 @@ impl Cpu {
                 panic!("Am I using this?");
             }
 
-            Err(InstructionFault::ReadWrite(fault)) => {
+            // a load or store that nothing on the bus responded to
+            Err(InstructionFault::ReadWrite(ReadWriteFault::BusError)) => {
+                let _ = self.bus_error_exception(ExceptionCode_DBE);
+                Ok(())
+            },
+
+            // ReadWriteFault::Break is left to stop below, since it's the debugger's memory
+            // breakpoints halting a load or store that's retried when execution resumes
+            Err(InstructionFault::ReadWrite(fault @ ReadWriteFault::Invalid)) => {
                 error!(target: "CPU", "crash at PC=${:16X}: {:?}", self.current_instruction_pc, fault);
                 info!(target: "CPU", "[$80000318] = ${:08X}", self.read_u32_phys(
                        Address {
@@ -1039,12 +1805,14 @@ impl Cpu {
                 panic!("cpu crash");
             }
 
-            // Other faults like Break, Unimplemented actually stop processing
+            // Other faults like Break (from a BREAK instruction or a breakpoint on the bus) and
+            // Unimplemented actually stop processing
             result @ Err(_) => {
                 // on error, restore the previous instruction since it didn't complete
                 self.pc -= 4;
                 self.next_instruction_pc = self.current_instruction_pc;
-                self.next_instruction = inst;
+                self.next_instruction = current.decode.v;
+                self.next_decoded = current;
                 result
             },
         };
@@ -1052,6 +1820,11 @@ impl Cpu {
         // r0 must always be zero
         self.gpr[0] = 0;
 
+        if self.external_access {
+            self.external_access = false;
+            self.cycles += self.bus.borrow_mut().take_stall_cycles();
+        }
+
         // PC and next_instruction_pc have changed, update current_instruction_pc
         self.current_instruction_pc = self.next_instruction_pc;
         self.is_delay_slot = self.next_is_delay_slot;
@@ -1091,6 +1864,11 @@ impl Cpu {
         Ok(())
     }
 
+    // stands in for an instruction whose fetch got a bus error
+    fn inst_bus_error(&mut self) -> Result<(), InstructionFault> {
+        self.bus_error_exception(ExceptionCode_IBE)
+    }
+
     fn inst_andi(&mut self) -> Result<(), InstructionFault> {
         self.gpr[self.inst.rt] = self.gpr[self.inst.rs] & self.inst.imm;
         Ok(())
@@ -1214,12 +1992,22 @@ impl Cpu {
                 ((value & 0xFFFF_FFFF) & !read_only_mask) | (self.cp0gpr[Cop0_Config] & read_only_mask)
             },
 
+            Cop0_WatchLo => {
+                value & 0x0000_0000_FFFF_FFFB // 32-bit register, bit 2 always 0
+            },
+
+            Cop0_WatchHi => {
+                value & 0x0F // PAddr1, physical address bits 35:32
+            },
+
             Cop0_XContext => { // read-only register
                 (self.cp0gpr[Cop0_XContext] & 0x1_FFFF_FFFF) | (value & 0xFFFF_FFFE_0000_0000)
             },
 
             Cop0_Compare => {
-                // TODO clear timer interrupt see 6.3.4
+                // writing Compare acknowledges the timer interrupt
+                self.cp0gpr[Cop0_Cause] &= !(InterruptCode_Timer << 8);
+
                 // truncate to 32-bits
                 value & 0x0000_0000_FFFF_FFFF
             },
@@ -1239,22 +2027,12 @@ impl Cpu {
             },
 
             Cop0_Status => {
-                // TODO testing if little endian mode or 32-bit mode is ever used
+                // TODO little endian mode (RE) isn't supported
                 if (value & 0x0200_0000) != 0 {
                     panic!("CPU: unsupported little endian mode ${value:08X}");
                 }
 
-                if (value & 0x0000_0080) != 0x00 {
-                    self.kernel_64bit_addressing = true;
-                    //warn!(target: "CPU", "64-bit kernel addressing enabled ${value:08X}");
-                } else {
-                    self.kernel_64bit_addressing = false;
-                    //debug!(target: "CPU", "32-bit kernel addressing enabled ${value:08X}");
-                }
-
-                if (value & 0x18) != 0 {
-                    panic!("CPU: unsupported user or supervisor mode");
-                }
+                // KSU and the KX/SX/UX addressing mode bits are read from Cop0_Status on every access
 
                 if (value & 0x800000) != 0 {
                     panic!("CPU: unsupported instruction trace mode");
@@ -1273,6 +2051,14 @@ impl Cpu {
                 value & 0xFF
             },
 
+            Cop0_TagLo => {
+                value & 0x0FFF_FFC0 // PTagLo and PState
+            },
+
+            Cop0_TagHi => {
+                0 // always zero on the VR4300
+            },
+
             // read-only registers
             Cop0_BadVAddr | Cop0_PRId  => {
                 self.cp0gpr[register_number]
@@ -1305,6 +2091,12 @@ impl Cpu {
     // convert into inst_cop at some point
     // all the cop should implement a common cop trait (mfc/mtc/ctc/etc)
     fn inst_cop0(&mut self) -> Result<(), InstructionFault> {
+        // COP0 is always usable in kernel mode, otherwise CU0 must be set
+        if self.operating_mode() != MemorySpace::Kernel && (self.cp0gpr[Cop0_Status] & 0x1000_0000) == 0 {
+            self.coprocessor_unusable_exception(0)?;
+            return Ok(());
+        }
+
         let cop0_op = (self.inst.v >> 21) & 0x1F;
         match cop0_op {
             0b00_000 => { // MFC
@@ -1533,6 +2325,109 @@ impl Cpu {
     }
 
     fn inst_cache(&mut self) -> Result<(), InstructionFault> {
+        let virtual_address = self.gpr[self.inst.rs].wrapping_add(self.inst.signed_imm);
+
+        // rt holds the cache in bits 1:0 (0 = I-cache, 1 = D-cache) and the operation in bits 4:2
+        let cache = self.inst.rt & 0x03;
+        let op = self.inst.rt >> 2;
+
+        // index operations only use the virtual address to select the line
+        let icache_index = ICache::index(virtual_address);
+        let dcache_index = DCache::index(virtual_address);
+
+        // hit operations translate the address and only act if the line holds that address
+        let is_hit_op = op >= 3;
+        let address = if is_hit_op {
+            match self.translate_address(virtual_address, true, false)? {
+                Some(address) => address,
+                None => return Ok(()),
+            }
+        } else {
+            Address { virtual_address, physical_address: 0, cached: true, mapped: false, space: MemorySpace::Kernel, tlb_index: None }
+        };
+
+        let icache_hit = is_hit_op && self.icache.is_hit(virtual_address, address.physical_address);
+        let dcache_hit = is_hit_op && self.dcache.is_hit(virtual_address, address.physical_address);
+
+        // Index_Invalidate, Index_Store_Tag, Hit_Invalidate and Fill change what the I-cache line holds
+        if cache == 0 && matches!(op, 0 | 2 | 4 | 5) {
+            self.forget_icache_line(icache_index);
+        }
+
+        let mut bus = self.bus.borrow_mut();
+        match (cache, op) {
+            (0, 0) => { // Index_Invalidate
+                self.icache.invalidate(icache_index);
+            },
+
+            (0, 1) => { // Index_Load_Tag
+                self.cp0gpr[Cop0_TagLo] = self.icache.line(icache_index).tag_lo() & !0x40;
+            },
+
+            (0, 2) => { // Index_Store_Tag
+                self.icache.line_mut(icache_index).set_tag_lo(self.cp0gpr[Cop0_TagLo] & !0x40);
+            },
+
+            (0, 4) => { // Hit_Invalidate
+                if icache_hit { self.icache.invalidate(icache_index); }
+            },
+
+            (0, 5) => { // Fill
+                self.icache.fill(&mut *bus, icache_index, address.physical_address, &mut self.cycles)?;
+            },
+
+            (0, 6) => { // Hit_Write_Back
+                // I-cache lines are never dirty, but this operation writes them out anyway
+                if icache_hit {
+                    self.icache.line_mut(icache_index).dirty = true;
+                    self.icache.write_back(&mut *bus, icache_index, &mut self.cycles)?;
+                }
+            },
+
+            (1, 0) => { // Index_Write_Back_Invalidate
+                self.dcache.write_back(&mut *bus, dcache_index, &mut self.cycles)?;
+                self.dcache.invalidate(dcache_index);
+            },
+
+            (1, 1) => { // Index_Load_Tag
+                self.cp0gpr[Cop0_TagLo] = self.dcache.line(dcache_index).tag_lo();
+            },
+
+            (1, 2) => { // Index_Store_Tag
+                self.dcache.line_mut(dcache_index).set_tag_lo(self.cp0gpr[Cop0_TagLo]);
+            },
+
+            (1, 3) => { // Create_Dirty_Exclusive
+                // claim the line for this address without reading memory
+                if !dcache_hit {
+                    self.dcache.write_back(&mut *bus, dcache_index, &mut self.cycles)?;
+                }
+                let line = self.dcache.line_mut(dcache_index);
+                line.set_tag_lo(((address.physical_address >> 4) & 0x0FFF_FF00) | 0xC0);
+            },
+
+            (1, 4) => { // Hit_Invalidate
+                if dcache_hit { self.dcache.invalidate(dcache_index); }
+            },
+
+            (1, 5) => { // Hit_Write_Back_Invalidate
+                if dcache_hit {
+                    self.dcache.write_back(&mut *bus, dcache_index, &mut self.cycles)?;
+                    self.dcache.invalidate(dcache_index);
+                }
+            },
+
+            (1, 6) => { // Hit_Write_Back
+                if dcache_hit {
+                    self.dcache.write_back(&mut *bus, dcache_index, &mut self.cycles)?;
+                }
+            },
+
+            _ => {
+                warn!(target: "CPU", "undefined cache operation {} on cache {} at ${:016X}", op, cache, virtual_address);
+            },
+        }
+
         Ok(())
     }
 
@@ -1571,6 +2466,7 @@ impl Cpu {
         let dest = ((self.pc - 4) & 0xFFFF_FFFF_F000_0000) | ((self.inst.target << 2) as u64);
 
         self.gpr[31] = self.pc;
+        self.push_call(dest);
         self.pc = dest;
 
         // note that the next instruction to execute is a delay slot instruction
@@ -1671,7 +2567,7 @@ impl Cpu {
         }
 
         // translate the address here
-        let address = match self.translate_address(virtual_address, true, false)? {
+        let address = match self.translate_data_address(virtual_address, false)? {
             Some(address) => address,
             None => {
                 // this shouldn't happen
@@ -1697,7 +2593,7 @@ impl Cpu {
         }
 
         // translate the address here
-        let address = match self.translate_address(virtual_address, true, false)? {
+        let address = match self.translate_data_address(virtual_address, false)? {
             Some(address) => address,
             None => {
                 // this shouldn't happen
@@ -1848,7 +2744,7 @@ impl Cpu {
         }
 
         // translate the address here
-        let address = match self.translate_address(virtual_address, true, false)? {
+        let address = match self.translate_data_address(virtual_address, true)? {
             Some(address) => address,
             None => {
                 // this shouldn't happen
@@ -1918,7 +2814,7 @@ impl Cpu {
         let virtual_address = self.gpr[self.inst.rs].wrapping_add(self.inst.signed_imm) as usize;
 
         // translate the address with the offset so that TLB errors produce the correct BadVAddr
-        let mut address = match self.translate_address(virtual_address as u64, true, true)? {
+        let mut address = match self.translate_data_address(virtual_address as u64, true)? {
             Some(address) => address,
             None => {
                 // this shouldn't happen, but if it does we act like a NOP
@@ -1951,7 +2847,7 @@ impl Cpu {
         let virtual_address = self.gpr[self.inst.rs].wrapping_add(self.inst.signed_imm) as usize;
 
         // translate the address with the offset so that TLB errors produce the correct BadVAddr
-        let mut address = match self.translate_address(virtual_address as u64, true, true)? {
+        let mut address = match self.translate_data_address(virtual_address as u64, true)? {
             Some(address) => address,
             None => {
                 // this shouldn't happen, but if it does we act like a NOP
@@ -2015,7 +2911,7 @@ impl Cpu {
         let virtual_address = self.gpr[self.inst.rs].wrapping_add(self.inst.signed_imm) as usize;
 
         // translate the address with the offset so that TLB errors produce the correct BadVAddr
-        let mut address = match self.translate_address(virtual_address as u64, true, true)? {
+        let mut address = match self.translate_data_address(virtual_address as u64, true)? {
             Some(address) => address,
             None => {
                 // this shouldn't happen, but if it does we act like a NOP
@@ -2051,7 +2947,7 @@ impl Cpu {
         let virtual_address = self.gpr[self.inst.rs].wrapping_add(self.inst.signed_imm) as usize;
 
         // translate the address with the offset so that TLB errors produce the correct BadVAddr
-        let mut address = match self.translate_address(virtual_address as u64, true, true)? {
+        let mut address = match self.translate_data_address(virtual_address as u64, true)? {
             Some(address) => address,
             None => {
                 // this shouldn't happen, but if it does we act like a NOP
@@ -2088,14 +2984,11 @@ impl Cpu {
         Ok(())
     }
 
-    fn regimm_unknown(&mut self) -> Result<(), InstructionFault> {
-        panic!("CPU: unimplemented regimm op: 0b{:02b}_{:03b}", self.inst.regimm >> 3, self.inst.regimm & 0x07);
-    }
-
     fn regimm_bgezal(&mut self) -> Result<(), InstructionFault> {
         // compute condition before changing gpr31 as rs can be 31...
         let condition = (self.gpr[self.inst.rs] as i64) >= 0;
         self.gpr[31] = self.pc; // unconditionally, the address after the delay slot is stored in the link register
+        if condition { self.push_call(self.pc.wrapping_sub(4).wrapping_add(self.inst.signed_imm << 2)); }
         self.branch(condition);
         Ok(())
     }
@@ -2105,6 +2998,7 @@ impl Cpu {
         self.gpr[31] = self.pc; // unconditionally, the address after the delay slot is stored in the link register
 
         let condition = (self.gpr[self.inst.rs] as i64) >= 0;
+        if condition { self.push_call(self.pc.wrapping_sub(4).wrapping_add(self.inst.signed_imm << 2)); }
         self.branch_likely(condition)?;
 
         Ok(())
@@ -2122,6 +3016,22 @@ impl Cpu {
         self.branch_likely(condition)
     }
 
+    fn regimm_bltzal(&mut self) -> Result<(), InstructionFault> {
+        // compute condition before changing gpr31 as rs can be 31...
+        let condition = (self.gpr[self.inst.rs] as i64) < 0;
+        self.gpr[31] = self.pc; // unconditionally, the address after the delay slot is stored in the link register
+        if condition { self.push_call(self.pc.wrapping_sub(4).wrapping_add(self.inst.signed_imm << 2)); }
+        self.branch(condition);
+        Ok(())
+    }
+
+    fn regimm_bltzall(&mut self) -> Result<(), InstructionFault> {
+        let condition = (self.gpr[self.inst.rs] as i64) < 0;
+        self.gpr[31] = self.pc; // unconditionally, the address after the delay slot is stored in the link register
+        if condition { self.push_call(self.pc.wrapping_sub(4).wrapping_add(self.inst.signed_imm << 2)); }
+        self.branch_likely(condition)
+    }
+
     fn regimm_bltz(&mut self) -> Result<(), InstructionFault> {
         let condition = (self.gpr[self.inst.rs] as i64) < 0;
         self.branch(condition);
@@ -2356,6 +3266,7 @@ impl Cpu {
     fn special_jalr(&mut self) -> Result<(), InstructionFault> {
         let dest = self.gpr[self.inst.rs]; // get dest before changing RD, as RS could be == RD
         self.gpr[self.inst.rd] = self.pc; // pc pointing to after the delay slot already
+        self.push_call(dest);
         self.pc = dest;
 
         // note that the next instruction to execute is a delay slot instruction
@@ -2366,6 +3277,7 @@ impl Cpu {
 
     fn special_jr(&mut self) -> Result<(), InstructionFault> {
         self.pc = self.gpr[self.inst.rs];
+        if self.inst.rs == 31 { self.pop_call(self.pc); }
 
         // note that the next instruction to execute is a delay slot instruction
         self.next_is_delay_slot = true;
@@ -2862,3 +3774,343 @@ impl Cpu {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAM_SIZE: usize = 0x10_0000;
+    const BOOT_ROM: usize = 0x1FC0_0000;
+
+    const STATUS_KX: u64 = 0x80;
+
+    // where test programs are placed, run uncached
+    const PROGRAM: u64 = 0xFFFF_FFFF_A000_1000;
+
+    const GENERAL_VECTOR: u64 = 0xFFFF_FFFF_8000_0180;
+
+    // RDRAM at 0 and a boot ROM at the reset vector. Nothing else on the bus answers, like the
+    // addresses from $8000_0000 up on the RCP bus
+    struct TestBus {
+        ram: Vec<u32>,
+        boot_rom: Vec<u32>,
+    }
+
+    impl Addressable for TestBus {
+        fn read_u32(&mut self, offset: usize) -> Result<u32, ReadWriteFault> {
+            match offset {
+                _ if offset < RAM_SIZE => Ok(self.ram[offset >> 2]),
+                _ if (BOOT_ROM..BOOT_ROM + 0x1000).contains(&offset) => Ok(self.boot_rom[(offset - BOOT_ROM) >> 2]),
+                _ => Err(ReadWriteFault::BusError),
+            }
+        }
+
+        fn write_u32(&mut self, value: u32, offset: usize) -> Result<WriteReturnSignal, ReadWriteFault> {
+            if offset >= RAM_SIZE {
+                return Err(ReadWriteFault::BusError);
+            }
+            self.ram[offset >> 2] = value;
+            Ok(WriteReturnSignal::None)
+        }
+    }
+
+    fn r(rs: usize, rt: usize, rd: usize, sa: u32, funct: u32) -> u32 {
+        ((rs as u32) << 21) | ((rt as u32) << 16) | ((rd as u32) << 11) | (sa << 6) | funct
+    }
+    fn i(op: u32, rs: usize, rt: usize, imm: u16) -> u32 {
+        (op << 26) | ((rs as u32) << 21) | ((rt as u32) << 16) | (imm as u32)
+    }
+
+    fn nop() -> u32 { 0 }
+    fn ori(rt: usize, rs: usize, imm: u16) -> u32 { i(0b001_101, rs, rt, imm) }
+    fn lui(rt: usize, imm: u16) -> u32 { i(0b001_111, 0, rt, imm) }
+    fn addu(rd: usize, rs: usize, rt: usize) -> u32 { r(rs, rt, rd, 0, 0b100_001) }
+    fn mult(rs: usize, rt: usize) -> u32 { r(rs, rt, 0, 0, 0b011_000) }
+    fn div(rs: usize, rt: usize) -> u32 { r(rs, rt, 0, 0, 0b011_010) }
+    fn jr(rs: usize) -> u32 { r(rs, 0, 0, 0, 0b001_000) }
+    fn dsll32(rd: usize, rt: usize, sa: u32) -> u32 { r(0, rt, rd, sa, 0b111_100) }
+    fn lb(rt: usize, offset: i16, base: usize) -> u32 { i(0b100_000, base, rt, offset as u16) }
+    fn lw(rt: usize, offset: i16, base: usize) -> u32 { i(0b100_011, base, rt, offset as u16) }
+    fn sw(rt: usize, offset: i16, base: usize) -> u32 { i(0b101_011, base, rt, offset as u16) }
+    fn mtc0(rt: usize, rd: usize) -> u32 { (0b010_000 << 26) | (0b00_100 << 21) | ((rt as u32) << 16) | ((rd as u32) << 11) }
+    fn syscall() -> u32 { 0x0000_000C }
+    fn eret() -> u32 { 0x4200_0018 }
+
+    // A CPU that's taken the boot ROM's jump to `program`, and is about to run its first
+    // instruction in kernel mode. Exceptions are vectored to RAM, which is otherwise nops
+    fn cpu(program: &[u32]) -> (Cpu, Rc<RefCell<TestBus>>) {
+        let mut bus = TestBus { ram: vec![0; RAM_SIZE >> 2], boot_rom: vec![0; 0x1000 >> 2] };
+        bus.boot_rom[..4].copy_from_slice(&[lui(26, 0xA000), ori(26, 26, 0x1000), jr(26), nop()]);
+        bus.ram[0x1000 >> 2..][..program.len()].copy_from_slice(program);
+        let bus = Rc::new(RefCell::new(bus));
+
+        let comms = SystemCommunication::new(None);
+        #[cfg(feature = "jit")]
+        { comms.settings.write().unwrap().jit = JitMode::Disabled; }
+
+        let mut cpu = Cpu::new(bus.clone(), comms);
+        run_to(&mut cpu, PROGRAM);
+        cpu.cp0gpr[Cop0_Status] = 0;
+        (cpu, bus)
+    }
+
+    // exceptions taken between instructions are reported by step(), like interrupts
+    fn step(cpu: &mut Cpu) {
+        match cpu.step() {
+            Ok(()) | Err(InstructionFault::OtherException(_)) => {},
+            Err(fault) => panic!("{:?}", fault),
+        }
+    }
+
+    fn run_to(cpu: &mut Cpu, pc: u64) {
+        for _ in 0..100 {
+            if cpu.next_instruction_pc == pc { return; }
+            step(cpu);
+        }
+        panic!("didn't reach ${:016X}", pc);
+    }
+
+    fn exception_code(cpu: &Cpu) -> u64 {
+        (cpu.cp0gpr[Cop0_Cause] >> 2) & 0x1F
+    }
+
+    #[test]
+    fn pipeline_cycles_and_count() {
+        let (mut cpu, _) = cpu(&[nop(), mult(2, 3), div(2, 3), lw(4, 0, 8), addu(5, 4, 4), nop()]);
+        cpu.gpr[3] = 7;
+        cpu.gpr[8] = 0xFFFF_FFFF_A000_2000;
+
+        // every instruction also pays for the uncached fetch of the next one, and the load for
+        // its uncached read. the add stalls a cycle waiting for the load
+        let fetch = UNCACHED_RDRAM_CYCLES;
+        let expected = [1 + fetch, 5 + fetch, 37 + fetch, 1 + UNCACHED_RDRAM_CYCLES + fetch, 2 + fetch, 1 + fetch];
+
+        // Count runs at half the pipeline rate, and catches up with an instruction's cycles
+        // at the start of the next step
+        let start = (cpu.cp0gpr[Cop0_Count] << 1) + (cpu.half_clock as u64) + cpu.cycles;
+        let mut elapsed = 0;
+        for cycles in expected {
+            step(&mut cpu);
+            assert_eq!((cpu.cp0gpr[Cop0_Count] << 1) + (cpu.half_clock as u64), start + elapsed);
+            assert_eq!(cpu.cycles(), cycles);
+            elapsed += cycles;
+        }
+    }
+
+    #[test]
+    fn compare_raises_the_timer_interrupt() {
+        let (mut cpu, bus) = cpu(&[nop(); 8]);
+
+        // acknowledge the interrupt by writing Compare
+        bus.borrow_mut().ram[0x180 >> 2] = mtc0(0, Cop0_Compare);
+
+        cpu.cp0gpr[Cop0_Status] = 0x8001; // IM7 and IE
+        cpu.cp0gpr[Cop0_Compare] = cpu.cp0gpr[Cop0_Count] + 40;
+
+        // IP7 is raised on the step that takes Count to Compare, and the interrupt is taken
+        let mut count = cpu.cp0gpr[Cop0_Count];
+        while cpu.next_instruction_pc != GENERAL_VECTOR {
+            assert!(count < cpu.cp0gpr[Cop0_Compare]);
+            assert_eq!(cpu.cp0gpr[Cop0_Cause] & 0x8000, 0);
+            step(&mut cpu);
+            count = cpu.cp0gpr[Cop0_Count];
+        }
+        assert!(count >= cpu.cp0gpr[Cop0_Compare]);
+        assert_eq!(cpu.cp0gpr[Cop0_Cause] & 0x8000, 0x8000);
+        assert_eq!(exception_code(&cpu), ExceptionCode_Int);
+
+        step(&mut cpu);
+        assert_eq!(cpu.cp0gpr[Cop0_Cause] & 0x8000, 0);
+    }
+
+    #[test]
+    fn address_spaces_follow_kx_sx_ux() {
+        let (mut cpu, _) = cpu(&[]);
+        let mapped = |cpu: &mut Cpu, address| cpu.classify_address(address, false, false).unwrap().map(|address| address.mapped);
+
+        // 32-bit kernel mode only takes sign extended addresses
+        assert_eq!(mapped(&mut cpu, 0xFFFF_FFFF_8000_0000), Some(false));
+        assert_eq!(mapped(&mut cpu, 0xFFFF_FFFF_C000_0000), Some(true));
+        assert_eq!(mapped(&mut cpu, 0x0000_0001_0000_0000), None);
+        assert_eq!(mapped(&mut cpu, 0x9000_0000_0000_1000), None);
+
+        // KX opens up xkuseg, xksseg, xkphys and xkseg
+        cpu.cp0gpr[Cop0_Status] = STATUS_KX;
+        assert_eq!(mapped(&mut cpu, 0x0000_00FF_FFFF_F000), Some(true));
+        assert_eq!(mapped(&mut cpu, 0x0000_0100_0000_0000), None);
+        assert_eq!(mapped(&mut cpu, 0x4000_00FF_0000_0000), Some(true));
+        assert_eq!(mapped(&mut cpu, 0x9000_0000_0000_1000), Some(false));
+        assert_eq!(mapped(&mut cpu, 0xC000_00FF_7FFF_F000), Some(true));
+        assert_eq!(mapped(&mut cpu, 0xC000_00FF_8000_0000), None);
+        assert_eq!(mapped(&mut cpu, 0xFFFF_FFFF_8000_0000), Some(false));
+
+        // supervisor mode goes by SX, and only has xsseg and csseg
+        cpu.cp0gpr[Cop0_Status] = 0x08;
+        assert_eq!(mapped(&mut cpu, 0x4000_0000_0000_0000), None);
+        assert_eq!(mapped(&mut cpu, 0xFFFF_FFFF_C000_0000), Some(true));
+        cpu.cp0gpr[Cop0_Status] = 0x08 | 0x40;
+        assert_eq!(mapped(&mut cpu, 0x4000_0000_0000_0000), Some(true));
+        assert_eq!(mapped(&mut cpu, 0x9000_0000_0000_1000), None);
+        assert_eq!(mapped(&mut cpu, 0xFFFF_FFFF_8000_0000), None);
+        assert_eq!(mapped(&mut cpu, 0xFFFF_FFFF_C000_0000), Some(true));
+
+        // and user mode by UX, with only xuseg
+        cpu.cp0gpr[Cop0_Status] = 0x10;
+        assert_eq!(mapped(&mut cpu, 0x0000_0001_0000_0000), None);
+        cpu.cp0gpr[Cop0_Status] = 0x10 | 0x20;
+        assert_eq!(mapped(&mut cpu, 0x0000_0001_0000_0000), Some(true));
+        assert_eq!(mapped(&mut cpu, 0x4000_0000_0000_0000), None);
+        assert_eq!(mapped(&mut cpu, 0xFFFF_FFFF_C000_0000), None);
+    }
+
+    #[test]
+    fn xkphys_windows() {
+        let (mut cpu, _) = cpu(&[]);
+        cpu.cp0gpr[Cop0_Status] = STATUS_KX;
+        let classify = |cpu: &mut Cpu, address| {
+            cpu.classify_address(address, false, false).unwrap().map(|address| (address.physical_address, address.cached))
+        };
+
+        // bits 61:59 are the cache algorithm, 2 being uncached
+        assert_eq!(classify(&mut cpu, 0x9000_0000_0000_1000), Some((0x1000, false)));
+        assert_eq!(classify(&mut cpu, 0x9800_0000_0000_1000), Some((0x1000, true)));
+        assert_eq!(classify(&mut cpu, 0x8800_0000_FFFF_FFFC), Some((0xFFFF_FFFC, true)));
+
+        // bits 58:32 past the cache algorithm have to be zero
+        assert_eq!(classify(&mut cpu, 0x9000_0001_0000_0000), None);
+        assert_eq!(classify(&mut cpu, 0x9020_0000_0000_0000), None);
+    }
+
+    #[test]
+    fn xtlb_miss_sets_xcontext() {
+        let (mut cpu, _) = cpu(&[]);
+        cpu.cp0gpr[Cop0_Status] = STATUS_KX;
+        cpu.cp0gpr[Cop0_XContext] = 0x1234_5678_0000_0000;
+        cpu.cp0gpr[Cop0_EntryHi] = 0x42;
+
+        let virtual_address = 0xC000_0012_3456_7000;
+        assert!(cpu.translate_address(virtual_address, true, false).is_err());
+        assert_eq!(exception_code(&cpu), ExceptionCode_TLBL);
+        assert_eq!(cpu.cp0gpr[Cop0_BadVAddr], virtual_address);
+
+        // XContext keeps PTEBase, with R in bits 32:31 and BadVPN2 in bits 30:4
+        assert_eq!(cpu.cp0gpr[Cop0_XContext], 0x1234_5678_0000_0000 | (3 << 31) | (((virtual_address >> 13) & 0x7FF_FFFF) << 4));
+        assert_eq!(cpu.cp0gpr[Cop0_EntryHi], 0xC000_0012_3456_6042);
+
+        // and a miss in 64-bit mode goes to the XTLB refill vector
+        assert_eq!(cpu.next_instruction_pc, 0xFFFF_FFFF_8000_0080);
+    }
+
+    #[test]
+    fn tlb_matches_region_and_vpn2() {
+        let (mut cpu, _) = cpu(&[]);
+        cpu.cp0gpr[Cop0_Status] = STATUS_KX;
+
+        // a pair of 4KiB pages in xkseg, at physical $2000 and $3000, for ASID $42
+        cpu.cp0gpr[Cop0_PageMask] = 0;
+        cpu.cp0gpr[Cop0_EntryHi] = 0xC000_0012_3456_0042;
+        cpu.cp0gpr[Cop0_EntryLo0] = (0x2000 >> 6) | 0x06;
+        cpu.cp0gpr[Cop0_EntryLo1] = (0x3000 >> 6) | 0x06;
+        cpu.set_tlb_entry(0);
+        let translate = |cpu: &mut Cpu, address| cpu.translate_address(address, false, false).unwrap().map(|address| address.physical_address);
+
+        assert_eq!(translate(&mut cpu, 0xC000_0012_3456_0ABC), Some(0x2ABC));
+        assert_eq!(translate(&mut cpu, 0xC000_0012_3456_1ABC), Some(0x3ABC));
+
+        // every bit of the 40-bit VPN2 and the region have to match
+        assert_eq!(translate(&mut cpu, 0xC000_0013_3456_0ABC), None);
+        assert_eq!(translate(&mut cpu, 0xC000_0092_3456_0ABC), None);
+        assert_eq!(translate(&mut cpu, 0x4000_0012_3456_0ABC), None);
+        assert_eq!(translate(&mut cpu, 0x0000_0012_3456_0ABC), None);
+
+        // as does the ASID, since the entry isn't global
+        cpu.cp0gpr[Cop0_EntryHi] = 0x43;
+        assert_eq!(translate(&mut cpu, 0xC000_0012_3456_0ABC), None);
+    }
+
+    #[test]
+    fn exceptions_keep_the_64bit_pc() {
+        // jump to $9000_0000_0000_2000, uncached xkphys, and take a syscall there
+        let (mut cpu, bus) = cpu(&[lui(8, 0x9000), dsll32(8, 8, 0), ori(8, 8, 0x2000), jr(8), nop()]);
+        bus.borrow_mut().ram[0x2000 >> 2] = syscall();
+        bus.borrow_mut().ram[0x180 >> 2] = eret();
+        cpu.cp0gpr[Cop0_Status] = STATUS_KX;
+
+        run_to(&mut cpu, 0x9000_0000_0000_2000);
+        step(&mut cpu);
+        assert_eq!(exception_code(&cpu), ExceptionCode_Sys);
+        assert_eq!(cpu.cp0gpr[Cop0_EPC], 0x9000_0000_0000_2000);
+        assert_eq!(cpu.next_instruction_pc, GENERAL_VECTOR);
+
+        // so ERET goes back to where the exception was taken
+        step(&mut cpu);
+        assert_eq!(cpu.next_instruction_pc, 0x9000_0000_0000_2000);
+    }
+
+    // run one load or store with base register 8 at $A000_2000 and WatchLo/WatchHi set. returns
+    // the exception taken, if any
+    fn watched(inst: u32, watch_lo: u64, watch_hi: u64) -> Option<u64> {
+        let (mut cpu, bus) = cpu(&[inst, nop()]);
+        cpu.gpr[2] = 0x1234_5678;
+        cpu.gpr[8] = 0xFFFF_FFFF_A000_2000;
+        cpu.cp0gpr[Cop0_WatchLo] = watch_lo;
+        cpu.cp0gpr[Cop0_WatchHi] = watch_hi;
+
+        step(&mut cpu);
+        if cpu.next_instruction_pc != GENERAL_VECTOR {
+            return None;
+        }
+
+        // the exception is taken before the access
+        assert_eq!(cpu.cp0gpr[Cop0_EPC], PROGRAM);
+        assert_eq!(cpu.gpr[2], 0x1234_5678);
+        assert!(bus.borrow().ram[0x2000 >> 2..][..2] == [0, 0]);
+        Some(exception_code(&cpu))
+    }
+
+    #[test]
+    fn watch_matches_loads_and_stores() {
+        // R (bit 1) watches loads and W (bit 0) stores, anywhere in the doubleword
+        assert_eq!(watched(lw(2, 4, 8), 0x2000 | 0x02, 0), Some(ExceptionCode_WATCH));
+        assert_eq!(watched(sw(2, 0, 8), 0x2000 | 0x01, 0), Some(ExceptionCode_WATCH));
+        assert_eq!(watched(lb(2, 7, 8), 0x2000 | 0x03, 0), Some(ExceptionCode_WATCH));
+        assert_eq!(watched(lw(2, 8, 8), 0x2000 | 0x03, 0), None);
+
+        // each needs its enable bit
+        assert_eq!(watched(lw(2, 0, 8), 0x2000 | 0x01, 0), None);
+        assert_eq!(watched(sw(2, 0, 8), 0x2000 | 0x02, 0), None);
+        assert_eq!(watched(lw(2, 0, 8), 0x2000, 0), None);
+
+        // WatchHi has physical address bits 35:32, which are never set on the N64's 32-bit bus
+        assert_eq!(watched(lw(2, 0, 8), 0x2000 | 0x02, 0x01), None);
+        assert_eq!(watched(sw(2, 0, 8), 0x2000 | 0x01, 0x08), None);
+    }
+
+    #[test]
+    fn bus_errors_raise_exceptions() {
+        // xkphys reaches physical addresses from $8000_0000 up, where nothing answers
+        let xkphys = 0x9000_0000_8000_0000;
+        for inst in [lw(2, 0, 8), sw(2, 0, 8)] {
+            let (mut cpu, _) = cpu(&[inst, nop()]);
+            cpu.cp0gpr[Cop0_Status] = STATUS_KX;
+            cpu.gpr[8] = xkphys;
+
+            step(&mut cpu);
+            assert_eq!(exception_code(&cpu), ExceptionCode_DBE);
+            assert_eq!(cpu.cp0gpr[Cop0_EPC], PROGRAM);
+            assert_eq!(cpu.next_instruction_pc, GENERAL_VECTOR);
+        }
+
+        // fetching from there raises IBE once the instruction would execute
+        let (mut cpu, _) = cpu(&[jr(8), nop()]);
+        cpu.cp0gpr[Cop0_Status] = STATUS_KX;
+        cpu.gpr[8] = xkphys;
+
+        run_to(&mut cpu, xkphys);
+        assert_eq!(exception_code(&cpu), 0);
+        step(&mut cpu);
+        assert_eq!(exception_code(&cpu), ExceptionCode_IBE);
+        assert_eq!(cpu.cp0gpr[Cop0_EPC], xkphys);
+        assert_eq!(cpu.next_instruction_pc, GENERAL_VECTOR);
+    }
+}
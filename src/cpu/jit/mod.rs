@@ -0,0 +1,1162 @@
+// x86-64 recompiler for the VR4300
+//
+// Blocks from the interpreter's block cache are compiled to host code. Blocks are found by
+// physical address and entered at whatever virtual address the CPU is running at, so code in
+// TLB mapped segments compiles the same as code in kseg0/kseg1.
+//
+// What runs as host code:
+//   - integer ALU, shift, set and multiply instructions, operating directly on Cpu::gpr/hi/lo
+//   - conditional branches and J at the end of the block. A taken branch returns with the CPU
+//     holding the target, and branch likely instructions that aren't taken call their handler
+//     to skip the delay slot
+//   - the load interlock, which for the first instruction is checked at run time
+//
+// Everything else calls the instruction's interpreter handler directly from compiled code,
+// without going through the fetch and decode of the interpreter loop: loads and stores (TLB
+// mapped or not, through the D-cache), COP1 moves and arithmetic, divides, linking jumps and
+// branches, traps and so on. Exceptions raised by a handler go through Cpu::finish_instruction()
+// just as they do in the interpreter, and compiled code returns as soon as execution leaves the
+// straight-line path, so exceptions, TLB remaps and invalidated blocks are all handled the way the
+// interpreter handles them.
+//
+// The last instruction of a block is left to the interpreter. Blocks end after the delay slot of a
+// branch, after a COP0 instruction (which can change the address mapping) or at the end of an
+// I-cache line or page, so the last instruction either needs the next block fetched first, which is
+// a full lookup that can raise a TLB exception, or is a COP0 instruction that should see Count
+// exactly as the interpreter keeps it. Returning to Cpu::step() between blocks also keeps Count,
+// Compare and interrupts current.
+//
+// In self-check mode every compiled run is replayed through the interpreter from the same starting
+// state and the results are compared, so a bad translation is reported at the block it happened in
+#[cfg(not(target_arch = "x86_64"))]
+compile_error!("the jit feature requires an x86-64 host");
+
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+#[allow(unused_imports)]
+use tracing::{debug,error,trace,warn,info};
+
+use super::*;
+
+mod x86;
+use x86::{Alu, Assembler, CodeBuffer, Condition, Reg, Shift};
+
+// size of the code buffer. when it fills up everything is thrown away and recompiled
+const CODE_BUFFER_SIZE: usize = 32 * 1024 * 1024;
+
+// Compiled code returns the number of instructions run, with these flags. EXITED_IN_HANDLER means
+// a handler left the Cpu in a consistent state, and BRANCH_TAKEN that a compiled branch left its
+// target in Cpu::pc
+const EXITED_IN_HANDLER: u32 = 0x8000_0000;
+const BRANCH_TAKEN: u32 = 0x4000_0000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum JitMode {
+    #[default]
+    Enabled,
+
+    // run every block compiled and interpreted, and report differences
+    SelfCheck,
+
+    Disabled,
+}
+
+type CompiledBlock = unsafe extern "sysv64" fn(*mut Cpu) -> u32;
+
+// where compiled code finds things inside Cpu
+struct Offsets {
+    gpr: i32,
+    lo: i32,
+    hi: i32,
+    pc: i32,
+    base_pc: i32,
+    cycles: i32,
+    load_delay_reg: i32,
+}
+
+impl Offsets {
+    fn new(cpu: &Cpu) -> Self {
+        let base = cpu as *const Cpu as usize;
+        let offset = |field: usize| (field - base) as i32;
+        Self {
+            gpr           : offset(&cpu.gpr as *const _ as usize),
+            lo            : offset(&cpu.lo as *const _ as usize),
+            hi            : offset(&cpu.hi as *const _ as usize),
+            pc            : offset(&cpu.pc as *const _ as usize),
+            base_pc       : offset(&cpu.jit_base_pc as *const _ as usize),
+            cycles        : offset(&cpu.cycles as *const _ as usize),
+            load_delay_reg: offset(&cpu.load_delay_reg as *const _ as usize),
+        }
+    }
+
+    fn gpr(&self, index: usize) -> i32 {
+        self.gpr + (index as i32) * 8
+    }
+}
+
+pub struct Jit {
+    mode: JitMode,
+    buffer: CodeBuffer,
+
+    // compiled code by block. the Weak keeps the allocation (and so the key) from being reused
+    // while the entry exists. None means the block isn't worth compiling
+    compiled: HashMap<*const Block<DecodedInstruction>, (Weak<Block<DecodedInstruction>>, Option<CompiledBlock>)>,
+
+    // the block being run, for handler calls
+    block: *const Block<DecodedInstruction>,
+    fault: Option<InstructionFault>,
+
+    // self-check statistics
+    checked: u64,
+    skipped: u64,
+    mismatches: u64,
+}
+
+impl Jit {
+    pub fn new(mode: JitMode) -> Option<Box<Self>> {
+        if mode == JitMode::Disabled {
+            return None;
+        }
+
+        let buffer = match CodeBuffer::new(CODE_BUFFER_SIZE) {
+            Some(buffer) => buffer,
+            None => {
+                error!(target: "JIT", "couldn't allocate code memory, falling back to the interpreter");
+                return None;
+            },
+        };
+
+        info!(target: "JIT", "recompiler enabled ({:?})", mode);
+        Some(Box::new(Self {
+            mode: mode,
+            buffer: buffer,
+            compiled: HashMap::new(),
+            block: std::ptr::null(),
+            fault: None,
+            checked: 0,
+            skipped: 0,
+            mismatches: 0,
+        }))
+    }
+
+    fn lookup(&mut self, block: &Rc<Block<DecodedInstruction>>, offsets: &Offsets) -> Option<CompiledBlock> {
+        let key = Rc::as_ptr(block);
+        if let Some((_, code)) = self.compiled.get(&key) {
+            return *code;
+        }
+
+        // drop entries for blocks that have been invalidated
+        if self.compiled.len() >= 0x10000 {
+            self.compiled.retain(|_, (weak, _)| weak.strong_count() != 0);
+        }
+
+        let code = match Self::compile(block, offsets) {
+            Some(asm) => {
+                let ptr = match self.buffer.append(asm.code()) {
+                    Some(ptr) => ptr,
+                    None => {
+                        debug!(target: "JIT", "code buffer full, flushing");
+                        self.compiled.clear();
+                        self.buffer.reset();
+                        self.buffer.append(asm.code())?
+                    },
+                };
+                Some(unsafe { std::mem::transmute::<*const u8, CompiledBlock>(ptr) })
+            },
+            None => None,
+        };
+
+        self.compiled.insert(key, (Rc::downgrade(block), code));
+        code
+    }
+
+    fn compile(block: &Block<DecodedInstruction>, offsets: &Offsets) -> Option<Assembler> {
+        // entering compiled code costs a lookup, which a single instruction doesn't make up for
+        let count = block.instructions.len() - 1;
+        if count < 2 {
+            return None;
+        }
+
+        let mut asm = Assembler::new();
+        asm.push_rbx();
+        asm.mov_rbx_rdi();
+
+        // cycles of compiled instructions are added in one go before anything that can leave
+        // compiled code. in uncached code that includes the fetch of the following instruction,
+        // which the interpreter charges
+        let fetch_cycles = if block.cached { 0 } else { UNCACHED_RDRAM_CYCLES };
+        let mut pending_cycles = 0;
+        for i in 0..count {
+            let decode = &block.instructions[i].decode;
+
+            // the load interlock of the first instruction depends on what ran before the block
+            let load_delay_reg = if i == 0 {
+                emit_first_interlock(&mut asm, decode, offsets);
+                0
+            } else {
+                Cpu::load_destination(&block.instructions[i - 1].decode)
+            };
+            pending_cycles += Cpu::instruction_cycles(decode, load_delay_reg) + fetch_cycles;
+
+            if is_native(decode) {
+                emit_native(&mut asm, decode, offsets);
+                continue;
+            }
+
+            asm.add_mem64_imm32(offsets.cycles, pending_cycles as i32);
+            pending_cycles = 0;
+
+            if is_native_branch(decode) {
+                emit_branch(&mut asm, decode, i, offsets);
+                continue;
+            }
+
+            emit_handler_call(&mut asm, i);
+        }
+
+        if pending_cycles != 0 {
+            asm.add_mem64_imm32(offsets.cycles, pending_cycles as i32);
+        }
+        emit_return(&mut asm, count as u32);
+
+        trace!(target: "JIT", "compiled block at ${:08X}: {} instructions, {} bytes", block.physical_address, count, asm.code().len());
+        Some(asm)
+    }
+}
+
+// instructions that only read and write GPRs, HI and LO, and can't raise exceptions
+fn is_native(inst: &InstructionDecode) -> bool {
+    match inst.op {
+        0b000_000 => matches!(inst.special,
+            0b000_000 | 0b000_010 | 0b000_011 | 0b000_100 | 0b000_110 | 0b000_111 // SLL, SRL, SRA, SLLV, SRLV, SRAV
+            | 0b010_000 | 0b010_010                                              // MFHI, MFLO
+            | 0b010_100 | 0b010_110 | 0b010_111                                  // DSLLV, DSRLV, DSRAV
+            | 0b011_000 | 0b011_001 | 0b011_100 | 0b011_101                      // MULT, MULTU, DMULT, DMULTU
+            | 0b100_001 | 0b100_011 | 0b100_100..=0b100_111                      // ADDU, SUBU, AND, OR, XOR, NOR
+            | 0b101_010 | 0b101_011 | 0b101_101 | 0b101_111                      // SLT, SLTU, DADDU, DSUBU
+            | 0b111_000 | 0b111_010 | 0b111_011 | 0b111_100 | 0b111_110 | 0b111_111), // DSLL, DSRL, DSRA, DSLL32, DSRL32, DSRA32
+        0b001_001 | 0b001_010..=0b001_111 | 0b011_001 => true, // ADDIU, SLTI, SLTIU, ANDI, ORI, XORI, LUI, DADDIU
+        _ => false,
+    }
+}
+
+// branches that don't link, and so only change the PC
+fn is_native_branch(inst: &InstructionDecode) -> bool {
+    match inst.op {
+        0b000_001 => matches!(inst.regimm, 0b00_000..=0b00_011), // BLTZ, BGEZ, BLTZL, BGEZL
+        0b000_010 | 0b000_100..=0b000_111 | 0b010_100..=0b010_111 => true, // J, BEQ, BNE, BLEZ, BGTZ, B*L
+        _ => false,
+    }
+}
+
+// leave compiled code, returning status
+fn emit_return(asm: &mut Assembler, status: u32) {
+    asm.mov_eax_imm32(status);
+    asm.pop_rbx();
+    asm.ret();
+}
+
+// the interpreter charges a cycle when an instruction reads the register loaded by the one before
+fn emit_first_interlock(asm: &mut Assembler, inst: &InstructionDecode, offsets: &Offsets) {
+    let mut registers = vec![inst.rs, inst.rt];
+    registers.dedup();
+    for register in registers {
+        if register != 0 && Cpu::instruction_cycles(inst, register) != Cpu::instruction_cycles(inst, 0) {
+            asm.cmp_mem64_imm8(offsets.load_delay_reg, register as i8);
+            let skip = asm.jcc(Condition::NotEqual);
+            asm.add_mem64_imm32(offsets.cycles, 1);
+            asm.bind(skip);
+        }
+    }
+}
+
+// call the handler for instruction `index`, and return if execution left the block
+fn emit_handler_call(asm: &mut Assembler, index: usize) {
+    asm.mov_rdi_rbx();
+    asm.mov_esi_imm32(index as u32);
+    asm.mov_rax_imm64(run_handler as usize as u64);
+    asm.call_rax();
+    asm.test_eax_eax();
+    asm.jz_rel8(7);
+    asm.mov_eax_imm32(((index + 1) as u32) | EXITED_IN_HANDLER); // 5 bytes
+    asm.pop_rbx();                                               // 1 byte
+    asm.ret();                                                   // 1 byte
+}
+
+// Branches come last before the delay slot. When taken the target goes in Cpu::pc and compiled
+// code returns; not taken falls through to the end of the block
+fn emit_branch(asm: &mut Assembler, inst: &InstructionDecode, index: usize, offsets: &Offsets) {
+    let delay_slot = ((index + 1) as i32) << 2;
+
+    if inst.op == 0b000_010 { // J
+        asm.load64(Reg::Rax, offsets.base_pc);
+        asm.alu_imm(Alu::Add, true, delay_slot);
+        asm.alu_imm(Alu::And, true, 0xF000_0000u32 as i32);
+        asm.alu_imm(Alu::Or, true, (inst.target << 2) as i32);
+        asm.store64(offsets.pc);
+        emit_return(asm, ((index + 1) as u32) | BRANCH_TAKEN);
+        return;
+    }
+
+    // compare and jump past the taken path when the condition fails
+    asm.load64(Reg::Rax, offsets.gpr(inst.rs));
+    let not_taken = match inst.op {
+        0b000_100 | 0b010_100 => { asm.alu_mem(Alu::Cmp, true, offsets.gpr(inst.rt)); Condition::NotEqual }, // BEQ, BEQL
+        0b000_101 | 0b010_101 => { asm.alu_mem(Alu::Cmp, true, offsets.gpr(inst.rt)); Condition::Equal },    // BNE, BNEL
+        0b000_110 | 0b010_110 => { asm.alu_imm(Alu::Cmp, true, 0); Condition::Greater },      // BLEZ, BLEZL
+        0b000_111 | 0b010_111 => { asm.alu_imm(Alu::Cmp, true, 0); Condition::LessEqual },    // BGTZ, BGTZL
+        0b000_001 if (inst.regimm & 0x01) == 0 => { asm.alu_imm(Alu::Cmp, true, 0); Condition::GreaterEqual }, // BLTZ, BLTZL
+        0b000_001 => { asm.alu_imm(Alu::Cmp, true, 0); Condition::Less },                     // BGEZ, BGEZL
+        _ => unreachable!(),
+    };
+    let skip = asm.jcc(not_taken);
+
+    asm.load64(Reg::Rax, offsets.base_pc);
+    asm.alu_imm(Alu::Add, true, delay_slot + ((inst.signed_imm << 2) as i32));
+    asm.store64(offsets.pc);
+    emit_return(asm, ((index + 1) as u32) | BRANCH_TAKEN);
+
+    asm.bind(skip);
+
+    // branch likely skips the delay slot when not taken, which needs the block after this one
+    let likely = if inst.op == 0b000_001 { (inst.regimm & 0x02) != 0 } else { inst.op >= 0b010_100 };
+    if likely {
+        emit_handler_call(asm, index);
+    }
+}
+
+// must produce exactly what the interpreter's handler does
+fn emit_native(asm: &mut Assembler, inst: &InstructionDecode, offsets: &Offsets) {
+    let rs = offsets.gpr(inst.rs);
+    let rt = offsets.gpr(inst.rt);
+    let sa = inst.sa as u8;
+
+    // multiplies write HI and LO rather than a GPR
+    if inst.op == 0b000_000 && matches!(inst.special, 0b011_000 | 0b011_001 | 0b011_100 | 0b011_101) {
+        match inst.special {
+            0b011_000 | 0b011_001 => { // MULT, MULTU: the low 64 bits of the product, split into words
+                if inst.special == 0b011_000 {
+                    asm.load32_signed(Reg::Rax, rs);
+                    asm.load32_signed(Reg::Rcx, rt);
+                } else {
+                    asm.load32(Reg::Rax, rs);
+                    asm.load32(Reg::Rcx, rt);
+                }
+                asm.imul_rax_rcx();
+                asm.store64_reg(Reg::Rax, offsets.hi);
+                asm.zero_extend_eax();
+                asm.store64(offsets.lo);
+                asm.load64(Reg::Rax, offsets.hi);
+                asm.shift_imm(Shift::Shr, true, 32);
+                asm.store64(offsets.hi);
+            },
+
+            _ => { // DMULT, DMULTU: the full 128-bit product
+                asm.load64(Reg::Rax, rs);
+                asm.mul_mem64(inst.special == 0b011_100, rt);
+                asm.store64(offsets.lo);
+                asm.store64_reg(Reg::Rdx, offsets.hi);
+            },
+        }
+        return;
+    }
+
+    let destination = if inst.op == 0b000_000 { inst.rd } else { inst.rt };
+    if destination == 0 {
+        return;
+    }
+
+    match inst.op {
+        0b000_000 => match inst.special {
+            0b000_000 => { asm.load32(Reg::Rax, rt); asm.shift_imm(Shift::Shl, false, sa); asm.movsxd_rax_eax(); }, // SLL
+            0b000_010 => { asm.load32(Reg::Rax, rt); asm.shift_imm(Shift::Shr, false, sa); asm.movsxd_rax_eax(); }, // SRL
+            0b000_011 => { asm.load64(Reg::Rax, rt); asm.shift_imm(Shift::Shr, true, sa); asm.movsxd_rax_eax(); },  // SRA
+            0b000_100 => { asm.load32(Reg::Rcx, rs); asm.load32(Reg::Rax, rt); asm.shift_cl(Shift::Shl, false); asm.movsxd_rax_eax(); }, // SLLV
+            0b000_110 => { asm.load32(Reg::Rcx, rs); asm.load32(Reg::Rax, rt); asm.shift_cl(Shift::Shr, false); asm.movsxd_rax_eax(); }, // SRLV
+            0b000_111 => { asm.load32(Reg::Rcx, rs); asm.and_ecx_31(); asm.load64(Reg::Rax, rt); asm.shift_cl(Shift::Shr, true); asm.movsxd_rax_eax(); }, // SRAV
+            0b010_000 => { asm.load64(Reg::Rax, offsets.hi); }, // MFHI
+            0b010_010 => { asm.load64(Reg::Rax, offsets.lo); }, // MFLO
+            0b010_100 => { asm.load32(Reg::Rcx, rs); asm.load64(Reg::Rax, rt); asm.shift_cl(Shift::Shl, true); }, // DSLLV
+            0b010_110 => { asm.load32(Reg::Rcx, rs); asm.load64(Reg::Rax, rt); asm.shift_cl(Shift::Shr, true); }, // DSRLV
+            0b010_111 => { asm.load32(Reg::Rcx, rs); asm.load64(Reg::Rax, rt); asm.shift_cl(Shift::Sar, true); }, // DSRAV
+            0b100_001 => { asm.load32(Reg::Rax, rs); asm.alu_mem(Alu::Add, false, rt); asm.movsxd_rax_eax(); }, // ADDU
+            0b100_011 => { asm.load32(Reg::Rax, rs); asm.alu_mem(Alu::Sub, false, rt); asm.movsxd_rax_eax(); }, // SUBU
+            0b100_100 => { asm.load64(Reg::Rax, rs); asm.alu_mem(Alu::And, true, rt); }, // AND
+            0b100_101 => { asm.load64(Reg::Rax, rs); asm.alu_mem(Alu::Or, true, rt); },  // OR
+            0b100_110 => { asm.load64(Reg::Rax, rs); asm.alu_mem(Alu::Xor, true, rt); }, // XOR
+            0b100_111 => { asm.load64(Reg::Rax, rs); asm.alu_mem(Alu::Or, true, rt); asm.not_rax(); }, // NOR
+            0b101_010 => { asm.load64(Reg::Rax, rs); asm.alu_mem(Alu::Cmp, true, rt); asm.set_rax(Condition::Less); },  // SLT
+            0b101_011 => { asm.load64(Reg::Rax, rs); asm.alu_mem(Alu::Cmp, true, rt); asm.set_rax(Condition::Below); }, // SLTU
+            0b101_101 => { asm.load64(Reg::Rax, rs); asm.alu_mem(Alu::Add, true, rt); }, // DADDU
+            0b101_111 => { asm.load64(Reg::Rax, rs); asm.alu_mem(Alu::Sub, true, rt); }, // DSUBU
+            0b111_000 => { asm.load64(Reg::Rax, rt); asm.shift_imm(Shift::Shl, true, sa); },      // DSLL
+            0b111_010 => { asm.load64(Reg::Rax, rt); asm.shift_imm(Shift::Shr, true, sa); },      // DSRL
+            0b111_011 => { asm.load64(Reg::Rax, rt); asm.shift_imm(Shift::Sar, true, sa); },      // DSRA
+            0b111_100 => { asm.load64(Reg::Rax, rt); asm.shift_imm(Shift::Shl, true, sa + 32); }, // DSLL32
+            0b111_110 => { asm.load64(Reg::Rax, rt); asm.shift_imm(Shift::Shr, true, sa + 32); }, // DSRL32
+            0b111_111 => { asm.load64(Reg::Rax, rt); asm.shift_imm(Shift::Sar, true, sa + 32); }, // DSRA32
+            _ => unreachable!(),
+        },
+
+        0b001_001 => { asm.load32(Reg::Rax, rs); asm.alu_imm(Alu::Add, false, inst.signed_imm as i32); asm.movsxd_rax_eax(); }, // ADDIU
+        0b011_001 => { asm.load64(Reg::Rax, rs); asm.alu_imm(Alu::Add, true, inst.signed_imm as i32); }, // DADDIU
+        0b001_010 => { asm.load64(Reg::Rax, rs); asm.alu_imm(Alu::Cmp, true, inst.signed_imm as i32); asm.set_rax(Condition::Less); },  // SLTI
+        0b001_011 => { asm.load64(Reg::Rax, rs); asm.alu_imm(Alu::Cmp, true, inst.signed_imm as i32); asm.set_rax(Condition::Below); }, // SLTIU
+        0b001_100 => { asm.load64(Reg::Rax, rs); asm.alu_imm(Alu::And, true, inst.imm as i32); }, // ANDI
+        0b001_101 => { asm.load64(Reg::Rax, rs); asm.alu_imm(Alu::Or, true, inst.imm as i32); },  // ORI
+        0b001_110 => { asm.load64(Reg::Rax, rs); asm.alu_imm(Alu::Xor, true, inst.imm as i32); }, // XORI
+        0b001_111 => { asm.mov_rax_simm32((inst.imm << 16) as u32 as i32); }, // LUI
+        _ => unreachable!(),
+    }
+
+    asm.store64(offsets.gpr(destination));
+}
+
+// Called from compiled code to run instruction `index` of the running block through its handler.
+// Returns nonzero when compiled code has to return because execution left the block
+extern "sysv64" fn run_handler(cpu: *mut Cpu, index: u32) -> u32 {
+    let cpu = unsafe { &mut *cpu };
+    let index = index as usize;
+
+    let current = cpu.jit_prepare(index);
+    let result = (current.handler)(cpu);
+    if let Err(fault) = cpu.finish_instruction(current, result) {
+        cpu.jit.as_mut().unwrap().fault = Some(fault);
+        return 1;
+    }
+
+    let next_pc = cpu.jit_base_pc.wrapping_add(((index + 2) as u64) << 2);
+    let same_block = cpu.block.as_ref().map_or(false, |block| Rc::as_ptr(block) == cpu.jit.as_ref().unwrap().block);
+    (cpu.pc != next_pc || !same_block || cpu.block_generation != cpu.blocks.generation()) as u32
+}
+
+// CPU state the self-check compares and restores
+struct Snapshot {
+    pc: u64,
+    current_instruction_pc: u64,
+    next_instruction: u32,
+    next_instruction_pc: u64,
+    next_decoded: DecodedInstruction,
+    is_delay_slot: bool,
+    next_is_delay_slot: bool,
+    gpr: [u64; 32],
+    lo: u64,
+    hi: u64,
+    cp0gpr: [u64; 32],
+    llbit: bool,
+    watch_pending: bool,
+    cycles: u64,
+    load_delay_reg: usize,
+    cop1: cop1::Cop1,
+    icache: ICache,
+    dcache: DCache,
+    block: Option<Rc<Block<DecodedInstruction>>>,
+    block_generation: u64,
+    block_index: usize,
+    block_pc: u64,
+}
+
+impl Snapshot {
+    fn take(cpu: &Cpu) -> Self {
+        Self {
+            pc                    : cpu.pc,
+            current_instruction_pc: cpu.current_instruction_pc,
+            next_instruction      : cpu.next_instruction,
+            next_instruction_pc   : cpu.next_instruction_pc,
+            next_decoded          : cpu.next_decoded,
+            is_delay_slot         : cpu.is_delay_slot,
+            next_is_delay_slot    : cpu.next_is_delay_slot,
+            gpr                   : cpu.gpr,
+            lo                    : cpu.lo,
+            hi                    : cpu.hi,
+            cp0gpr                : cpu.cp0gpr,
+            llbit                 : cpu.llbit,
+            watch_pending         : cpu.watch_pending,
+            cycles                : cpu.cycles,
+            load_delay_reg        : cpu.load_delay_reg,
+            cop1                  : cpu.cop1.clone(),
+            icache                : cpu.icache.clone(),
+            dcache                : cpu.dcache.clone(),
+            block                 : cpu.block.clone(),
+            block_generation      : cpu.block_generation,
+            block_index           : cpu.block_index,
+            block_pc              : cpu.block_pc,
+        }
+    }
+
+    fn restore(self, cpu: &mut Cpu) {
+        cpu.pc                     = self.pc;
+        cpu.current_instruction_pc = self.current_instruction_pc;
+        cpu.next_instruction       = self.next_instruction;
+        cpu.next_instruction_pc    = self.next_instruction_pc;
+        cpu.next_decoded           = self.next_decoded;
+        cpu.is_delay_slot          = self.is_delay_slot;
+        cpu.next_is_delay_slot     = self.next_is_delay_slot;
+        cpu.gpr                    = self.gpr;
+        cpu.lo                     = self.lo;
+        cpu.hi                     = self.hi;
+        cpu.cp0gpr                 = self.cp0gpr;
+        cpu.llbit                  = self.llbit;
+        cpu.watch_pending          = self.watch_pending;
+        cpu.cycles                 = self.cycles;
+        cpu.load_delay_reg         = self.load_delay_reg;
+        cpu.cop1                   = self.cop1;
+        cpu.icache                 = self.icache;
+        cpu.dcache                 = self.dcache;
+        cpu.block                  = self.block;
+        cpu.block_generation       = self.block_generation;
+        cpu.block_index            = self.block_index;
+        cpu.block_pc               = self.block_pc;
+    }
+
+    // describe every difference between the compiled result (self) and the interpreted one
+    fn differences(&self, reference: &Snapshot) -> Vec<String> {
+        let mut values: Vec<(String, u64, u64)> = vec![
+            ("pc".into()                , self.pc                    , reference.pc),
+            ("next_instruction_pc".into(), self.next_instruction_pc  , reference.next_instruction_pc),
+            ("next_is_delay_slot".into(), self.next_is_delay_slot as u64, reference.next_is_delay_slot as u64),
+            ("lo".into()                , self.lo                    , reference.lo),
+            ("hi".into()                , self.hi                    , reference.hi),
+            ("llbit".into()             , self.llbit as u64          , reference.llbit as u64),
+            ("watch_pending".into()     , self.watch_pending as u64  , reference.watch_pending as u64),
+            ("cycles".into()            , self.cycles                , reference.cycles),
+            ("load_delay_reg".into()    , self.load_delay_reg as u64 , reference.load_delay_reg as u64),
+            ("fcr31".into()             , self.cop1.control_status() , reference.cop1.control_status()),
+        ];
+        for i in 0..32 {
+            values.push((format!("r{}({})", i, Cpu::abi_name(i)), self.gpr[i], reference.gpr[i]));
+            values.push((format!("cop0 r{}", i), self.cp0gpr[i], reference.cp0gpr[i]));
+            values.push((format!("f{}", i), self.cop1.fgr(i), reference.cop1.fgr(i)));
+        }
+
+        values.into_iter()
+              .filter(|(_, compiled, interpreted)| compiled != interpreted)
+              .map(|(name, compiled, interpreted)| format!("{}: compiled=${:016X} interpreted=${:016X}", name, compiled, interpreted))
+              .collect()
+    }
+}
+
+impl Cpu {
+    // Run the current block as compiled code if the CPU is sitting at its start. Returns None
+    // when the interpreter should execute the instruction instead
+    pub(super) fn run_jit(&mut self) -> Option<Result<(), InstructionFault>> {
+        // only enter at the top of a block, outside of a delay slot
+        if self.jit_paused != 0 || self.next_is_delay_slot || self.block_index != 1 || self.pc != self.block_pc
+            || self.block_generation != self.blocks.generation() {
+            return None;
+        }
+
+        let block = self.block.clone()?;
+        let offsets = Offsets::new(self);
+        let jit = self.jit.as_mut()?;
+        let code = jit.lookup(&block, &offsets)?;
+
+        jit.block = Rc::as_ptr(&block);
+        jit.fault = None;
+        let self_check = jit.mode == JitMode::SelfCheck;
+        self.jit_base_pc = self.next_instruction_pc;
+
+        let before = if self_check {
+            self.uncached_access = false;
+            Some(Snapshot::take(self))
+        } else {
+            None
+        };
+
+        let status = unsafe { code(self as *mut Cpu) };
+        let executed = (status & !(EXITED_IN_HANDLER | BRANCH_TAKEN)) as usize;
+        if (status & EXITED_IN_HANDLER) == 0 {
+            let target = self.pc;
+            self.jit_sync(executed);
+            if (status & BRANCH_TAKEN) != 0 {
+                self.pc = target;
+            }
+        }
+
+        let fault = self.jit.as_mut().unwrap().fault.take();
+        if let Some(before) = before {
+            if fault.is_none() {
+                self.jit_self_check(before, &block, executed);
+            }
+        }
+
+        // step() has already accounted for the first instruction
+        self.num_steps += (executed as u64) - 1;
+        for _ in 1..executed {
+            self.advance_random();
+        }
+
+        Some(match fault {
+            Some(fault) => Err(fault),
+            None => Ok(()),
+        })
+    }
+
+    // Put the CPU in the state it would be in after the interpreter ran everything in the block
+    // before `index`: instruction `index` prefetched and the lookahead PC one past it
+    fn jit_sync(&mut self, index: usize) {
+        let block = self.block.as_ref().unwrap();
+        let pc = self.jit_base_pc.wrapping_add((index as u64) << 2);
+
+        self.next_decoded = block.instructions[index];
+        self.next_instruction = self.next_decoded.decode.v;
+        self.next_instruction_pc = pc;
+        self.current_instruction_pc = pc;
+        self.pc = pc.wrapping_add(4);
+        self.block_index = index + 1;
+        self.block_pc = self.pc;
+
+        if index != 0 {
+            let previous = &block.instructions[index - 1].decode;
+            self.next_is_delay_slot = Self::is_branch(previous);
+            self.is_delay_slot = self.next_is_delay_slot;
+
+            // compiled instructions don't track the load interlock
+            self.load_delay_reg = Self::load_destination(previous);
+        }
+    }
+
+    // Put the CPU in the state execute() has it in while running instruction `index` of the
+    // block: the instruction after it prefetched, and its cycles already counted
+    fn jit_prepare(&mut self, index: usize) -> DecodedInstruction {
+        let block = self.block.as_ref().unwrap();
+        let current = block.instructions[index];
+        let pc = self.jit_base_pc.wrapping_add((index as u64) << 2);
+
+        self.inst = current.decode;
+        self.next_decoded = block.instructions[index + 1];
+        self.next_instruction = self.next_decoded.decode.v;
+        self.current_instruction_pc = pc;
+        self.next_instruction_pc = pc.wrapping_add(4);
+        self.pc = pc.wrapping_add(8);
+        self.block_index = index + 2;
+        self.block_pc = self.pc;
+
+        // branches only come at the end of a block, so nothing before them is in a delay slot
+        self.is_delay_slot = false;
+        self.next_is_delay_slot = false;
+        self.load_delay_reg = Self::load_destination(&current.decode);
+
+        current
+    }
+
+    // replay the instructions just run by compiled code through the interpreter and compare
+    fn jit_self_check(&mut self, before: Snapshot, block: &Block<DecodedInstruction>, executed: usize) {
+        // anything that touched uncached memory might not give the same result twice
+        if self.uncached_access {
+            self.jit.as_mut().unwrap().skipped += 1;
+            return;
+        }
+
+        let compiled = Snapshot::take(self);
+        let base_pc = before.next_instruction_pc;
+        before.restore(self);
+
+        for _ in 0..executed {
+            if let Err(fault) = self.execute() {
+                warn!(target: "JIT", "self-check: interpreter faulted replaying block at ${:016X}: {:?}", base_pc, fault);
+                break;
+            }
+        }
+
+        let differences = compiled.differences(&Snapshot::take(self));
+        let jit = self.jit.as_mut().unwrap();
+        jit.checked += 1;
+        if differences.is_empty() {
+            return;
+        }
+
+        // keep running from the interpreter's result
+        jit.mismatches += 1;
+        error!(target: "JIT", "self-check mismatch #{} in block at ${:016X} (PA ${:08X}) after {} instruction(s), {} block(s) checked, {} skipped",
+               jit.mismatches, base_pc, block.physical_address, executed, jit.checked, jit.skipped);
+        for difference in differences {
+            error!(target: "JIT", "    {}", difference);
+        }
+        for (i, decoded) in block.instructions.iter().enumerate() {
+            let address = base_pc.wrapping_add((i as u64) << 2);
+            let native = i != block.instructions.len() - 1 && (is_native(&decoded.decode) || is_native_branch(&decoded.decode));
+            error!(target: "JIT", "  {} {}", if native { "*" } else { " " }, Cpu::disassemble(address, decoded.decode.v, true));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SystemCommunication, WriteReturnSignal};
+
+    const RAM_SIZE: usize = 0x10_0000;
+    const BOOT_ROM: usize = 0x1FC0_0000;
+
+    // where every test program ends up, as `end: j end; nop`
+    const END: u64 = 0xFFFF_FFFF_8000_0F00;
+
+    // RDRAM at 0 and zeros (nops) where the reset vector is
+    struct TestBus {
+        ram: Vec<u32>,
+    }
+
+    impl Addressable for TestBus {
+        fn read_u32(&mut self, offset: usize) -> Result<u32, ReadWriteFault> {
+            match offset {
+                _ if offset < RAM_SIZE => Ok(self.ram[offset >> 2]),
+                _ if (BOOT_ROM..BOOT_ROM + 0x1000).contains(&offset) => Ok(0),
+                _ => Err(ReadWriteFault::BusError),
+            }
+        }
+
+        fn write_u32(&mut self, value: u32, offset: usize) -> Result<WriteReturnSignal, ReadWriteFault> {
+            if offset >= RAM_SIZE {
+                return Err(ReadWriteFault::BusError);
+            }
+            self.ram[offset >> 2] = value;
+            Ok(WriteReturnSignal::None)
+        }
+    }
+
+    // a small MIPS assembler with labels. Words are placed at a virtual address for branches and
+    // jumps, and a physical address to load them into RAM
+    struct Program {
+        words: Vec<(u64, usize, u32)>,
+        address: (u64, usize),
+        labels: HashMap<&'static str, u64>,
+        fixups: Vec<(usize, &'static str)>,
+    }
+
+    impl Program {
+        fn new() -> Self {
+            Self { words: Vec::new(), address: (0, 0), labels: HashMap::new(), fixups: Vec::new() }
+        }
+
+        fn org(&mut self, virtual_address: u64, physical_address: usize) -> &mut Self {
+            self.address = (virtual_address, physical_address);
+            self
+        }
+
+        fn label(&mut self, name: &'static str) -> &mut Self {
+            self.labels.insert(name, self.address.0);
+            self
+        }
+
+        fn emit(&mut self, words: &[u32]) -> &mut Self {
+            for word in words {
+                self.words.push((self.address.0, self.address.1, *word));
+                self.address = (self.address.0.wrapping_add(4), self.address.1 + 4);
+            }
+            self
+        }
+
+        // a branch or jump to label, with the offset or target filled in later
+        fn to(&mut self, word: u32, label: &'static str) -> &mut Self {
+            self.fixups.push((self.words.len(), label));
+            self.emit(&[word])
+        }
+
+        // end the program by jumping to END
+        fn finish(&mut self) -> &mut Self {
+            self.emit(&[lui(26, 0x8000), ori(26, 26, (END & 0xFFFF) as u16), jr(26), nop()])
+        }
+
+        fn load(&self, bus: &mut TestBus) {
+            for (index, label) in &self.fixups {
+                let (address, physical_address, word) = self.words[*index];
+                let target = self.labels[label];
+                let word = if (word >> 26) == 0b000_010 || (word >> 26) == 0b000_011 {
+                    word | (((target >> 2) as u32) & 0x03FF_FFFF)
+                } else {
+                    let offset = (target.wrapping_sub(address + 4) as i64) >> 2;
+                    assert!(offset == (offset as i16) as i64, "branch to {} out of range", label);
+                    word | ((offset as u32) & 0xFFFF)
+                };
+                bus.ram[physical_address >> 2] = word;
+            }
+            for (index, (_, physical_address, word)) in self.words.iter().enumerate() {
+                if !self.fixups.iter().any(|(fixup, _)| *fixup == index) {
+                    bus.ram[*physical_address >> 2] = *word;
+                }
+            }
+
+            // end: j end; nop
+            bus.ram[(END as usize & 0x1FFF_FFFF) >> 2] = 0x0800_0000 | (((END >> 2) as u32) & 0x03FF_FFFF);
+        }
+    }
+
+    fn r(rs: usize, rt: usize, rd: usize, sa: u32, funct: u32) -> u32 {
+        ((rs as u32) << 21) | ((rt as u32) << 16) | ((rd as u32) << 11) | (sa << 6) | funct
+    }
+    fn i(op: u32, rs: usize, rt: usize, imm: u16) -> u32 {
+        (op << 26) | ((rs as u32) << 21) | ((rt as u32) << 16) | (imm as u32)
+    }
+
+    fn nop() -> u32 { 0 }
+    fn addiu(rt: usize, rs: usize, imm: i16) -> u32 { i(0b001_001, rs, rt, imm as u16) }
+    fn daddiu(rt: usize, rs: usize, imm: i16) -> u32 { i(0b011_001, rs, rt, imm as u16) }
+    fn slti(rt: usize, rs: usize, imm: i16) -> u32 { i(0b001_010, rs, rt, imm as u16) }
+    fn sltiu(rt: usize, rs: usize, imm: i16) -> u32 { i(0b001_011, rs, rt, imm as u16) }
+    fn andi(rt: usize, rs: usize, imm: u16) -> u32 { i(0b001_100, rs, rt, imm) }
+    fn ori(rt: usize, rs: usize, imm: u16) -> u32 { i(0b001_101, rs, rt, imm) }
+    fn xori(rt: usize, rs: usize, imm: u16) -> u32 { i(0b001_110, rs, rt, imm) }
+    fn lui(rt: usize, imm: u16) -> u32 { i(0b001_111, 0, rt, imm) }
+    fn sll(rd: usize, rt: usize, sa: u32) -> u32 { r(0, rt, rd, sa, 0b000_000) }
+    fn srl(rd: usize, rt: usize, sa: u32) -> u32 { r(0, rt, rd, sa, 0b000_010) }
+    fn sra(rd: usize, rt: usize, sa: u32) -> u32 { r(0, rt, rd, sa, 0b000_011) }
+    fn sllv(rd: usize, rt: usize, rs: usize) -> u32 { r(rs, rt, rd, 0, 0b000_100) }
+    fn srav(rd: usize, rt: usize, rs: usize) -> u32 { r(rs, rt, rd, 0, 0b000_111) }
+    fn dsrlv(rd: usize, rt: usize, rs: usize) -> u32 { r(rs, rt, rd, 0, 0b010_110) }
+    fn dsll32(rd: usize, rt: usize, sa: u32) -> u32 { r(0, rt, rd, sa, 0b111_100) }
+    fn dsra32(rd: usize, rt: usize, sa: u32) -> u32 { r(0, rt, rd, sa, 0b111_111) }
+    fn dsra(rd: usize, rt: usize, sa: u32) -> u32 { r(0, rt, rd, sa, 0b111_011) }
+    fn addu(rd: usize, rs: usize, rt: usize) -> u32 { r(rs, rt, rd, 0, 0b100_001) }
+    fn subu(rd: usize, rs: usize, rt: usize) -> u32 { r(rs, rt, rd, 0, 0b100_011) }
+    fn and(rd: usize, rs: usize, rt: usize) -> u32 { r(rs, rt, rd, 0, 0b100_100) }
+    fn or(rd: usize, rs: usize, rt: usize) -> u32 { r(rs, rt, rd, 0, 0b100_101) }
+    fn xor(rd: usize, rs: usize, rt: usize) -> u32 { r(rs, rt, rd, 0, 0b100_110) }
+    fn nor(rd: usize, rs: usize, rt: usize) -> u32 { r(rs, rt, rd, 0, 0b100_111) }
+    fn slt(rd: usize, rs: usize, rt: usize) -> u32 { r(rs, rt, rd, 0, 0b101_010) }
+    fn sltu(rd: usize, rs: usize, rt: usize) -> u32 { r(rs, rt, rd, 0, 0b101_011) }
+    fn daddu(rd: usize, rs: usize, rt: usize) -> u32 { r(rs, rt, rd, 0, 0b101_101) }
+    fn dsubu(rd: usize, rs: usize, rt: usize) -> u32 { r(rs, rt, rd, 0, 0b101_111) }
+    fn mult(rs: usize, rt: usize) -> u32 { r(rs, rt, 0, 0, 0b011_000) }
+    fn multu(rs: usize, rt: usize) -> u32 { r(rs, rt, 0, 0, 0b011_001) }
+    fn div(rs: usize, rt: usize) -> u32 { r(rs, rt, 0, 0, 0b011_010) }
+    fn dmult(rs: usize, rt: usize) -> u32 { r(rs, rt, 0, 0, 0b011_100) }
+    fn dmultu(rs: usize, rt: usize) -> u32 { r(rs, rt, 0, 0, 0b011_101) }
+    fn mfhi(rd: usize) -> u32 { r(0, 0, rd, 0, 0b010_000) }
+    fn mflo(rd: usize) -> u32 { r(0, 0, rd, 0, 0b010_010) }
+    fn jr(rs: usize) -> u32 { r(rs, 0, 0, 0, 0b001_000) }
+    fn lb(rt: usize, offset: i16, base: usize) -> u32 { i(0b100_000, base, rt, offset as u16) }
+    fn lhu(rt: usize, offset: i16, base: usize) -> u32 { i(0b100_101, base, rt, offset as u16) }
+    fn lw(rt: usize, offset: i16, base: usize) -> u32 { i(0b100_011, base, rt, offset as u16) }
+    fn ld(rt: usize, offset: i16, base: usize) -> u32 { i(0b110_111, base, rt, offset as u16) }
+    fn sb(rt: usize, offset: i16, base: usize) -> u32 { i(0b101_000, base, rt, offset as u16) }
+    fn sw(rt: usize, offset: i16, base: usize) -> u32 { i(0b101_011, base, rt, offset as u16) }
+    fn sd(rt: usize, offset: i16, base: usize) -> u32 { i(0b111_111, base, rt, offset as u16) }
+    fn beq(rs: usize, rt: usize) -> u32 { i(0b000_100, rs, rt, 0) }
+    fn bne(rs: usize, rt: usize) -> u32 { i(0b000_101, rs, rt, 0) }
+    fn blez(rs: usize) -> u32 { i(0b000_110, rs, 0, 0) }
+    fn bgtz(rs: usize) -> u32 { i(0b000_111, rs, 0, 0) }
+    fn beql(rs: usize, rt: usize) -> u32 { i(0b010_100, rs, rt, 0) }
+    fn bnel(rs: usize, rt: usize) -> u32 { i(0b010_101, rs, rt, 0) }
+    fn bltz(rs: usize) -> u32 { i(0b000_001, rs, 0b00_000, 0) }
+    fn bgez(rs: usize) -> u32 { i(0b000_001, rs, 0b00_001, 0) }
+    fn bltzl(rs: usize) -> u32 { i(0b000_001, rs, 0b00_010, 0) }
+    fn bgezl(rs: usize) -> u32 { i(0b000_001, rs, 0b00_011, 0) }
+    fn j() -> u32 { 0b000_010 << 26 }
+    fn jal() -> u32 { 0b000_011 << 26 }
+    fn mtc0(rt: usize, rd: usize) -> u32 { (0b010_000 << 26) | (0b00_100 << 21) | ((rt as u32) << 16) | ((rd as u32) << 11) }
+    fn mfc0(rt: usize, rd: usize) -> u32 { (0b010_000 << 26) | ((rt as u32) << 16) | ((rd as u32) << 11) }
+    fn syscall() -> u32 { 0x0000_000C }
+    fn eret() -> u32 { 0x4200_0018 }
+    fn tlbwi() -> u32 { 0x4200_0002 }
+    fn mtc1(rt: usize, fs: usize) -> u32 { (0b010_001 << 26) | (0b00_100 << 21) | ((rt as u32) << 16) | ((fs as u32) << 11) }
+    fn mfc1(rt: usize, fs: usize) -> u32 { (0b010_001 << 26) | ((rt as u32) << 16) | ((fs as u32) << 11) }
+    fn ctc1(rt: usize, fs: usize) -> u32 { (0b010_001 << 26) | (0b00_110 << 21) | ((rt as u32) << 16) | ((fs as u32) << 11) }
+    fn fop(fmt: u32, ft: usize, fs: usize, fd: usize, funct: u32) -> u32 {
+        (0b010_001 << 26) | (fmt << 21) | ((ft as u32) << 16) | ((fs as u32) << 11) | ((fd as u32) << 6) | funct
+    }
+    fn bc1t() -> u32 { (0b010_001 << 26) | (0b01_000 << 21) | (1 << 16) }
+
+    const FMT_S: u32 = 16;
+    const FMT_D: u32 = 17;
+    const FMT_W: u32 = 20;
+
+    // everything about a finished run that compiled code has to reproduce
+    struct Outcome {
+        state: Snapshot,
+        num_steps: u64,
+        time: u64,
+        ram: Vec<u32>,
+        compiled: usize,
+        checked: u64,
+        mismatches: u64,
+    }
+
+    fn run(program: &Program, start: u64, mode: JitMode) -> Outcome {
+        let mut bus = TestBus { ram: vec![0; RAM_SIZE >> 2] };
+        program.load(&mut bus);
+        let bus = Rc::new(RefCell::new(bus));
+
+        let comms = SystemCommunication::new(None);
+        comms.settings.write().unwrap().jit = mode;
+        let mut cpu = Cpu::new(bus.clone(), comms);
+        cpu.set_pc(start).unwrap();
+
+        let mut steps = 0;
+        while cpu.next_instruction_pc != END {
+            // exceptions taken between instructions are reported by step(), like interrupts
+            match cpu.step() {
+                Ok(()) | Err(InstructionFault::OtherException(_)) => {},
+                Err(fault) => panic!("{:?}", fault),
+            }
+            steps += 1;
+            assert!(steps < 100_000, "program didn't reach the end");
+        }
+
+        let (compiled, checked, mismatches) = match &cpu.jit {
+            Some(jit) => (jit.compiled.values().filter(|(_, code)| code.is_some()).count(), jit.checked, jit.mismatches),
+            None => (0, 0, 0),
+        };
+
+        // Count advances at half the cycle rate, with the cycles of the last step still pending
+        let time = (cpu.cp0gpr[Cop0_Count] << 1) + (cpu.half_clock as u64) + cpu.cycles;
+        let ram = bus.borrow().ram.clone();
+        Outcome { state: Snapshot::take(&cpu), num_steps: cpu.num_steps, time, ram, compiled, checked, mismatches }
+    }
+
+    // run program through the interpreter and as compiled code, with and without the self-check,
+    // and expect the same result
+    fn compare(program: &Program, start: u64) -> Outcome {
+        let interpreted = run(program, start, JitMode::Disabled);
+        for mode in [JitMode::Enabled, JitMode::SelfCheck] {
+            let compiled = run(program, start, mode);
+            assert_eq!(compiled.state.differences(&interpreted.state), Vec::<String>::new(), "{:?}", mode);
+            assert_eq!(compiled.num_steps, interpreted.num_steps, "{:?}", mode);
+            assert_eq!(compiled.time, interpreted.time, "{:?}", mode);
+            assert!(compiled.ram == interpreted.ram, "{:?}: RAM differs", mode);
+            assert!(compiled.compiled > 0, "{:?}: nothing was compiled", mode);
+            assert_eq!(compiled.mismatches, 0, "{:?}", mode);
+            if mode == JitMode::SelfCheck {
+                assert!(compiled.checked > 0);
+            }
+        }
+        interpreted
+    }
+
+    // kernel mode with exceptions vectored to RAM
+    fn setup(program: &mut Program, status: u16) {
+        program.emit(&[lui(1, status), mtc0(1, Cop0_Status)]);
+    }
+
+    #[test]
+    fn alu_and_multiply() {
+        let mut program = Program::new();
+        program.org(0xFFFF_FFFF_8000_1000, 0x1000);
+        setup(&mut program, 0);
+        program.emit(&[lui(2, 0x8765), ori(2, 2, 0x4321), addiu(3, 0, -7), addiu(4, 0, 40), lui(5, 0xFFFF)])
+               .label("loop")
+               .emit(&[addu(6, 2, 3), subu(7, 3, 2), and(8, 6, 7), or(9, 6, 7), xor(10, 9, 2), nor(11, 10, 3),
+                       slt(12, 3, 2), sltu(13, 3, 2), slti(14, 2, -1), sltiu(15, 3, 5), andi(16, 2, 0xF0F0),
+                       xori(17, 16, 0xFFFF), sll(18, 2, 3), srl(19, 3, 7), sra(20, 2, 4), sllv(21, 2, 4),
+                       srav(22, 3, 4), dsrlv(23, 5, 4), dsll32(24, 2, 1), dsra32(25, 24, 3), dsra(24, 5, 9),
+                       daddu(6, 6, 24), dsubu(7, 7, 25), daddiu(2, 2, 0x1234)])
+               .emit(&[mult(2, 3), mflo(8), mfhi(9), multu(2, 3), mflo(10), mfhi(11), dmult(24, 3), mflo(12),
+                       mfhi(13), dmultu(24, 3), mflo(14), mfhi(15), div(2, 3), mflo(16), mfhi(17), addiu(4, 4, -1)])
+               .to(bne(4, 0), "loop")
+               .emit(&[addu(3, 3, 6)])
+               .finish();
+
+        let outcome = compare(&program, 0xFFFF_FFFF_8000_1000);
+        assert_eq!(outcome.state.gpr[4], 0);
+    }
+
+    #[test]
+    fn loads_stores_and_interlocks() {
+        let mut program = Program::new();
+        program.org(0xFFFF_FFFF_8000_1000, 0x1000);
+        setup(&mut program, 0);
+        program.emit(&[lui(2, 0x8000), ori(2, 2, 0x8000), lui(3, 0xA000), ori(3, 3, 0x9000), addiu(4, 0, 64), addiu(5, 0, 0x55)])
+               .label("loop")
+               .emit(&[sw(5, 0, 2), lw(6, 0, 2), addu(7, 6, 5), sb(7, 3, 3), lb(8, 3, 3), sd(7, 8, 2), ld(9, 8, 2)])
+               .label("next")
+               .emit(&[addu(10, 9, 8), sw(10, 4, 3), lhu(11, 6, 3), addiu(2, 2, 16), addiu(3, 3, 16), addiu(5, 5, 0x1111),
+                       addiu(4, 4, -1)])
+               .to(bgtz(4), "loop")
+               .emit(&[lw(12, -16, 2)])
+               .emit(&[addu(13, 12, 12), lw(14, -16, 3), lui(1, 0xA000), ori(1, 1, 0x2000), jr(1), nop()]);
+
+        // the same kind of loop running uncached
+        program.org(0xFFFF_FFFF_A000_2000, 0x2000)
+               .emit(&[addiu(4, 0, 8)])
+               .label("uncached_loop")
+               .emit(&[lw(6, 0, 3), addu(7, 6, 4), sw(7, 0, 3), addiu(3, 3, 4), addiu(4, 4, -1)])
+               .to(bne(4, 0), "uncached_loop")
+               .emit(&[nop()])
+               .finish();
+
+        compare(&program, 0xFFFF_FFFF_8000_1000);
+    }
+
+    #[test]
+    fn branches_likely_and_calls() {
+        let mut program = Program::new();
+        program.org(0xFFFF_FFFF_8000_1000, 0x1000);
+        setup(&mut program, 0);
+        program.emit(&[addiu(2, 0, -3), addiu(20, 0, 0)])
+               .label("loop")
+               .emit(&[addiu(3, 0, 1)]).to(bltz(2), "a").emit(&[addiu(20, 20, 1)])
+               .emit(&[addiu(20, 20, 100)])
+               .label("a")
+               .emit(&[addiu(3, 3, 1)]).to(bgez(2), "b").emit(&[addiu(20, 20, 2)])
+               .emit(&[addiu(20, 20, 200)])
+               .label("b")
+               .emit(&[addiu(3, 3, 1)]).to(blez(2), "c").emit(&[addiu(20, 20, 3)])
+               .emit(&[addiu(20, 20, 300)])
+               .label("c")
+               .emit(&[addiu(3, 3, 1)]).to(bltzl(2), "d").emit(&[addiu(20, 20, 4)])
+               .emit(&[addiu(20, 20, 400)])
+               .label("d")
+               .emit(&[addiu(3, 3, 1)]).to(bgezl(2), "e").emit(&[addiu(20, 20, 5)])
+               .emit(&[addiu(20, 20, 500)])
+               .label("e")
+               .emit(&[addiu(3, 3, 1)]).to(beql(2, 0), "f").emit(&[addiu(20, 20, 6)])
+               .emit(&[addiu(20, 20, 600)])
+               .label("f")
+               .emit(&[addiu(3, 3, 1)]).to(bnel(2, 0), "g").emit(&[addiu(20, 20, 7)])
+               .emit(&[addiu(20, 20, 700)])
+               .label("g")
+               .emit(&[addiu(3, 3, 1)]).to(beq(2, 0), "h").emit(&[addiu(20, 20, 8)])
+               .emit(&[addiu(20, 20, 800)])
+               .label("h")
+               .emit(&[addiu(3, 3, 1)]).to(jal(), "function").emit(&[addiu(4, 3, 0)])
+               .emit(&[addiu(2, 2, 1), slti(5, 2, 4)])
+               .to(bgtz(5), "loop")
+               .emit(&[nop()])
+               .to(j(), "done")
+               .emit(&[nop()])
+               .label("function")
+               .emit(&[sll(6, 4, 2), addu(21, 21, 6), jr(31), addiu(22, 22, 1)])
+               .label("done")
+               .finish();
+
+        let outcome = compare(&program, 0xFFFF_FFFF_8000_1000);
+        assert_eq!(outcome.state.gpr[22], 7);
+    }
+
+    #[test]
+    fn exception_in_compiled_code() {
+        let mut program = Program::new();
+
+        // skip the faulting instruction
+        program.org(0xFFFF_FFFF_8000_0180, 0x180)
+               .emit(&[mfc0(26, Cop0_EPC), addiu(26, 26, 4), mtc0(26, Cop0_EPC), addiu(27, 27, 1), eret()]);
+
+        program.org(0xFFFF_FFFF_8000_1000, 0x1000);
+        setup(&mut program, 0);
+        program.emit(&[lui(2, 0x8000), ori(2, 2, 0x8000), addiu(4, 0, 5)])
+               .label("loop")
+               .emit(&[addiu(3, 3, 1), lw(5, 2, 2), addiu(3, 3, 2), addiu(4, 4, -1)])
+               .to(bne(4, 0), "loop")
+               .emit(&[nop()])
+               .finish();
+
+        let outcome = compare(&program, 0xFFFF_FFFF_8000_1000);
+        assert_eq!(outcome.state.gpr[27], 5);
+        assert_eq!(outcome.state.cp0gpr[Cop0_BadVAddr], 0xFFFF_FFFF_8000_8002);
+    }
+
+    #[test]
+    fn tlb_mapped_code() {
+        let mut program = Program::new();
+        program.org(0xFFFF_FFFF_8000_1000, 0x1000);
+        setup(&mut program, 0);
+
+        // map 0xC000_0000 to $2000 and 0xC000_1000 to $3000, cached, dirty, valid and global
+        program.emit(&[mtc0(0, Cop0_Index), mtc0(0, Cop0_PageMask), lui(1, 0xC000), mtc0(1, Cop0_EntryHi),
+                       addiu(1, 0, 0x9F), mtc0(1, Cop0_EntryLo0), addiu(1, 0, 0xDF), mtc0(1, Cop0_EntryLo1), tlbwi(),
+                       lui(1, 0xC000), jr(1), nop()]);
+
+        program.org(0xFFFF_FFFF_C000_0000, 0x2000)
+               .emit(&[lui(2, 0xC000), ori(2, 2, 0x1000), addiu(4, 0, 32)])
+               .label("mapped_loop")
+               .emit(&[lw(5, 0, 2), addu(5, 5, 4), sw(5, 0, 2), sll(6, 5, 2), addu(7, 7, 6), addiu(2, 2, 4), addiu(4, 4, -1)])
+               .to(bne(4, 0), "mapped_loop")
+               .emit(&[nop()])
+               .finish();
+
+        let outcome = compare(&program, 0xFFFF_FFFF_8000_1000);
+        assert_eq!(outcome.state.gpr[4], 0);
+    }
+
+    #[test]
+    fn cop1_in_compiled_code() {
+        let mut program = Program::new();
+        program.org(0xFFFF_FFFF_8000_1000, 0x1000);
+
+        // CU1 and FR set
+        setup(&mut program, 0x2400);
+        program.emit(&[addiu(1, 0, 1), ctc1(1, 31), addiu(2, 0, 10), addiu(3, 0, 3), mtc1(3, 2),
+                       fop(FMT_W, 0, 2, 2, 32)]) // cvt.s.w f2, f2
+               .label("loop")
+               .emit(&[mtc1(2, 1), fop(FMT_W, 0, 1, 1, 32), // cvt.s.w f1, f1
+                       fop(FMT_S, 2, 1, 4, 3),              // div.s f4, f1, f2
+                       fop(FMT_S, 4, 4, 5, 2),              // mul.s f5, f4, f4
+                       fop(FMT_S, 5, 4, 6, 0),              // add.s f6, f4, f5
+                       fop(FMT_S, 0, 6, 7, 33),             // cvt.d.s f7, f6
+                       fop(FMT_D, 7, 7, 8, 0),              // add.d f8, f7, f7
+                       fop(FMT_S, 0, 6, 9, 36),             // cvt.w.s f9, f6
+                       mfc1(4, 9), addu(5, 5, 4), addiu(2, 2, -1),
+                       fop(FMT_S, 2, 4, 0, 0x3C)])          // c.lt.s f4, f2
+               .to(bc1t(), "loop")
+               .emit(&[nop()])
+               .finish();
+
+        compare(&program, 0xFFFF_FFFF_8000_1000);
+    }
+
+    #[test]
+    fn watch_is_deferred_while_exl_is_set() {
+        let mut program = Program::new();
+
+        // the syscall handler loads from the watched address with EXL set, and the Watch
+        // exception follows its eret
+        program.org(0xFFFF_FFFF_8000_0180, 0x180)
+               .emit(&[mfc0(26, Cop0_Cause), andi(26, 26, 0x7C), addiu(1, 0, 23 << 2)])
+               .to(beq(26, 1), "watch")
+               .emit(&[nop(), lw(5, 0, 3), mfc0(26, Cop0_EPC), addiu(26, 26, 4), mtc0(26, Cop0_EPC), addiu(27, 27, 1), eret()])
+               .label("watch")
+               .emit(&[mtc0(0, Cop0_WatchLo), mfc0(28, Cop0_EPC), eret()]);
+
+        program.org(0xFFFF_FFFF_8000_1000, 0x1000);
+        setup(&mut program, 0);
+        program.emit(&[lui(3, 0x8000), ori(3, 3, 0x8000), ori(1, 0, 0x8002), mtc0(1, Cop0_WatchLo), syscall()])
+               .label("after_syscall")
+               .emit(&[addiu(4, 0, 1), addiu(4, 4, 1), addiu(4, 4, 1)])
+               .finish();
+
+        let outcome = compare(&program, 0xFFFF_FFFF_8000_1000);
+        assert_eq!(outcome.state.gpr[27], 1);
+        assert_eq!(outcome.state.gpr[28], program.labels["after_syscall"]);
+        assert_eq!(outcome.state.gpr[4], 3);
+    }
+
+    #[test]
+    fn paused_jit_steps_one_instruction() {
+        let mut program = Program::new();
+        program.org(0xFFFF_FFFF_8000_1000, 0x1000);
+        setup(&mut program, 0);
+        program.label("loop")
+               .emit(&[addiu(2, 2, 1), addiu(3, 3, 1), addiu(4, 4, 1), addiu(5, 5, 1)])
+               .to(bne(2, 0), "loop")
+               .emit(&[nop()]);
+
+        let mut bus = TestBus { ram: vec![0; RAM_SIZE >> 2] };
+        program.load(&mut bus);
+        let comms = SystemCommunication::new(None);
+        comms.settings.write().unwrap().jit = JitMode::Enabled;
+        let mut cpu = Cpu::new(Rc::new(RefCell::new(bus)), comms);
+        cpu.set_pc(0xFFFF_FFFF_8000_1000).unwrap();
+
+        // once compiled, a step runs the whole loop body
+        let start = program.labels["loop"];
+        while cpu.next_instruction_pc != start || cpu.gpr[2] < 2 {
+            cpu.step().unwrap();
+        }
+        let steps = cpu.num_steps;
+        cpu.step().unwrap();
+        assert!(cpu.num_steps - steps > 1);
+
+        // but one instruction at a time while paused
+        while cpu.next_instruction_pc != start {
+            cpu.step().unwrap();
+        }
+        cpu.pause_jit(true);
+        for offset in [4, 8, 12, 16] {
+            let steps = cpu.num_steps;
+            cpu.step().unwrap();
+            assert_eq!(cpu.num_steps - steps, 1);
+            assert_eq!(cpu.next_instruction_pc, start + offset);
+        }
+    }
+}
@@ -0,0 +1,341 @@
+// Just enough of an x86-64 assembler for the JIT, plus the executable memory it writes into.
+// Generated code keeps the Cpu pointer in rbx and uses rax, rcx and rdx as scratch, so every
+// memory operand is [rbx+disp32]. Code memory is never writable and executable at the same time
+
+#[derive(Copy, Clone)]
+pub enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+}
+
+#[derive(Copy, Clone)]
+pub enum Alu {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Cmp,
+}
+
+impl Alu {
+    // opcode for `op r, r/m`. the eax, imm32 form is this plus 2
+    fn opcode(&self) -> u8 {
+        match self {
+            Alu::Add => 0x03,
+            Alu::Or  => 0x0B,
+            Alu::And => 0x23,
+            Alu::Sub => 0x2B,
+            Alu::Xor => 0x33,
+            Alu::Cmp => 0x3B,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum Shift {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+#[derive(Copy, Clone)]
+pub enum Condition {
+    Below        = 0x2,
+    Equal        = 0x4,
+    NotEqual     = 0x5,
+    Less         = 0xC,
+    GreaterEqual = 0xD,
+    LessEqual    = 0xE,
+    Greater      = 0xF,
+}
+
+// a forward jump waiting for its target
+pub struct Label(usize);
+
+pub struct Assembler {
+    code: Vec<u8>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self { code: Vec::with_capacity(1024) }
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> &mut Self {
+        self.code.extend_from_slice(bytes);
+        self
+    }
+
+    fn imm32(&mut self, value: i32) -> &mut Self {
+        self.emit(&value.to_le_bytes())
+    }
+
+    // ModRM for [rbx+disp32] with reg in the reg field
+    fn rbx_disp32(&mut self, reg: u8, disp: i32) -> &mut Self {
+        self.emit(&[0x80 | (reg << 3) | 0x03]).imm32(disp)
+    }
+
+    pub fn push_rbx(&mut self)      { self.emit(&[0x53]); }
+    pub fn pop_rbx(&mut self)       { self.emit(&[0x5B]); }
+    pub fn ret(&mut self)           { self.emit(&[0xC3]); }
+    pub fn mov_rbx_rdi(&mut self)   { self.emit(&[0x48, 0x89, 0xFB]); }
+    pub fn mov_rdi_rbx(&mut self)   { self.emit(&[0x48, 0x89, 0xDF]); }
+    pub fn call_rax(&mut self)      { self.emit(&[0xFF, 0xD0]); }
+    pub fn test_eax_eax(&mut self)  { self.emit(&[0x85, 0xC0]); }
+    pub fn not_rax(&mut self)       { self.emit(&[0x48, 0xF7, 0xD0]); }
+    pub fn movsxd_rax_eax(&mut self) { self.emit(&[0x48, 0x63, 0xC0]); }
+    pub fn and_ecx_31(&mut self)    { self.emit(&[0x83, 0xE1, 0x1F]); }
+
+    pub fn jz_rel8(&mut self, offset: u8) {
+        self.emit(&[0x74, offset]);
+    }
+
+    // jcc rel32 to a label bound later
+    pub fn jcc(&mut self, condition: Condition) -> Label {
+        self.emit(&[0x0F, 0x80 | (condition as u8)]).imm32(0);
+        Label(self.code.len())
+    }
+
+    // point a forward jump at the current position
+    pub fn bind(&mut self, label: Label) {
+        let offset = (self.code.len() - label.0) as i32;
+        self.code[label.0 - 4..label.0].copy_from_slice(&offset.to_le_bytes());
+    }
+
+    // mov eax, eax, which clears the upper half of rax
+    pub fn zero_extend_eax(&mut self) {
+        self.emit(&[0x89, 0xC0]);
+    }
+
+    // imul rax, rcx
+    pub fn imul_rax_rcx(&mut self) {
+        self.emit(&[0x48, 0x0F, 0xAF, 0xC1]);
+    }
+
+    // rdx:rax = rax * qword [rbx+disp], signed or unsigned
+    pub fn mul_mem64(&mut self, signed: bool, disp: i32) {
+        self.emit(&[0x48, 0xF7]).rbx_disp32(if signed { 5 } else { 4 }, disp);
+    }
+
+    // movsxd r64, dword [rbx+disp]
+    pub fn load32_signed(&mut self, reg: Reg, disp: i32) {
+        self.emit(&[0x48, 0x63]).rbx_disp32(reg as u8, disp);
+    }
+
+    // cmp qword [rbx+disp], imm8 (sign extended)
+    pub fn cmp_mem64_imm8(&mut self, disp: i32, value: i8) {
+        self.emit(&[0x48, 0x83]).rbx_disp32(7, disp).emit(&[value as u8]);
+    }
+
+    pub fn mov_eax_imm32(&mut self, value: u32) {
+        self.emit(&[0xB8]).imm32(value as i32);
+    }
+
+    pub fn mov_esi_imm32(&mut self, value: u32) {
+        self.emit(&[0xBE]).imm32(value as i32);
+    }
+
+    pub fn mov_rax_imm64(&mut self, value: u64) {
+        self.emit(&[0x48, 0xB8]).emit(&value.to_le_bytes());
+    }
+
+    // mov rax, imm32 sign extended to 64 bits
+    pub fn mov_rax_simm32(&mut self, value: i32) {
+        self.emit(&[0x48, 0xC7, 0xC0]).imm32(value);
+    }
+
+    // mov r64, [rbx+disp]
+    pub fn load64(&mut self, reg: Reg, disp: i32) {
+        self.emit(&[0x48, 0x8B]).rbx_disp32(reg as u8, disp);
+    }
+
+    // mov r32, [rbx+disp], which zero extends into the upper half
+    pub fn load32(&mut self, reg: Reg, disp: i32) {
+        self.emit(&[0x8B]).rbx_disp32(reg as u8, disp);
+    }
+
+    // mov [rbx+disp], rax
+    pub fn store64(&mut self, disp: i32) {
+        self.store64_reg(Reg::Rax, disp);
+    }
+
+    // mov [rbx+disp], r64
+    pub fn store64_reg(&mut self, reg: Reg, disp: i32) {
+        self.emit(&[0x48, 0x89]).rbx_disp32(reg as u8, disp);
+    }
+
+    // add qword [rbx+disp], imm32
+    pub fn add_mem64_imm32(&mut self, disp: i32, value: i32) {
+        self.emit(&[0x48, 0x81]).rbx_disp32(0, disp).imm32(value);
+    }
+
+    // op rax, [rbx+disp] or op eax, [rbx+disp]
+    pub fn alu_mem(&mut self, op: Alu, wide: bool, disp: i32) {
+        if wide { self.emit(&[0x48]); }
+        self.emit(&[op.opcode()]).rbx_disp32(Reg::Rax as u8, disp);
+    }
+
+    // op rax, imm32 (sign extended) or op eax, imm32
+    pub fn alu_imm(&mut self, op: Alu, wide: bool, value: i32) {
+        if wide { self.emit(&[0x48]); }
+        self.emit(&[op.opcode() + 2]).imm32(value);
+    }
+
+    pub fn shift_imm(&mut self, op: Shift, wide: bool, amount: u8) {
+        if wide { self.emit(&[0x48]); }
+        self.emit(&[0xC1, 0xC0 | ((op as u8) << 3), amount]);
+    }
+
+    // shift rax or eax by cl. the hardware masks cl to 5 or 6 bits like MIPS does
+    pub fn shift_cl(&mut self, op: Shift, wide: bool) {
+        if wide { self.emit(&[0x48]); }
+        self.emit(&[0xD3, 0xC0 | ((op as u8) << 3)]);
+    }
+
+    // rax = condition ? 1 : 0, from the flags of a previous cmp
+    pub fn set_rax(&mut self, condition: Condition) {
+        self.emit(&[0x0F, 0x90 | (condition as u8), 0xC0]); // setcc al
+        self.emit(&[0x0F, 0xB6, 0xC0]);                    // movzx eax, al
+    }
+}
+
+// A fixed size region of memory that code is appended to until it fills up. Pages are made
+// writable only while code is being copied into them, and executable again afterwards
+pub struct CodeBuffer {
+    ptr: *mut u8,
+    size: usize,
+    used: usize,
+}
+
+impl CodeBuffer {
+    pub fn new(size: usize) -> Option<Self> {
+        let ptr = os::allocate(size)?;
+        Some(Self { ptr, size, used: 0 })
+    }
+
+    // forget everything written so far. any pointer previously returned is invalid after this
+    pub fn reset(&mut self) {
+        self.used = 0;
+    }
+
+    pub fn append(&mut self, code: &[u8]) -> Option<*const u8> {
+        // keep each function 16-byte aligned
+        let start = (self.used + 15) & !15;
+        if start + code.len() > self.size {
+            return None;
+        }
+
+        // the pages holding the new code, which can share a page with code already appended
+        let first_page = start & !(os::PAGE_SIZE - 1);
+        let end_page = (start + code.len() + os::PAGE_SIZE - 1) & !(os::PAGE_SIZE - 1);
+        let pages = unsafe { self.ptr.add(first_page) };
+
+        if !os::protect(pages, end_page - first_page, false) {
+            return None;
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(code.as_ptr(), self.ptr.add(start), code.len());
+        }
+        if !os::protect(pages, end_page - first_page, true) {
+            return None;
+        }
+
+        self.used = start + code.len();
+        Some(unsafe { self.ptr.add(start) as *const u8 })
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        os::free(self.ptr, self.size);
+    }
+}
+
+#[cfg(unix)]
+mod os {
+    use std::ffi::c_void;
+
+    pub const PAGE_SIZE: usize = 4096;
+
+    const PROT_READ : i32 = 0x1;
+    const PROT_WRITE: i32 = 0x2;
+    const PROT_EXEC : i32 = 0x4;
+    const MAP_PRIVATE: i32 = 0x2;
+    #[cfg(target_os = "macos")]
+    const MAP_ANONYMOUS: i32 = 0x1000;
+    #[cfg(not(target_os = "macos"))]
+    const MAP_ANONYMOUS: i32 = 0x20;
+
+    extern "C" {
+        fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+        fn munmap(addr: *mut c_void, len: usize) -> i32;
+        fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    }
+
+    pub fn allocate(size: usize) -> Option<*mut u8> {
+        let ptr = unsafe { mmap(std::ptr::null_mut(), size, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0) };
+        if ptr as isize == -1 { None } else { Some(ptr as *mut u8) }
+    }
+
+    // make page aligned memory either read/write or read/execute
+    pub fn protect(ptr: *mut u8, size: usize, executable: bool) -> bool {
+        let prot = if executable { PROT_READ | PROT_EXEC } else { PROT_READ | PROT_WRITE };
+        unsafe { mprotect(ptr as *mut c_void, size, prot) == 0 }
+    }
+
+    pub fn free(ptr: *mut u8, size: usize) {
+        unsafe { munmap(ptr as *mut c_void, size); }
+    }
+}
+
+#[cfg(windows)]
+mod os {
+    use std::ffi::c_void;
+
+    pub const PAGE_SIZE: usize = 4096;
+
+    const MEM_COMMIT : u32 = 0x1000;
+    const MEM_RESERVE: u32 = 0x2000;
+    const MEM_RELEASE: u32 = 0x8000;
+    const PAGE_READWRITE   : u32 = 0x04;
+    const PAGE_EXECUTE_READ: u32 = 0x20;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn VirtualAlloc(addr: *mut c_void, size: usize, allocation_type: u32, protect: u32) -> *mut c_void;
+        fn VirtualFree(addr: *mut c_void, size: usize, free_type: u32) -> i32;
+        fn VirtualProtect(addr: *mut c_void, size: usize, new_protect: u32, old_protect: *mut u32) -> i32;
+        fn FlushInstructionCache(process: *mut c_void, addr: *const c_void, size: usize) -> i32;
+        fn GetCurrentProcess() -> *mut c_void;
+    }
+
+    pub fn allocate(size: usize) -> Option<*mut u8> {
+        let ptr = unsafe { VirtualAlloc(std::ptr::null_mut(), size, MEM_COMMIT | MEM_RESERVE, PAGE_READWRITE) };
+        if ptr.is_null() { None } else { Some(ptr as *mut u8) }
+    }
+
+    // make page aligned memory either read/write or read/execute
+    pub fn protect(ptr: *mut u8, size: usize, executable: bool) -> bool {
+        let mut old_protect = 0;
+        let new_protect = if executable { PAGE_EXECUTE_READ } else { PAGE_READWRITE };
+        unsafe {
+            if VirtualProtect(ptr as *mut c_void, size, new_protect, &mut old_protect) == 0 {
+                return false;
+            }
+            if executable {
+                FlushInstructionCache(GetCurrentProcess(), ptr as *const c_void, size);
+            }
+        }
+        true
+    }
+
+    pub fn free(ptr: *mut u8, _size: usize) {
+        unsafe { VirtualFree(ptr as *mut c_void, 0, MEM_RELEASE); }
+    }
+}
@@ -1,7 +1,8 @@
 use std::cell::RefCell;
+use std::fmt;
 use std::rc::Rc;
 
-use std::collections::HashMap;
+use std::fs;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
@@ -16,28 +17,146 @@ use mips::{InterruptUpdate, InterruptUpdateMode};
 
 //use crate::cpu::Cpu;
 
+mod backtrace;
+mod condition;
+mod coprocessors;
+mod gdb;
+mod rsp;
+mod script;
+mod symbols;
+mod trace;
+
+use condition::{Condition, RegisterState};
+use symbols::Symbols;
+use trace::Tracer;
+
 const BP_READ : u8 = 0x01;
 const BP_WRITE: u8 = 0x02;
 const BP_EXEC : u8 = 0x04;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
+enum BreakpointAction {
+    Stop,
+    Log,
+}
+
+#[derive(Clone)]
 struct BreakpointInfo {
     id     : u64,
     address: u64,
+    end    : u64, // inclusive
     mode   : u8,
     enable : bool,
+
+    condition   : Option<Condition>,
+    value       : Option<u32>, // only stop on writes of this value
+    ignore_count: u64,         // hits left to skip before stopping
+    hit_count   : u64,
+    action      : BreakpointAction,
+}
+
+impl BreakpointInfo {
+    fn new(address: u64, end: u64, mode: u8) -> BreakpointInfo {
+        BreakpointInfo {
+            id          : 0,
+            address     : address,
+            end         : end,
+            mode        : mode,
+            enable      : true,
+            condition   : None,
+            value       : None,
+            ignore_count: 0,
+            hit_count   : 0,
+            action      : BreakpointAction::Stop,
+        }
+    }
+
+    fn covers(&self, address: u64, size: u64) -> bool {
+        address <= self.end && address.saturating_add(size - 1) >= self.address
+    }
+
+    // read and write breakpoints are checked on the bus, so they hold physical addresses
+    fn is_physical(&self) -> bool {
+        (self.mode & BP_EXEC) == 0
+    }
+
+    fn format_range(&self) -> String {
+        let format = |address: u64| if self.is_physical() { format!("p:${:08X}", address) } else { format!("${:016X}", address) };
+        if self.end != self.address { format!("{}-{}", format(self.address), format(self.end)) } else { format(self.address) }
+    }
+}
+
+impl fmt::Display for BreakpointInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.id, self.format_range())?;
+        write!(f, " mode {}", format_breakpoint_mode(self.mode))?;
+        if let Some(value) = self.value { write!(f, " value=${:08X}", value)?; }
+        if self.action == BreakpointAction::Log { write!(f, " log")?; }
+        if self.ignore_count != 0 { write!(f, " ignore next {}", self.ignore_count)?; }
+        if let Some(condition) = &self.condition { write!(f, " if {}", condition)?; }
+        if !self.enable { write!(f, " (disabled)")?; }
+        write!(f, ", hit {} time{}", self.hit_count, if self.hit_count == 1 { "" } else { "s" })
+    }
+}
+
+// addresses given to the memory commands are virtual unless prefixed with "p:"
+#[derive(Copy, Clone)]
+enum MemoryAddress {
+    Virtual(u64),
+    Physical(u64),
+}
+
+impl MemoryAddress {
+    fn offset(&self, offset: u64) -> MemoryAddress {
+        match self {
+            MemoryAddress::Virtual(v)  => MemoryAddress::Virtual(v.wrapping_add(offset)),
+            MemoryAddress::Physical(v) => MemoryAddress::Physical(v.wrapping_add(offset)),
+        }
+    }
+
+    fn value(&self) -> u64 {
+        match self {
+            MemoryAddress::Virtual(v) | MemoryAddress::Physical(v) => *v,
+        }
+    }
+}
+
+impl fmt::Display for MemoryAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryAddress::Virtual(v) => {
+                if ((*v as i32) as u64) == *v { write!(f, "${:08X}", *v as u32) } else { write!(f, "${:016X}", v) }
+            },
+            MemoryAddress::Physical(v) => write!(f, "p:${:08X}", v),
+        }
+    }
 }
 
 struct Breakpoints {
     breakpoint_id: u64,
     global_enable: bool,
-    table: HashMap<u64, BreakpointInfo>,
+    table: Vec<BreakpointInfo>,
+
+    // registers for conditions, captured before each step while bus breakpoints need them
+    registers: RegisterState,
+
+    // address, mode and breakpoint id of the last read or write breakpoint hit on the bus
+    last_hit: Option<(u64, u8, u64)>,
+
+    // let the instruction whose load or store stopped execution through when execution resumes
+    resume_step: bool,
 }
 
 pub struct Debugger {
     alive: bool,
     ctrlc_count: u32,
 
+    // set by quit
+    exit_status: Option<i32>,
+
+    // how many scripts are running inside each other
+    source_depth: u32,
+
     cpu_run_til: Option<u64>,
 
     // Ctrl-C help
@@ -45,6 +164,11 @@ pub struct Debugger {
 
     breakpoints: Rc<RefCell<Breakpoints>>,
 
+    symbols: Symbols,
+
+    // writes each instruction stepped to a file
+    tracer: Option<Tracer>,
+
     system: System,
 
     change_logging: Box<dyn Fn(&str, Level) -> ()>,
@@ -55,50 +179,121 @@ impl Breakpoints {
         Breakpoints {
             breakpoint_id: 0,
             global_enable: true,
-            table: HashMap::new(),
+            table: Vec::new(),
+            registers: RegisterState::default(),
+            last_hit: None,
+            resume_step: false,
         }
     }
 
-    fn check_breakpoint(&self, address: u64, mode: u8) -> Option<BreakpointInfo> {
+    // Find the first breakpoint that stops this access, counting hits and printing the ones
+    // that only log along the way. value is the value being written, and read_word reads
+    // memory for conditions
+    fn check_breakpoint(&mut self, address: u64, size: u64, mode: u8, value: Option<u32>, 
+                        read_word: &mut dyn FnMut(u64) -> Option<u32>) -> Option<BreakpointInfo> {
         if !self.global_enable { return None; }
 
-        if let Some(breakpoint) = self.table.get(&address) {
-            if breakpoint.enable && ((breakpoint.mode & mode) != 0) {
-                return Some(*breakpoint);
+        let registers = self.registers;
+        for breakpoint in self.table.iter_mut() {
+            if !breakpoint.enable || (breakpoint.mode & mode) == 0 || !breakpoint.covers(address, size) {
+                continue;
+            }
+
+            match (breakpoint.value, value) {
+                (Some(expected), Some(value)) => {
+                    let mask = if size >= 4 { 0xFFFF_FFFF } else { (1u32 << (size * 8)) - 1 };
+                    if (value & mask) != expected { continue; }
+                },
+                (Some(_), None) => continue,
+                _ => {},
+            }
+
+            if let Some(condition) = &breakpoint.condition {
+                match condition.evaluate(&registers, read_word) {
+                    Ok(true)  => {},
+                    Ok(false) => continue,
+                    Err(err)  => println!("breakpoint {} condition: {}", breakpoint.id, err),
+                }
+            }
+
+            breakpoint.hit_count += 1;
+            if breakpoint.ignore_count > 0 {
+                breakpoint.ignore_count -= 1;
+                continue;
+            }
+
+            if breakpoint.action == BreakpointAction::Log {
+                println!("Breakpoint {} hit at ${:016X} (PC ${:08X}, hit {})", breakpoint.id, address, registers.pc, breakpoint.hit_count);
+                continue;
             }
+
+            return Some(breakpoint.clone());
         }
 
         None
     }
 
+    fn has_mode(&self, mode: u8) -> bool {
+        self.global_enable && self.table.iter().any(|breakpoint| breakpoint.enable && (breakpoint.mode & mode) != 0)
+    }
+
+    // conditions and logs on the bus need registers captured before the instruction runs
+    fn wants_registers(&self) -> bool {
+        self.global_enable && self.table.iter().any(|breakpoint| {
+            breakpoint.enable && (breakpoint.mode & (BP_READ | BP_WRITE)) != 0
+                && (breakpoint.condition.is_some() || breakpoint.action == BreakpointAction::Log)
+        })
+    }
+
+    // let the instruction that stopped on a load or store run on the next step
+    fn resume(&mut self) {
+        self.resume_step = self.last_hit.take().is_some();
+    }
+
     fn print_breakpoints(&self) {
-        for (_key, v) in self.table.iter() {
-            println!("{}: ${:016X} mode {}", v.id, v.address, format_breakpoint_mode(v.mode));
+        for v in self.table.iter() {
+            println!("{}", v);
         }
     }
 
-    fn add_breakpoint(&mut self, address: u64, mode: u8, enable: bool) {
+    fn add_breakpoint(&mut self, mut breakpoint: BreakpointInfo) -> u64 {
         let id = self.breakpoint_id;
         self.breakpoint_id += 1;
 
-        self.table.insert(address, BreakpointInfo {
-            id     : id,
-            address: address,
-            mode   : mode,
-            enable : enable,
-        });
+        breakpoint.id = id;
+        self.table.push(breakpoint);
+        id
     }
 
-    fn delete_breakpoint(&mut self, search_id: u64) -> Result<(), String> {
-        let mut found_key: Option<u64> = None;
-        for (key, v) in self.table.iter() {
-            if v.id == search_id {
-                found_key = Some(*key);
+    fn get_breakpoint(&mut self, search_id: u64) -> Result<&mut BreakpointInfo, String> {
+        match self.table.iter_mut().find(|v| v.id == search_id) {
+            Some(breakpoint) => Ok(breakpoint),
+            None => Err(format!("breakpoint id {} not valid", search_id)),
+        }
+    }
+
+    // add mode to the plain breakpoint at address, creating it if necessary
+    fn set_mode(&mut self, address: u64, mode: u8) {
+        if let Some(breakpoint) = self.table.iter_mut().find(|v| v.address == address && v.end == address && v.condition.is_none()) {
+            breakpoint.mode |= mode;
+        } else {
+            self.add_breakpoint(BreakpointInfo::new(address, address, mode));
+        }
+    }
+
+    // remove mode from the plain breakpoint at address, deleting it once no modes are left
+    fn clear_mode(&mut self, address: u64, mode: u8) {
+        if let Some(index) = self.table.iter().position(|v| v.address == address && v.end == address && v.condition.is_none()) {
+            self.table[index].mode &= !mode;
+            if self.table[index].mode == 0 {
+                self.table.remove(index);
             }
         }
+    }
 
-        if let Some(key) = found_key {
-            self.table.remove(&key);
+    fn delete_breakpoint(&mut self, search_id: u64) -> Result<(), String> {
+        if let Some(index) = self.table.iter().position(|v| v.id == search_id) {
+            self.table.remove(index);
         } else {
             return Err(format!("breakpoint id {} not valid", search_id));
         }
@@ -109,27 +304,37 @@ impl Breakpoints {
 
 impl Debugger {
 
-    pub fn new(mut system: System, change_logging: Box<dyn Fn(&str, Level) -> ()>) -> Debugger {
-        let cpu_running = Arc::new(AtomicBool::new(false));
-        let r = cpu_running.clone();
+    pub fn new(system: System, change_logging: Box<dyn Fn(&str, Level) -> ()>) -> Debugger {
+        let debugger = Debugger::with_system(system, change_logging);
+        let r = debugger.cpu_running.clone();
 
         ctrlc::set_handler(move || {
             println!("Break!");
             r.store(false, Ordering::SeqCst);
         }).expect("Error setting ctrl-c handler");
 
+        debugger
+    }
+
+    // everything but the ctrl-c handler, which can only be installed once per process
+    fn with_system(mut system: System, change_logging: Box<dyn Fn(&str, Level) -> ()>) -> Debugger {
+        let cpu_running = Arc::new(AtomicBool::new(false));
         let breakpoints = Rc::new(RefCell::new(Breakpoints::new()));
 
-        // replace the bus the CPU is connected to to our debugger bus
-        let old_bus = system.cpu.bus.clone();
-        system.cpu.bus = Rc::new(RefCell::new(DebuggerBus::new(old_bus, breakpoints.clone())));
+        // read and write breakpoints are checked on every load and store the CPU makes
+        let watch = DebuggerWatch::new(system.cpu.bus.clone(), breakpoints.clone());
+        system.cpu.set_data_watch(Some(Rc::new(RefCell::new(watch))));
 
         Debugger {
             alive         : true,
             ctrlc_count   : 0,
+            exit_status   : None,
+            source_depth  : 0,
             cpu_run_til   : None,
             cpu_running   : cpu_running,
             breakpoints   : breakpoints,
+            symbols       : Symbols::new(),
+            tracer        : None,
             system        : system,
             change_logging: change_logging,
         }
@@ -145,10 +350,13 @@ impl Debugger {
         let mut lastline = String::from("");
         let mut last_printed_pc = 0;
         while self.alive {
+            // the RSP keeps running on its own while the CPU is stopped here
+            self.report_rsp_breakpoint();
+
             let next_instruction_pc = *self.system.cpu.next_instruction_pc();
             if last_printed_pc != next_instruction_pc {
                 let inst = cpu::Cpu::disassemble(next_instruction_pc, *self.system.cpu.next_instruction(), true);
-                print!("${:08X}: {} (next instruction)", next_instruction_pc, inst);
+                print!("${:08X}{}: {} (next instruction)", next_instruction_pc, self.symbols.describe(next_instruction_pc), inst);
                 if *self.system.cpu.next_is_delay_slot() {
                     print!(" (delay slot)");
                 }
@@ -156,7 +364,10 @@ impl Debugger {
                 last_printed_pc = next_instruction_pc;
             }
 
-            let prompt = format!("<PC:${:08X}>@ ", next_instruction_pc);
+            let prompt = match self.symbols.lookup(next_instruction_pc) {
+                Some(name) => format!("<PC:${:08X} {}>@ ", next_instruction_pc, name),
+                None => format!("<PC:${:08X}>@ ", next_instruction_pc),
+            };
             let readline = rl.readline(&prompt);
 
             match readline {
@@ -209,6 +420,8 @@ impl Debugger {
     fn handle_line(&mut self, line: &str) -> Result<(), String> {
         let lines = line.split(";").collect::<Vec<&str>>();
         for line in lines {
+            if !self.alive { break; }
+
             let parts = line.split_whitespace().collect::<Vec<&str>>();
 
             if parts.len() == 0 { return Ok(()); }
@@ -219,13 +432,32 @@ impl Debugger {
                 "res" | "rese" | "reset"    => { self.reset(&parts) },
                 "regs" | "rd"               => { self.dump_regs(&parts) },
                 "rw"                        => { self.dump_regs_as_words(&parts) },
+                "cop0" | "c0"               => { self.dump_cop0(&parts) },
+                "fpu" | "cop1" | "c1"       => { self.dump_fpu(&parts) },
+                "tlb"                       => { self.dump_tlb(&parts) },
+                "tr" | "translate"          => { self.translate(&parts) },
                 "b" | "br" | "bre" | "brea"
                  | "break" | "bp"           => { self.breakpoint(&parts) },
                 "db" | "del" | "dbr" 
                  | "dbrea" | "dbreak"       => { self.delete_breakpoint(&parts) },
+                "cond"                      => { self.breakpoint_condition(&parts) },
+                "ignore"                    => { self.breakpoint_ignore(&parts) },
                 "log"                       => { self.logging(&parts) },
                 "l" | "li" | "lis" | "list" => { self.listing(&parts) },
                 "int"                       => { self.interrupt(&parts) },
+                "screenshot"                => { self.screenshot(&parts) },
+                "x" | "mem"                 => { self.examine(&parts) },
+                "poke"                      => { self.poke(&parts) },
+                "fill"                      => { self.fill(&parts) },
+                "find"                      => { self.find(&parts) },
+                "loadbin"                   => { self.load_binary(&parts) },
+                "savebin"                   => { self.save_binary(&parts) },
+                "rsp"                       => { self.rsp_command(&parts) },
+                "sym" | "symbols"           => { self.symbol_command(&parts) },
+                "trace"                     => { self.trace_command(&parts) },
+                "bt" | "backtrace"          => { self.backtrace_command(&parts) },
+                "source"                    => { self.source_command(&parts) },
+                "q" | "quit" | "exit"       => { self.quit(&parts) },
 
                 _ => {
                     Err(format!("unsupported debugger command \"{}\"", parts[0]))
@@ -243,30 +475,31 @@ impl Debugger {
         self.cpu_running.store(true, Ordering::SeqCst);
 
         if parts.len() > 1 {
-            self.cpu_run_til = match parse_int(&parts[1]) {
-                Ok(v) => Some(v as u64),
-                Err(err) => {
-                    return Err(err);
-                }
-            };
+            self.cpu_run_til = Some(parse_breakpoint_address(&parts[1], &self.symbols)?);
         }
 
         let start_steps = *self.system.cpu.num_steps();
         let now = std::time::Instant::now();
 
+        self.breakpoints.borrow_mut().resume();
         while self.cpu_running.load(Ordering::SeqCst) {
             //let address = *self.system.cpu.next_instruction_pc();
             //let inst = cpu::Cpu::disassemble(address, *self.system.cpu.next_instruction(), true);
             //println!("${:08X}: {}", address, inst);
 
             // Break loop on any instruction error or memory access
-            if let Err(_) = self.system.step(1) {
+            if let Err(_) = self.step_system() {
                 break;
             }
 
             // Check breakpoints
-            if let Some(breakpoint) = self.breakpoints.borrow().check_breakpoint((*self.system.cpu.next_instruction_pc() as i32) as u64, BP_EXEC) {
-                println!("Breakpoint ${:016X} hit", breakpoint.address);
+            if let Some(breakpoint) = self.check_exec_breakpoint() {
+                println!("Breakpoint {} hit at ${:016X}{}", breakpoint.id, breakpoint.address, self.symbols.describe(breakpoint.address));
+                self.cpu_running.store(false, Ordering::SeqCst);
+            }
+
+            // The RSP stopping on a breakpoint stops everything
+            if self.report_rsp_breakpoint() {
                 self.cpu_running.store(false, Ordering::SeqCst);
             }
 
@@ -303,9 +536,10 @@ impl Debugger {
         };
 
         self.cpu_running.store(true, Ordering::SeqCst);
+        self.breakpoints.borrow_mut().resume();
         while count > 0 && self.cpu_running.load(Ordering::SeqCst) {
             // Break loop on any instruction error
-            if let Err(_) = self.system.step(1) {
+            if let Err(_) = self.step_system() {
                 break;
             }
 
@@ -313,8 +547,8 @@ impl Debugger {
             count -= 1;
 
             // Check breakpoints
-            if let Some(breakpoint) = self.breakpoints.borrow().check_breakpoint((*self.system.cpu.next_instruction_pc() as i32) as u64, BP_EXEC) {
-                println!("Breakpoint ${:016X} hit", breakpoint.address);
+            if let Some(breakpoint) = self.check_exec_breakpoint() {
+                println!("Breakpoint {} hit at ${:016X}{}", breakpoint.id, breakpoint.address, self.symbols.describe(breakpoint.address));
                 self.cpu_running.store(false, Ordering::SeqCst);
             }
         }
@@ -322,6 +556,61 @@ impl Debugger {
         Ok(())
     }
 
+    fn capture_registers(&self) -> RegisterState {
+        let mut gpr = [0u64; 32];
+        gpr.copy_from_slice(self.system.cpu.regs());
+
+        RegisterState {
+            gpr: gpr,
+            pc : *self.system.cpu.next_instruction_pc(),
+            lo : self.system.cpu.lo(),
+            hi : self.system.cpu.hi(),
+        }
+    }
+
+    // step one instruction, first capturing the registers if bus breakpoints need them
+    fn step_system(&mut self) -> Result<(), cpu::InstructionFault> {
+        if self.breakpoints.borrow().wants_registers() {
+            let registers = self.capture_registers();
+            self.breakpoints.borrow_mut().registers = registers;
+        }
+
+        let traced = self.trace_before_step();
+
+        let result = self.system.step(1);
+        self.breakpoints.borrow_mut().resume_step = false;
+
+        if let (Some((pc, inst)), Ok(_)) = (traced, &result) {
+            self.trace_after_step(pc, inst);
+        }
+        result
+    }
+
+    // print the breakpoint the RSP stopped on since the last check, if any
+    fn report_rsp_breakpoint(&mut self) -> bool {
+        match self.system.rcp.borrow().rsp.debug_break_hit() {
+            Some(pc) => {
+                println!("RSP breakpoint hit at ${:03X}", pc);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // check for an execution breakpoint stopping on the next instruction
+    fn check_exec_breakpoint(&mut self) -> Option<BreakpointInfo> {
+        let breakpoints = self.breakpoints.clone();
+        let mut breakpoints = breakpoints.borrow_mut();
+        if !breakpoints.has_mode(BP_EXEC) { return None; }
+
+        breakpoints.registers = self.capture_registers();
+        let pc = breakpoints.registers.pc;
+        let system = &mut self.system;
+        breakpoints.check_breakpoint(pc, 4, BP_EXEC, None, &mut |address| {
+            system.cpu.debug_read_u32(&mut *system.rcp.borrow_mut(), address).ok()
+        })
+    }
+
     fn reset(&mut self, _: &Vec<&str>) -> Result<(), String> {
         self.system.reset();
         Ok(())
@@ -362,42 +651,118 @@ impl Debugger {
             self.breakpoints.borrow().print_breakpoints();
             Ok(())
         } else {
-            let breakpoint_address = match parse_int(&parts[1]) {
-                Err(err) => { return Err(err); },
-                Ok(v) => {
-                    if (v as u64) < 0x1_0000_0000 { // sign extend 32-bit value
-                        (v as i32) as u64
-                    } else {
-                        v as u64 // 64-bit
-                    }
-                },
+            // break [p:]addr[-end|+length] [rwx] [value=X] [ignore=N] [log] [if condition]
+            // where "symbol+offset" is an address rather than a range
+            let (physical, range) = match parts[1].strip_prefix("p:") {
+                Some(range) => (true, range),
+                None => (false, parts[1]),
             };
-
-            let mode = if parts.len() > 2 {
-                let mode_str = parts[2];
-                let mut mode_result: u8 = 0;
-                for c in mode_str.as_bytes() {
-                    mode_result |= match c {
-                        b'r' | b'R' => { BP_READ },
-                        b'w' | b'W' => { BP_WRITE },
-                        b'x' | b'X' => { BP_EXEC },
-                        _ => { return Err(format!("invalid format option '{}' (only rwx are valid)", c)); },
-                    };
-                }
-                mode_result
+            let symbols = &self.symbols;
+            let parse = |s: &str| if physical { parse_u64(s) } else { parse_breakpoint_address(s, symbols) };
+            let (mut breakpoint_address, mut breakpoint_end) = if let Ok(address) = parse(range) {
+                (address, address)
+            } else if let Some((start, end)) = range.split_once('-') {
+                (parse(start)?, parse(end)?)
+            } else if let Some((start, length)) = range.split_once('+') {
+                let start = parse(start)?;
+                let length = parse_u64(length)?;
+                if length == 0 { return Err(format!("breakpoint length must not be zero")); }
+                (start, start.wrapping_add(length - 1))
             } else {
-                // default to 'x' only
-                BP_EXEC
+                return Err(format!("invalid address \"{}\"", parts[1]));
             };
 
-            self.breakpoints.borrow_mut().add_breakpoint(breakpoint_address, mode, true);
+            if breakpoint_end < breakpoint_address {
+                return Err(format!("breakpoint range ends before it starts"));
+            }
 
-            println!("breakpoint set at ${:016X} (mode {})", breakpoint_address, format_breakpoint_mode(mode));
+            // default to 'x' only
+            let mut breakpoint = BreakpointInfo::new(breakpoint_address, breakpoint_end, BP_EXEC);
+            for (i, option) in parts.iter().enumerate().skip(2) {
+                if *option == "if" {
+                    breakpoint.condition = Some(Condition::parse(&parts[i+1..].join(" "), &self.symbols)?);
+                    break;
+                } else if *option == "log" {
+                    breakpoint.action = BreakpointAction::Log;
+                } else if let Some(value) = option.strip_prefix("value=") {
+                    let value = parse_u64(value)?;
+                    if value > 0xFFFF_FFFF { return Err(format!("value must fit in 32 bits")); }
+                    breakpoint.value = Some(value as u32);
+                } else if let Some(count) = option.strip_prefix("ignore=") {
+                    breakpoint.ignore_count = parse_u64(count)?;
+                } else {
+                    let mut mode_result: u8 = 0;
+                    for c in option.as_bytes() {
+                        mode_result |= match c {
+                            b'r' | b'R' => { BP_READ },
+                            b'w' | b'W' => { BP_WRITE },
+                            b'x' | b'X' => { BP_EXEC },
+                            _ => { return Err(format!("invalid format option '{}' (only rwx are valid)", *c as char)); },
+                        };
+                    }
+                    breakpoint.mode = mode_result;
+                }
+            }
+
+            if breakpoint.value.is_some() && (breakpoint.mode & BP_WRITE) == 0 {
+                return Err(format!("value= only applies to write breakpoints"));
+            }
+
+            // The bus only sees physical addresses, so read and write breakpoints are translated
+            // now and stay on the same memory even if the TLB changes later, like gdb watchpoints
+            if breakpoint.is_physical() && !physical {
+                let start = self.system.cpu.debug_translate_address(breakpoint_address);
+                let end = self.system.cpu.debug_translate_address(breakpoint_end);
+                match (start, end) {
+                    (Some(start), Some(end)) if end.wrapping_sub(start) == breakpoint_end - breakpoint_address => {
+                        breakpoint_address = start;
+                        breakpoint_end = end;
+                    },
+                    _ => return Err(format!("{} doesn't map to contiguous physical memory, use p: addresses instead", parts[1])),
+                }
+            } else if !breakpoint.is_physical() && physical {
+                return Err(format!("execution breakpoints take virtual addresses"));
+            } else if !breakpoint.is_physical() && (breakpoint.mode & (BP_READ | BP_WRITE)) != 0 {
+                return Err(format!("x watches virtual addresses and r/w physical ones, so set them separately"));
+            }
+
+            breakpoint.address = breakpoint_address;
+            breakpoint.end = breakpoint_end;
+            let mode = breakpoint.mode;
+            let range = breakpoint.format_range();
+            let id = self.breakpoints.borrow_mut().add_breakpoint(breakpoint);
+            println!("breakpoint {} set at {} (mode {})", id, range, format_breakpoint_mode(mode));
 
             Ok(())
         }
     }
 
+    fn breakpoint_condition(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        if parts.len() < 2 {
+            return Err(format!("usage: cond [breakpoint id] [condition, or nothing to remove it]"));
+        }
+
+        let condition = if parts.len() > 2 { Some(Condition::parse(&parts[2..].join(" "), &self.symbols)?) } else { None };
+        let mut breakpoints = self.breakpoints.borrow_mut();
+        let breakpoint = breakpoints.get_breakpoint(parse_u64(parts[1])?)?;
+        breakpoint.condition = condition;
+        println!("{}", breakpoint);
+        Ok(())
+    }
+
+    fn breakpoint_ignore(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        if parts.len() != 3 {
+            return Err(format!("usage: ignore [breakpoint id] [count]"));
+        }
+
+        let count = parse_u64(parts[2])?;
+        let mut breakpoints = self.breakpoints.borrow_mut();
+        let breakpoint = breakpoints.get_breakpoint(parse_u64(parts[1])?)?;
+        breakpoint.ignore_count = count;
+        println!("{}", breakpoint);
+        Ok(())
+    }
+
     fn delete_breakpoint(&mut self, parts: &Vec<&str>) -> Result<(), String> {
         if parts.len() != 2 {
             return Err(format!("usage: db [breakpoint id]"));
@@ -439,14 +804,18 @@ impl Debugger {
     }
 
     fn listing(&mut self, parts: &Vec<&str>) -> Result<(), String> {
-        let start_pc = self.system.cpu.next_instruction_pc(); // TODO set from an argument
+        let mut start_pc = *self.system.cpu.next_instruction_pc();
         let mut count = 10;
 
-        if parts.len() >= 3 {
-            return Err(format!("usage: l[ist] [count (default 10)]"));
+        if parts.len() >= 4 {
+            return Err(format!("usage: l[ist] [count (default 10)] [address (default PC)]"));
+        }
+
+        if parts.len() == 3 {
+            start_pc = parse_breakpoint_address(parts[2], &self.symbols)? & !0x03;
         }
 
-        if parts.len() == 2 {
+        if parts.len() >= 2 {
             count = match parse_int(&parts[1]) {
                 Err(err) => { return Err(err); },
                 Ok(v) => { if v < 0 { return Err(format!("number must be positive")); } v as u32 },
@@ -454,12 +823,21 @@ impl Debugger {
         }
 
         for i in 0..count {
-            let addr = start_pc + (i as u64) * 4;
-            print!("${:08X}: ", addr);
+            let addr = start_pc.wrapping_add((i as u64) * 4);
 
-            if let Ok(op) = self.system.cpu.bus.borrow_mut().read_u32(addr as usize) {
+            print!("${:08X}{}: ", addr, self.symbols.describe(addr));
+
+            if let Ok(op) = self.read_word(MemoryAddress::Virtual(addr)) {
                 let inst = cpu::Cpu::disassemble(addr, op, true);
                 print!("{}", inst);
+
+                // name the target of j and jal
+                if (op >> 26) == 0b000_010 || (op >> 26) == 0b000_011 {
+                    let target = (addr.wrapping_add(4) & !0x0FFF_FFFF) | (((op & 0x03FF_FFFF) as u64) << 2);
+                    if let Some(name) = self.symbols.lookup(target) {
+                        print!(" <{}>", name);
+                    }
+                }
             } else {
                 print!("<unaccessable>");
             }
@@ -494,87 +872,328 @@ impl Debugger {
         chan.send(InterruptUpdate(signal, InterruptUpdateMode::SetInterrupt)).unwrap();
         Ok(())
     }
-}
 
-pub struct DebuggerBus {
-    bus: Rc<RefCell<dyn Addressable>>,
-    breakpoints: Rc<RefCell<Breakpoints>>,
-}
+    fn screenshot(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        if parts.len() != 2 {
+            return Err(format!("usage: screenshot file.ppm"));
+        }
 
-impl DebuggerBus {
-    fn new(bus: Rc<RefCell<dyn Addressable>>, breakpoints: Rc<RefCell<Breakpoints>> ) -> DebuggerBus {
-        DebuggerBus {
-            bus: bus,
-            breakpoints: breakpoints,
+        let frame = match self.system.screenshot() {
+            Some(frame) => frame,
+            None => { return Err(format!("video output is blanked")); }
+        };
+
+        frame.write_ppm(parts[1]).map_err(|err| format!("couldn't write \"{}\": {}", parts[1], err))?;
+        println!("wrote {}x{} image to {}", frame.width, frame.height, parts[1]);
+        Ok(())
+    }
+
+    // Memory commands bypass the debugger bus, so they don't trip breakpoints. Virtual addresses
+    // are translated with the current TLB and operating mode and see what's in the D-cache,
+    // physical addresses go straight to the RCP bus. Either way the bus only sees word accesses,
+    // and smaller writes read, modify and write back the word, as the CPU's debug writes do
+    fn read_word(&mut self, address: MemoryAddress) -> Result<u32, String> {
+        let result = match address {
+            MemoryAddress::Virtual(v) => {
+                if self.system.cpu.debug_translate_address(v).is_none() {
+                    return Err(format!("{} doesn't map to a physical address", address));
+                }
+
+                let mut rcp = self.system.rcp.borrow_mut();
+                self.system.cpu.debug_read_u32(&mut *rcp, v)
+            },
+
+            MemoryAddress::Physical(v) => {
+                self.system.rcp.borrow_mut().read_u32((v & !0x03) as usize)
+            },
+        };
+        result.map_err(|err| format!("{:?} reading {}", err, address))
+    }
+
+    fn write_word(&mut self, address: MemoryAddress, value: u32, mask: u32) -> Result<(), String> {
+        let result = match address {
+            MemoryAddress::Virtual(v) => {
+                if self.system.cpu.debug_translate_address(v).is_none() {
+                    return Err(format!("{} doesn't map to a physical address", address));
+                }
+
+                let mut rcp = self.system.rcp.borrow_mut();
+                self.system.cpu.debug_write_u32(&mut *rcp, v, value, mask)
+            },
+
+            MemoryAddress::Physical(v) => {
+                let mut rcp = self.system.rcp.borrow_mut();
+                let physical_address = (v & !0x03) as usize;
+                let word = if mask == 0xFFFF_FFFF {
+                    Ok(value)
+                } else {
+                    rcp.read_u32(physical_address).map(|word| (word & !mask) | (value & mask))
+                };
+                word.and_then(|word| rcp.write_u32(word, physical_address).map(|_| ()))
+            },
+        };
+        result.map_err(|err| format!("{:?} writing {}", err, address))
+    }
+
+    fn read_memory(&mut self, address: MemoryAddress, size: usize) -> Result<u64, String> {
+        if (address.value() & (size as u64 - 1)) != 0 {
+            return Err(format!("{} isn't aligned to {} bytes", address, size));
         }
+
+        if size == 8 {
+            return Ok(((self.read_word(address)? as u64) << 32) | (self.read_word(address.offset(4))? as u64));
+        }
+
+        // big endian, so the lowest address is in the top bits of the word
+        let word = self.read_word(address)? as u64;
+        let shift = (4 - size - (address.value() & 0x03) as usize) << 3;
+        Ok((word >> shift) & (u64::MAX >> (64 - (size << 3))))
     }
 
-    //pub fn set_read_u32<F>(&mut self, read_u32: F)
-    //    where F: Fn(usize) -> u32 {
-    //    println!("{}", read_u32(0xbfc00000));
-    //}
-}
+    fn write_memory(&mut self, address: MemoryAddress, value: u64, size: usize) -> Result<(), String> {
+        if (address.value() & (size as u64 - 1)) != 0 {
+            return Err(format!("{} isn't aligned to {} bytes", address, size));
+        }
 
-impl Addressable for DebuggerBus {
-    fn read_u32(&mut self, offset: usize) -> Result<u32, ReadWriteFault> {
-        if let Some(breakpoint) = self.breakpoints.borrow().check_breakpoint(offset as u64, BP_READ) {
-            println!("Breakpoint ${:016X} hit", breakpoint.address);
-            return Err(ReadWriteFault::Break);
+        if size == 8 {
+            self.write_word(address, (value >> 32) as u32, 0xFFFF_FFFF)?;
+            return self.write_word(address.offset(4), value as u32, 0xFFFF_FFFF);
         }
 
-        self.bus.borrow_mut().read_u32(offset)
+        let shift = (4 - size - (address.value() & 0x03) as usize) << 3;
+        let mask = (u32::MAX >> (32 - (size << 3))) << shift;
+        self.write_word(address, (value as u32) << shift, mask)
     }
 
-    fn write_u32(&mut self, value: u32, offset: usize) -> Result<WriteReturnSignal, ReadWriteFault> {
-        if let Some(breakpoint) = self.breakpoints.borrow().check_breakpoint(offset as u64, BP_WRITE) {
-            println!("Breakpoint ${:016X} hit", breakpoint.address);
-            return Err(ReadWriteFault::Break);
+    // `length` bytes from any alignment, a word at a time
+    fn read_bytes(&mut self, address: MemoryAddress, length: u64) -> Result<Vec<u8>, String> {
+        let skip = address.value() & 0x03;
+        let start = address.offset(skip.wrapping_neg());
+
+        let mut data = Vec::with_capacity((length + 8) as usize);
+        for offset in (0..(skip + length)).step_by(4) {
+            data.extend_from_slice(&self.read_word(start.offset(offset))?.to_be_bytes());
         }
+        Ok(data[skip as usize..(skip + length) as usize].to_vec())
+    }
 
-        self.bus.borrow_mut().write_u32(value, offset)
+    // `data` to any alignment, a word at a time. only the words at either end are partial
+    fn write_bytes(&mut self, address: MemoryAddress, data: &[u8]) -> Result<(), String> {
+        let skip = (address.value() & 0x03) as usize;
+        let start = address.offset((skip as u64).wrapping_neg());
+
+        for offset in (0..(skip + data.len())).step_by(4) {
+            let (mut value, mut mask) = ([0u8; 4], [0u8; 4]);
+            for byte in 0..4 {
+                if let Some(index) = (offset + byte).checked_sub(skip).filter(|index| *index < data.len()) {
+                    value[byte] = data[index];
+                    mask[byte] = 0xFF;
+                }
+            }
+            self.write_word(start.offset(offset as u64), u32::from_be_bytes(value), u32::from_be_bytes(mask))?;
+        }
+        Ok(())
     }
 
-    /// not every device needs to implement these, so defaults are provided
-    fn read_u16(&mut self, offset: usize) -> Result<u16, ReadWriteFault> {
-        if let Some(breakpoint) = self.breakpoints.borrow().check_breakpoint(offset as u64, BP_READ) {
-            println!("Breakpoint ${:016X} hit", breakpoint.address);
-            return Err(ReadWriteFault::Break);
+    fn examine(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        if parts.len() < 2 || parts.len() > 4 {
+            return Err(format!("usage: x [p:]address [length in bytes (default 128)] [b|h|w|d]"));
         }
 
-        self.bus.borrow_mut().read_u16(offset)
+        let address = parse_address(parts[1], &self.symbols)?;
+        let length = if parts.len() > 2 { parse_int(parts[2])? as u64 } else { 128 };
+        let size = if parts.len() > 3 { parse_size(parts[3])? } else { 4 };
+
+        self.hexdump(address, length, size);
+        Ok(())
     }
 
-    fn write_u16(&mut self, value: u32, offset: usize) -> Result<WriteReturnSignal, ReadWriteFault> {
-        if let Some(breakpoint) = self.breakpoints.borrow().check_breakpoint(offset as u64, BP_WRITE) {
-            println!("Breakpoint ${:016X} hit", breakpoint.address);
-            return Err(ReadWriteFault::Break);
+    fn hexdump(&mut self, address: MemoryAddress, length: u64, size: usize) {
+        // 16 bytes per line, with the ASCII on the right
+        for line in (0..length).step_by(16) {
+            let mut hex = String::new();
+            let mut ascii = String::new();
+
+            for unit in (line..std::cmp::min(line + 16, length)).step_by(size) {
+                match self.read_memory(address.offset(unit), size) {
+                    Ok(value) => {
+                        hex.push_str(&format!("{:0width$X} ", value, width = size * 2));
+                        for byte in &value.to_be_bytes()[8 - size..] {
+                            ascii.push(if (0x20..0x7F).contains(byte) { *byte as char } else { '.' });
+                        }
+                    },
+                    Err(_) => {
+                        hex.push_str(&format!("{} ", "?".repeat(size * 2)));
+                        ascii.push_str(&"?".repeat(size));
+                    },
+                }
+            }
+
+            // pad a short last line so the ASCII lines up
+            let line_width = (16 / size) * (size * 2 + 1);
+            println!("{}: {:width$} |{}|", address.offset(line), hex, ascii, width = line_width);
         }
+    }
 
-        self.bus.borrow_mut().write_u16(value, offset)
+    fn poke(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        if parts.len() < 3 || parts.len() > 4 {
+            return Err(format!("usage: poke [p:]address value [b|h|w|d]"));
+        }
+
+        let address = parse_address(parts[1], &self.symbols)?;
+        let value = parse_u64(parts[2])?;
+        let size = if parts.len() > 3 { parse_size(parts[3])? } else { 4 };
+
+        self.write_memory(address, value, size)
     }
 
-    fn read_u8(&mut self, offset: usize) -> Result<u8, ReadWriteFault> {
-        if let Some(breakpoint) = self.breakpoints.borrow().check_breakpoint(offset as u64, BP_READ) {
-            println!("Breakpoint ${:016X} hit", breakpoint.address);
-            return Err(ReadWriteFault::Break);
+    fn fill(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        if parts.len() < 4 || parts.len() > 5 {
+            return Err(format!("usage: fill [p:]address length value [b|h|w|d]"));
         }
 
-        self.bus.borrow_mut().read_u8(offset)
+        let address = parse_address(parts[1], &self.symbols)?;
+        let length = parse_int(parts[2])? as u64;
+        let value = parse_u64(parts[3])?;
+        let size = if parts.len() > 4 { parse_size(parts[4])? } else { 4 };
+
+        for offset in (0..length).step_by(size) {
+            self.write_memory(address.offset(offset), value, size)?;
+        }
+
+        println!("filled {} bytes at {}", length, address);
+        Ok(())
     }
 
-    fn write_u8(&mut self, value: u32, offset: usize) -> Result<WriteReturnSignal, ReadWriteFault> {
-        if let Some(breakpoint) = self.breakpoints.borrow().check_breakpoint(offset as u64, BP_WRITE) {
-            println!("Breakpoint ${:016X} hit", breakpoint.address);
-            return Err(ReadWriteFault::Break);
+    fn find(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        if parts.len() != 4 {
+            return Err(format!("usage: find [p:]address length hexbytes|\"text\""));
+        }
+
+        let address = parse_address(parts[1], &self.symbols)?;
+        let length = parse_int(parts[2])? as u64;
+        let pattern = parse_pattern(parts[3])?;
+
+        let memory = self.read_bytes(address, length)?;
+
+        let mut count = 0;
+        for (offset, window) in memory.windows(pattern.len()).enumerate() {
+            if window == pattern.as_slice() {
+                println!("found at {}", address.offset(offset as u64));
+                count += 1;
+            }
+        }
+
+        println!("{} match(es)", count);
+        Ok(())
+    }
+
+    fn load_binary(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        if parts.len() != 3 {
+            return Err(format!("usage: loadbin file [p:]address"));
         }
 
-        self.bus.borrow_mut().write_u8(value, offset)
+        let data = fs::read(parts[1]).map_err(|err| format!("couldn't read \"{}\": {}", parts[1], err))?;
+        let address = parse_address(parts[2], &self.symbols)?;
+
+        self.write_bytes(address, &data)?;
+
+        println!("loaded {} bytes from {} to {}", data.len(), parts[1], address);
+        Ok(())
+    }
+
+    fn save_binary(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        if parts.len() != 4 {
+            return Err(format!("usage: savebin file [p:]address length"));
+        }
+
+        let address = parse_address(parts[2], &self.symbols)?;
+        let length = parse_int(parts[3])? as u64;
+
+        let data = self.read_bytes(address, length)?;
+
+        fs::write(parts[1], &data).map_err(|err| format!("couldn't write \"{}\": {}", parts[1], err))?;
+        println!("saved {} bytes from {} to {}", data.len(), address, parts[1]);
+        Ok(())
+    }
+
+    pub fn load_symbols(&mut self, path: &str) -> Result<usize, String> {
+        self.symbols.load(path)
+    }
+
+    fn symbol_command(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        match parts.get(1).copied() {
+            None => {
+                println!("{} symbols loaded", self.symbols.len());
+            },
+
+            Some("load") if parts.len() == 3 => {
+                let count = self.load_symbols(parts[2])?;
+                println!("loaded {} symbols from {}", count, parts[2]);
+            },
+
+            Some("clear") => self.symbols.clear(),
+
+            // look up a name or an address
+            Some(s) if parts.len() == 2 => {
+                let address = parse_breakpoint_address(s, &self.symbols)?;
+                match self.symbols.lookup(address) {
+                    Some(name) => println!("${:016X} <{}>", address, name),
+                    None => println!("${:016X}", address),
+                }
+            },
+
+            _ => return Err(format!("usage: sym [load file | clear | name | address]")),
+        }
+
+        Ok(())
+    }
+}
+
+pub struct DebuggerWatch {
+    bus: Rc<RefCell<dyn Addressable>>,
+    breakpoints: Rc<RefCell<Breakpoints>>,
+}
+
+impl DebuggerWatch {
+    fn new(bus: Rc<RefCell<dyn Addressable>>, breakpoints: Rc<RefCell<Breakpoints>> ) -> DebuggerWatch {
+        DebuggerWatch {
+            bus: bus,
+            breakpoints: breakpoints,
+        }
+    }
+}
+
+impl cpu::DataWatch for DebuggerWatch {
+    // stop the access if it hits a breakpoint, remembering where for the debugger
+    fn check(&mut self, physical_address: u64, size: u64, is_write: bool, value: Option<u32>) -> Result<(), ReadWriteFault> {
+        let mut breakpoints = self.breakpoints.borrow_mut();
+        if breakpoints.table.len() == 0 || breakpoints.resume_step { return Ok(()); }
+
+        // Conditions can only read the directly mapped segments from here, since the TLB
+        // belongs to the CPU that's in the middle of this access. The D-cache isn't seen either
+        let bus = &self.bus;
+        let mode = if is_write { BP_WRITE } else { BP_READ };
+        let hit = breakpoints.check_breakpoint(physical_address, size, mode, value, &mut |address| {
+            if (0xFFFF_FFFF_8000_0000..=0xFFFF_FFFF_BFFF_FFFF).contains(&address) {
+                bus.borrow_mut().read_u32((address & 0x1FFF_FFFF) as usize).ok()
+            } else {
+                None
+            }
+        });
+
+        if let Some(breakpoint) = hit {
+            println!("Breakpoint {} hit at p:${:08X}", breakpoint.id, physical_address);
+            breakpoints.last_hit = Some((physical_address, mode, breakpoint.id));
+            return Err(ReadWriteFault::Break);
+        }
+        Ok(())
     }
 }
 
 fn parse_int(s: &str) -> Result<i64, String> {
-    if &s[0..1] == "$" {
-        match i64::from_str_radix(s.trim_start_matches("$"), 16) {
+    if let Some(hex) = s.strip_prefix("$") {
+        match i64::from_str_radix(hex, 16) {
             Ok(v) => { Ok(v) },
             Err(err) => {
                 Err(err.to_string())
@@ -590,6 +1209,67 @@ fn parse_int(s: &str) -> Result<i64, String> {
     }
 }
 
+// like parse_int, but takes the full range of 64-bit values
+fn parse_u64(s: &str) -> Result<u64, String> {
+    let result = if let Some(hex) = s.strip_prefix("$") {
+        u64::from_str_radix(hex, 16)
+    } else {
+        u64::from_str_radix(s, 10)
+    };
+    result.map_err(|err| err.to_string())
+}
+
+// numbers, or symbols with an optional offset
+fn parse_breakpoint_address(s: &str, symbols: &Symbols) -> Result<u64, String> {
+    let v = match parse_u64(s) {
+        Ok(v) => v,
+        Err(_) => return symbols.resolve(s).ok_or(format!("\"{}\" isn't a number or a known symbol", s)),
+    };
+    if v < 0x1_0000_0000 { // sign extend 32-bit value
+        Ok((v as i32) as u64)
+    } else {
+        Ok(v) // 64-bit
+    }
+}
+
+// virtual addresses that fit in 32 bits are sign extended, as in the breakpoint command
+fn parse_address(s: &str, symbols: &Symbols) -> Result<MemoryAddress, String> {
+    if let Some(physical) = s.strip_prefix("p:") {
+        Ok(MemoryAddress::Physical(parse_u64(physical)?))
+    } else {
+        Ok(MemoryAddress::Virtual(parse_breakpoint_address(s, symbols)?))
+    }
+}
+
+fn parse_size(s: &str) -> Result<usize, String> {
+    match s {
+        "b" => Ok(1),
+        "h" => Ok(2),
+        "w" => Ok(4),
+        "d" => Ok(8),
+        _ => Err(format!("invalid size \"{}\" (only b, h, w and d are valid)", s)),
+    }
+}
+
+// either a string of hex digits ("3C1A8000") or quoted text ("\"SM64\"")
+fn parse_pattern(s: &str) -> Result<Vec<u8>, String> {
+    let pattern = if let Some(text) = s.strip_prefix("\"") {
+        text.trim_end_matches("\"").as_bytes().to_vec()
+    } else {
+        if (s.len() & 1) != 0 || !s.is_ascii() {
+            return Err(format!("hex pattern \"{}\" must have an even number of digits", s));
+        }
+        (0..s.len()).step_by(2)
+                    .map(|i| u8::from_str_radix(&s[i..i+2], 16).map_err(|err| err.to_string()))
+                    .collect::<Result<Vec<u8>, String>>()?
+    };
+
+    if pattern.len() == 0 {
+        return Err(format!("empty search pattern"));
+    }
+    Ok(pattern)
+}
+
 fn format_breakpoint_mode(mode: u8) -> String {
     let mut res = String::new();
     if (mode & BP_READ) != 0 { res.push_str("r"); }
@@ -598,3 +1278,129 @@ fn format_breakpoint_mode(mode: u8) -> String {
     res
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    // A debugger over a system with a blank cartridge and boot ROM, that hasn't run anything yet.
+    // The JIT is off so every step is a single instruction
+    pub(super) fn debugger() -> Debugger {
+        let comms = SystemCommunication::new(None);
+        #[cfg(feature="jit")]
+        { comms.settings.write().unwrap().jit = cpu::JitMode::Disabled; }
+        debugger_with(comms)
+    }
+
+    // the same, with settings already in comms
+    pub(super) fn debugger_with(comms: SystemCommunication) -> Debugger {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("n64-debugger-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::SeqCst));
+        let boot_rom = std::env::temp_dir().join(format!("{}.pifrom", name));
+        let cartridge = std::env::temp_dir().join(format!("{}.z64", name));
+        fs::write(&boot_rom, vec![0u8; 0x800]).unwrap();
+        fs::write(&cartridge, vec![0u8; 0x10_0000]).unwrap();
+
+        let system = System::new(comms, boot_rom.to_str().unwrap(), cartridge.to_str().unwrap());
+        let _ = fs::remove_file(boot_rom);
+        let _ = fs::remove_file(cartridge);
+        Debugger::with_system(system, Box::new(|_, _| {}))
+    }
+
+    #[test]
+    fn memory_commands_use_word_accesses() {
+        let mut debugger = debugger();
+
+        // registers only decode word accesses, so every size reads through the word
+        assert_eq!(debugger.read_memory(MemoryAddress::Physical(0x0460_0040), 4), Ok(0x0040_0040));
+        assert_eq!(debugger.read_memory(MemoryAddress::Physical(0x0460_0042), 2), Ok(0x0040));
+        assert_eq!(debugger.read_memory(MemoryAddress::Physical(0x0460_0043), 1), Ok(0x40));
+        assert_eq!(debugger.read_memory(MemoryAddress::Physical(0x0460_0040), 8), Ok(0x0040_0040_0044_0044));
+        assert!(debugger.read_memory(MemoryAddress::Physical(0x0450_0001), 1).is_ok());
+        assert!(debugger.read_memory(MemoryAddress::Physical(0x0490_0000), 2).is_ok());
+        assert!(debugger.read_memory(MemoryAddress::Physical(0x8000_0000), 4).is_err());
+        assert!(debugger.read_memory(MemoryAddress::Virtual(0x0000_0000), 4).is_err());
+        assert!(debugger.read_memory(MemoryAddress::Physical(0x0000_0002), 4).is_err());
+
+        // partial words are merged with what's there
+        debugger.write_memory(MemoryAddress::Physical(0x0000_1000), 0x1122_3344, 4).unwrap();
+        debugger.write_memory(MemoryAddress::Virtual(0xFFFF_FFFF_A000_1001), 0xAA, 1).unwrap();
+        debugger.write_memory(MemoryAddress::Physical(0x0000_1002), 0xBBCC, 2).unwrap();
+        assert_eq!(debugger.read_memory(MemoryAddress::Virtual(0xFFFF_FFFF_A000_1000), 4), Ok(0x11AA_BBCC));
+    }
+
+    #[test]
+    fn bulk_memory_access() {
+        let mut debugger = debugger();
+        debugger.write_bytes(MemoryAddress::Physical(0x0000_2000), &[0xEE; 16]).unwrap();
+
+        // unaligned at both ends
+        debugger.write_bytes(MemoryAddress::Virtual(0xFFFF_FFFF_A000_2003), b"N64 memory").unwrap();
+        assert_eq!(debugger.read_bytes(MemoryAddress::Physical(0x0000_2003), 10).unwrap(), b"N64 memory");
+        assert_eq!(debugger.read_bytes(MemoryAddress::Physical(0x0000_2000), 16).unwrap(),
+                   [&[0xEE; 3][..], b"N64 memory", &[0xEE; 3][..]].concat());
+        assert_eq!(debugger.read_bytes(MemoryAddress::Physical(0x0000_2005), 0).unwrap(), b"");
+
+        // within a single word
+        debugger.write_bytes(MemoryAddress::Physical(0x0000_2005), b"!").unwrap();
+        assert_eq!(debugger.read_bytes(MemoryAddress::Physical(0x0000_2004), 3).unwrap(), b"6! ");
+
+        // a read that runs off the end of the bus fails rather than returning short
+        assert!(debugger.read_bytes(MemoryAddress::Physical(0x7FFF_FFFE), 4).is_err());
+    }
+
+    #[test]
+    fn unexpected_register_values_are_written() {
+        let mut debugger = debugger();
+
+        // IPL3 only ever sets RI_MODE to 0 or $0E, but other values are stored rather than refused
+        debugger.write_memory(MemoryAddress::Physical(0x0470_0000), 0x1234, 4).unwrap();
+        assert_eq!(debugger.read_memory(MemoryAddress::Physical(0x0470_0000), 4), Ok(0x1234));
+    }
+
+    #[test]
+    fn memory_breakpoints_are_physical() {
+        let mut debugger = debugger();
+
+        debugger.breakpoint(&vec!["break", "$a0002000+8", "r"]).unwrap();
+        debugger.breakpoint(&vec!["break", "p:$3000-p:$3003", "w"]).unwrap_err();
+        debugger.breakpoint(&vec!["break", "p:$3000-$3003", "rw"]).unwrap();
+        debugger.breakpoint(&vec!["break", "$80001000", "x"]).unwrap();
+        {
+            let breakpoints = debugger.breakpoints.borrow();
+            assert_eq!(breakpoints.table.iter().map(|b| (b.address, b.end, b.mode)).collect::<Vec<_>>(),
+                       vec![(0x2000, 0x2007, BP_READ), (0x3000, 0x3003, BP_READ | BP_WRITE), (0xFFFF_FFFF_8000_1000, 0xFFFF_FFFF_8000_1000, BP_EXEC)]);
+        }
+
+        // nothing is mapped at 0 until the TLB is set up, and x needs the virtual address
+        assert!(debugger.breakpoint(&vec!["break", "0", "w"]).is_err());
+        assert!(debugger.breakpoint(&vec!["break", "p:$1000", "x"]).is_err());
+        assert!(debugger.breakpoint(&vec!["break", "$80001000", "rwx"]).is_err());
+    }
+
+    #[test]
+    fn cached_write_breakpoint() {
+        let mut debugger = debugger();
+
+        // lui t0, $8000; lui t1, $1234; sw t1, $2000(t0); sw t1, $2004(t0)
+        let code = [0x3C08_8000u32, 0x3C09_1234, 0xAD09_2000, 0xAD09_2004, 0, 0];
+        for (i, inst) in code.iter().enumerate() {
+            debugger.write_memory(MemoryAddress::Physical(0x1000 + (i as u64) * 4), *inst as u64, 4).unwrap();
+        }
+        debugger.system.cpu.set_pc(0xFFFF_FFFF_8000_1000).unwrap();
+
+        // KSEG0 stores only reach the D-cache, but still stop on the physical address
+        debugger.breakpoint(&vec!["break", "$80002000", "w", "value=$12340000"]).unwrap();
+        debugger.step(&vec!["step", "4"]).unwrap();
+        assert_eq!(debugger.breakpoints.borrow().last_hit, Some((0x2000, BP_WRITE, 0)));
+        assert_eq!(*debugger.system.cpu.next_instruction_pc(), 0xFFFF_FFFF_8000_1008);
+        assert_eq!(debugger.read_memory(MemoryAddress::Virtual(0xFFFF_FFFF_8000_2000), 4), Ok(0));
+
+        // and the store goes through when execution resumes
+        debugger.step(&vec!["step", "2"]).unwrap();
+        assert_eq!(debugger.breakpoints.borrow().last_hit, None);
+        assert_eq!(*debugger.system.cpu.next_instruction_pc(), 0xFFFF_FFFF_8000_1010);
+        assert_eq!(debugger.read_memory(MemoryAddress::Virtual(0xFFFF_FFFF_8000_2000), 8), Ok(0x1234_0000_1234_0000));
+    }
+}
@@ -0,0 +1,233 @@
+// Call stack reconstruction. With `bt shadow on` the CPU keeps a shadow call stack of every
+// jal/jalr/bgezal/bltzal that hasn't returned yet, which is exact as long as code calls and returns
+// normally. Otherwise the stack is unwound from memory: each function is scanned for the
+// `addiu sp, sp, -N` that allocates its frame and the `sw ra, X(sp)` that saves its return
+// address. Function starts come from the symbols when they're loaded, or from scanning back to
+// the previous `jr ra`, so functions with early returns or hand written prologues can fool it.
+use super::*;
+
+const DEFAULT_FRAMES: usize = 64;
+
+// how far back to look for the start of a function when there's no symbol for it
+const MAX_FUNCTION_SCAN: u64 = 0x4000;
+
+const INST_JR_RA: u32 = 0x03E0_0008;
+
+impl Debugger {
+    // bt [count] | unwind [count] | shadow on|off
+    pub(super) fn backtrace_command(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        let count = |arg: Option<&&str>| -> Result<usize, String> {
+            match arg {
+                Some(count) => Ok(parse_u64(count)? as usize),
+                None => Ok(DEFAULT_FRAMES),
+            }
+        };
+
+        match parts.get(1).copied() {
+            Some("shadow") => {
+                match parts.get(2).copied() {
+                    Some("on") => {
+                        self.system.cpu.set_call_stack_tracking(true);
+                        println!("keeping a shadow call stack, calls made before now won't show up");
+                    },
+                    Some("off") => {
+                        self.system.cpu.set_call_stack_tracking(false);
+                        println!("shadow call stack off");
+                    },
+                    _ => return Err(format!("usage: bt shadow on|off")),
+                }
+                Ok(())
+            },
+
+            Some("unwind") => {
+                let count = count(parts.get(2))?;
+                self.print_unwound_stack(count)
+            },
+
+            _ => {
+                let count = count(parts.get(1))?;
+                if self.system.cpu.call_stack().is_some() {
+                    self.print_shadow_stack(count)
+                } else {
+                    self.print_unwound_stack(count)
+                }
+            },
+        }
+    }
+
+    fn print_frame(&self, index: usize, pc: u64, sp: u64) {
+        println!("#{:<2} ${:016X}{} sp=${:016X}", index, pc, self.symbols.describe(pc), sp);
+    }
+
+    fn print_shadow_stack(&mut self, count: usize) -> Result<(), String> {
+        let registers = self.capture_registers();
+        self.print_frame(0, registers.pc, registers.gpr[29]);
+
+        let call_stack = self.system.cpu.call_stack().unwrap();
+        for (index, frame) in call_stack.iter().rev().take(count.saturating_sub(1)).enumerate() {
+            self.print_frame(index + 1, frame.call_pc, frame.sp);
+        }
+
+        Ok(())
+    }
+
+    fn print_unwound_stack(&mut self, count: usize) -> Result<(), String> {
+        let registers = self.capture_registers();
+        let mut pc = registers.pc;
+        let mut sp = registers.gpr[29];
+
+        // the return address is only in ra for the innermost frame
+        let mut ra = Some(registers.gpr[31]);
+
+        for index in 0..count {
+            self.print_frame(index, pc, sp);
+
+            let (frame_size, ra_offset) = match self.find_frame(pc) {
+                Ok(frame) => frame,
+                Err(err) => {
+                    println!("can't unwind further: {}", err);
+                    break;
+                },
+            };
+
+            let return_address = match (ra_offset, ra) {
+                (Some(offset), _) => match self.read_word(MemoryAddress::Virtual(sp.wrapping_add(offset))) {
+                    Ok(word) => (word as i32) as u64,
+                    Err(err) => {
+                        println!("can't unwind further: {}", err);
+                        break;
+                    },
+                },
+                (None, Some(ra)) => ra,
+                (None, None) => {
+                    println!("can't unwind further: the function at ${:016X} doesn't save ra", pc);
+                    break;
+                },
+            };
+
+            // the caller continues after the call and its delay slot, so show the call itself
+            if return_address < 8 || (return_address & 0x03) != 0 {
+                break;
+            }
+
+            let caller_pc = return_address - 8;
+            let caller_sp = sp.wrapping_add(frame_size);
+            if caller_pc == pc && caller_sp == sp {
+                break;
+            }
+
+            pc = caller_pc;
+            sp = caller_sp;
+            ra = None;
+        }
+
+        Ok(())
+    }
+
+    // the frame size and where ra is saved for the function containing pc, as far as it has run
+    fn find_frame(&mut self, pc: u64) -> Result<(u64, Option<u64>), String> {
+        let start = match self.symbols.function_start(pc) {
+            Some(start) => start,
+            None => self.find_function_start(pc)?,
+        };
+
+        let mut frame_size = 0;
+        let mut ra_offset = None;
+        let mut address = start;
+        while address < pc {
+            let inst = self.read_word(MemoryAddress::Virtual(address))?;
+            let imm = (inst as i16) as u64;
+            match inst >> 16 {
+                // addiu/daddiu sp, sp, -N
+                0x27BD | 0x67BD if (inst as i16) < 0 => frame_size = imm.wrapping_neg(),
+
+                // sw ra, X(sp)
+                0xAFBF => ra_offset = Some(imm),
+
+                // sd ra, X(sp) keeps the low word at X+4
+                0xFFBF => ra_offset = Some(imm.wrapping_add(4)),
+
+                _ => {},
+            }
+            address += 4;
+        }
+
+        Ok((frame_size, ra_offset))
+    }
+
+    // look back for the instruction allocating the stack frame, or the end of the previous function
+    fn find_function_start(&mut self, pc: u64) -> Result<u64, String> {
+        let mut address = pc;
+        while pc - address < MAX_FUNCTION_SCAN {
+            address = match address.checked_sub(4) {
+                Some(address) => address,
+                None => break,
+            };
+            let inst = self.read_word(MemoryAddress::Virtual(address))?;
+
+            if ((inst >> 16) == 0x27BD || (inst >> 16) == 0x67BD) && (inst as i16) < 0 {
+                return Ok(address);
+            }
+
+            // the previous function ends after the delay slot
+            if inst == INST_JR_RA {
+                return Ok(std::cmp::min(address + 8, pc));
+            }
+        }
+
+        Err(format!("couldn't find the start of the function at ${:016X}", pc))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::tests::debugger;
+
+    fn frames(debugger: &Debugger) -> Vec<(u64, u64, u64)> {
+        debugger.system.cpu.call_stack().unwrap().iter().map(|frame| (frame.call_pc, frame.target, frame.return_address)).collect()
+    }
+
+    #[test]
+    fn shadow_stack_branch_and_link() {
+        let mut debugger = debugger();
+
+        // lui t0, $8000; bltzal t0, $80001100; nop; bltzall zero, $8000104C; nop; nop
+        // with a function at $80001100 that returns straight away
+        let code = [(0x1000, 0x3C08_8000u32), (0x1004, 0x0510_003E), (0x1008, 0), (0x100C, 0x0412_000F), (0x1010, 0), (0x1014, 0),
+                    (0x1100, 0), (0x1104, INST_JR_RA), (0x1108, 0)];
+        for (address, inst) in code {
+            debugger.write_memory(MemoryAddress::Physical(address), inst as u64, 4).unwrap();
+        }
+        debugger.system.cpu.set_pc(0xFFFF_FFFF_8000_1000).unwrap();
+        debugger.backtrace_command(&vec!["bt", "shadow", "on"]).unwrap();
+
+        debugger.step(&vec!["step", "3"]).unwrap();
+        assert_eq!(*debugger.system.cpu.next_instruction_pc(), 0xFFFF_FFFF_8000_1100);
+        assert_eq!(frames(&debugger), vec![(0xFFFF_FFFF_8000_1004, 0xFFFF_FFFF_8000_1100, 0xFFFF_FFFF_8000_100C)]);
+
+        debugger.step(&vec!["step", "3"]).unwrap();
+        assert_eq!(*debugger.system.cpu.next_instruction_pc(), 0xFFFF_FFFF_8000_100C);
+        assert_eq!(frames(&debugger), vec![]);
+
+        // not taken, so the delay slot is skipped and nothing was called, but ra is still set
+        debugger.step(&vec!["step", "1"]).unwrap();
+        assert_eq!(*debugger.system.cpu.next_instruction_pc(), 0xFFFF_FFFF_8000_1014);
+        assert_eq!(debugger.system.cpu.regs()[31], 0xFFFF_FFFF_8000_1014);
+        assert_eq!(frames(&debugger), vec![]);
+    }
+
+    #[test]
+    fn function_start_scan() {
+        let mut debugger = debugger();
+
+        // addiu sp, sp, -$18 at $80001000, and a jr ra ending the function before $80002000
+        debugger.write_memory(MemoryAddress::Physical(0x1000), 0x27BD_FFE8, 4).unwrap();
+        debugger.write_memory(MemoryAddress::Physical(0x1F00), INST_JR_RA as u64, 4).unwrap();
+        assert_eq!(debugger.find_function_start(0xFFFF_FFFF_8000_1040), Ok(0xFFFF_FFFF_8000_1000));
+        assert_eq!(debugger.find_function_start(0xFFFF_FFFF_8000_2000), Ok(0xFFFF_FFFF_8000_1F08));
+
+        // nothing before the start of memory
+        assert!(debugger.find_function_start(0).is_err());
+    }
+}
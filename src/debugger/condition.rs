@@ -0,0 +1,307 @@
+// Breakpoint conditions, like `a0 == $80001234 && [sp+$10] != 0`. Comparisons can be joined
+// with && and ||, where && binds tighter. An operand is a register (ABI or rN name, pc, hi or
+// lo), a number, a symbol, or the word at a virtual address in brackets. All values are 64-bit,
+// and numbers and memory words are sign extended from 32 bits the way lw would. Numbers can be
+// negative (`v0 == -1`), and <, <=, > and >= compare signed values like slt, so `a0 < 0` works.
+// Addresses in kseg0 and kseg1 are negative too, but they still order among themselves.
+use std::fmt;
+
+use crate::cpu;
+
+use super::parse_u64;
+use super::symbols::Symbols;
+
+// register values a condition sees. Bus breakpoints are checked in the middle of an
+// instruction, so the debugger captures these before each step when they're needed
+#[derive(Copy, Clone, Default)]
+pub(super) struct RegisterState {
+    pub gpr: [u64; 32],
+    pub pc : u64,
+    pub lo : u64,
+    pub hi : u64,
+}
+
+#[derive(Clone)]
+enum Operand {
+    Register(usize),
+    Pc,
+    Lo,
+    Hi,
+    Constant(u64),
+    Memory(Box<Operand>, u64),
+}
+
+#[derive(Copy, Clone)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Clone)]
+struct Term {
+    lhs       : Operand,
+    comparison: Comparison,
+    rhs       : Operand,
+}
+
+#[derive(Clone)]
+pub(super) struct Condition {
+    text: String,
+
+    // true when all terms of any one group are true
+    groups: Vec<Vec<Term>>,
+}
+
+impl Condition {
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<Condition, String> {
+        let text = text.trim();
+        if text.len() == 0 {
+            return Err(format!("empty condition"));
+        }
+
+        let mut groups = Vec::new();
+        for group in text.split("||") {
+            let terms = group.split("&&")
+                             .map(|term| Condition::parse_term(term.trim(), symbols))
+                             .collect::<Result<Vec<Term>, String>>()?;
+            groups.push(terms);
+        }
+
+        Ok(Condition {
+            text  : text.to_string(),
+            groups: groups,
+        })
+    }
+
+    // a term without a comparison is true when it isn't zero
+    fn parse_term(s: &str, symbols: &Symbols) -> Result<Term, String> {
+        const OPERATORS: [(&str, Comparison); 6] = [
+            ("==", Comparison::Equal), ("!=", Comparison::NotEqual), ("<=", Comparison::LessEqual),
+            (">=", Comparison::GreaterEqual), ("<", Comparison::Less), (">", Comparison::Greater),
+        ];
+
+        for (operator, comparison) in OPERATORS.iter() {
+            if let Some(index) = s.find(operator) {
+                return Ok(Term {
+                    lhs       : Condition::parse_operand(&s[..index], symbols)?,
+                    comparison: *comparison,
+                    rhs       : Condition::parse_operand(&s[index+operator.len()..], symbols)?,
+                });
+            }
+        }
+
+        Ok(Term {
+            lhs       : Condition::parse_operand(s, symbols)?,
+            comparison: Comparison::NotEqual,
+            rhs       : Operand::Constant(0),
+        })
+    }
+
+    fn parse_operand(s: &str, symbols: &Symbols) -> Result<Operand, String> {
+        let s = s.trim();
+        if s.len() == 0 {
+            return Err(format!("missing operand in condition"));
+        }
+
+        if let Some(inner) = s.strip_prefix("[") {
+            let inner = match inner.strip_suffix("]") {
+                Some(inner) => inner,
+                None => return Err(format!("missing ] in \"{}\"", s)),
+            };
+
+            // [base], [base+offset] or [base-offset], where base can itself be in brackets
+            let base_end = inner.rfind(']').map_or(0, |index| index + 1);
+            return Ok(if let Some(index) = inner[base_end..].rfind(|c| c == '+' || c == '-').map(|index| index + base_end) {
+                let offset = parse_u64(inner[index+1..].trim())?;
+                let offset = if &inner[index..index+1] == "-" { offset.wrapping_neg() } else { offset };
+                Operand::Memory(Box::new(Condition::parse_operand(&inner[..index], symbols)?), offset)
+            } else {
+                Operand::Memory(Box::new(Condition::parse_operand(inner, symbols)?), 0)
+            });
+        }
+
+        let register = s.to_lowercase();
+        match register.as_str() {
+            "pc" => return Ok(Operand::Pc),
+            "lo" => return Ok(Operand::Lo),
+            "hi" => return Ok(Operand::Hi),
+            _ => {},
+        }
+
+        for i in 0..32 {
+            if register == cpu::Cpu::abi_name(i) || register == cpu::Cpu::register_name(i) {
+                return Ok(Operand::Register(i));
+            }
+        }
+
+        // negative numbers are negated as written, so -$80000000 is the sign extended $80000000
+        if let Some(Ok(v)) = s.strip_prefix("-").map(|v| parse_u64(v.trim())) {
+            return Ok(Operand::Constant(v.wrapping_neg()));
+        }
+
+        match parse_u64(s) {
+            Ok(v) => Ok(Operand::Constant(if v < 0x1_0000_0000 { (v as i32) as u64 } else { v })),
+            Err(_) => match symbols.resolve(s) {
+                Some(address) => Ok(Operand::Constant(address)),
+                None => Err(format!("unknown register, value or symbol \"{}\" in condition", s)),
+            },
+        }
+    }
+
+    // read_word reads the word at a virtual address, if it can be read
+    pub fn evaluate(&self, registers: &RegisterState, read_word: &mut dyn FnMut(u64) -> Option<u32>) -> Result<bool, String> {
+        for group in self.groups.iter() {
+            let mut result = true;
+            for term in group.iter() {
+                let lhs = Condition::operand_value(&term.lhs, registers, read_word)?;
+                let rhs = Condition::operand_value(&term.rhs, registers, read_word)?;
+                result = match term.comparison {
+                    Comparison::Equal        => lhs == rhs,
+                    Comparison::NotEqual     => lhs != rhs,
+                    Comparison::Less         => (lhs as i64) < (rhs as i64),
+                    Comparison::LessEqual    => (lhs as i64) <= (rhs as i64),
+                    Comparison::Greater      => (lhs as i64) > (rhs as i64),
+                    Comparison::GreaterEqual => (lhs as i64) >= (rhs as i64),
+                };
+                if !result { break; }
+            }
+
+            if result { return Ok(true); }
+        }
+
+        Ok(false)
+    }
+
+    fn operand_value(operand: &Operand, registers: &RegisterState, read_word: &mut dyn FnMut(u64) -> Option<u32>) -> Result<u64, String> {
+        Ok(match operand {
+            Operand::Register(i) => registers.gpr[*i],
+            Operand::Pc          => registers.pc,
+            Operand::Lo          => registers.lo,
+            Operand::Hi          => registers.hi,
+            Operand::Constant(v) => *v,
+            Operand::Memory(base, offset) => {
+                let address = Condition::operand_value(base, registers, read_word)?.wrapping_add(*offset);
+                match read_word(address & !0x03) {
+                    Some(word) => (word as i32) as u64,
+                    None => return Err(format!("can't read ${:016X}", address)),
+                }
+            },
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registers() -> RegisterState {
+        let mut registers = RegisterState::default();
+        registers.gpr[4] = 0xFFFF_FFFF_8000_1234; // a0
+        registers.gpr[29] = 0xFFFF_FFFF_801F_FF00; // sp
+        registers.pc = 0xFFFF_FFFF_8000_0400;
+        registers.hi = 5;
+        registers.lo = 4;
+        registers
+    }
+
+    fn evaluate(text: &str, symbols: &Symbols) -> Result<bool, String> {
+        Condition::parse(text, symbols)?.evaluate(&registers(), &mut |address| match address {
+            0xFFFF_FFFF_801F_FF10 => Some(0x8000_0000),
+            0xFFFF_FFFF_8000_0000 => Some(7),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn comparisons() {
+        let symbols = Symbols::new();
+        let cases = [
+            ("a0 == $80001234", true), ("A0 == $80001234", true), ("r4 != $80001234", false),
+            ("a0 == $FFFFFFFF80001234", true), ("a0 == 2147488308", true), ("pc == $80000400", true),
+            ("a0", true), ("t0", false), ("lo < 4", false), ("lo <= 4", true), ("lo > 3", true), ("lo >= 5", false),
+            ("hi==5", true), ("hi != lo", true), ("0 > a0", true),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(evaluate(text, &symbols), Ok(expected), "{}", text);
+        }
+    }
+
+    #[test]
+    fn signed_values() {
+        let symbols = Symbols::new();
+        let cases = [
+            ("a0 < 0", true), ("a0 >= 0", false), ("lo > -1", true), ("lo < -1", false), ("-4 < lo", true),
+            ("a0 < $80001235", true), ("a0 > $80001233", true), ("pc >= $80000400 && pc < $80000500", true),
+            ("a0 == -$7FFFEDCC", true), ("a0 == -2147478988", true), ("[sp+$10] == -$80000000", true),
+            ("[sp+$10] <= -1", true), ("lo == - 4", false),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(evaluate(text, &symbols), Ok(expected), "{}", text);
+        }
+
+        let mut registers = registers();
+        registers.gpr[2] = u64::MAX; // v0
+        let condition = Condition::parse("v0 == -1 && v0 < 0 && v0 == $FFFFFFFF && v0 == $FFFFFFFFFFFFFFFF", &symbols).unwrap();
+        assert_eq!(condition.evaluate(&registers, &mut |_| None), Ok(true));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let symbols = Symbols::new();
+        assert_eq!(evaluate("a0 == 1 || pc == $80000400", &symbols), Ok(true));
+        assert_eq!(evaluate("a0 == 1 || pc == 2", &symbols), Ok(false));
+        assert_eq!(evaluate("a0 != 0 && hi == 3 || lo == 4", &symbols), Ok(true));
+        assert_eq!(evaluate("lo == 4 || a0 != 0 && hi == 3", &symbols), Ok(true));
+        assert_eq!(evaluate("a0 != 0 && hi == 3 || lo == 0", &symbols), Ok(false));
+        assert_eq!(evaluate("a0 != 0 && hi == 5 && lo == 4", &symbols), Ok(true));
+    }
+
+    #[test]
+    fn memory_operands() {
+        let symbols = Symbols::new();
+
+        // words are sign extended, and read from the word the address is in
+        assert_eq!(evaluate("[sp+$10] == $80000000", &symbols), Ok(true));
+        assert_eq!(evaluate("[sp+$12] == $80000000", &symbols), Ok(true));
+        assert_eq!(evaluate("[a0-$1234] == 7", &symbols), Ok(true));
+        assert_eq!(evaluate("[[sp+$10]] == 7", &symbols), Ok(true));
+        assert_eq!(evaluate("[$80000000]", &symbols), Ok(true));
+        assert!(evaluate("[sp+$20] == 0", &symbols).is_err_and(|err| err.contains("FFFFFFFF801FFF20")));
+
+        // an unreadable word only matters if that term is reached
+        assert_eq!(evaluate("a0 == 1 && [sp+$20] == 0", &symbols), Ok(false));
+    }
+
+    #[test]
+    fn symbols() {
+        let path = std::env::temp_dir().join(format!("n64-condition-{}.txt", std::process::id()));
+        std::fs::write(&path, "gFoo = 0x80001230;\n").unwrap();
+        let mut symbols = Symbols::new();
+        symbols.load(path.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_file(path);
+
+        assert_eq!(evaluate("a0 == gFoo", &symbols), Ok(false));
+        assert_eq!(evaluate("a0 == gFoo+4", &symbols), Ok(true));
+        assert_eq!(evaluate("[gFoo-$1230] == 7", &symbols), Ok(true));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let symbols = Symbols::new();
+        for text in ["", "  ", "a0 ==", "== 1", "[sp", "[sp+] == 0", "bogus == 1", "a0 == $zz", "a0 == 1 ||", "&& a0", "-lo == 4", "a0 == -"] {
+            assert!(Condition::parse(text, &symbols).is_err(), "{}", text);
+        }
+
+        assert_eq!(Condition::parse("  a0 == 1 ", &symbols).unwrap().to_string(), "a0 == 1");
+    }
+}
@@ -0,0 +1,131 @@
+// COP0, FPU and TLB inspection, and virtual address translation
+use super::*;
+
+const COP0_NAMES: [&str; 32] = [
+    "Index"  , "Random"  , "EntryLo0", "EntryLo1", "Context", "PageMask", "Wired"   , "7"       ,
+    "BadVAddr", "Count"  , "EntryHi" , "Compare" , "Status" , "Cause"   , "EPC"     , "PRId"    ,
+    "Config" , "LLAddr"  , "WatchLo" , "WatchHi" , "XContext", "21"     , "22"      , "23"      ,
+    "24"     , "25"      , "PErr"    , "CacheErr", "TagLo"  , "TagHi"   , "ErrorEPC", "31"      ,
+];
+
+const COP0_ERROREPC: usize = 30;
+
+const EXCEPTION_NAMES: [&str; 32] = [
+    "Int (interrupt)", "Mod (TLB modification)", "TLBL (TLB miss on load or fetch)", "TLBS (TLB miss on store)",
+    "AdEL (address error on load or fetch)", "AdES (address error on store)", "IBE (bus error on fetch)", "DBE (bus error on data)",
+    "Sys (syscall)", "Bp (breakpoint)", "RI (reserved instruction)", "CpU (coprocessor unusable)",
+    "Ov (overflow)", "Tr (trap)", "14", "FPE (floating point)",
+    "16", "17", "18", "19", "20", "21", "22", "WATCH (watch)",
+    "24", "25", "26", "27", "28", "29", "30", "31",
+];
+
+impl Debugger {
+    pub(super) fn dump_cop0(&mut self, _: &Vec<&str>) -> Result<(), String> {
+        let cpu = &self.system.cpu;
+
+        for k in 0..8 {
+            for j in 0..4 {
+                print!("{:>8}: ${:016X} ", COP0_NAMES[k*4+j], cpu.cp0_register(k*4+j));
+            }
+            println!("");
+        }
+        println!("");
+
+        let status = cpu.cp0_register(cpu::Cop0_Status);
+        let flags = [(27, "RP"), (26, "FR"), (25, "RE"), (24, "ITS"), (22, "BEV"), (21, "TS"), (20, "SR"), (18, "CH"),
+                     (17, "CE"), (16, "DE"), (7, "KX"), (6, "SX"), (5, "UX"), (2, "ERL"), (1, "EXL"), (0, "IE")];
+        let mode = match (status >> 3) & 0x03 {
+            0 => "kernel",
+            1 => "supervisor",
+            2 => "user",
+            _ => "reserved",
+        };
+        println!("  Status: ${:08X} CU={:04b} IM={:08b} KSU={} {}", status, (status >> 28) & 0x0F, (status >> 8) & 0xFF, mode,
+                 flags.iter().filter(|(bit, _)| ((status >> bit) & 0x01) != 0).map(|(_, name)| *name).collect::<Vec<&str>>().join(" "));
+
+        let cause = cpu.cp0_register(cpu::Cop0_Cause);
+        println!("   Cause: ${:08X} ExcCode={} IP={:08b} CE={}{}", cause, EXCEPTION_NAMES[((cause >> 2) & 0x1F) as usize],
+                 (cause >> 8) & 0xFF, (cause >> 28) & 0x03, if (cause & 0x8000_0000) != 0 { " BD" } else { "" });
+
+        for (name, index) in [("EPC", cpu::Cop0_EPC), ("ErrorEPC", COP0_ERROREPC), ("BadVAddr", cpu::Cop0_BadVAddr)] {
+            let value = cpu.cp0_register(index);
+            println!("{:>8}: {}{}", name, MemoryAddress::Virtual(value), self.symbols.describe(value));
+        }
+
+        Ok(())
+    }
+
+    // single and double views of each FPU register, and FCR31. With FR clear, doubles only live
+    // in the even registers
+    pub(super) fn dump_fpu(&mut self, _: &Vec<&str>) -> Result<(), String> {
+        let cop1 = self.system.cpu.cop1();
+
+        for i in 0..32 {
+            let word = cop1.fpr_u32(i);
+            print!("f{:<2}: {:08X} {:<16?}", i, word, f32::from_bits(word));
+            if cop1.fr() || (i & 0x01) == 0 {
+                let double = cop1.fpr_u64(i);
+                print!(" {:016X} {:?}", double, f64::from_bits(double));
+            }
+            println!("");
+        }
+
+        let fcr31 = cop1.control_status();
+        let rounding = match fcr31 & 0x03 {
+            0 => "nearest",
+            1 => "zero",
+            2 => "up",
+            _ => "down",
+        };
+        let bits = |value: u64, names: &str| -> String {
+            names.chars().enumerate().filter(|(i, _)| ((value >> i) & 0x01) != 0).map(|(_, c)| c).collect::<String>()
+        };
+        println!("FCR31: ${:08X} RM={} C={} FS={} cause={} enables={} flags={} FR={}", fcr31, rounding, (fcr31 >> 23) & 0x01,
+                 (fcr31 >> 24) & 0x01, bits(fcr31 >> 12, "IUOZVE"), bits(fcr31 >> 7, "IUOZV"), bits(fcr31 >> 2, "IUOZV"), cop1.fr() as u8);
+
+        Ok(())
+    }
+
+    pub(super) fn dump_tlb(&mut self, _: &Vec<&str>) -> Result<(), String> {
+        let wired = self.system.cpu.cp0_register(cpu::Cop0_Wired) & 0x3F;
+        let asid = self.system.cpu.cp0_register(cpu::Cop0_EntryHi) & 0xFF;
+        println!("Wired: {} current ASID: ${:02X}", wired, asid);
+
+        // EntryLo as PFN, C and D/V flags
+        let entry_lo = |entry_lo: u64| -> String {
+            format!("PFN=${:05X} C={} {}{}", (entry_lo >> 6) & 0xF_FFFF, (entry_lo >> 3) & 0x07,
+                    if (entry_lo & 0x04) != 0 { "D" } else { "-" }, if (entry_lo & 0x02) != 0 { "V" } else { "-" })
+        };
+
+        for i in 0..32 {
+            let tlb = self.system.cpu.tlb_entry(i);
+            let page_size = ((tlb.page_mask >> 1) | 0xFFF) + 1;
+            let vpn2 = tlb.entry_hi & 0xFF_FFFF_E000 & !tlb.page_mask;
+            println!("{:2}: mask=${:07X} ({:>5}K) R={} VPN2=${:010X} ASID=${:02X} {} even: {} odd: {}", i, tlb.page_mask, page_size / 1024,
+                     tlb.entry_hi >> 62, vpn2, tlb.entry_hi & 0xFF, if (tlb.entry_hi & 0x1000) != 0 { "G" } else { "-" },
+                     entry_lo(tlb.entry_lo0), entry_lo(tlb.entry_lo1));
+        }
+
+        Ok(())
+    }
+
+    // translate virtual_address
+    pub(super) fn translate(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        if parts.len() != 2 {
+            return Err(format!("usage: translate virtual_address"));
+        }
+
+        let virtual_address = parse_breakpoint_address(parts[1], &self.symbols)?;
+        let translation = self.system.cpu.debug_translation(virtual_address)
+                                         .map_err(|err| format!("{} {}", MemoryAddress::Virtual(virtual_address), err))?;
+
+        let how = match translation.tlb_index {
+            Some(index) => format!("mapped by TLB entry {}", index),
+            None => format!("unmapped"),
+        };
+        println!("{} -> {} ({}, {}, {})", MemoryAddress::Virtual(virtual_address), MemoryAddress::Physical(translation.physical_address),
+                 translation.space, how, if translation.cached { "cached" } else { "uncached" });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,711 @@
+// GDB remote serial protocol stub. Point gdb-multiarch at it with `target remote :PORT`.
+// Execution breakpoints go in the same table as the `break` command, and watchpoints become
+// range breakpoints on their physical addresses, caught by the DebuggerWatch
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+
+use super::*;
+
+// number of instructions run between checks for a Ctrl-C from gdb
+const INTERRUPT_POLL_STEPS: u64 = 4096;
+
+// register numbers gdb uses for MIPS
+const GDB_REG_STATUS  : usize = 32;
+const GDB_REG_LO      : usize = 33;
+const GDB_REG_HI      : usize = 34;
+const GDB_REG_BADVADDR: usize = 35;
+const GDB_REG_CAUSE   : usize = 36;
+const GDB_REG_PC      : usize = 37;
+const GDB_REG_F0      : usize = 38;
+const GDB_REG_FCSR    : usize = 70;
+const GDB_REG_FIR     : usize = 71;
+const GDB_REG_COUNT   : usize = 72;
+
+struct GdbConnection {
+    stream: TcpStream,
+    last_packet: Vec<u8>,
+}
+
+impl GdbConnection {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8; 1];
+        self.stream.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    // Read the next packet, acking it. Returns None for a Ctrl-C sent outside of a packet
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                b'$' => {},
+                0x03 => return Ok(None),
+                b'-' => {
+                    let packet = self.last_packet.clone();
+                    self.stream.write_all(&packet)?;
+                    continue;
+                },
+                _ => continue, // acks and noise between packets
+            }
+
+            // the checksum covers the packet as sent, before '}' escapes are undone
+            let mut data = Vec::new();
+            let mut sum = 0u8;
+            let mut escaped = false;
+            loop {
+                let byte = self.read_byte()?;
+                if byte == b'#' && !escaped { break; }
+                sum = sum.wrapping_add(byte);
+
+                if escaped {
+                    data.push(byte ^ 0x20);
+                    escaped = false;
+                } else if byte == b'}' {
+                    escaped = true;
+                } else {
+                    data.push(byte);
+                }
+            }
+
+            let checksum_str = [self.read_byte()?, self.read_byte()?];
+            let checksum = u8::from_str_radix(std::str::from_utf8(&checksum_str).unwrap_or(""), 16).ok();
+            if checksum != Some(sum) {
+                self.stream.write_all(b"-")?;
+                continue;
+            }
+
+            self.stream.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    // '$', '#', '}' and '*' can't appear in a packet, so they're sent as '}' and the byte xor 0x20
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let mut escaped = Vec::with_capacity(data.len());
+        for byte in data.bytes() {
+            if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+                escaped.extend_from_slice(&[b'}', byte ^ 0x20]);
+            } else {
+                escaped.push(byte);
+            }
+        }
+
+        let checksum = escaped.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        self.last_packet = [&[b'$'][..], &escaped, format!("#{:02x}", checksum).as_bytes()].concat();
+        let packet = self.last_packet.clone();
+        self.stream.write_all(&packet)
+    }
+
+    // check for a Ctrl-C without blocking
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0u8; 1];
+        let result = match self.stream.read(&mut byte) {
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "gdb disconnected")),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+}
+
+// why execution stopped
+enum StopReason {
+    Signal(u8),
+    Watch(&'static str, u64),
+}
+
+impl StopReason {
+    fn packet(&self) -> String {
+        match self {
+            StopReason::Signal(signal) => format!("S{:02x}", signal),
+            StopReason::Watch(kind, address) => format!("T05{}:{:x};", kind, address),
+        }
+    }
+}
+
+const SIGINT : u8 = 2;
+const SIGILL : u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// a watchpoint as gdb set it, and the range breakpoint placed on its physical address
+struct Watchpoint {
+    virtual_address: u64,
+    physical_address: u64,
+    length: u64,
+    mode: u8,
+    breakpoint_id: u64,
+}
+
+impl Debugger {
+    // Serve one gdb connection on localhost:port, until gdb detaches or kills the target
+    pub fn run_gdb_server(&mut self, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for gdb on localhost:{}...", port);
+
+        let (stream, peer) = listener.accept()?;
+        println!("gdb connected from {}", peer);
+        stream.set_nodelay(true)?;
+
+        let mut connection = GdbConnection { stream: stream, last_packet: Vec::new() };
+        let mut watchpoints: Vec<Watchpoint> = Vec::new();
+
+        // breakpoints and single steps need to stop on every instruction
+        #[cfg(feature="jit")]
+        self.system.cpu.pause_jit(true);
+
+        let result = self.gdb_serve(&mut connection, &mut watchpoints);
+
+        #[cfg(feature="jit")]
+        self.system.cpu.pause_jit(false);
+
+        // leave nothing behind for the prompt
+        for watchpoint in watchpoints.iter() {
+            let _ = self.breakpoints.borrow_mut().delete_breakpoint(watchpoint.breakpoint_id);
+        }
+
+        println!("gdb disconnected");
+        match result {
+            // gdb going away without detaching isn't an error
+            Err(err) if matches!(err.kind(), io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::UnexpectedEof) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn gdb_serve(&mut self, connection: &mut GdbConnection, watchpoints: &mut Vec<Watchpoint>) -> io::Result<()> {
+        let mut stop_reason = StopReason::Signal(SIGTRAP);
+
+        loop {
+            // a Ctrl-C while already stopped has nothing to interrupt
+            let packet = match connection.read_packet()? {
+                Some(packet) => packet,
+                None => continue,
+            };
+
+            let reply = match packet.chars().next().unwrap_or(' ') {
+                '?' => stop_reason.packet(),
+                'g' => self.gdb_read_registers(),
+                'G' => self.gdb_write_registers(&packet[1..]),
+                'p' => self.gdb_read_register(&packet[1..]),
+                'P' => self.gdb_write_register(&packet[1..]),
+                'm' => self.gdb_read_memory(&packet[1..]),
+                'M' => self.gdb_write_memory(&packet[1..]),
+                'H' => "OK".to_string(),
+                'T' => "OK".to_string(),
+                'Z' => self.gdb_insert_breakpoint(&packet[1..], watchpoints),
+                'z' => self.gdb_remove_breakpoint(&packet[1..], watchpoints),
+
+                'c' | 's' => {
+                    // resume somewhere else only if the address is good
+                    let address = &packet[1..];
+                    let pc_ok = address.is_empty() || u64::from_str_radix(address, 16).ok().map_or(false, |address| {
+                        self.system.cpu.set_pc(sign_extend_address(address)).is_ok()
+                    });
+
+                    if !pc_ok {
+                        "E01".to_string()
+                    } else {
+                        stop_reason = self.gdb_resume(connection, watchpoints, packet.starts_with('s'))?;
+                        stop_reason.packet()
+                    }
+                },
+
+                'q' => self.gdb_query(&packet[1..]),
+
+                'D' => {
+                    connection.send_packet("OK")?;
+                    break;
+                },
+
+                'k' => break,
+
+                _ => String::new(), // unsupported
+            };
+
+            connection.send_packet(&reply)?;
+        }
+
+        Ok(())
+    }
+
+    fn gdb_query(&mut self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            "PacketSize=4000;qXfer:features:read+".to_string()
+        } else if let Some(annex) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let xml = target_xml();
+            let (offset, length) = match parse_pair(annex) {
+                Some(v) => v,
+                None => return "E01".to_string(),
+            };
+
+            let start = std::cmp::min(offset as usize, xml.len());
+            let end = std::cmp::min(start.saturating_add(length as usize), xml.len());
+            format!("{}{}", if end < xml.len() { "m" } else { "l" }, &xml[start..end])
+        } else if query == "Attached" {
+            "1".to_string()
+        } else if query == "C" {
+            "QC1".to_string()
+        } else if query == "fThreadInfo" {
+            "m1".to_string()
+        } else if query == "sThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    fn gdb_register(&self, index: usize) -> Option<u64> {
+        let cpu = &self.system.cpu;
+        Some(match index {
+            0..=31           => cpu.regs()[index],
+            GDB_REG_STATUS   => cpu.cp0_register(cpu::Cop0_Status),
+            GDB_REG_LO       => cpu.lo(),
+            GDB_REG_HI       => cpu.hi(),
+            GDB_REG_BADVADDR => cpu.cp0_register(cpu::Cop0_BadVAddr),
+            GDB_REG_CAUSE    => cpu.cp0_register(cpu::Cop0_Cause),
+            GDB_REG_PC       => *cpu.next_instruction_pc(),
+            GDB_REG_F0..=69  => cpu.cop1().fgr(index - GDB_REG_F0),
+            GDB_REG_FCSR     => cpu.cop1().control_status(),
+            GDB_REG_FIR      => cpu.cop1().implementation_revision(),
+            _ => return None,
+        })
+    }
+
+    fn gdb_set_register(&mut self, index: usize, value: u64) -> bool {
+        let cpu = &mut self.system.cpu;
+        match index {
+            0..=31           => cpu.set_reg(index, value),
+            GDB_REG_STATUS   => cpu.set_cp0_register(cpu::Cop0_Status, value),
+            GDB_REG_LO       => cpu.set_lo(value),
+            GDB_REG_HI       => cpu.set_hi(value),
+            GDB_REG_BADVADDR => cpu.set_cp0_register(cpu::Cop0_BadVAddr, value),
+            GDB_REG_CAUSE    => cpu.set_cp0_register(cpu::Cop0_Cause, value),
+            GDB_REG_PC       => if cpu.set_pc(value).is_err() { return false; },
+            GDB_REG_F0..=69  => cpu.cop1_mut().set_fgr(index - GDB_REG_F0, value),
+            GDB_REG_FCSR     => cpu.cop1_mut().set_control_status(value),
+            GDB_REG_FIR      => {}, // read-only
+            _ => return false,
+        }
+        true
+    }
+
+    // registers are sent as 64-bit big endian values, in register number order
+    fn gdb_read_registers(&self) -> String {
+        (0..GDB_REG_COUNT).map(|index| format!("{:016x}", self.gdb_register(index).unwrap())).collect()
+    }
+
+    fn gdb_write_registers(&mut self, data: &str) -> String {
+        for index in 0..GDB_REG_COUNT {
+            let hex = match data.get(index * 16..(index + 1) * 16) {
+                Some(hex) => hex,
+                None => break,
+            };
+
+            // registers gdb doesn't know the value of are sent as x's
+            if let Ok(value) = u64::from_str_radix(hex, 16) {
+                self.gdb_set_register(index, value);
+            }
+        }
+        "OK".to_string()
+    }
+
+    fn gdb_read_register(&self, data: &str) -> String {
+        match usize::from_str_radix(data, 16).ok().and_then(|index| self.gdb_register(index)) {
+            Some(value) => format!("{:016x}", value),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn gdb_write_register(&mut self, data: &str) -> String {
+        let (index, value) = match data.split_once('=') {
+            Some(v) => v,
+            None => return "E01".to_string(),
+        };
+
+        match (usize::from_str_radix(index, 16), u64::from_str_radix(value, 16)) {
+            (Ok(index), Ok(value)) if self.gdb_set_register(index, value) => "OK".to_string(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn gdb_read_memory(&mut self, data: &str) -> String {
+        let (address, length) = match parse_pair(data) {
+            Some(v) => v,
+            None => return "E01".to_string(),
+        };
+
+        let address = MemoryAddress::Virtual(sign_extend_address(address));
+        let mut reply = String::new();
+        for offset in 0..length {
+            match self.read_memory(address.offset(offset), 1) {
+                Ok(byte) => reply.push_str(&format!("{:02x}", byte)),
+                Err(_) => {
+                    // a partial read is fine as long as something was read
+                    if offset == 0 { return "E01".to_string(); }
+                    break;
+                },
+            }
+        }
+        reply
+    }
+
+    fn gdb_write_memory(&mut self, data: &str) -> String {
+        let (range, bytes) = match data.split_once(':') {
+            Some(v) => v,
+            None => return "E01".to_string(),
+        };
+
+        let (address, length) = match parse_pair(range) {
+            Some(v) => v,
+            None => return "E01".to_string(),
+        };
+
+        let address = MemoryAddress::Virtual(sign_extend_address(address));
+        for offset in 0..length {
+            let byte = match bytes.get((offset * 2) as usize..(offset * 2 + 2) as usize).map(|hex| u8::from_str_radix(hex, 16)) {
+                Some(Ok(byte)) => byte,
+                _ => return "E01".to_string(),
+            };
+
+            if self.write_memory(address.offset(offset), byte as u64, 1).is_err() {
+                return "E01".to_string();
+            }
+        }
+        "OK".to_string()
+    }
+
+    // Z0/Z1 are software and hardware breakpoints, which work the same here. Z2, Z3 and Z4 are
+    // write, read and access watchpoints
+    fn gdb_breakpoint_args(data: &str) -> Option<(u8, u64, u64)> {
+        let mut parts = data.split(',');
+        let kind = parts.next()?.parse::<u8>().ok()?;
+        let address = u64::from_str_radix(parts.next()?, 16).ok()?;
+        let length = u64::from_str_radix(parts.next()?.split(';').next()?, 16).ok()?;
+        Some((kind, sign_extend_address(address), length))
+    }
+
+    fn gdb_watch_mode(kind: u8) -> u8 {
+        match kind {
+            2 => BP_WRITE,
+            3 => BP_READ,
+            _ => BP_READ | BP_WRITE,
+        }
+    }
+
+    fn gdb_insert_breakpoint(&mut self, data: &str, watchpoints: &mut Vec<Watchpoint>) -> String {
+        let (kind, address, length) = match Debugger::gdb_breakpoint_args(data) {
+            Some(v) => v,
+            None => return "E01".to_string(),
+        };
+
+        match kind {
+            0 | 1 => self.breakpoints.borrow_mut().set_mode(address, BP_EXEC),
+
+            2..=4 => {
+                // the watch stays on the physical address even if the TLB changes later
+                let physical_address = match self.system.cpu.debug_translate_address(address) {
+                    Some(physical_address) => physical_address,
+                    None => return "E02".to_string(),
+                };
+
+                let length = std::cmp::max(length, 1);
+                let end = match physical_address.checked_add(length - 1) {
+                    Some(end) => end,
+                    None => return "E01".to_string(),
+                };
+
+                let mode = Debugger::gdb_watch_mode(kind);
+                let breakpoint = BreakpointInfo::new(physical_address, end, mode);
+                let breakpoint_id = self.breakpoints.borrow_mut().add_breakpoint(breakpoint);
+                watchpoints.push(Watchpoint {
+                    virtual_address: address,
+                    physical_address: physical_address,
+                    length: length,
+                    mode: mode,
+                    breakpoint_id: breakpoint_id,
+                });
+            },
+
+            _ => return String::new(),
+        }
+
+        "OK".to_string()
+    }
+
+    fn gdb_remove_breakpoint(&mut self, data: &str, watchpoints: &mut Vec<Watchpoint>) -> String {
+        let (kind, address, length) = match Debugger::gdb_breakpoint_args(data) {
+            Some(v) => v,
+            None => return "E01".to_string(),
+        };
+
+        match kind {
+            0 | 1 => self.breakpoints.borrow_mut().clear_mode(address, BP_EXEC),
+
+            2..=4 => {
+                let mode = Debugger::gdb_watch_mode(kind);
+                let length = std::cmp::max(length, 1);
+                if let Some(index) = watchpoints.iter().position(|w| w.virtual_address == address && w.length == length && w.mode == mode) {
+                    let watchpoint = watchpoints.remove(index);
+                    let _ = self.breakpoints.borrow_mut().delete_breakpoint(watchpoint.breakpoint_id);
+                }
+            },
+
+            _ => return String::new(),
+        }
+
+        "OK".to_string()
+    }
+
+    // run until a breakpoint, a watchpoint, a Ctrl-C from gdb or the prompt, or for one instruction
+    fn gdb_resume(&mut self, connection: &mut GdbConnection, watchpoints: &[Watchpoint], single_step: bool) -> io::Result<StopReason> {
+        self.cpu_running.store(true, Ordering::SeqCst);
+        self.breakpoints.borrow_mut().resume();
+
+        let mut steps = 0u64;
+        loop {
+            match self.step_system() {
+                Ok(_) => {},
+
+                Err(cpu::InstructionFault::ReadWrite(ReadWriteFault::Break)) => {
+                    let last_hit = self.breakpoints.borrow().last_hit;
+                    if let Some((physical_address, mode, breakpoint_id)) = last_hit {
+                        // report the address gdb asked to watch, not the physical one
+                        let watch = watchpoints.iter().find(|w| w.breakpoint_id == breakpoint_id);
+                        if let Some(watch) = watch {
+                            let kind = match (watch.mode, mode) {
+                                (BP_WRITE, _) => "watch",
+                                (BP_READ, _)  => "rwatch",
+                                _             => "awatch",
+                            };
+                            let offset = physical_address.saturating_sub(watch.physical_address);
+                            return Ok(StopReason::Watch(kind, watch.virtual_address + offset));
+                        }
+                    }
+                    return Ok(StopReason::Signal(SIGTRAP));
+                },
+
+                Err(_) => return Ok(StopReason::Signal(SIGILL)),
+            }
+
+            if single_step {
+                return Ok(StopReason::Signal(SIGTRAP));
+            }
+
+            if self.check_exec_breakpoint().is_some() {
+                return Ok(StopReason::Signal(SIGTRAP));
+            }
+
+            steps += 1;
+            if (steps % INTERRUPT_POLL_STEPS) == 0 {
+                if connection.interrupted()? || !self.cpu_running.load(Ordering::SeqCst) {
+                    return Ok(StopReason::Signal(SIGINT));
+                }
+            }
+        }
+    }
+}
+
+// gdb sends 32-bit addresses for 32-bit programs
+fn sign_extend_address(address: u64) -> u64 {
+    if address < 0x1_0000_0000 { (address as i32) as u64 } else { address }
+}
+
+// "addr,length" in hex
+fn parse_pair(s: &str) -> Option<(u64, u64)> {
+    let (first, second) = s.split_once(',')?;
+    Some((u64::from_str_radix(first, 16).ok()?, u64::from_str_radix(second, 16).ok()?))
+}
+
+// Describes the registers in the 'g' packet. All registers are 64 bits, and the numbering has
+// to follow gdb's MIPS layout
+fn target_xml() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target version=\"1.0\"><architecture>mips:4300</architecture>");
+
+    xml.push_str("<feature name=\"org.gnu.gdb.mips.cpu\">");
+    for i in 0..32 {
+        xml.push_str(&format!("<reg name=\"r{}\" bitsize=\"64\" regnum=\"{}\"/>", i, i));
+    }
+    xml.push_str(&format!("<reg name=\"lo\" bitsize=\"64\" regnum=\"{}\"/>", GDB_REG_LO));
+    xml.push_str(&format!("<reg name=\"hi\" bitsize=\"64\" regnum=\"{}\"/>", GDB_REG_HI));
+    xml.push_str(&format!("<reg name=\"pc\" bitsize=\"64\" regnum=\"{}\"/>", GDB_REG_PC));
+    xml.push_str("</feature>");
+
+    xml.push_str("<feature name=\"org.gnu.gdb.mips.cp0\">");
+    xml.push_str(&format!("<reg name=\"status\" bitsize=\"64\" regnum=\"{}\"/>", GDB_REG_STATUS));
+    xml.push_str(&format!("<reg name=\"badvaddr\" bitsize=\"64\" regnum=\"{}\"/>", GDB_REG_BADVADDR));
+    xml.push_str(&format!("<reg name=\"cause\" bitsize=\"64\" regnum=\"{}\"/>", GDB_REG_CAUSE));
+    xml.push_str("</feature>");
+
+    xml.push_str("<feature name=\"org.gnu.gdb.mips.fpu\">");
+    for i in 0..32 {
+        xml.push_str(&format!("<reg name=\"f{}\" bitsize=\"64\" type=\"ieee_double\" regnum=\"{}\"/>", i, GDB_REG_F0 + i));
+    }
+    xml.push_str(&format!("<reg name=\"fcsr\" bitsize=\"64\" group=\"float\" regnum=\"{}\"/>", GDB_REG_FCSR));
+    xml.push_str(&format!("<reg name=\"fir\" bitsize=\"64\" group=\"float\" regnum=\"{}\"/>", GDB_REG_FIR));
+    xml.push_str("</feature></target>");
+
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::tests::debugger;
+
+    // the stub's end of a loopback connection, and gdb's end
+    fn connection() -> (GdbConnection, TcpStream) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let gdb = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        gdb.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        (GdbConnection { stream: stream, last_packet: Vec::new() }, gdb)
+    }
+
+    fn frame(data: &[u8]) -> Vec<u8> {
+        let checksum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        [&[b'$'][..], data, format!("#{:02x}", checksum).as_bytes()].concat()
+    }
+
+    fn receive(gdb: &mut TcpStream, length: usize) -> Vec<u8> {
+        let mut data = vec![0u8; length];
+        gdb.read_exact(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn packet_checksums() {
+        let (mut connection, mut gdb) = connection();
+
+        // a bad checksum is nacked and dropped, the retransmission is acked
+        gdb.write_all(b"+$m80000000,4#00").unwrap();
+        gdb.write_all(&frame(b"m80000000,4")).unwrap();
+        assert_eq!(connection.read_packet().unwrap(), Some("m80000000,4".to_string()));
+        assert_eq!(receive(&mut gdb, 2), b"-+");
+
+        // checksums are hex in either case
+        gdb.write_all(b"$g#67").unwrap();
+        assert_eq!(connection.read_packet().unwrap(), Some("g".to_string()));
+        gdb.write_all(b"$qC#B4").unwrap();
+        assert_eq!(connection.read_packet().unwrap(), Some("qC".to_string()));
+        assert_eq!(receive(&mut gdb, 2), b"++");
+
+        // Ctrl-C outside of a packet
+        gdb.write_all(&[0x03]).unwrap();
+        assert_eq!(connection.read_packet().unwrap(), None);
+
+        // a nack asks for the last packet again
+        connection.send_packet("OK").unwrap();
+        assert_eq!(receive(&mut gdb, 6), b"$OK#9a");
+        gdb.write_all(b"-").unwrap();
+        gdb.write_all(&frame(b"?")).unwrap();
+        assert_eq!(connection.read_packet().unwrap(), Some("?".to_string()));
+        assert_eq!(receive(&mut gdb, 7), b"$OK#9a+");
+    }
+
+    #[test]
+    fn packet_escaping() {
+        let (mut connection, mut gdb) = connection();
+
+        // '}' escapes the next byte, xor 0x20, and the checksum is over the escaped bytes
+        gdb.write_all(&frame(b"a}\x03b}]c}\x04")).unwrap();
+        assert_eq!(connection.read_packet().unwrap(), Some("a#b}c$".to_string()));
+        assert_eq!(receive(&mut gdb, 1), b"+");
+
+        // an escaped '#' doesn't end the packet
+        gdb.write_all(&frame(b"}\x03}\x03")).unwrap();
+        assert_eq!(connection.read_packet().unwrap(), Some("##".to_string()));
+        assert_eq!(receive(&mut gdb, 1), b"+");
+
+        connection.send_packet("l<a>$#*}").unwrap();
+        let expected = frame(b"l<a>}\x04}\x03}\x0a}]");
+        assert_eq!(receive(&mut gdb, expected.len()), expected);
+    }
+
+    #[test]
+    fn memory_packets() {
+        let mut debugger = debugger();
+
+        assert_eq!(debugger.gdb_write_memory("80001001,5:0102030405"), "OK");
+        assert_eq!(debugger.gdb_read_memory("80001000,8"), "0001020304050000");
+        assert_eq!(debugger.gdb_read_memory("ffffffffa0001003,2"), "0304");
+
+        // bad hex, or fewer bytes than the length
+        assert_eq!(debugger.gdb_write_memory("80001000,2:01zz"), "E01");
+        assert_eq!(debugger.gdb_write_memory("80001000,4:0102"), "E01");
+        assert_eq!(debugger.gdb_write_memory("80001000,4"), "E01");
+        assert_eq!(debugger.gdb_read_memory("80001000"), "E01");
+
+        // nothing is mapped at 0 until the TLB is set up
+        assert_eq!(debugger.gdb_read_memory("0,4"), "E01");
+        assert_eq!(debugger.gdb_write_memory("0,1:00"), "E01");
+    }
+
+    #[test]
+    fn breakpoint_packets() {
+        let mut debugger = debugger();
+        let mut watchpoints = Vec::new();
+
+        assert_eq!(debugger.gdb_insert_breakpoint("0,80001000,4", &mut watchpoints), "OK");
+        assert!(debugger.breakpoints.borrow().table.iter().any(|b| b.address == 0xFFFF_FFFF_8000_1000 && b.mode == BP_EXEC));
+        assert_eq!(debugger.gdb_remove_breakpoint("0,80001000,4", &mut watchpoints), "OK");
+        assert!(debugger.breakpoints.borrow().table.is_empty());
+
+        // watchpoints go on the physical address
+        assert_eq!(debugger.gdb_insert_breakpoint("2,a0002000,8", &mut watchpoints), "OK");
+        assert_eq!(debugger.gdb_insert_breakpoint("3,80003000,4;cond", &mut watchpoints), "OK");
+        {
+            let breakpoints = debugger.breakpoints.borrow();
+            assert_eq!(breakpoints.table.iter().map(|b| (b.address, b.end, b.mode)).collect::<Vec<_>>(),
+                       vec![(0x2000, 0x2007, BP_WRITE), (0x3000, 0x3003, BP_READ)]);
+        }
+        assert_eq!(debugger.gdb_remove_breakpoint("2,a0002000,8", &mut watchpoints), "OK");
+        assert_eq!(watchpoints.len(), 1);
+        assert_eq!(debugger.breakpoints.borrow().table.len(), 1);
+
+        // unmapped addresses can't be watched, and unknown kinds aren't supported
+        assert_eq!(debugger.gdb_insert_breakpoint("4,1000,4", &mut watchpoints), "E02");
+        assert_eq!(debugger.gdb_insert_breakpoint("5,80001000,4", &mut watchpoints), "");
+        assert_eq!(debugger.gdb_insert_breakpoint("2,80001000", &mut watchpoints), "E01");
+
+        // a length running past the end of the address space
+        assert_eq!(debugger.gdb_insert_breakpoint("2,a0002000,ffffffffffffffff", &mut watchpoints), "E01");
+        assert_eq!(watchpoints.len(), 1);
+    }
+
+    #[test]
+    fn target_description_reads() {
+        let mut debugger = debugger();
+        let xml = target_xml();
+
+        assert_eq!(debugger.gdb_query("Xfer:features:read:target.xml:0,10"), format!("m{}", &xml[..0x10]));
+        assert_eq!(debugger.gdb_query(&format!("Xfer:features:read:target.xml:10,{:x}", xml.len())), format!("l{}", &xml[0x10..]));
+
+        // huge lengths read to the end
+        assert_eq!(debugger.gdb_query("Xfer:features:read:target.xml:0,ffffffffffffffff"), format!("l{}", xml));
+    }
+
+    #[test]
+    fn bad_pc_is_rejected() {
+        let mut debugger = debugger();
+        let pc = debugger.gdb_register(GDB_REG_PC).unwrap();
+
+        // unmapped or unaligned addresses leave the CPU alone
+        assert_eq!(debugger.gdb_write_register(&format!("{:x}=0", GDB_REG_PC)), "E01");
+        assert_eq!(debugger.gdb_write_register(&format!("{:x}=ffffffff80001002", GDB_REG_PC)), "E01");
+        assert_eq!(debugger.gdb_register(GDB_REG_PC), Some(pc));
+        assert_eq!(debugger.gdb_register(GDB_REG_CAUSE), Some(debugger.system.cpu.cp0_register(cpu::Cop0_Cause)));
+
+        assert_eq!(debugger.gdb_write_register(&format!("{:x}=ffffffff80001000", GDB_REG_PC)), "OK");
+        assert_eq!(debugger.gdb_register(GDB_REG_PC), Some(0xFFFF_FFFF_8000_1000));
+
+        // continuing from a bad address is an error rather than running from somewhere else
+        let (mut connection, mut gdb) = connection();
+        gdb.write_all(&[frame(b"c0"), frame(b"k")].concat()).unwrap();
+        debugger.gdb_serve(&mut connection, &mut Vec::new()).unwrap();
+        let expected = [&b"+"[..], &frame(b"E01"), b"+"].concat();
+        assert_eq!(receive(&mut gdb, expected.len()), expected);
+        assert_eq!(debugger.gdb_register(GDB_REG_PC), Some(0xFFFF_FFFF_8000_1000));
+    }
+}
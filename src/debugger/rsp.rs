@@ -0,0 +1,116 @@
+// RSP commands. The RSP keeps running on its own thread until `rsp halt`, after which the
+// debugger steps it directly. IMEM addresses are given and shown as offsets into IMEM
+use super::*;
+
+use crate::rsp::Rsp;
+
+impl Debugger {
+    pub(super) fn rsp_command(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        let args = if parts.len() > 2 { &parts[2..] } else { &[] };
+        match parts.get(1).copied() {
+            None | Some("status")     => self.rsp_status(),
+            Some("h") | Some("halt")  => { self.system.rcp.borrow_mut().rsp.debug_halt(true); self.rsp_status() },
+            Some("c") | Some("cont")  => { self.system.rcp.borrow_mut().rsp.debug_halt(false); Ok(()) },
+            Some("s") | Some("step")  => self.rsp_step(args),
+            Some("regs")              => self.rsp_regs(),
+            Some("v") | Some("vregs") => self.rsp_vregs(),
+            Some("l") | Some("list")  => self.rsp_listing(args),
+            Some("dmem")              => self.rsp_dmem(args),
+            Some("b") | Some("break") => self.rsp_breakpoint(args, true),
+            Some("db") | Some("del")  => self.rsp_breakpoint(args, false),
+            Some(command) => {
+                Err(format!("unknown rsp command \"{}\" (status, halt, cont, step, regs, vregs, list, dmem, break, del)", command))
+            },
+        }
+    }
+
+    fn rsp_status(&mut self) -> Result<(), String> {
+        let state = self.system.rcp.borrow().rsp.debug_state();
+        println!("RSP {}{}{}", if state.debug_halted { "stopped by the debugger" } else { "running" },
+                 if state.halted { ", halted" } else { "" }, if state.broke { ", broke" } else { "" });
+        println!("${:03X}: {} (next instruction)", state.pc, Rsp::disassemble(state.pc, state.next_inst));
+        Ok(())
+    }
+
+    // stepping halts the RSP first, so it stays where it stopped
+    fn rsp_step(&mut self, args: &[&str]) -> Result<(), String> {
+        let count = if args.len() > 0 { parse_u64(args[0])? } else { 1 };
+
+        {
+            let mut rcp = self.system.rcp.borrow_mut();
+            rcp.rsp.debug_halt(true);
+            for _ in 0..count {
+                rcp.rsp.debug_step()?;
+            }
+        }
+
+        self.rsp_status()
+    }
+
+    fn rsp_regs(&mut self) -> Result<(), String> {
+        let state = self.system.rcp.borrow().rsp.debug_state();
+
+        for k in 0..8 {
+            for j in 0..4 {
+                print!("R{:02}(${}): {:08X} ", k*4+j, cpu::Cpu::abi_name(k*4+j), state.gpr[k*4+j]);
+            }
+            println!("");
+        }
+
+        println!("PC: ${:03X}", state.pc);
+        Ok(())
+    }
+
+    fn rsp_vregs(&mut self) -> Result<(), String> {
+        let state = self.system.rcp.borrow().rsp.debug_state();
+
+        for (i, v) in state.v.iter().enumerate() {
+            println!("$v{:02}: {}", i, v.iter().map(|e| format!("{:04X}", e)).collect::<Vec<String>>().join(" "));
+        }
+
+        println!("VCO: ${:04X} VCC: ${:04X} VCE: ${:02X}", state.vco, state.vcc, state.vce);
+        println!("ACC: {}", state.acc.iter().map(|acc| format!("{:04X}_{:04X}_{:04X}", (acc >> 32) as u16, (acc >> 16) as u16, *acc as u16))
+                                             .collect::<Vec<String>>().join(" "));
+        Ok(())
+    }
+
+    fn rsp_listing(&mut self, args: &[&str]) -> Result<(), String> {
+        let pc = self.system.rcp.borrow().rsp.debug_state().pc;
+        let start = if args.len() > 0 { (parse_u64(args[0])? as u32) & 0xFFC } else { pc };
+        let count = if args.len() > 1 { parse_u64(args[1])? as u32 } else { 10 };
+
+        for i in 0..count {
+            let address = (start + i * 4) & 0xFFC;
+            let inst = self.system.rcp.borrow_mut().read_u32(0x0400_1000 | (address as usize)).map_err(|err| format!("{:?}", err))?;
+            println!("${:03X}: {:08X} {}{}", address, inst, Rsp::disassemble(address, inst), if address == pc { " <-" } else { "" });
+        }
+
+        Ok(())
+    }
+
+    fn rsp_dmem(&mut self, args: &[&str]) -> Result<(), String> {
+        let start = if args.len() > 0 { parse_u64(args[0])? & 0xFFF } else { 0 };
+        let length = if args.len() > 1 { parse_u64(args[1])? } else { 128 };
+        self.hexdump(MemoryAddress::Physical(0x0400_0000 + start), std::cmp::min(length, 0x1000 - start), 4);
+        Ok(())
+    }
+
+    fn rsp_breakpoint(&mut self, args: &[&str], set: bool) -> Result<(), String> {
+        let mut rcp = self.system.rcp.borrow_mut();
+        if args.len() == 0 {
+            if !set { return Err(format!("usage: rsp del [IMEM address]")); }
+            for pc in rcp.rsp.debug_breakpoints() {
+                println!("${:03X}", pc);
+            }
+            return Ok(());
+        }
+
+        let pc = (parse_u64(args[0])? as u32) & 0xFFC;
+        if !rcp.rsp.debug_set_breakpoint(pc, set) {
+            return Err(format!("{} breakpoint at ${:03X}", if set { "already a" } else { "no" }, pc));
+        }
+
+        println!("RSP breakpoint {} ${:03X}", if set { "set at" } else { "removed from" }, pc);
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+// Debugger scripts. A script is a file of debugger commands, one line at a time, with blank
+// lines and lines starting with # skipped. Scripts run with `-x FILE` at startup or `source FILE`
+// from the prompt and stop at the first command that fails. `quit [status]` ends the debugger,
+// which sets the exit status of the emulator. Without a quit, --batch exits with 1 if a command
+// failed and 0 otherwise
+use std::fs;
+
+use super::*;
+
+// scripts sourcing scripts can't go deeper than this
+const MAX_SOURCE_DEPTH: u32 = 16;
+
+impl Debugger {
+    // run the commands in path, echoing each one. Errors carry the file name and line number
+    pub fn source(&mut self, path: &str) -> Result<(), String> {
+        if self.source_depth == MAX_SOURCE_DEPTH {
+            return Err(format!("scripts nested too deeply at \"{}\"", path));
+        }
+
+        let text = fs::read_to_string(path).map_err(|err| format!("couldn't read \"{}\": {}", path, err))?;
+
+        self.source_depth += 1;
+        let mut result = Ok(());
+        for (index, line) in text.lines().enumerate() {
+            if !self.alive { break; }
+
+            let line = line.trim();
+            if line.len() == 0 || line.starts_with("#") { continue; }
+
+            println!("> {}", line);
+            if let Err(err) = self.handle_line(line) {
+                result = Err(format!("{}:{}: {}", path, index + 1, err));
+                break;
+            }
+        }
+        self.source_depth -= 1;
+
+        result
+    }
+
+    // the status given to quit, if the debugger was quit
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+
+    // Finish writing the trace, if there is one. std::process::exit doesn't run destructors, so
+    // this has to happen before exiting with a status
+    pub fn finish(&mut self) -> Result<(), String> {
+        if self.tracer.is_some() {
+            self.stop_trace()?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn source_command(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        if parts.len() != 2 {
+            return Err(format!("usage: source file"));
+        }
+
+        self.source(parts[1])
+    }
+
+    pub(super) fn quit(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        let status = match parts.get(1) {
+            Some(status) => parse_u64(status)? as i32,
+            None => 0,
+        };
+
+        self.alive = false;
+        self.exit_status = Some(status);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::tests::debugger;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("n64-script-{}-{}", std::process::id(), name)).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn quit_from_a_script() {
+        let mut debugger = debugger();
+        let script = temp_path("quit.txt");
+        let trace = temp_path("quit.trace");
+
+        debugger.write_memory(MemoryAddress::Physical(0x1000), 0, 4).unwrap();
+        debugger.system.cpu.set_pc(0xFFFF_FFFF_8000_1000).unwrap();
+
+        // nothing after quit runs
+        fs::write(&script, format!("# trace one instruction\ntrace {}\n\nstep 1\nquit 3\nstep 1\n", trace)).unwrap();
+        debugger.source(&script).unwrap();
+        assert_eq!(debugger.exit_status(), Some(3));
+        assert_eq!(*debugger.system.cpu.next_instruction_pc(), 0xFFFF_FFFF_8000_1004);
+
+        // the trace is only complete once the debugger is finished with it
+        debugger.finish().unwrap();
+        assert_eq!(fs::read_to_string(&trace).unwrap().lines().count(), 1);
+        assert!(debugger.tracer.is_none());
+
+        let _ = fs::remove_file(script);
+        let _ = fs::remove_file(trace);
+    }
+
+    #[test]
+    fn scripts_stop_at_errors() {
+        let mut debugger = debugger();
+        let script = temp_path("error.txt");
+
+        fs::write(&script, "step 1\nbogus\nquit 2\n").unwrap();
+        let err = debugger.source(&script).unwrap_err();
+        assert!(err.starts_with(&format!("{}:2: ", script)), "{}", err);
+        assert_eq!(debugger.exit_status(), None);
+
+        let _ = fs::remove_file(script);
+    }
+}
@@ -0,0 +1,377 @@
+// Symbol tables for the debugger, loaded from ELF files or from text symbol files. The text
+// formats understood are GNU ld .map files, `name = 0xADDRESS;` lines as used by the decomp
+// projects, and `nm` output. 32-bit addresses are sign extended like everywhere else in the
+// debugger.
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+// text symbols without a size don't get offsets larger than this
+const MAX_UNSIZED_OFFSET: u64 = 0x1_0000;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+const STT_FUNC: u8 = 2;
+
+struct Symbol {
+    name    : String,
+    size    : u64, // 0 if unknown
+    function: bool,
+}
+
+pub(super) struct Symbols {
+    by_address: BTreeMap<u64, Symbol>,
+    by_name: HashMap<String, u64>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols {
+            by_address: BTreeMap::new(),
+            by_name: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.by_address.clear();
+        self.by_name.clear();
+    }
+
+    // load symbols from an ELF or symbol file, returning how many were read
+    pub fn load(&mut self, path: &str) -> Result<usize, String> {
+        let data = fs::read(path).map_err(|err| format!("couldn't read \"{}\": {}", path, err))?;
+
+        if data.starts_with(b"\x7FELF") {
+            self.load_elf(&data).map_err(|err| format!("\"{}\": {}", path, err))
+        } else {
+            Ok(self.load_text(&String::from_utf8_lossy(&data)))
+        }
+    }
+
+    fn add(&mut self, name: &str, address: u64, size: u64, function: bool) {
+        let address = if address < 0x1_0000_0000 { (address as i32) as u64 } else { address };
+        self.by_name.insert(name.to_string(), address);
+
+        // functions win over labels and data at the same address
+        if let Some(existing) = self.by_address.get(&address) {
+            if existing.function || !function { return; }
+        }
+
+        self.by_address.insert(address, Symbol {
+            name    : name.to_string(),
+            size    : size,
+            function: function,
+        });
+    }
+
+    fn load_elf(&mut self, data: &[u8]) -> Result<usize, String> {
+        let is_64bit = match data.get(4) {
+            Some(1) => false,
+            Some(2) => true,
+            _ => return Err(format!("unknown ELF class")),
+        };
+        let elf = Elf { data: data, big_endian: data.get(5) == Some(&2) };
+
+        let (section_offset, section_size, section_count) = if is_64bit {
+            (elf.u64(0x28)?, elf.u16(0x3A)? as u64, elf.u16(0x3C)? as u64)
+        } else {
+            (elf.u32(0x20)? as u64, elf.u16(0x2E)? as u64, elf.u16(0x30)? as u64)
+        };
+
+        // offsets come straight from the file, so none of them can be trusted not to overflow
+        let header = |index: u64| -> Result<u64, String> {
+            index.checked_mul(section_size).and_then(|offset| offset.checked_add(section_offset))
+                 .ok_or(format!("section header {} is out of range", index))
+        };
+
+        // (offset, size, link) of a section
+        let section = |index: u64| -> Result<(u64, u64, u64), String> {
+            let header = header(index)?;
+            if is_64bit {
+                Ok((elf.u64(at(header, 0x18)?)?, elf.u64(at(header, 0x20)?)?, elf.u32(at(header, 0x28)?)? as u64))
+            } else {
+                Ok((elf.u32(at(header, 0x10)?)? as u64, elf.u32(at(header, 0x14)?)? as u64, elf.u32(at(header, 0x18)?)? as u64))
+            }
+        };
+
+        let mut found = false;
+        let mut count = 0;
+        for index in 0..section_count {
+            if elf.u32(at(header(index)?, 4)?)? != SHT_SYMTAB { continue; }
+            found = true;
+
+            let (symbols_offset, symbols_size, link) = section(index)?;
+            let (strings_offset, _, _) = section(link)?;
+            let entry_size = if is_64bit { 24 } else { 16 };
+
+            for entry in (symbols_offset..at(symbols_offset, symbols_size)?).step_by(entry_size) {
+                let (name, value, size, info, section_index) = if is_64bit {
+                    (elf.u32(entry)?, elf.u64(at(entry, 8)?)?, elf.u64(at(entry, 16)?)?, elf.u8(at(entry, 4)?)?, elf.u16(at(entry, 6)?)?)
+                } else {
+                    (elf.u32(entry)?, elf.u32(at(entry, 4)?)? as u64, elf.u32(at(entry, 8)?)? as u64, elf.u8(at(entry, 12)?)?, elf.u16(at(entry, 14)?)?)
+                };
+
+                let kind = info & 0x0F;
+                if section_index == 0 || kind == STT_SECTION || kind == STT_FILE { continue; }
+
+                let name = elf.string(at(strings_offset, name as u64)?)?;
+                if name.len() == 0 { continue; }
+
+                self.add(&name, value, size, kind == STT_FUNC);
+                count += 1;
+            }
+        }
+
+        if !found {
+            return Err(format!("no symbol table"));
+        }
+        Ok(count)
+    }
+
+    fn load_text(&mut self, text: &str) -> usize {
+        let mut count = 0;
+        for line in text.lines() {
+            let line = line.split("//").next().unwrap().trim();
+            let tokens = line.split_whitespace().collect::<Vec<&str>>();
+
+            let (name, address) = match tokens.as_slice() {
+                // decomp style "name = 0x80246000;"
+                [name, "=", address] => (*name, address.trim_end_matches(';')),
+
+                // ld map "                0x0000000080246000                name"
+                [address, name] if address.starts_with("0x") => (*name, *address),
+
+                // nm "80246000 T name"
+                [address, kind, name] if kind.len() == 1 => (*name, *address),
+
+                _ => continue,
+            };
+
+            if !Symbols::is_identifier(name) { continue; }
+
+            let address = address.trim_start_matches("0x").trim_start_matches("0X");
+            if let Ok(address) = u64::from_str_radix(address, 16) {
+                self.add(name, address, 0, false);
+                count += 1;
+            }
+        }
+        count
+    }
+
+    fn is_identifier(name: &str) -> bool {
+        let mut chars = name.chars();
+        match chars.next() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$'),
+            _ => false,
+        }
+    }
+
+    // an address from "name", "name+offset" or "name-offset"
+    pub fn resolve(&self, s: &str) -> Option<u64> {
+        if let Some(address) = self.by_name.get(s) {
+            return Some(*address);
+        }
+
+        let index = s.rfind(|c| c == '+' || c == '-')?;
+        let address = self.by_name.get(&s[..index])?;
+        let offset = super::parse_u64(&s[index+1..]).ok()?;
+        Some(if &s[index..index+1] == "+" { address.wrapping_add(offset) } else { address.wrapping_sub(offset) })
+    }
+
+    fn containing(&self, address: u64) -> Option<(u64, &Symbol)> {
+        let (start, symbol) = self.by_address.range(..=address).next_back()?;
+        let offset = address - start;
+        let limit = if symbol.size != 0 { symbol.size } else { MAX_UNSIZED_OFFSET };
+        if offset != 0 && offset >= limit {
+            return None;
+        }
+
+        Some((*start, symbol))
+    }
+
+    // "name" or "name+$offset" for the symbol containing address
+    pub fn lookup(&self, address: u64) -> Option<String> {
+        let (start, symbol) = self.containing(address)?;
+        let offset = address - start;
+        Some(if offset == 0 { symbol.name.clone() } else { format!("{}+${:X}", symbol.name, offset) })
+    }
+
+    // start of the function containing address. Only symbols known to be functions count
+    pub fn function_start(&self, address: u64) -> Option<u64> {
+        match self.containing(address)? {
+            (start, symbol) if symbol.function => Some(start),
+            _ => None,
+        }
+    }
+
+    // " <name+$offset>" to follow an address, or nothing
+    pub fn describe(&self, address: u64) -> String {
+        match self.lookup(address) {
+            Some(name) => format!(" <{}>", name),
+            None => String::new(),
+        }
+    }
+}
+
+fn at(base: u64, offset: u64) -> Result<u64, String> {
+    base.checked_add(offset).ok_or(format!("offset ${:X}+${:X} is out of range", base, offset))
+}
+
+struct Elf<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Elf<'a> {
+    fn bytes<const N: usize>(&self, offset: u64) -> Result<[u8; N], String> {
+        let bytes = usize::try_from(offset).ok()
+                          .and_then(|start| self.data.get(start..start.checked_add(N)?))
+                          .ok_or(format!("truncated ELF file"))?;
+
+        let mut result = [0u8; N];
+        result.copy_from_slice(bytes);
+        if !self.big_endian { result.reverse(); }
+        Ok(result)
+    }
+
+    fn u8(&self, offset: u64) -> Result<u8, String> {
+        Ok(self.bytes::<1>(offset)?[0])
+    }
+
+    fn u16(&self, offset: u64) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.bytes(offset)?))
+    }
+
+    fn u32(&self, offset: u64) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.bytes(offset)?))
+    }
+
+    fn u64(&self, offset: u64) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.bytes(offset)?))
+    }
+
+    fn string(&self, offset: u64) -> Result<String, String> {
+        let data = usize::try_from(offset).ok()
+                         .and_then(|start| self.data.get(start..))
+                         .ok_or(format!("truncated ELF file"))?;
+        let end = data.iter().position(|c| *c == 0).unwrap_or(data.len());
+        Ok(String::from_utf8_lossy(&data[..end]).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a big endian ELF32 with a function, a data symbol and a file symbol, like a linked N64 game
+    fn elf32() -> Vec<u8> {
+        let mut data = vec![0u8; 0x118];
+        let mut put = |offset: usize, bytes: &[u8]| data[offset..offset + bytes.len()].copy_from_slice(bytes);
+
+        put(0x00, b"\x7FELF\x01\x02\x01");
+        put(0x20, &0xA0u32.to_be_bytes()); // section headers
+        put(0x2E, &40u16.to_be_bytes());
+        put(0x30, &3u16.to_be_bytes());
+
+        put(0x40, b"\0main\0gData\0main.c\0");
+
+        // name, value, size, info, section
+        let symbols = [(0u32, 0u32, 0u32, 0u8, 0u16), (1, 0x8000_1000, 0x20, 0x12, 1), (6, 0x8000_2000, 4, 0x11, 2), (12, 0, 0, STT_FILE, 0xFFF1)];
+        for (i, (name, value, size, info, section)) in symbols.iter().enumerate() {
+            let entry = 0x60 + i * 16;
+            put(entry, &name.to_be_bytes());
+            put(entry + 4, &value.to_be_bytes());
+            put(entry + 8, &size.to_be_bytes());
+            put(entry + 12, &[*info]);
+            put(entry + 14, &section.to_be_bytes());
+        }
+
+        // the null section, then .symtab linked to .strtab
+        put(0xA0 + 40 + 4, &SHT_SYMTAB.to_be_bytes());
+        put(0xA0 + 40 + 0x10, &0x60u32.to_be_bytes());
+        put(0xA0 + 40 + 0x14, &64u32.to_be_bytes());
+        put(0xA0 + 40 + 0x18, &2u32.to_be_bytes());
+        put(0xA0 + 80 + 4, &3u32.to_be_bytes());
+        put(0xA0 + 80 + 0x10, &0x40u32.to_be_bytes());
+        put(0xA0 + 80 + 0x14, &0x20u32.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn elf_symbols() {
+        let mut symbols = Symbols::new();
+        assert_eq!(symbols.load_elf(&elf32()), Ok(2));
+
+        assert_eq!(symbols.resolve("main"), Some(0xFFFF_FFFF_8000_1000));
+        assert_eq!(symbols.resolve("gData+4"), Some(0xFFFF_FFFF_8000_2004));
+        assert_eq!(symbols.resolve("main.c"), None);
+        assert_eq!(symbols.lookup(0xFFFF_FFFF_8000_101C), Some("main+$1C".to_string()));
+        assert_eq!(symbols.lookup(0xFFFF_FFFF_8000_1020), None);
+        assert_eq!(symbols.function_start(0xFFFF_FFFF_8000_1008), Some(0xFFFF_FFFF_8000_1000));
+        assert_eq!(symbols.function_start(0xFFFF_FFFF_8000_2000), None);
+    }
+
+    #[test]
+    fn bad_elf_files_are_errors() {
+        let mut symbols = Symbols::new();
+        let elf = elf32();
+
+        assert!(symbols.load_elf(&elf[..0x100]).is_err());
+        assert!(symbols.load_elf(&elf[..0x20]).is_err());
+
+        // section headers and tables that run off the end, or wrap around
+        let mut bad = elf.clone();
+        bad[0x20..0x24].copy_from_slice(&0xFFFF_FFF0u32.to_be_bytes());
+        assert!(symbols.load_elf(&bad).is_err());
+
+        let mut bad = elf.clone();
+        bad[0xA0 + 40 + 0x14..0xA0 + 40 + 0x18].copy_from_slice(&0xFFFF_FFFFu32.to_be_bytes());
+        assert!(symbols.load_elf(&bad).is_err());
+
+        let mut bad = elf.clone();
+        bad[0xA0 + 40 + 0x18..0xA0 + 40 + 0x1C].copy_from_slice(&0xFFFFu32.to_be_bytes());
+        assert!(symbols.load_elf(&bad).is_err());
+
+        // in 64-bit files the offsets themselves can overflow
+        let mut bad = vec![0u8; 0x40];
+        bad[..6].copy_from_slice(b"\x7FELF\x02\x02");
+        bad[0x28..0x30].copy_from_slice(&u64::MAX.to_be_bytes());
+        bad[0x3A..0x3C].copy_from_slice(&64u16.to_be_bytes());
+        bad[0x3C..0x3E].copy_from_slice(&2u16.to_be_bytes());
+        assert!(symbols.load_elf(&bad).is_err());
+
+        let elf = Elf { data: &[0u8; 8], big_endian: true };
+        assert!(elf.u32(u64::MAX - 1).is_err());
+        assert!(elf.string(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn symbol_files() {
+        let mut symbols = Symbols::new();
+        let text = "\
+            // decomp style\n\
+            osInitialize = 0x80000400;\n\
+            gCurrLevelNum = 0x8032DDF8; // comment\n\
+            \n\
+            .text          0x0000000080246000     0x1000 build/src/game/main.o\n\
+            \x20               0x0000000080246000                handle_dp_complete\n\
+            80247000 T thread3_main\n\
+            80247100 t 1bad\n";
+        assert_eq!(symbols.load_text(text), 4);
+
+        assert_eq!(symbols.resolve("osInitialize"), Some(0xFFFF_FFFF_8000_0400));
+        assert_eq!(symbols.resolve("gCurrLevelNum"), Some(0xFFFF_FFFF_8032_DDF8));
+        assert_eq!(symbols.resolve("handle_dp_complete"), Some(0xFFFF_FFFF_8024_6000));
+        assert_eq!(symbols.resolve("thread3_main-$10"), Some(0xFFFF_FFFF_8024_6FF0));
+        assert_eq!(symbols.resolve("1bad"), None);
+
+        // text symbols have no size, so they cover a limited distance
+        assert_eq!(symbols.lookup(0xFFFF_FFFF_8024_6010), Some("handle_dp_complete+$10".to_string()));
+        assert_eq!(symbols.lookup(0xFFFF_FFFF_8024_7000 + MAX_UNSIZED_OFFSET), None);
+        assert_eq!(symbols.function_start(0xFFFF_FFFF_8024_7004), None);
+    }
+}
@@ -0,0 +1,438 @@
+// Execution traces of every instruction the debugger steps, for diffing against other emulators.
+//
+// The text format is one line per instruction: the PC, the opcode, the disassembly and then the
+// registers the instruction changed, like
+//
+//   80001234: 3C088000 lui t0, $8000 t0=ffffffff80000000
+//
+// The binary format is the "N64TRACE" magic and a u32 version, followed by a record per
+// instruction: the PC as a u64, the opcode as a u32, a u8 count of changed registers, and then
+// for each change a u8 register (0-31 GPRs, 32-63 FPRs, 64 HI, 65 LO) and its u64 value. All
+// values are big endian. `trace convert` turns a binary trace into the text format.
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use super::*;
+
+const BINARY_MAGIC: &[u8; 8] = b"N64TRACE";
+const BINARY_VERSION: u32 = 1;
+
+const TRACE_REG_FPR: u8 = 32;
+const TRACE_REG_HI : u8 = 64;
+const TRACE_REG_LO : u8 = 65;
+
+#[derive(Copy, Clone, PartialEq)]
+enum TraceFormat {
+    Text,
+    Binary,
+}
+
+pub(super) struct Tracer {
+    path: String,
+    writer: BufWriter<File>,
+    format: TraceFormat,
+
+    // only instructions inside this range are written
+    range: Option<(u64, u64)>,
+
+    // breakpoint id and its hit count when the trace was set up. Nothing is written until it's hit
+    trigger: Option<(u64, u64)>,
+
+    // register values after the last instruction, to find what changed
+    gpr: [u64; 32],
+    fpr: [u64; 32],
+    hi : u64,
+    lo : u64,
+
+    count: u64,
+}
+
+impl Tracer {
+    fn new(path: &str, format: TraceFormat, range: Option<(u64, u64)>, trigger: Option<(u64, u64)>, cpu: &cpu::Cpu) -> io::Result<Tracer> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        if format == TraceFormat::Binary {
+            writer.write_all(BINARY_MAGIC)?;
+            writer.write_all(&BINARY_VERSION.to_be_bytes())?;
+        }
+
+        let mut tracer = Tracer {
+            path   : path.to_string(),
+            writer : writer,
+            format : format,
+            range  : range,
+            trigger: trigger,
+            gpr    : [0; 32],
+            fpr    : [0; 32],
+            hi     : 0,
+            lo     : 0,
+            count  : 0,
+        };
+        tracer.changes(cpu);
+        Ok(tracer)
+    }
+
+    // update the saved registers, returning the ones that changed
+    fn changes(&mut self, cpu: &cpu::Cpu) -> Vec<(u8, u64)> {
+        let mut changes = Vec::new();
+
+        for (i, value) in cpu.regs().iter().enumerate() {
+            if self.gpr[i] != *value {
+                self.gpr[i] = *value;
+                changes.push((i as u8, *value));
+            }
+        }
+
+        for i in 0..32 {
+            let value = cpu.cop1().fgr(i);
+            if self.fpr[i] != value {
+                self.fpr[i] = value;
+                changes.push((TRACE_REG_FPR + i as u8, value));
+            }
+        }
+
+        if self.hi != cpu.hi() {
+            self.hi = cpu.hi();
+            changes.push((TRACE_REG_HI, self.hi));
+        }
+
+        if self.lo != cpu.lo() {
+            self.lo = cpu.lo();
+            changes.push((TRACE_REG_LO, self.lo));
+        }
+
+        changes
+    }
+
+    // write the instruction at pc that just ran
+    fn record(&mut self, pc: u64, inst: u32, cpu: &cpu::Cpu) -> io::Result<()> {
+        // registers are always compared, so changes made outside of the range don't show up later
+        let changes = self.changes(cpu);
+
+        if self.trigger.is_some() { return Ok(()); }
+        if let Some((start, end)) = self.range {
+            if pc < start || pc > end { return Ok(()); }
+        }
+
+        self.count += 1;
+        match self.format {
+            TraceFormat::Text => {
+                write_text_record(&mut self.writer, pc, inst, &changes)
+            },
+
+            TraceFormat::Binary => {
+                self.writer.write_all(&pc.to_be_bytes())?;
+                self.writer.write_all(&inst.to_be_bytes())?;
+                self.writer.write_all(&[changes.len() as u8])?;
+                for (register, value) in changes {
+                    self.writer.write_all(&[register])?;
+                    self.writer.write_all(&value.to_be_bytes())?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+fn write_text_record(writer: &mut dyn Write, pc: u64, inst: u32, changes: &[(u8, u64)]) -> io::Result<()> {
+    if ((pc as i32) as u64) == pc {
+        write!(writer, "{:08x}: {:08X} {}", pc as u32, inst, cpu::Cpu::disassemble(pc, inst, true))?;
+    } else {
+        write!(writer, "{:016x}: {:08X} {}", pc, inst, cpu::Cpu::disassemble(pc, inst, true))?;
+    }
+
+    for (register, value) in changes {
+        match *register {
+            TRACE_REG_HI => write!(writer, " hi={:016x}", value)?,
+            TRACE_REG_LO => write!(writer, " lo={:016x}", value)?,
+            r if r >= TRACE_REG_FPR => write!(writer, " f{}={:016x}", r - TRACE_REG_FPR, value)?,
+            r => write!(writer, " {}={:016x}", cpu::Cpu::abi_name(r as usize), value)?,
+        }
+    }
+
+    writeln!(writer)
+}
+
+// convert a binary trace to text, returning the number of instructions
+fn convert_trace(input: &str, output: &str) -> io::Result<u64> {
+    let mut reader = BufReader::new(File::open(input)?);
+    let mut writer = BufWriter::new(File::create(output)?);
+
+    let mut header = [0u8; 12];
+    reader.read_exact(&mut header)?;
+    if &header[..8] != BINARY_MAGIC || u32::from_be_bytes(header[8..].try_into().unwrap()) != BINARY_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a binary trace"));
+    }
+
+    let mut count = 0;
+    let mut record = [0u8; 13];
+    loop {
+        match reader.read_exact(&mut record) {
+            Ok(_) => {},
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        }
+
+        let pc = u64::from_be_bytes(record[0..8].try_into().unwrap());
+        let inst = u32::from_be_bytes(record[8..12].try_into().unwrap());
+
+        let mut changes = Vec::with_capacity(record[12] as usize);
+        for _ in 0..record[12] {
+            let mut change = [0u8; 9];
+            reader.read_exact(&mut change)?;
+            changes.push((change[0], u64::from_be_bytes(change[1..].try_into().unwrap())));
+        }
+
+        write_text_record(&mut writer, pc, inst, &changes)?;
+        count += 1;
+    }
+
+    writer.flush()?;
+    Ok(count)
+}
+
+impl Debugger {
+    // trace [file [bin] [start-end] [on breakpoint id]] | stop | convert binfile textfile
+    pub(super) fn trace_command(&mut self, parts: &Vec<&str>) -> Result<(), String> {
+        match parts.get(1).copied() {
+            None => {
+                match &self.tracer {
+                    Some(tracer) => println!("tracing to {} ({} instructions so far{})", tracer.path, tracer.count,
+                                             if tracer.trigger.is_some() { ", waiting for the trigger" } else { "" }),
+                    None => println!("not tracing"),
+                }
+            },
+
+            Some("stop") => self.stop_trace()?,
+
+            Some("convert") => {
+                if parts.len() != 4 {
+                    return Err(format!("usage: trace convert binary_file text_file"));
+                }
+
+                let count = convert_trace(parts[2], parts[3]).map_err(|err| format!("couldn't convert \"{}\": {}", parts[2], err))?;
+                println!("converted {} instructions to {}", count, parts[3]);
+            },
+
+            Some(path) => {
+                if self.tracer.is_some() {
+                    return Err(format!("already tracing, use \"trace stop\" first"));
+                }
+
+                let mut format = TraceFormat::Text;
+                let mut range = None;
+                let mut trigger = None;
+
+                let mut options = parts[2..].iter();
+                while let Some(option) = options.next() {
+                    if *option == "bin" {
+                        format = TraceFormat::Binary;
+                    } else if *option == "on" {
+                        let id = match options.next() {
+                            Some(id) => parse_u64(id)?,
+                            None => return Err(format!("missing breakpoint id after \"on\"")),
+                        };
+                        let mut breakpoints = self.breakpoints.borrow_mut();
+                        trigger = Some((id, breakpoints.get_breakpoint(id)?.hit_count));
+                    } else if let Some((start, end)) = option.split_once('-') {
+                        range = Some((parse_breakpoint_address(start, &self.symbols)?, parse_breakpoint_address(end, &self.symbols)?));
+                    } else {
+                        return Err(format!("usage: trace file [bin] [start-end] [on breakpoint_id]"));
+                    }
+                }
+
+                let tracer = Tracer::new(path, format, range, trigger, &self.system.cpu).map_err(|err| format!("couldn't create \"{}\": {}", path, err))?;
+                self.tracer = Some(tracer);
+
+                // every instruction is recorded, so none can run inside a compiled block
+                #[cfg(feature="jit")]
+                self.system.cpu.pause_jit(true);
+                println!("tracing to {}", path);
+            },
+        }
+
+        Ok(())
+    }
+
+    // flush and close the trace file
+    pub(super) fn stop_trace(&mut self) -> Result<(), String> {
+        match self.tracer.take() {
+            Some(mut tracer) => {
+                #[cfg(feature="jit")]
+                self.system.cpu.pause_jit(false);
+                tracer.writer.flush().map_err(|err| format!("couldn't write \"{}\": {}", tracer.path, err))?;
+                println!("wrote {} instructions to {}", tracer.count, tracer.path);
+                Ok(())
+            },
+            None => Err(format!("not tracing")),
+        }
+    }
+
+    // the pc and opcode of the instruction about to be stepped, if tracing
+    pub(super) fn trace_before_step(&mut self) -> Option<(u64, u32)> {
+        let tracer = self.tracer.as_mut()?;
+
+        // start once the trigger breakpoint has been hit again
+        if let Some((id, hit_count)) = tracer.trigger {
+            if let Ok(breakpoint) = self.breakpoints.borrow_mut().get_breakpoint(id) {
+                if breakpoint.hit_count != hit_count { tracer.trigger = None; }
+            }
+        }
+
+        Some((*self.system.cpu.next_instruction_pc(), *self.system.cpu.next_instruction()))
+    }
+
+    // write the instruction that was just stepped
+    pub(super) fn trace_after_step(&mut self, pc: u64, inst: u32) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            if let Err(err) = tracer.record(pc, inst, &self.system.cpu) {
+                println!("trace stopped, couldn't write \"{}\": {}", tracer.path, err);
+                self.tracer = None;
+                #[cfg(feature="jit")]
+                self.system.cpu.pause_jit(false);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::tests::debugger;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("n64-trace-{}-{}", std::process::id(), name)).to_str().unwrap().to_string()
+    }
+
+    fn text(pc: u64, inst: u32, changes: &[(u8, u64)]) -> String {
+        let mut text = Vec::new();
+        write_text_record(&mut text, pc, inst, changes).unwrap();
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test]
+    fn text_records() {
+        // lui t0, $8000
+        let lui = format!("3C088000 {}", cpu::Cpu::disassemble(0xFFFF_FFFF_8000_1000, 0x3C08_8000, true));
+        assert_eq!(text(0xFFFF_FFFF_8000_1000, 0x3C08_8000, &[(8, 0xFFFF_FFFF_8000_0000)]),
+                   format!("80001000: {} t0=ffffffff80000000\n", lui));
+
+        // 64-bit addresses are written in full
+        let lui = format!("3C088000 {}", cpu::Cpu::disassemble(0x9000_0000_0000_1000, 0x3C08_8000, true));
+        assert_eq!(text(0x9000_0000_0000_1000, 0x3C08_8000, &[]), format!("9000000000001000: {}\n", lui));
+        assert_eq!(text(0x0000_0000_8000_1000, 0x3C08_8000, &[]).split(':').next(), Some("0000000080001000"));
+
+        let changes = [(TRACE_REG_HI, 1), (TRACE_REG_LO, 2), (TRACE_REG_FPR + 31, 3), (31, 4)];
+        assert!(text(0xFFFF_FFFF_8000_1000, 0, &changes).ends_with(" hi=0000000000000001 lo=0000000000000002 f31=0000000000000003 ra=0000000000000004\n"));
+    }
+
+    #[test]
+    fn convert_binary_traces() {
+        let binary = temp_path("convert.bin");
+        let output = temp_path("convert.txt");
+
+        let mut data = Vec::new();
+        data.extend_from_slice(BINARY_MAGIC);
+        data.extend_from_slice(&BINARY_VERSION.to_be_bytes());
+        data.extend_from_slice(&0x9000_0000_0000_1000u64.to_be_bytes());
+        data.extend_from_slice(&0x3C08_8000u32.to_be_bytes());
+        data.extend_from_slice(&[2, 8]);
+        data.extend_from_slice(&0xFFFF_FFFF_8000_0000u64.to_be_bytes());
+        data.push(TRACE_REG_LO);
+        data.extend_from_slice(&5u64.to_be_bytes());
+        data.extend_from_slice(&0xFFFF_FFFF_8000_1004u64.to_be_bytes());
+        data.extend_from_slice(&[0, 0, 0, 0, 0]);
+        fs::write(&binary, &data).unwrap();
+
+        assert_eq!(convert_trace(&binary, &output).unwrap(), 2);
+        let expected = text(0x9000_0000_0000_1000, 0x3C08_8000, &[(8, 0xFFFF_FFFF_8000_0000), (TRACE_REG_LO, 5)])
+                     + &text(0xFFFF_FFFF_8000_1004, 0, &[]);
+        assert_eq!(fs::read_to_string(&output).unwrap(), expected);
+
+        // a record cut off in its register changes
+        fs::write(&binary, &data[..data.len() - 20]).unwrap();
+        assert!(convert_trace(&binary, &output).is_err());
+
+        // and files that aren't binary traces
+        fs::write(&binary, b"N64TRACE\0\0\0\x02").unwrap();
+        assert!(convert_trace(&binary, &output).is_err());
+        fs::write(&binary, b"80001000: 00000000 nop\n").unwrap();
+        assert!(convert_trace(&binary, &output).is_err());
+
+        let _ = fs::remove_file(binary);
+        let _ = fs::remove_file(output);
+    }
+
+    #[test]
+    fn trace_stepped_instructions() {
+        let mut debugger = debugger();
+        let binary = temp_path("step.bin");
+        let output = temp_path("step.txt");
+
+        // lui t0, $8000; addiu t1, t0, 4; nop
+        let code = [0x3C08_8000u32, 0x2509_0004, 0];
+        for (i, inst) in code.iter().enumerate() {
+            debugger.write_memory(MemoryAddress::Physical(0x1000 + (i as u64) * 4), *inst as u64, 4).unwrap();
+        }
+        debugger.system.cpu.set_pc(0xFFFF_FFFF_8000_1000).unwrap();
+        debugger.system.cpu.set_reg(8, 0);
+        debugger.system.cpu.set_reg(9, 0);
+
+        debugger.trace_command(&vec!["trace", &binary, "bin"]).unwrap();
+        assert!(debugger.trace_command(&vec!["trace", &binary]).is_err());
+        debugger.step(&vec!["step", "2"]).unwrap();
+        debugger.trace_command(&vec!["trace", "stop"]).unwrap();
+        debugger.step(&vec!["step", "1"]).unwrap();
+
+        assert_eq!(convert_trace(&binary, &output).unwrap(), 2);
+        let expected = text(0xFFFF_FFFF_8000_1000, code[0], &[(8, 0xFFFF_FFFF_8000_0000)])
+                     + &text(0xFFFF_FFFF_8000_1004, code[1], &[(9, 0xFFFF_FFFF_8000_0004)]);
+        assert_eq!(fs::read_to_string(&output).unwrap(), expected);
+
+        let _ = fs::remove_file(binary);
+        let _ = fs::remove_file(output);
+    }
+
+    #[cfg(feature="jit")]
+    #[test]
+    fn trace_compiled_code() {
+        let comms = SystemCommunication::new(None);
+        comms.settings.write().unwrap().jit = cpu::JitMode::Enabled;
+        let mut debugger = crate::debugger::tests::debugger_with(comms);
+        let path = temp_path("jit.txt");
+
+        // a block long enough to be compiled: addiu t0, t0, 1 (x4); nop
+        let code = [0x2508_0001u32, 0x2508_0001, 0x2508_0001, 0x2508_0001, 0];
+        for (i, inst) in code.iter().enumerate() {
+            debugger.write_memory(MemoryAddress::Physical(0x1000 + (i as u64) * 4), *inst as u64, 4).unwrap();
+        }
+        debugger.system.cpu.set_pc(0xFFFF_FFFF_8000_1000).unwrap();
+
+        // every step is still a single instruction
+        debugger.trace_command(&vec!["trace", &path]).unwrap();
+        debugger.step(&vec!["step", "2"]).unwrap();
+        debugger.trace_command(&vec!["trace", "stop"]).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        assert_eq!(*debugger.system.cpu.next_instruction_pc(), 0xFFFF_FFFF_8000_1008);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn trace_64bit_addresses() {
+        let mut debugger = debugger();
+        let path = temp_path("xkphys.txt");
+
+        // run the nop at p:$1000 through xkphys, which needs 64-bit kernel addressing
+        debugger.write_memory(MemoryAddress::Physical(0x1000), 0, 4).unwrap();
+        let status = debugger.system.cpu.cp0_register(cpu::Cop0_Status);
+        debugger.system.cpu.set_cp0_register(cpu::Cop0_Status, status | 0x80);
+        debugger.system.cpu.set_pc(0x9000_0000_0000_1000).unwrap();
+
+        debugger.trace_command(&vec!["trace", &path]).unwrap();
+        debugger.step(&vec!["step", "1"]).unwrap();
+        debugger.trace_command(&vec!["trace", "stop"]).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), text(0x9000_0000_0000_1000, 0, &[]));
+        let _ = fs::remove_file(path);
+    }
+}
@@ -1,7 +1,9 @@
 #include <fenv.h>
 #include <math.h>
 #include <stdint.h>
+#if defined(__x86_64__)
 #include <xmmintrin.h>
+#endif
 
 int32_t c_fe_upward     = FE_UPWARD;
 int32_t c_fe_downward   = FE_DOWNWARD;
@@ -50,14 +52,14 @@ extern double c_f64_div(double a, double b) { return a / b; }
 // the compiler seems to incorrectly optimize the call to rint, making the rounding mode incorrect
 // this inline assembly seems to prevent that optimization
 extern double c_rint_f64(double a) { 
-#if defined(__GNUC__) || defined(__clang__)
+#if (defined(__GNUC__) || defined(__clang__)) && defined(__x86_64__)
     asm __volatile__(
             "movsd %1,%%xmm0\n\t"
             "call rint\n\t"
             "movsd %%xmm0,%0\n\t"
             : "=m"(a) : "m"(a) : "xmm0", "memory");
 #else
-    // MSVC seems to work for now. Might need to fix in the future
+    // MSVC and non-x86 targets seem to work for now. Might need to fix in the future
     a = rint(a);
 #endif
     return a;
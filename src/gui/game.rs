@@ -16,6 +16,7 @@ use crate::*;
 use gui::{App, AppWindow};
 
 use n64::{SystemCommunication, ButtonState};
+use n64::limiter::EmulationSpeed;
 use n64::hle::{
     self,
     HleRenderCommand, 
@@ -1030,6 +1031,18 @@ impl App for Game {
                 self.capture_display_list = 1;
             }
 
+            // CTRL+= and CTRL+- to speed up and slow down emulation, CTRL+0 to return to normal speed
+            if appwnd.input().key_pressed(KeyCode::Equal) || appwnd.input().key_pressed(KeyCode::Minus) || appwnd.input().key_pressed(KeyCode::Digit0) {
+                let mut settings = self.comms.settings.write().unwrap();
+                settings.speed = if appwnd.input().key_pressed(KeyCode::Equal) {
+                    settings.speed.faster()
+                } else if appwnd.input().key_pressed(KeyCode::Minus) {
+                    settings.speed.slower()
+                } else {
+                    EmulationSpeed::Normal
+                };
+            }
+
             // CTRL+S to toggle sync_ui_to_game
             if appwnd.input().key_pressed(KeyCode::KeyS) {
                 self.args.sync_ui_to_game = !self.args.sync_ui_to_game;
@@ -1156,78 +1169,51 @@ impl App for Game {
                     } else if self.game_color_texture_bind_groups.contains_key(&(video_buffer.wrapping_sub(1280))) { // hmmm?
                         self.game_color_texture_bind_groups.get(&(video_buffer - 1280)).unwrap()
                     } else {
-                        // restore video_buffer address for render
-                        let video_buffer = self.comms.vi_origin.load(Ordering::SeqCst);
-
-                        // no game render texture found, if video_buffer is valid, render directly from RDRAM if possible
-                        let width = self.comms.vi_width.load(Ordering::SeqCst) as usize;
-                        let height = if width == 320 { 240 } else if width == 640 { 480 } else { /*warn!(target: "RENDER", "unknown render size {}", width);*/ return; } as usize;
-                        let format = self.comms.vi_format.load(Ordering::SeqCst);
-                        //println!("width={} height={} format={}", width, height, format);
-
-                        if self.rdram_framebuffer_texture.is_none() {
-                            let (texture, bind_group) = self.create_color_texture(appwnd, format!("${:08X}", video_buffer).as_str(), width as u32, height as u32, true, false);
+                        // no game render texture found, so run the VI output stage over RDRAM
+                        let vi_state = *self.comms.vi_state.read().unwrap();
+                        let framebuffer = self.comms.rdram.read().unwrap().as_deref().map(|rdram| vi_state.copy_framebuffer(rdram, &self.comms.rdram_map));
+                        let frame = match framebuffer.and_then(|framebuffer| vi_state.compose(&framebuffer)) {
+                            Some(frame) => frame,
+                            None => return,
+                        };
+                        let (width, height) = (frame.width as u32, frame.height as u32);
+
+                        // the output size changes with the VI registers
+                        let size_changed = self.rdram_framebuffer_texture.as_ref().map_or(true, |texture| texture.width() != width || texture.height() != height);
+                        if size_changed {
+                            let (texture, bind_group) = self.create_color_texture(appwnd, format!("${:08X}", vi_state.origin).as_str(), width, height, true, false);
                             self.rdram_framebuffer_texture = Some(texture);
                             self.rdram_framebuffer_texture_bind_group = Some(bind_group);
                         }
 
-                        // access RDRAM directly
-                        // would be nice if I could copy RGB555 into a texture, but this copy seems acceptable for now
-                        if format != 0 {
-                            if let Some(rdram) = self.comms.rdram.read().as_deref().unwrap() { // rdram = &[u32]
-                                let start = (video_buffer >> 2) as usize;
-
-                                let bpp = if format == 2 { 2 } else if format == 3 { 4 } else { todo!("unknown format {}", format) };
-                                let size = ((width * height * bpp) >> 2) as usize;
-                                if start + size >= rdram.len() { return; }
-
-                                let mut image_data = vec![0u32; width*height];
-                                for y in 0..height {
-                                    for x in 0..width {
-                                        let i = (y * width) + x;
-                                        let iy = (y * width) + x;
-
-                                        match format {
-                                            2 => {
-                                                let shift = 16 - ((i & 1) << 4);
-                                                let pix = (rdram[start + (i >> 1)] >> shift) as u16;
-                                                let r = ((pix >> 11) & 0x1F) as u8;
-                                                let g = ((pix >>  6) & 0x1F) as u8;
-                                                let b = ((pix >>  1) & 0x1F) as u8;
-                                                let a = (pix & 0x01) as u8;
-                                                image_data[iy] = ((b as u32) << 27) | ((g as u32) << 19) | ((r as u32) << 11) | (if a == 1 { 0xFF } else { 0 });
-                                                image_data[iy] = u32::from_le_bytes(image_data[iy].to_be_bytes());
-                                            },
-                                            3 => { 
-                                                image_data[iy] = rdram[start+i] | 0xff;
-                                            },
-                                            _ => break,
-                                        }
-                                    }
-                                }
-
-                                appwnd.queue().write_texture(
-                                    wgpu::ImageCopyTexture {
-                                        texture: self.rdram_framebuffer_texture.as_ref().unwrap(),
-                                        mip_level: 0,
-                                        origin: wgpu::Origin3d::ZERO,
-                                        aspect: wgpu::TextureAspect::All,
-                                    },
-                                    bytemuck::cast_slice(&image_data),
-                                    wgpu::ImageDataLayout {
-                                        offset: 0,
-                                        bytes_per_row: Some(1 * 4 * width as u32), // 320 pix, rgba*f32,
-                                        rows_per_image: Some(height as u32),
-                                    },
-                                    wgpu::Extent3d {
-                                        width: width as u32,
-                                        height: height as u32,
-                                        depth_or_array_layers: 1,
-                                    },
-                                );
+                        // the texture uses the surface format, which is usually BGRA
+                        let mut image_data = frame.pixels;
+                        if matches!(appwnd.surface_config().format, wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb) {
+                            for pixel in image_data.chunks_exact_mut(4) {
+                                pixel.swap(0, 2);
                             }
                         }
 
+                        appwnd.queue().write_texture(
+                            wgpu::ImageCopyTexture {
+                                texture: self.rdram_framebuffer_texture.as_ref().unwrap(),
+                                mip_level: 0,
+                                origin: wgpu::Origin3d::ZERO,
+                                aspect: wgpu::TextureAspect::All,
+                            },
+                            &image_data,
+                            wgpu::ImageDataLayout {
+                                offset: 0,
+                                bytes_per_row: Some(4 * width),
+                                rows_per_image: Some(height),
+                            },
+                            wgpu::Extent3d {
+                                width: width,
+                                height: height,
+                                depth_or_array_layers: 1,
+                            },
+                        );
+
                         self.rdram_framebuffer_texture_bind_group.as_ref().unwrap()
                     };
 
@@ -1304,6 +1290,7 @@ impl App for Game {
                       ui.text(format!("UI   FPS: {}", self.ui_fps));
                       ui.text(format!("GAME FPS: {}", self.game_fps));
                       ui.text(format!("VIEW    : {:?} (Ctrl+V)", self.view_mode));
+                      ui.text(format!("SPEED   : {} (Ctrl+-/=/0)", self.comms.settings.read().unwrap().speed));
                   }
             );
         }
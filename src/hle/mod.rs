@@ -898,16 +898,36 @@ impl Hle {
         r
     }
 
+    // 4MiB, or 8MiB with the Expansion Pak
+    fn rdram_size(&self) -> u32 {
+        let access = self.comms.rdram.read().unwrap();
+        access.as_ref().map_or(0, |rdram| (rdram.len() << 2) as u32)
+    }
+
+    // Resolve a segmented address the way the microcode does: the segment base plus a 24-bit
+    // offset, wrapping in the RSP's 24-bit DRAM address space. KSEG0/1 addresses are taken as
+    // physical addresses. The result isn't necessarily inside RDRAM, see load_from_rdram
+    fn segmented_address(&self, addr: u32) -> u32 {
+        if (addr & 0xE000_0000) != 0 {
+            addr & 0x00FF_FFFF
+        } else {
+            let segment = ((addr >> 24) & 0x0F) as usize;
+            self.segments[segment].wrapping_add(addr & 0x00FF_FFFF) & 0x00FF_FFFF
+        }
+    }
+
+    // Reads past the end of RDRAM (or from an address without a module behind it) return zeros,
+    // the same as a DMA from there would
     fn load_from_rdram(&self, start: u32, length: u32) -> Vec<u32> {
-        let access = self.comms.rdram.read();
-        let rdram: &[u32] = access.as_deref().unwrap().as_ref().unwrap();
-        let length = ((length + 7) & !7) as usize;
-        let start = ((start & !0x8000_0000) >> 2) as usize;
-        let end   = start + (length >> 2);
-        if end > rdram.len() {
-            panic!("read from ${:08X} length {} reads outside of RDRAM", start << 2, length);
+        let length = (length + 7) & !7;
+        let start = start & 0x00FF_FFFC;
+        if (start as u64 + length as u64) > self.rdram_size() as u64 {
+            warn!(target: "HLE", "read from ${:08X} length {} reads outside of RDRAM", start, length);
         }
-        rdram[start..end].into()
+
+        let access = self.comms.rdram.read().unwrap();
+        let rdram: &[u32] = access.as_deref().unwrap();
+        self.comms.rdram_map.read_words(rdram, start as usize, (length >> 2) as usize)
     }
 
     // read memory until a \0 is encountered, and decode into a printable string
@@ -982,10 +1002,7 @@ impl Hle {
     fn handle_noop(&mut self) { // G_NOOP
         let addr = (self.command & 0xFFFF_FFFF) as u32;
         if addr != 0 {
-            let translated_addr = (if (addr & 0xE000_0000) != 0 { addr } else {
-                let segment = ((addr >> 24) & 0x0F) as usize;
-                self.segments[segment] + (addr & 0x007F_FFFF)
-            } & 0x007F_FFFF);
+            let translated_addr = self.segmented_address(addr);
 
             let s = self.load_string(translated_addr, 64);
             trace!(target: "HLE", "{} gsDPNoOpString([0x{:08X}] \"{}\")", self.command_prefix, addr, s);
@@ -1015,10 +1032,7 @@ impl Hle {
 
         let addr   = self.command as u32;
 
-        let translated_addr = (if (addr & 0xE000_0000) != 0 { addr } else {
-            let segment = ((addr >> 24) & 0x0F) as usize;
-            self.segments[segment] + (addr & 0x007F_FFFF)
-        } & 0x007F_FFFF);
+        let translated_addr = self.segmented_address(addr);
 
 
         let mut s = String::from("0");
@@ -1156,10 +1170,7 @@ impl Hle {
             2 => todo!("G_MV_MMTX"),
             6 => todo!("G_MV_PMTX"),
             8 => { // G_VIEWPORT
-                let segment = (addr >> 24) as u8;
-                let translated_addr = (if (addr & 0xE000_0000) != 0 { addr } else { 
-                    (addr & 0x007F_FFFF) + self.segments[segment as usize]
-                } & 0x007F_FFFF);
+                let translated_addr = self.segmented_address(addr);
 
                 let vp = self.load_from_rdram(translated_addr, size as u32);
 
@@ -1217,10 +1228,7 @@ impl Hle {
                     return;
                 }
 
-                let translated_addr = (if (addr & 0xE000_0000) != 0 { addr } else { 
-                    let segment = (addr >> 24) as u8;
-                    (addr & 0x007F_FFFF) + self.segments[segment as usize]
-                } & 0x007F_FFFF);
+                let translated_addr = self.segmented_address(addr);
 
                 let light_data = self.load_from_rdram(translated_addr, size as u32);
 
@@ -1301,10 +1309,7 @@ impl Hle {
             },
 
             0x84 => { // G_LOOKATX - use this vector for lighting?
-                let translated_addr = (if (addr & 0xE000_0000) != 0 { addr } else { 
-                    let segment = (addr >> 24) as u8;
-                    (addr & 0x007F_FFFF) + self.segments[segment as usize]
-                } & 0x007F_FFFF);
+                let translated_addr = self.segmented_address(addr);
 
                 let lookat_data = self.load_from_rdram(translated_addr, size as u32);
                 let _x = ((((lookat_data[2] >> 24) & 0xFF) as i8) as f32) / 127.0;
@@ -1363,10 +1368,7 @@ impl Hle {
         let is_link = (self.command & 0x00FF_0000_0000_0000) == 0;
         let addr    = self.command as u32;
 
-        let translated_addr = if (addr & 0xE000_0000) != 0 { addr } else {
-            let segment = ((addr >> 24) & 0x0F) as usize;
-            (addr & 0x007F_FFFF) + self.segments[segment]
-        } & 0x007F_FFFF;
+        let translated_addr = self.segmented_address(addr);
 
         if is_link {
             trace!(target: "HLE", "{} gsSPDisplayList(0x{:08X} [0x{:08X}])", self.command_prefix, addr, translated_addr);
@@ -1409,10 +1411,7 @@ impl Hle {
         let vbidx = ((self.command >> 32) & 0xFFF) >> 1;
         let zval = self.command as u32;
 
-        let translated_addr = if (addr & 0xE000_0000) != 0 { addr } else {
-            let segment = ((addr >> 24) & 0x0F) as usize;
-            (self.segments[segment] + (addr & 0x007F_FFFF)) & 0x007F_FFFF
-        };
+        let translated_addr = self.segmented_address(addr);
 
         trace!(target: "HLE", "{} gsSPBranchLessZraw(0x{:08X} [0x{:08X}], {}, 0x{:08X})", self.command_prefix, addr, translated_addr, vbidx, zval);
     }
@@ -1463,10 +1462,7 @@ impl Hle {
 
         let addr  = self.command as u32;
 
-        let translated_addr = (if (addr & 0xE000_0000) != 0 { addr } else {
-            let segment = ((addr >> 24) & 0x0F) as usize;
-            self.segments[segment] + (addr & 0x007F_FFFF)
-        } & 0x007F_FFFF);
+        let translated_addr = self.segmented_address(addr);
 
         let vtx_size = mem::size_of::<F3DZEX2_Vertex>();
         let data_size = numv as usize * vtx_size;
@@ -2855,7 +2851,7 @@ impl Hle {
 
         // load all texels from rdram in one go
         let padded_size = (data_size + 7) & !7;
-        let data = if ((self.tex.address + padded_size) & 0x7FFF_FFFF) >= 0x0080_0000 {
+        let data = if (self.tex.address + padded_size) > self.rdram_size() {
             warn!(target: "HLE", "invalid read outside of RDRAM (address=${:08X}, length={})!", self.tex.address, padded_size);
             let mut v = Vec::new();    
             v.resize((padded_size >> 2) as usize, 0xFF0000FF);
@@ -3550,10 +3546,7 @@ impl Hle {
         let width = (self.command >> 32) & 0x0FFF;
         let addr  = self.command as u32;
 
-        let translated_addr = (if (addr & 0xE000_0000) != 0 { addr } else {
-            let segment = ((addr >> 24) & 0x0F) as usize;
-            self.segments[segment] + (addr & 0x007F_FFFF)
-        } & 0x007F_FFFF);
+        let translated_addr = self.segmented_address(addr);
 
         let fmtstr = match fmt {
             0 => "G_IM_FMT_RGBA", 1 => "G_IM_FMT_YUV", 2 => "G_IM_FMT_CI", 3 => "G_IM_FMT_IA",
@@ -3575,10 +3568,7 @@ impl Hle {
     fn handle_setzimg(&mut self) { // G_SETZIMG (S3DEX2, F3DEX2)
         let addr = self.command as u32;
 
-        let translated_addr = if (addr & 0xE000_0000) != 0 { addr } else {
-            let segment = ((addr >> 24) & 0x0F) as usize;
-            (self.segments[segment] + (addr & 0x007F_FFFF)) & 0x007F_FFFF
-        } & 0x07FF_FFFF;
+        let translated_addr = self.segmented_address(addr);
 
         trace!(target: "HLE", "{} gsDPSetDepthImage(0x{:08X} [0x{:08X}])", self.command_prefix, addr, translated_addr);
 
@@ -3603,10 +3593,7 @@ impl Hle {
         let bpp   = ((self.command >> 51) & 0x03) as u8;
         let fmt   = ((self.command >> 53) & 0x07) as u8;
 
-        let translated_addr = if (addr & 0xE000_0000) != 0 { addr } else {
-            let segment = ((addr >> 24) & 0x0F) as usize;
-            (self.segments[segment] + (addr & 0x007F_FFFF)) & 0x007F_FFFF
-        } & 0x07FF_FFFF;
+        let translated_addr = self.segmented_address(addr);
 
         trace!(target: "HLE", "{} gsDPSetColorImage({}, {}, {}, 0x{:08X} [0x{:08X}])", self.command_prefix, fmt, bpp, width, addr, translated_addr);
 
@@ -3888,3 +3875,51 @@ impl OtherModes {
         ((self.lo >> 16) & 0x0C00) == 0x0800
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hle over 4MiB of RDRAM, with the last word of RDRAM and the word at $0030_0010 set
+    fn hle() -> Hle {
+        let comms = SystemCommunication::new(None);
+        comms.settings.write().unwrap().expansion_pak = false;
+        let _ri = rdram::RdramInterface::new(comms.clone());
+        {
+            let mut access = comms.rdram.write().unwrap();
+            let rdram = access.as_deref_mut().unwrap();
+            rdram[0x0030_0010 >> 2] = 0x1122_3344;
+            rdram[(0x0040_0000 >> 2) - 1] = 0x5566_7788;
+        }
+        Hle::new(comms, Arc::new(HleCommandBuffer::with_capacity(16)))
+    }
+
+    #[test]
+    fn segmented_addresses() {
+        let mut hle = hle();
+        hle.segments[1] = 0x0030_0000;
+        hle.segments[2] = 0x00FF_FFF0;
+
+        assert_eq!(hle.segmented_address(0x0100_0010), 0x0030_0010);
+        assert_eq!(hle.segmented_address(0x0000_0010), 0x0000_0010);
+        assert_eq!(hle.segmented_address(0x8030_0010), 0x0030_0010);
+        assert_eq!(hle.segmented_address(0xA030_0010), 0x0030_0010);
+
+        // the offset isn't limited to the size of RDRAM, and the sum wraps at 16MiB
+        assert_eq!(hle.segmented_address(0x0080_0000), 0x0080_0000);
+        assert_eq!(hle.segmented_address(0x0200_0020), 0x0000_0010);
+    }
+
+    #[test]
+    fn reads_outside_of_rdram_are_zero() {
+        let hle = hle();
+        assert_eq!(hle.rdram_size(), 0x0040_0000);
+
+        assert_eq!(hle.load_from_rdram(0x0030_0010, 8), vec![0x1122_3344, 0]);
+        assert_eq!(hle.load_from_rdram(0x003F_FFFC, 8), vec![0x5566_7788, 0]);
+        assert_eq!(hle.load_from_rdram(0x0070_0000, 16), vec![0; 4]);
+
+        // lengths are rounded up to 8 bytes
+        assert_eq!(hle.load_from_rdram(0x00FF_FFF8, 4).len(), 2);
+    }
+}
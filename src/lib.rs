@@ -11,14 +11,20 @@ use std::sync::atomic::{AtomicU32, AtomicBool, Ordering};
 use atomic_counter::{AtomicCounter, RelaxedCounter};
 
 pub mod audio;
+#[cfg(target_arch="x86_64")]
 pub mod avx512f_wrapper;
+pub mod block_cache;
+pub mod cache;
 pub mod cop1;
 pub mod cpu;
 pub mod debugger;
 pub mod hle;
+pub mod limiter;
 pub mod mips;
 pub mod peripheral;
 pub mod pifrom;
+#[cfg(any(not(target_arch="x86_64"), feature="portable-rsp", test))]
+pub mod portable_intrinsics;
 pub mod rcp;
 pub mod rdp;
 pub mod rdram;
@@ -33,7 +39,8 @@ pub enum WriteReturnSignal {
 #[derive(Debug)]
 pub enum ReadWriteFault {
     Invalid,
-    Break
+    Break,
+    BusError, // nothing on the bus responded to the address
 }
 
 pub trait Addressable {
@@ -88,6 +95,18 @@ pub trait Addressable {
     fn write_block(&mut self, offset: usize, _block: &[u32], _length: u32) -> Result<WriteReturnSignal, ReadWriteFault> {
         todo!("write_block not implemented for address offset ${offset:08X}");
     }
+
+    // cycles the cpu had to wait on slow devices since the last call
+    fn take_stall_cycles(&mut self) -> u64 {
+        0
+    }
+}
+
+// Reads that nothing answers see what's left on the bus from the address phase, which is the low
+// 16 bits of the address in both halves
+pub fn open_bus(address: usize) -> u32 {
+    let low = (address & 0xFFFF) as u32;
+    (low << 16) | low
 }
 
 pub struct LockedAddressable<T> {
@@ -154,8 +173,39 @@ pub struct Tweakables {
 }
 
 // Settings -- normal things people may want to configure (like antialiasing, audio playback rate, etc.)
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy)]
 pub struct Settings {
+    // emulation speed relative to real hardware
+    pub speed: limiter::EmulationSpeed,
+
+    // whether the CPU runs compiled code, and whether it's checked against the interpreter
+    #[cfg(feature = "jit")]
+    pub jit: cpu::JitMode,
+
+    // do COP1 arithmetic in software so results don't depend on the host FPU
+    pub soft_float: bool,
+
+    // 8MiB of RDRAM with the Expansion Pak installed, otherwise the base 4MiB
+    pub expansion_pak: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            speed        : limiter::EmulationSpeed::default(),
+            #[cfg(feature = "jit")]
+            jit          : cpu::JitMode::default(),
+            soft_float   : false,
+            expansion_pak: true,
+        }
+    }
+}
+
+impl Settings {
+    // bytes of RDRAM fitted, which decides how much memory is allocated at power on
+    pub fn rdram_size(&self) -> usize {
+        if self.expansion_pak { 8 * 1024 * 1024 } else { 4 * 1024 * 1024 }
+    }
 }
 
 // Collection of thread-safe channels for the front end to communicate with the emulating system
@@ -171,10 +221,8 @@ pub struct SystemCommunication {
 
     // current render framebuffer
     pub vi_origin: Arc<AtomicU32>,
-    // framebuffer width
-    pub vi_width: Arc<AtomicU32>,
-    // framebuffer format
-    pub vi_format: Arc<AtomicU32>, // 2 = 5/5/5/3 (16bpp), 3 = 8/8/8/8 (32bpp)
+    // VI registers needed to composite the displayed image
+    pub vi_state: Arc<RwLock<video::ViState>>,
 
     // interrupt signal
     pub mi_interrupts_tx: Option<mpsc::Sender<mips::InterruptUpdate>>,
@@ -189,6 +237,12 @@ pub struct SystemCommunication {
     // direct access to RDRAM as a speed optimization (rather than going through all the RCP code)
     pub rdram: Arc<RwLock<Option<Vec<u32>>>>,
 
+    // physical address to RDRAM module translation, for anything that reads `rdram` directly
+    pub rdram_map: Arc<rdram::RdramMap>,
+
+    // RDRAM pages the CPU has decoded code from, so writes to them can invalidate it
+    pub code_pages: Arc<block_cache::CodePages>,
+
     // current controller states
     pub controllers: Arc<RwLock<Vec<ControllerState>>>,
 
@@ -206,13 +260,14 @@ impl SystemCommunication {
             reset_signal      : Arc::new(AtomicU32::new(0)),
             total_cpu_steps   : Arc::new(RelaxedCounter::new(0)),
             vi_origin         : Arc::new(AtomicU32::new(0)),
-            vi_width          : Arc::new(AtomicU32::new(0)),
-            vi_format         : Arc::new(AtomicU32::new(0)),
+            vi_state          : Arc::new(RwLock::new(video::ViState::default())),
             mi_interrupts_tx  : None,
             break_cpu_cycles  : Arc::new(AtomicBool::new(false)),
             rdp_full_sync     : Arc::new(AtomicU32::new(0)),
             start_dma_tx      : None,
             rdram             : Arc::new(RwLock::new(None)),
+            rdram_map         : Arc::new(rdram::RdramMap::new(Settings::default().rdram_size())),
+            code_pages        : Arc::new(block_cache::CodePages::new(Settings::default().rdram_size() as u64)),
             controllers       : Arc::new(RwLock::new(vec![ControllerState::default(); 4])),
             settings          : Arc::new(RwLock::new(Settings::default())),
             tweakables        : Arc::new(RwLock::new(Tweakables::default())),
@@ -232,7 +287,12 @@ pub struct System {
 }
 
 impl System {
-    pub fn new(comms: SystemCommunication, boot_rom_file_name: &str, cartridge_file_name: &str) -> System {
+    pub fn new(mut comms: SystemCommunication, boot_rom_file_name: &str, cartridge_file_name: &str) -> System {
+        // settings can change after comms is created, so size the RDRAM maps to the RDRAM actually fitted
+        let rdram_size = comms.settings.read().unwrap().rdram_size();
+        comms.rdram_map = Arc::new(rdram::RdramMap::new(rdram_size));
+        comms.code_pages = Arc::new(block_cache::CodePages::new(rdram_size as u64));
+
         // load cartridge into memory
         let cartridge_rom = fs::read(cartridge_file_name).expect("Could not open cartridge ROM file");
 
@@ -244,7 +304,7 @@ impl System {
         rcp.borrow_mut().start();
 
         // create the CPU with reference to the bus
-        let cpu = cpu::Cpu::new(rcp.clone());
+        let cpu = cpu::Cpu::new(rcp.clone(), comms.clone());
 
         System {
             comms: comms,
@@ -274,8 +334,9 @@ impl System {
         let mut cycles_ran = 0;
         while cycles_ran < cpu_cycles && !self.comms.break_cpu_cycles.load(Ordering::SeqCst) {
             self.cpu.step()?;
-            cycles_ran += 1;
-            self.comms.total_cpu_steps.inc();
+            let cycles = self.cpu.cycles();
+            cycles_ran += cycles;
+            self.comms.total_cpu_steps.add(cycles as usize);
         }
 
         // set here, since rcp.step() could re-set it, e.g., another dma needs to happen
@@ -310,12 +371,33 @@ impl System {
     }
 
     pub fn run(&mut self) {
+        let mut limiter = limiter::SpeedLimiter::new();
+        let mut last_field = self.rcp.borrow().vi.field_count();
+        let mut last_cpu_steps = self.comms.total_cpu_steps.get();
+
         loop { 
             let num_cycles = self.rcp.borrow().calculate_free_cycles();
             let _ = self.step(num_cycles); 
+
+            // pace emulation once per displayed field
+            let field = self.rcp.borrow().vi.field_count();
+            if field != last_field {
+                let cpu_steps = self.comms.total_cpu_steps.get();
+                let speed = self.comms.settings.read().unwrap().speed;
+                limiter.sync((cpu_steps - last_cpu_steps) as u64, speed);
+                last_field = field;
+                last_cpu_steps = cpu_steps;
+            }
         }
     }
 
+    // composite the image the VI is currently displaying
+    pub fn screenshot(&self) -> Option<video::ViFrame> {
+        let vi_state = *self.comms.vi_state.read().unwrap();
+        let framebuffer = self.comms.rdram.read().unwrap().as_deref().map(|rdram| vi_state.copy_framebuffer(rdram, &self.comms.rdram_map))?;
+        vi_state.compose(&framebuffer)
+    }
+
 }
 
 #[derive(Default, Debug, Copy, Clone)]
@@ -0,0 +1,184 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[allow(unused_imports)]
+use tracing::{debug,error,trace,warn,info};
+
+// How fast emulation runs relative to real hardware
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum EmulationSpeed {
+    // real hardware speed
+    #[default]
+    Normal,
+
+    // N times faster than hardware, or as fast as possible when N is 0
+    FastForward(u32),
+
+    // N times slower than hardware
+    SlowMotion(u32),
+}
+
+impl EmulationSpeed {
+    // fastest fixed fast-forward rate before switching to unlimited
+    const MAX_FAST_FORWARD: u32 = 8;
+    const MAX_SLOW_MOTION: u32 = 8;
+
+    // speed relative to hardware, or None when unlimited
+    pub fn multiplier(&self) -> Option<f64> {
+        match *self {
+            EmulationSpeed::Normal         => Some(1.0),
+            EmulationSpeed::FastForward(0) => None,
+            EmulationSpeed::FastForward(n) => Some(n as f64),
+            EmulationSpeed::SlowMotion(n)  => Some(1.0 / (n.max(1) as f64)),
+        }
+    }
+
+    // next step up: 1/8x .. 1/2x, 1x, 2x .. 8x, unlimited
+    pub fn faster(self) -> Self {
+        match self {
+            EmulationSpeed::SlowMotion(n) if n > 2 => EmulationSpeed::SlowMotion(n / 2),
+            EmulationSpeed::SlowMotion(_)          => EmulationSpeed::Normal,
+            EmulationSpeed::Normal                 => EmulationSpeed::FastForward(2),
+            EmulationSpeed::FastForward(0)         => EmulationSpeed::FastForward(0),
+            EmulationSpeed::FastForward(n) if n >= Self::MAX_FAST_FORWARD => EmulationSpeed::FastForward(0),
+            EmulationSpeed::FastForward(n)         => EmulationSpeed::FastForward(n * 2),
+        }
+    }
+
+    // next step down, stopping at 1/8x
+    pub fn slower(self) -> Self {
+        match self {
+            EmulationSpeed::FastForward(0)          => EmulationSpeed::FastForward(Self::MAX_FAST_FORWARD),
+            EmulationSpeed::FastForward(n) if n > 2 => EmulationSpeed::FastForward(n / 2),
+            EmulationSpeed::FastForward(_)          => EmulationSpeed::Normal,
+            EmulationSpeed::Normal                  => EmulationSpeed::SlowMotion(2),
+            EmulationSpeed::SlowMotion(n) if n >= Self::MAX_SLOW_MOTION => EmulationSpeed::SlowMotion(Self::MAX_SLOW_MOTION),
+            EmulationSpeed::SlowMotion(n)           => EmulationSpeed::SlowMotion(n * 2),
+        }
+    }
+}
+
+impl fmt::Display for EmulationSpeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            EmulationSpeed::Normal         => write!(f, "1x"),
+            EmulationSpeed::FastForward(0) => write!(f, "unlimited"),
+            EmulationSpeed::FastForward(n) => write!(f, "{}x", n),
+            EmulationSpeed::SlowMotion(n)  => write!(f, "1/{}x", n),
+        }
+    }
+}
+
+// Keeps emulated time in step with wall clock time. The system calls sync() once per VI field
+// with the number of CPU cycles emulated, so pacing follows the refresh rate the game programmed
+pub struct SpeedLimiter {
+    start: Instant,
+    cycles: u64,
+    speed: EmulationSpeed,
+}
+
+impl SpeedLimiter {
+    const CPU_FREQ: f64 = 93_750_000.0;
+
+    // if emulation falls further behind than this (slow host, debugger pause, etc.), don't try to
+    // catch up by running unthrottled
+    const MAX_LAG: Duration = Duration::from_millis(100);
+
+    pub fn new() -> Self {
+        Self {
+            start : Instant::now(),
+            cycles: 0,
+            speed : EmulationSpeed::Normal,
+        }
+    }
+
+    fn restart(&mut self, speed: EmulationSpeed) {
+        self.start = Instant::now();
+        self.cycles = 0;
+        self.speed = speed;
+    }
+
+    pub fn sync(&mut self, cpu_cycles: u64, speed: EmulationSpeed) {
+        if speed != self.speed {
+            info!(target: "LIMITER", "emulation speed changed to {}", speed);
+            self.restart(speed);
+        }
+
+        let multiplier = match speed.multiplier() {
+            Some(multiplier) => multiplier,
+            None => return,
+        };
+
+        self.cycles += cpu_cycles;
+        let target = Duration::from_secs_f64((self.cycles as f64) / (Self::CPU_FREQ * multiplier));
+        let elapsed = self.start.elapsed();
+
+        if elapsed < target {
+            std::thread::sleep(target - elapsed);
+        } else if (elapsed - target) > Self::MAX_LAG {
+            debug!(target: "LIMITER", "emulation is running {:?} behind", elapsed - target);
+            self.restart(speed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_steps() {
+        let mut speed = EmulationSpeed::SlowMotion(8);
+        let mut steps = vec![speed.to_string()];
+        while speed != EmulationSpeed::FastForward(0) {
+            speed = speed.faster();
+            steps.push(speed.to_string());
+        }
+        assert_eq!(steps, ["1/8x", "1/4x", "1/2x", "1x", "2x", "4x", "8x", "unlimited"]);
+        assert_eq!(speed.faster(), EmulationSpeed::FastForward(0));
+
+        while speed != EmulationSpeed::SlowMotion(8) {
+            speed = speed.slower();
+            steps.pop();
+            assert_eq!(speed.to_string(), *steps.last().unwrap());
+        }
+        assert_eq!(speed.slower(), EmulationSpeed::SlowMotion(8));
+
+        assert_eq!(EmulationSpeed::Normal.multiplier(), Some(1.0));
+        assert_eq!(EmulationSpeed::FastForward(4).multiplier(), Some(4.0));
+        assert_eq!(EmulationSpeed::SlowMotion(2).multiplier(), Some(0.5));
+        assert_eq!(EmulationSpeed::SlowMotion(0).multiplier(), Some(1.0));
+        assert_eq!(EmulationSpeed::FastForward(0).multiplier(), None);
+    }
+
+    #[test]
+    fn sync_paces_to_the_speed() {
+        let mut limiter = SpeedLimiter::new();
+
+        // 20ms of CPU time at 1x, then 10ms of CPU time at 1/2x
+        let start = Instant::now();
+        limiter.sync((SpeedLimiter::CPU_FREQ * 0.02) as u64, EmulationSpeed::Normal);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let start = Instant::now();
+        limiter.sync((SpeedLimiter::CPU_FREQ * 0.01) as u64, EmulationSpeed::SlowMotion(2));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(limiter.speed, EmulationSpeed::SlowMotion(2));
+
+        // unlimited never waits or counts
+        limiter.sync(u64::MAX / 2, EmulationSpeed::FastForward(0));
+        assert_eq!(limiter.cycles, 0);
+    }
+
+    #[test]
+    fn sync_gives_up_catching_up() {
+        let mut limiter = SpeedLimiter::new();
+        limiter.sync(1000, EmulationSpeed::Normal);
+
+        // a long pause starts over rather than running flat out until emulation catches up
+        limiter.start = Instant::now() - SpeedLimiter::MAX_LAG * 2;
+        limiter.sync(1000, EmulationSpeed::Normal);
+        assert_eq!(limiter.cycles, 0);
+        assert!(limiter.start.elapsed() < SpeedLimiter::MAX_LAG);
+    }
+}
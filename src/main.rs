@@ -15,6 +15,7 @@ use clap::Parser;
 
 use n64::{System, SystemCommunication};
 use n64::debugger::Debugger;
+use n64::limiter::EmulationSpeed;
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = "long about string")]
@@ -26,6 +27,22 @@ struct Args {
     #[arg(short('D'), long)]
     debug: bool,
 
+    /// Wait for a GDB remote connection on localhost:PORT instead of entering the debugger prompt
+    #[arg(long, value_name("PORT"))]
+    gdb: Option<u16>,
+
+    /// Load debugger symbols from an ELF, linker map or decomp symbol file
+    #[arg(long, value_name("FILE"))]
+    symbols: Option<String>,
+
+    /// Enter the debugger and run the commands in FILE before the prompt. Can be specified multiple times.
+    #[arg(short('x'), long("execute"), value_name("FILE"), conflicts_with("gdb"))]
+    execute: Vec<String>,
+
+    /// Run the -x scripts without a prompt, exiting with 1 if a command fails or the status given to quit
+    #[arg(long, requires("execute"))]
+    batch: bool,
+
     /// Increase logging verbosity. Can be specified multiple times. Default is WARN, then INFO -> DEBUG -> TRACE.
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
@@ -45,6 +62,28 @@ struct Args {
     /// Synchronize the UI with the Game render (useful for RenderDoc captures)
     #[arg(short('S'), long("syncui"))]
     sync_ui_to_game: bool,
+
+    /// Run as fast as possible instead of at the speed of real hardware
+    #[arg(short('u'), long("unlimited"))]
+    unlimited_speed: bool,
+
+    /// Run the CPU in the interpreter only
+    #[cfg(feature="jit")]
+    #[arg(long("no-jit"))]
+    no_jit: bool,
+
+    /// Check every block of recompiled code against the interpreter and log any differences
+    #[cfg(feature="jit")]
+    #[arg(long("jit-check"), conflicts_with("no_jit"))]
+    jit_check: bool,
+
+    /// Use software floating point for the FPU, so results are identical on every host
+    #[arg(long("soft-float"))]
+    soft_float: bool,
+
+    /// Run without the Expansion Pak, with only the base 4MiB of RDRAM
+    #[arg(long("no-expansion-pak"))]
+    no_expansion_pak: bool,
 }
 
 fn main() {
@@ -121,16 +160,66 @@ fn main() {
     }
 
     let program_rom = args.game_file.clone();
+    let unlimited_speed = args.unlimited_speed;
+    let soft_float = args.soft_float;
+    let expansion_pak = !args.no_expansion_pak;
+    #[cfg(feature="jit")]
+    let jit_mode = if args.jit_check {
+        n64::cpu::JitMode::SelfCheck
+    } else if args.no_jit {
+        n64::cpu::JitMode::Disabled
+    } else {
+        n64::cpu::JitMode::Enabled
+    };
     let make_system = move |comms: SystemCommunication| {
+        if unlimited_speed {
+            comms.settings.write().unwrap().speed = EmulationSpeed::FastForward(0);
+        }
+        comms.settings.write().unwrap().soft_float = soft_float;
+        comms.settings.write().unwrap().expansion_pak = expansion_pak;
+        #[cfg(feature="jit")]
+        {
+            comms.settings.write().unwrap().jit = jit_mode;
+        }
         System::new(comms, "bios/pifrom.z64", &program_rom)
     };
 
     // either run or debug
-    if args.debug {
+    if let Some(port) = args.gdb {
         let comms = SystemCommunication::new(None);
         let mut debugger = Debugger::new(make_system(comms), change_logging);
-        println!("Entering debugger...");
-        debugger.run().expect("Debugger failed");
+        load_symbols(&mut debugger, &args.symbols);
+        debugger.run_gdb_server(port).expect("GDB server failed");
+    } else if args.debug || !args.execute.is_empty() {
+        let comms = SystemCommunication::new(None);
+        let mut debugger = Debugger::new(make_system(comms), change_logging);
+        load_symbols(&mut debugger, &args.symbols);
+
+        let mut failed = false;
+        for script in args.execute.iter() {
+            if let Err(err) = debugger.source(script) {
+                println!("error: {}", err);
+                failed = true;
+                break;
+            }
+        }
+
+        let status = if args.batch {
+            Some(debugger.exit_status().unwrap_or(if failed { 1 } else { 0 }))
+        } else {
+            println!("Entering debugger...");
+            debugger.run().expect("Debugger failed");
+            debugger.exit_status()
+        };
+
+        if let Err(err) = debugger.finish() {
+            println!("error: {}", err);
+            std::process::exit(1);
+        }
+
+        if let Some(status) = status {
+            std::process::exit(status);
+        }
     } else {
         cfg_if! {
             if #[cfg(feature="headless")] {
@@ -160,3 +249,12 @@ fn main() {
     }
 }
 
+
+fn load_symbols(debugger: &mut Debugger, path: &Option<String>) {
+    if let Some(path) = path {
+        match debugger.load_symbols(path) {
+            Ok(count) => info!("loaded {} symbols from {}", count, path),
+            Err(err) => error!("{}", err),
+        }
+    }
+}
@@ -201,7 +201,9 @@ impl Addressable for MipsInterface {
                 }
             },
 
-            _ => panic!("MI: unhandled write32 ${:08X}", offset),
+            _ => {
+                error!(target: "MI", "unimplemented write32 value=${:08X} offset=${:08X}", value, offset);
+            },
         };
 
         Ok(WriteReturnSignal::None)
@@ -10,6 +10,44 @@ use crate::*;
 use rcp::DmaInfo;
 use mips::{InterruptUpdate, InterruptUpdateMode, IMask_PI};
 
+/// Bus timing for one of the two PI domains, programmed via PI_BSD_DOMx_* in RCP clocks
+#[derive(Copy, Clone, Debug)]
+struct BusDomain {
+    latency: u32,
+    pulse_width: u32,
+    page_size: u32,
+    release: u32,
+}
+
+impl BusDomain {
+    // the usual values found in cartridge headers for ROM (domain 1) and SRAM (domain 2),
+    // so timing is sane even if the boot code never programs them
+    const DEFAULT_DOM1: BusDomain = BusDomain { latency: 0x40, pulse_width: 0x12, page_size: 0x07, release: 0x03 };
+    const DEFAULT_DOM2: BusDomain = BusDomain { latency: 0x05, pulse_width: 0x0C, page_size: 0x0D, release: 0x02 };
+
+    fn page_bytes(&self) -> u32 {
+        1 << (self.page_size + 2)
+    }
+
+    // Time in CPU cycles to transfer `length` bytes starting at `address`. Every page costs the
+    // latency, then each 16-bit word on the bus costs the pulse width and release time
+    fn transfer_cycles(&self, address: u32, length: u32) -> u64 {
+        let page_bytes = self.page_bytes();
+        let mut address = address;
+        let mut remaining = length;
+        let mut rcp_cycles = 0u64;
+        while remaining > 0 {
+            let chunk = cmp::min(remaining, page_bytes - (address & (page_bytes - 1)));
+            rcp_cycles += (self.latency as u64 + 1) + (((chunk as u64) + 1) >> 1) * ((self.pulse_width as u64 + 1) + (self.release as u64 + 1));
+            address += chunk;
+            remaining -= chunk;
+        }
+
+        // the RCP runs at 62.5MHz, 2/3 of the CPU clock
+        (rcp_cycles * 3) / 2
+    }
+}
+
 /// N64 Peripheral Interface
 /// Connects EEPROM, cartridge, controllers, and more
 pub struct PeripheralInterface {
@@ -18,11 +56,22 @@ pub struct PeripheralInterface {
     dram_addr: u32,
     cart_addr: u32,
     dma_status: u32,
-    io_busy: u32,
+
+    // PI_BSD_DOM1_* and PI_BSD_DOM2_*
+    domains: [BusDomain; 2],
+
+    // cpu cycle when the current DMA finishes, and whether the data has been moved yet
+    dma_busy_until: u64,
+    dma_transferred: bool,
+
+    // cpu cycle when the current single word write finishes
+    io_busy_until: u64,
+
+    // cycles the cpu had to wait on the last access
+    stall_cycles: u64,
 
     cartridge_rom: Vec<u32>,
     cartridge_rom_write: Option<u32>,
-    cartridge_rom_write_time: u64,
 
     dma_completed_rx: mpsc::Receiver<DmaInfo>,
     dma_completed_tx: mpsc::Sender<DmaInfo>,
@@ -52,11 +101,15 @@ impl PeripheralInterface {
             dram_addr: 0,
             cart_addr: 0,
             dma_status: 0,
-            io_busy: 0,
+
+            domains: [BusDomain::DEFAULT_DOM1, BusDomain::DEFAULT_DOM2],
+            dma_busy_until: 0,
+            dma_transferred: false,
+            io_busy_until: 0,
+            stall_cycles: 0,
 
             cartridge_rom: word_rom,
             cartridge_rom_write: None,
-            cartridge_rom_write_time: 0,
 
             dma_completed_rx: dma_completed_rx,
             dma_completed_tx: dma_completed_tx,
@@ -75,7 +128,11 @@ impl PeripheralInterface {
         self.dram_addr    = 0;
         self.cart_addr    = 0;
         self.dma_status   = 0;
-        self.io_busy      = 0;
+        self.domains      = [BusDomain::DEFAULT_DOM1, BusDomain::DEFAULT_DOM2];
+        self.dma_busy_until  = 0;
+        self.dma_transferred = false;
+        self.io_busy_until   = 0;
+        self.stall_cycles    = 0;
         self.debug_buffer = vec![0; 0xFFE0];
         self.debug_string = String::new();
         self.is_magic     = 0;
@@ -86,78 +143,126 @@ impl PeripheralInterface {
     }
 
     pub fn step(&mut self) {
-        // if dma is running, check for dma completed
-        if let Ok(_) = self.dma_completed_rx.try_recv() {
-            if (self.dma_status & 0x01) != 0 {
-                self.dma_status = 0x08;
-                self.comms.mi_interrupts_tx.as_ref().unwrap().send(InterruptUpdate(IMask_PI, InterruptUpdateMode::SetInterrupt)).unwrap();
-            }
+        self.update_dma();
+    }
+
+    fn now(&self) -> u64 {
+        self.comms.total_cpu_steps.get() as u64
+    }
+
+    // the DMA data is moved right away, but the busy flag and interrupt wait until the
+    // transfer would have finished on the bus
+    fn update_dma(&mut self) {
+        while let Ok(_) = self.dma_completed_rx.try_recv() {
+            self.dma_transferred = true;
+        }
+
+        if (self.dma_status & 0x01) != 0 && self.dma_transferred && self.now() >= self.dma_busy_until {
+            self.dma_status = 0x08;
+            self.comms.mi_interrupts_tx.as_ref().unwrap().send(InterruptUpdate(IMask_PI, InterruptUpdateMode::SetInterrupt)).unwrap();
+        }
+    }
+
+    fn start_dma(&mut self, dma_info: DmaInfo, cart_address: u32) {
+        let duration = self.domain(cart_address).transfer_cycles(cart_address, dma_info.length);
+        trace!(target: "PI", "DMA of {} bytes will take {} cycles", dma_info.length, duration);
+
+        self.dma_status |= 0x01;
+        self.dma_busy_until = self.now() + duration;
+        self.dma_transferred = false;
+        self.comms.start_dma_tx.as_ref().unwrap().send(dma_info).unwrap();
+        self.comms.break_cpu();
+    }
+
+    pub fn calculate_free_cycles(&self) -> u64 {
+        if (self.dma_status & 0x01) != 0 {
+            cmp::max(1, self.dma_busy_until.saturating_sub(self.now()))
+        } else {
+            u64::MAX
         }
     }
 
+    // extra cycles the cpu waited on the last access to the PI bus
+    pub fn take_stall_cycles(&mut self) -> u64 {
+        std::mem::replace(&mut self.stall_cycles, 0)
+    }
+
+    // domain 2 covers the 64DD registers and SRAM/FlashRAM, everything else is domain 1
+    fn domain(&self, address: u32) -> BusDomain {
+        let address = address & 0x1FFF_FFFF;
+        if (address >= 0x0500_0000 && address < 0x0600_0000) || (address >= 0x0800_0000 && address < 0x1000_0000) {
+            self.domains[1]
+        } else {
+            self.domains[0]
+        }
+    }
+
+    fn io_busy(&self) -> bool {
+        self.now() < self.io_busy_until
+    }
+
     fn read_register(&mut self, offset: usize) -> Result<u32, ReadWriteFault> {
         match offset & 0xF_FFFF {
             // PI_STATUS
             0x0_0010 => {
                 trace!(target: "PI", "read PI_STATUS");
 
-                let ret = self.dma_status | self.io_busy;
-
-                if self.io_busy != 0 {
-                    self.io_busy = 0;
-                }
-
-                Ok(ret)
+                // the IO busy flag is also set for the whole of a DMA
+                self.update_dma();
+                Ok(self.dma_status | if self.io_busy() || (self.dma_status & 0x01) != 0 { 0x02 } else { 0 })
             },
 
             // PI_BSD_DOM1_LAT
             0x0_0014 => {
-                info!(target: "PI", "read PI_BSD_DOM1_LAT");
-                Ok(0)
+                trace!(target: "PI", "read PI_BSD_DOM1_LAT");
+                Ok(self.domains[0].latency)
             },
 
             // PI_BSD_DOM1_PWD
             0x0_0018 => {
-                info!(target: "PI", "read PI_BSD_DOM1_PWD");
-                Ok(0)
+                trace!(target: "PI", "read PI_BSD_DOM1_PWD");
+                Ok(self.domains[0].pulse_width)
             },
 
             // PI_BSD_DOM1_PGS
             0x0_001C => {
-                info!(target: "PI", "read PI_BSD_DOM1_PGS");
-                Ok(0)
+                trace!(target: "PI", "read PI_BSD_DOM1_PGS");
+                Ok(self.domains[0].page_size)
             },
 
             // PI_BSD_DOM1_RLS
             0x0_0020 => {
-                info!(target: "PI", "read PI_BSD_DOM1_RLS");
-                Ok(0)
+                trace!(target: "PI", "read PI_BSD_DOM1_RLS");
+                Ok(self.domains[0].release)
             },
 
             // PI_BSD_DOM2_LAT
             0x0_0024 => {
-                info!(target: "PI", "read PI_BSD_DOM2_LAT");
-                Ok(0)
+                trace!(target: "PI", "read PI_BSD_DOM2_LAT");
+                Ok(self.domains[1].latency)
             },
 
             // PI_BSD_DOM2_PWD
             0x0_0028 => {
-                info!(target: "PI", "read PI_BSD_DOM2_PWD");
-                Ok(0)
+                trace!(target: "PI", "read PI_BSD_DOM2_PWD");
+                Ok(self.domains[1].pulse_width)
             },
 
             // PI_BSD_DOM2_PGS
             0x0_002C => {
-                info!(target: "PI", "read PI_BSD_DOM2_PGS");
-                Ok(0)
+                trace!(target: "PI", "read PI_BSD_DOM2_PGS");
+                Ok(self.domains[1].page_size)
             },
 
             // PI_BSD_DOM2_RLS
             0x0_0030 => {
-                info!(target: "PI", "read PI_BSD_DOM2_RLS");
-                Ok(0)
+                trace!(target: "PI", "read PI_BSD_DOM2_RLS");
+                Ok(self.domains[1].release)
+            },
+            _ => {
+                warn!(target: "PI", "read from unmapped PI register ${:08X}", offset);
+                Ok(open_bus(offset))
             },
-            _ => panic!("PI: unhandled register read ${:08X}", offset),
         }
     }
 
@@ -186,11 +291,7 @@ impl PeripheralInterface {
                 //info!(target: "PI", "DMA INTO PI from DRAM=${:08X} DEST=${:08X}", self.dram_addr, self.cart_addr);
 
                 // see if previous dma has completed
-                if (self.dma_status & 0x01) != 0 {
-                    if let Ok(_) = self.dma_completed_rx.try_recv() {
-                        self.dma_status = 0x08;
-                    }
-                }
+                self.update_dma();
 
                 if (self.dma_status & 0x01) == 0 { // don't initiate another DMA if one is in progress
                     if self.cart_addr >= 0x0800_0000 && self.cart_addr < 0x1000_0000 {
@@ -207,9 +308,7 @@ impl PeripheralInterface {
                             ..Default::default()
                         };
 
-                        self.dma_status |= 0x01;
-                        self.comms.start_dma_tx.as_ref().unwrap().send(dma_info).unwrap();
-                        self.comms.break_cpu();
+                        self.start_dma(dma_info, self.cart_addr);
                     }
                 }
 
@@ -220,17 +319,16 @@ impl PeripheralInterface {
             0x0_000C => {
                 trace!(target: "PI", "write PI_WR_LEN value=${:08X}", value);
 
-                assert!((self.cart_addr & 0xF000_0000) == 0x1000_0000 ||
-                        (self.cart_addr & 0xF800_0000) == 0x0800_0000, "PI DMA initiated from ${:08X}", self.cart_addr); // right now only cartridge rom is valid for dma
+                // right now only cartridge rom and sram are valid for dma
+                if (self.cart_addr & 0xF000_0000) != 0x1000_0000 && (self.cart_addr & 0xF800_0000) != 0x0800_0000 {
+                    warn!(target: "PI", "ignoring PI DMA from unsupported address ${:08X}", self.cart_addr);
+                    return Ok(WriteReturnSignal::None);
+                }
 
                 // TODO the logic determining DMA completions might not be correct, but it's fine for now.
 
                 // see if previous dma has completed
-                if (self.dma_status & 0x01) != 0 {
-                    if let Ok(_) = self.dma_completed_rx.try_recv() {
-                        self.dma_status = 0x08;
-                    }
-                }
+                self.update_dma();
 
                 if (self.dma_status & 0x01) == 0 { // don't initiate another DMA if one is in progress
                     if self.cart_addr < 0x1000_0000 {
@@ -247,9 +345,7 @@ impl PeripheralInterface {
                             ..Default::default()
                         };
 
-                        self.dma_status |= 0x01;
-                        self.comms.start_dma_tx.as_ref().unwrap().send(dma_info).unwrap();
-                        self.comms.break_cpu();
+                        self.start_dma(dma_info, self.cart_addr);
                         WriteReturnSignal::None
                     } else {
                         let start = self.cart_addr & !0xF000_0000;
@@ -268,16 +364,15 @@ impl PeripheralInterface {
                                 ..Default::default()
                             };
 
-                            self.dma_status |= 0x01;
-                            self.comms.start_dma_tx.as_ref().unwrap().send(dma_info).unwrap();
-                            self.comms.break_cpu();
+                            self.start_dma(dma_info, self.cart_addr);
                             WriteReturnSignal::None
                         } else {
                             WriteReturnSignal::None
                         }
                     }
                 } else {
-                    panic!("might need to handle this case at some point");
+                    warn!(target: "PI", "ignoring PI_WR_LEN write while a DMA is in progress");
+                    WriteReturnSignal::None
                 }
             },
 
@@ -286,6 +381,9 @@ impl PeripheralInterface {
                 trace!(target: "PI", "write PI_STATUS value=${:08X}", value);
 
                 if (value & 0x01) != 0 { // DMA stop/reset
+                    // data already moved stays moved, but the DMA no longer completes
+                    self.dma_status &= !0x01;
+                    self.io_busy_until = 0;
                 }
 
                 if (value & 0x02) != 0 { // clear INT flag 
@@ -298,53 +396,64 @@ impl PeripheralInterface {
 
             // PI_BSD_DOM1_LAT
             0x0_0014 => {
-                trace!(target: "PI", "write PI_BSD_DOM1_LAT");
+                trace!(target: "PI", "write PI_BSD_DOM1_LAT value=${:08X}", value);
+                self.domains[0].latency = value & 0xFF;
                 WriteReturnSignal::None
             },
 
             // PI_BSD_DOM1_PWD
             0x0_0018 => {
-                trace!(target: "PI", "write PI_BSD_DOM1_PWD");
+                trace!(target: "PI", "write PI_BSD_DOM1_PWD value=${:08X}", value);
+                self.domains[0].pulse_width = value & 0xFF;
                 WriteReturnSignal::None
             },
 
             // PI_BSD_DOM1_PGS
             0x0_001C => {
-                trace!(target: "PI", "write PI_BSD_DOM1_PGS");
+                trace!(target: "PI", "write PI_BSD_DOM1_PGS value=${:08X}", value);
+                self.domains[0].page_size = value & 0x0F;
                 WriteReturnSignal::None
             },
 
             // PI_BSD_DOM1_RLS
             0x0_0020 => {
-                trace!(target: "PI", "write PI_BSD_DOM1_RLS");
+                trace!(target: "PI", "write PI_BSD_DOM1_RLS value=${:08X}", value);
+                self.domains[0].release = value & 0x03;
                 WriteReturnSignal::None
             },
 
             // PI_BSD_DOM2_LAT
             0x0_0024 => {
-                trace!(target: "PI", "write PI_BSD_DOM2_LAT");
+                trace!(target: "PI", "write PI_BSD_DOM2_LAT value=${:08X}", value);
+                self.domains[1].latency = value & 0xFF;
                 WriteReturnSignal::None
             },
 
             // PI_BSD_DOM2_PWD
             0x0_0028 => {
-                trace!(target: "PI", "write PI_BSD_DOM2_PWD");
+                trace!(target: "PI", "write PI_BSD_DOM2_PWD value=${:08X}", value);
+                self.domains[1].pulse_width = value & 0xFF;
                 WriteReturnSignal::None
             },
 
             // PI_BSD_DOM2_PGS
             0x0_002C => {
-                trace!(target: "PI", "write PI_BSD_DOM2_PGS");
+                trace!(target: "PI", "write PI_BSD_DOM2_PGS value=${:08X}", value);
+                self.domains[1].page_size = value & 0x0F;
                 WriteReturnSignal::None
             },
 
             // PI_BSD_DOM2_RLS
             0x0_0030 => {
-                trace!(target: "PI", "write PI_BSD_DOM2_RLS");
+                trace!(target: "PI", "write PI_BSD_DOM2_RLS value=${:08X}", value);
+                self.domains[1].release = value & 0x03;
                 WriteReturnSignal::None
             },
 
-            _ => panic!("PI: unhandled register write ${:08X}", offset),
+            _ => {
+                warn!(target: "PI", "write to unmapped PI register ${:08X} value=${:08X}", offset, value);
+                WriteReturnSignal::None
+            },
         };
 
         Ok(result)
@@ -383,20 +492,20 @@ impl Addressable for PeripheralInterface {
             let cartridge_rom_offset = offset & 0x0FFF_FFFF;
             //debug!(target: "CART", "read32 offset=${:08X}", cartridge_rom_offset);
 
-            match self.cartridge_rom_write {
-                Some(value) => {
-                    let delta = (self.comms.total_cpu_steps.get() as u64) - self.cartridge_rom_write_time;
-                    self.cartridge_rom_write = None;
-                    if delta < 50 { // TODO I don't konw the correct value for this write decay, but 
-                                    // 50 is working on n64-systemtests
-                        return Ok(value)
-                    }
-                },
-                _ => {},
+            // a read while a write is still on the bus waits for it, and gets the written
+            // value back from the PI latch instead of ROM
+            if self.io_busy() {
+                self.stall_cycles += self.io_busy_until - self.now();
+                if let Some(value) = self.cartridge_rom_write.take() {
+                    return Ok(value);
+                }
             }
+            self.cartridge_rom_write = None;
+            self.stall_cycles += self.domain(offset as u32).transfer_cycles(offset as u32, 4);
 
+            // nothing answers past the end of the ROM
             if cartridge_rom_offset >= self.cartridge_rom.len() * 4 {
-                Ok(0x00000000)
+                Ok(open_bus(offset))
             } else {
                 Ok(self.cartridge_rom[(cartridge_rom_offset >> 2) as usize])
             }
@@ -405,8 +514,7 @@ impl Addressable for PeripheralInterface {
             Ok(0)
         } else {
             debug!(target: "PI", "open bus read at ${:08X}", offset);
-            // lower 16 bits repeated in both halves of the word
-            Ok((((offset & 0xFFFF) << 16) | (offset & 0xFFFF)) as u32)
+            Ok(open_bus(offset))
         }
     }
 
@@ -436,14 +544,13 @@ impl Addressable for PeripheralInterface {
             self.is_magic = value;
             Ok(WriteReturnSignal::None)
         } else if offset == 0x13FF_0014 { // ISViewer put - set write position
-            let end = value as usize;
+            let end = cmp::min(value as usize, self.debug_buffer.len());
 
             let slice = if end < self.is_write_pos { 
-                todo!();
-                //// need to concatenate two slices, is_write_pos..END and START..end
-                //let slice_a = &self.debug_buffer[self.is_write_pos..];
-                //let slice_b = &self.debug_buffer[..end];
-                //[slice_a, slice_b].concat()
+                // the write position wrapped, so concatenate is_write_pos..END and START..end
+                let slice_a = &self.debug_buffer[self.is_write_pos..];
+                let slice_b = &self.debug_buffer[..end];
+                [slice_a, slice_b].concat()
             } else {
                 (&self.debug_buffer[self.is_write_pos..end]).to_vec()
             };
@@ -492,15 +599,11 @@ impl Addressable for PeripheralInterface {
             Ok(WriteReturnSignal::None)
         } else if offset >= 0x1000_0000 && offset < 0x1FC0_0000 {
             //debug!(target: "PI", "wrote to ROM value=${:08X} offset=${:08X}", value, offset);
-            match self.cartridge_rom_write {
-                // if cartridge_rom_write isn't set, note the first write to cart
-                None => {
-                    self.cartridge_rom_write = Some(value);
-                    self.cartridge_rom_write_time = self.comms.total_cpu_steps.get() as u64;
-                    // set the IO busy flag of PI_STATUS on cart write
-                    self.io_busy = 0x02;
-                },
-                _ => {},
+            // writes are ignored while the PI is busy with the previous one. otherwise the value
+            // is latched and the IO busy flag of PI_STATUS is set until the write completes
+            if !self.io_busy() {
+                self.cartridge_rom_write = Some(value);
+                self.io_busy_until = self.now() + self.domain(offset as u32).transfer_cycles(offset as u32, 4);
             }
             Ok(WriteReturnSignal::None)
         } else if offset >= 0x1FFF_0000 && offset < 0x2000_0000 {
@@ -575,3 +678,88 @@ impl Addressable for PeripheralInterface {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn busy_flags_stay_set_during_dma() {
+        let mut comms = SystemCommunication::new(None);
+        let (dma_tx, _dma_rx) = mpsc::channel();
+        let (interrupts_tx, _interrupts_rx) = mpsc::channel();
+        comms.start_dma_tx = Some(dma_tx);
+        comms.mi_interrupts_tx = Some(interrupts_tx);
+
+        let mut pi = PeripheralInterface::new(comms.clone(), vec![0; 0x1000]);
+        pi.write_u32(0x0000_0000, 0x0460_0000).unwrap(); // PI_DRAM_ADDR
+        pi.write_u32(0x1000_0000, 0x0460_0004).unwrap(); // PI_CART_ADDR
+        pi.write_u32(0x0000_0FFF, 0x0460_000C).unwrap(); // PI_WR_LEN
+
+        // DMA busy and IO busy until the transfer time has passed, even once the data has moved
+        pi.dma_completed_tx.send(DmaInfo::default()).unwrap();
+        assert_eq!(pi.read_u32(0x0460_0010).unwrap() & 0x0B, 0x03);
+
+        comms.total_cpu_steps.add(pi.calculate_free_cycles() as usize);
+        assert_eq!(pi.read_u32(0x0460_0010).unwrap() & 0x0B, 0x08);
+    }
+
+    #[test]
+    fn dma_duration_follows_the_domain_registers() {
+        let mut comms = SystemCommunication::new(None);
+        let (dma_tx, _dma_rx) = mpsc::channel();
+        let (interrupts_tx, interrupts_rx) = mpsc::channel();
+        comms.start_dma_tx = Some(dma_tx);
+        comms.mi_interrupts_tx = Some(interrupts_tx);
+
+        // 8 byte pages, and only the low bits of each register are kept
+        let mut pi = PeripheralInterface::new(comms.clone(), vec![0; 0x1000]);
+        pi.write_u32(0xFFFF_FF10, 0x0460_0014).unwrap(); // PI_BSD_DOM1_LAT
+        pi.write_u32(0xFFFF_FF03, 0x0460_0018).unwrap(); // PI_BSD_DOM1_PWD
+        pi.write_u32(0xFFFF_FFF1, 0x0460_001C).unwrap(); // PI_BSD_DOM1_PGS
+        pi.write_u32(0xFFFF_FFFD, 0x0460_0020).unwrap(); // PI_BSD_DOM1_RLS
+        assert_eq!(pi.read_u32(0x0460_0014).unwrap(), 0x10);
+        assert_eq!(pi.read_u32(0x0460_0018).unwrap(), 0x03);
+        assert_eq!(pi.read_u32(0x0460_001C).unwrap(), 0x01);
+        assert_eq!(pi.read_u32(0x0460_0020).unwrap(), 0x01);
+
+        // 16 bytes from the middle of a page touch three pages, of 4, 8 and 4 bytes. Each costs
+        // LAT+1, and each 16-bit word PWD+1 and RLS+1 RCP cycles: (17+2*6) + (17+4*6) + (17+2*6)
+        // is 99 RCP cycles, or 148 CPU cycles
+        pi.write_u32(0x0000_0000, 0x0460_0000).unwrap(); // PI_DRAM_ADDR
+        pi.write_u32(0x1000_0004, 0x0460_0004).unwrap(); // PI_CART_ADDR
+        pi.write_u32(0x0000_000F, 0x0460_000C).unwrap(); // PI_WR_LEN
+        pi.dma_completed_tx.send(DmaInfo::default()).unwrap();
+        assert_eq!(pi.calculate_free_cycles(), 148);
+
+        comms.total_cpu_steps.add(147);
+        assert_eq!(pi.read_u32(0x0460_0010).unwrap() & 0x0B, 0x03);
+        assert!(interrupts_rx.try_recv().is_err());
+
+        comms.total_cpu_steps.add(1);
+        assert_eq!(pi.read_u32(0x0460_0010).unwrap() & 0x0B, 0x08);
+        assert!(interrupts_rx.try_recv().is_ok());
+
+        // a single word write to ROM is one page of two 16-bit words: 17+2*6 RCP cycles
+        pi.write_u32(0x1234_5678, 0x1000_0000).unwrap();
+        comms.total_cpu_steps.add(42);
+        assert_eq!(pi.read_u32(0x0460_0010).unwrap() & 0x03, 0x02);
+        comms.total_cpu_steps.add(1);
+        assert_eq!(pi.read_u32(0x0460_0010).unwrap() & 0x03, 0x00);
+    }
+
+    #[test]
+    fn unmapped_reads_return_open_bus() {
+        let mut pi = PeripheralInterface::new(SystemCommunication::new(None), vec![0x5A; 0x1000]);
+
+        // registers past PI_BSD_DOM2_RLS, with writes ignored
+        assert_eq!(pi.read_u32(0x0460_0040).unwrap(), 0x0040_0040);
+        pi.write_u32(0x1234_5678, 0x0460_0040).unwrap();
+        assert_eq!(pi.read_u32(0x0460_0040).unwrap(), 0x0040_0040);
+
+        // the end of the cartridge ROM
+        assert_eq!(pi.read_u32(0x1000_0FFC).unwrap(), 0x5A5A_5A5A);
+        assert_eq!(pi.read_u32(0x1000_1000).unwrap(), 0x1000_1000);
+        assert_eq!(pi.read_u32(0x1012_3454).unwrap(), 0x3454_3454);
+    }
+}
@@ -258,7 +258,7 @@ impl PifRom {
             } else if (value & 0x08) != 0 {
                 info!(target: "PIF", "IPL1 finished");
             } else if (value & 0x07) != 0 {
-                panic!("PIF: not implemented PIF command ${:08X}", value);
+                warn!(target: "PIF", "not implemented PIF command ${:08X}", value);
             }
         }
     }
@@ -300,8 +300,10 @@ impl PifRom {
                     continue 'cmd_loop;
                 },
 
-                0x3D => { // reset current channel
-                    todo!("reset channel");
+                0x3D => { // reset current channel. nothing needs resetting, so move on to next
+                    debug!(target: "JOY", "{}: reset channel {}", cmd_count - 1, channel);
+                    channel += 1;
+                    continue 'cmd_loop;
                 },
 
                 0x3E => { // end of commands
@@ -418,8 +420,7 @@ impl PifRom {
 
                         _ => {
                             error!(target: "JOY", "unhandled joybus command ${:02X} on channel {}", cmd, channel);
-                            unimplemented!();
-                            //break 'cmd_loop;
+                            break 'cmd_loop;
                         }
                     }
 
@@ -474,7 +475,8 @@ impl Addressable for PifRom {
                 //info!(target: "PIF-ROM", "read offset=${:08X}", offset);
                 Ok(self.ram[ram_offset as usize])
             } else {
-                panic!("unhandled PIF read offset=${:08X}", offset)
+                warn!(target: "PIF", "read32 from unmapped offset=${:08X}", offset);
+                Ok(open_bus(offset))
             }
         }
     }
@@ -490,7 +492,7 @@ impl Addressable for PifRom {
                     self.update_control_write();
                 }
             } else {
-                panic!("PIF: invalid write value=${:08X} offset=${:08X}", value, offset);
+                warn!(target: "PIF", "write32 to unmapped offset=${:08X} value=${:08X}", offset, value);
             }
         }
 
@@ -0,0 +1,738 @@
+// Portable versions of the x86 SIMD intrinsics used by the RSP vector unit
+// This module replaces both core::arch::x86_64 and avx512f_wrapper on targets other than x86_64,
+// or on x86_64 with the "portable-rsp" feature so the two can be compared against each other.
+// Every function follows the definition of the x86 instruction it's named after, lane by lane,
+// and keeps the same (unsafe) signature so the vector unit code doesn't change
+#![allow(non_camel_case_types)]
+
+use core::array;
+use core::simd::Simd;
+
+// 8x16-bit
+#[derive(Debug, Copy, Clone)]
+pub struct __m128i(Simd<i16, 8>);
+
+// 8x32-bit
+#[derive(Debug, Copy, Clone)]
+pub struct __m256i(Simd<i32, 8>);
+
+// 8x64-bit
+#[derive(Debug, Copy, Clone)]
+pub struct __wm512i(Simd<i64, 8>);
+
+pub type __mmask8 = u8;
+
+// Byte views are little endian like x86, so byte 0 is the low byte of lane 0 on any host
+impl __m128i {
+    #[inline(always)]
+    fn lanes(&self) -> [i16; 8] {
+        self.0.to_array()
+    }
+
+    #[inline(always)]
+    fn from_lanes(lanes: [i16; 8]) -> Self {
+        Self(Simd::from_array(lanes))
+    }
+
+    #[inline(always)]
+    fn bytes(&self) -> [u8; 16] {
+        let lanes = self.lanes();
+        array::from_fn(|i| lanes[i >> 1].to_le_bytes()[i & 1])
+    }
+
+    #[inline(always)]
+    fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self::from_lanes(array::from_fn(|i| i16::from_le_bytes([bytes[i*2], bytes[i*2+1]])))
+    }
+
+    #[inline(always)]
+    fn i32s(&self) -> [i32; 4] {
+        let bytes = self.bytes();
+        array::from_fn(|i| i32::from_le_bytes(bytes[i*4..i*4+4].try_into().unwrap()))
+    }
+
+    #[inline(always)]
+    fn i64s(&self) -> [i64; 2] {
+        let bytes = self.bytes();
+        array::from_fn(|i| i64::from_le_bytes(bytes[i*8..i*8+8].try_into().unwrap()))
+    }
+}
+
+impl __m256i {
+    #[inline(always)]
+    fn lanes(&self) -> [i32; 8] {
+        self.0.to_array()
+    }
+
+    #[inline(always)]
+    fn from_lanes(lanes: [i32; 8]) -> Self {
+        Self(Simd::from_array(lanes))
+    }
+
+    #[inline(always)]
+    fn map(&self, f: impl Fn(i32) -> i32) -> Self {
+        let lanes = self.lanes();
+        Self::from_lanes(array::from_fn(|i| f(lanes[i])))
+    }
+}
+
+impl __wm512i {
+    #[inline(always)]
+    fn lanes(&self) -> [i64; 8] {
+        self.0.to_array()
+    }
+
+    #[inline(always)]
+    fn from_lanes(lanes: [i64; 8]) -> Self {
+        Self(Simd::from_array(lanes))
+    }
+
+    #[inline(always)]
+    fn map(&self, f: impl Fn(i64) -> i64) -> Self {
+        let lanes = self.lanes();
+        Self::from_lanes(array::from_fn(|i| f(lanes[i])))
+    }
+}
+
+#[inline(always)]
+fn mask8(f: impl Fn(usize) -> bool) -> __mmask8 {
+    (0..8).fold(0, |mask, i| if f(i) { mask | (1 << i) } else { mask })
+}
+
+// 128-bit
+
+#[inline(always)]
+pub unsafe fn _mm_setzero_si128() -> __m128i {
+    __m128i(Simd::splat(0))
+}
+
+#[inline(always)]
+pub unsafe fn _mm_set1_epi8(a: i8) -> __m128i {
+    __m128i::from_bytes([a as u8; 16])
+}
+
+#[inline(always)]
+pub unsafe fn _mm_set1_epi16(a: i16) -> __m128i {
+    __m128i(Simd::splat(a))
+}
+
+#[inline(always)]
+pub unsafe fn _mm_set_epi8(e15: i8, e14: i8, e13: i8, e12: i8, e11: i8, e10: i8, e9: i8, e8: i8,
+                           e7: i8, e6: i8, e5: i8, e4: i8, e3: i8, e2: i8, e1: i8, e0: i8) -> __m128i {
+    __m128i::from_bytes([e0, e1, e2, e3, e4, e5, e6, e7, e8, e9, e10, e11, e12, e13, e14, e15].map(|e| e as u8))
+}
+
+#[inline(always)]
+pub unsafe fn _mm_set_epi64x(e1: i64, e0: i64) -> __m128i {
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&e0.to_le_bytes());
+    bytes[8..].copy_from_slice(&e1.to_le_bytes());
+    __m128i::from_bytes(bytes)
+}
+
+#[inline(always)]
+pub unsafe fn _mm_extract_epi8(a: __m128i, imm8: i32) -> i32 {
+    a.bytes()[(imm8 & 15) as usize] as i32
+}
+
+#[inline(always)]
+pub unsafe fn _mm_extract_epi16(a: __m128i, imm8: i32) -> i32 {
+    a.lanes()[(imm8 & 7) as usize] as u16 as i32
+}
+
+#[inline(always)]
+pub unsafe fn _mm_extract_epi32(a: __m128i, imm8: i32) -> i32 {
+    a.i32s()[(imm8 & 3) as usize]
+}
+
+#[inline(always)]
+pub unsafe fn _mm_extract_epi64(a: __m128i, imm1: i32) -> i64 {
+    a.i64s()[(imm1 & 1) as usize]
+}
+
+#[inline(always)]
+pub unsafe fn _mm_insert_epi8(a: __m128i, i: i32, imm8: i32) -> __m128i {
+    let mut bytes = a.bytes();
+    bytes[(imm8 & 15) as usize] = i as u8;
+    __m128i::from_bytes(bytes)
+}
+
+#[inline(always)]
+pub unsafe fn _mm_add_epi16(a: __m128i, b: __m128i) -> __m128i {
+    __m128i(a.0 + b.0)
+}
+
+#[inline(always)]
+pub unsafe fn _mm_sub_epi16(a: __m128i, b: __m128i) -> __m128i {
+    __m128i(a.0 - b.0)
+}
+
+#[inline(always)]
+pub unsafe fn _mm_sub_epi8(a: __m128i, b: __m128i) -> __m128i {
+    let (a, b) = (a.bytes(), b.bytes());
+    __m128i::from_bytes(array::from_fn(|i| a[i].wrapping_sub(b[i])))
+}
+
+#[inline(always)]
+pub unsafe fn _mm_and_si128(a: __m128i, b: __m128i) -> __m128i {
+    __m128i(a.0 & b.0)
+}
+
+#[inline(always)]
+pub unsafe fn _mm_or_si128(a: __m128i, b: __m128i) -> __m128i {
+    __m128i(a.0 | b.0)
+}
+
+#[inline(always)]
+pub unsafe fn _mm_xor_si128(a: __m128i, b: __m128i) -> __m128i {
+    __m128i(a.0 ^ b.0)
+}
+
+#[inline(always)]
+pub unsafe fn _mm_srli_epi16(a: __m128i, imm8: i32) -> __m128i {
+    __m128i::from_lanes(a.lanes().map(|v| if imm8 > 15 { 0 } else { ((v as u16) >> imm8) as i16 }))
+}
+
+#[inline(always)]
+pub unsafe fn _mm_cmpeq_epi16(a: __m128i, b: __m128i) -> __m128i {
+    let (a, b) = (a.lanes(), b.lanes());
+    __m128i::from_lanes(array::from_fn(|i| if a[i] == b[i] { -1 } else { 0 }))
+}
+
+// negate, zero or keep each lane of a by the sign of the lane in b
+#[inline(always)]
+pub unsafe fn _mm_sign_epi16(a: __m128i, b: __m128i) -> __m128i {
+    let (a, b) = (a.lanes(), b.lanes());
+    __m128i::from_lanes(array::from_fn(|i| match b[i] {
+        0 => 0,
+        x if x < 0 => a[i].wrapping_neg(),
+        _ => a[i],
+    }))
+}
+
+// pick bytes from b where the mask byte has its top bit set
+#[inline(always)]
+pub unsafe fn _mm_blendv_epi8(a: __m128i, b: __m128i, mask: __m128i) -> __m128i {
+    let (a, b, mask) = (a.bytes(), b.bytes(), mask.bytes());
+    __m128i::from_bytes(array::from_fn(|i| if (mask[i] & 0x80) != 0 { b[i] } else { a[i] }))
+}
+
+// select bytes of a by the low 4 bits of each byte in b, or zero when bit 7 is set
+#[inline(always)]
+pub unsafe fn _mm_shuffle_epi8(a: __m128i, b: __m128i) -> __m128i {
+    let (a, b) = (a.bytes(), b.bytes());
+    __m128i::from_bytes(array::from_fn(|i| if (b[i] & 0x80) != 0 { 0 } else { a[(b[i] & 0x0F) as usize] }))
+}
+
+// 256-bit
+
+#[inline(always)]
+pub unsafe fn _mm256_setzero_si256() -> __m256i {
+    __m256i(Simd::splat(0))
+}
+
+#[inline(always)]
+pub unsafe fn _mm256_set1_epi32(a: i32) -> __m256i {
+    __m256i(Simd::splat(a))
+}
+
+#[inline(always)]
+pub unsafe fn _mm256_extract_epi32(a: __m256i, index: i32) -> i32 {
+    a.lanes()[(index & 7) as usize]
+}
+
+#[inline(always)]
+pub unsafe fn _mm256_extract_epi64(a: __m256i, index: i32) -> i64 {
+    let lanes = a.lanes();
+    let index = ((index & 3) * 2) as usize;
+    ((lanes[index + 1] as i64) << 32) | (lanes[index] as u32 as i64)
+}
+
+#[inline(always)]
+pub unsafe fn _mm256_cvtepi16_epi32(a: __m128i) -> __m256i {
+    __m256i::from_lanes(a.lanes().map(|v| v as i32))
+}
+
+#[inline(always)]
+pub unsafe fn _mm256_cvtepu16_epi32(a: __m128i) -> __m256i {
+    __m256i::from_lanes(a.lanes().map(|v| v as u16 as i32))
+}
+
+#[inline(always)]
+pub unsafe fn _mm256_add_epi32(a: __m256i, b: __m256i) -> __m256i {
+    __m256i(a.0 + b.0)
+}
+
+#[inline(always)]
+pub unsafe fn _mm256_sub_epi32(a: __m256i, b: __m256i) -> __m256i {
+    __m256i(a.0 - b.0)
+}
+
+// low 32 bits of each product
+#[inline(always)]
+pub unsafe fn _mm256_mullo_epi32(a: __m256i, b: __m256i) -> __m256i {
+    __m256i(a.0 * b.0)
+}
+
+#[inline(always)]
+pub unsafe fn _mm256_and_si256(a: __m256i, b: __m256i) -> __m256i {
+    __m256i(a.0 & b.0)
+}
+
+#[inline(always)]
+pub unsafe fn _mm256_slli_epi32(a: __m256i, imm8: i32) -> __m256i {
+    a.map(|v| if imm8 > 31 { 0 } else { ((v as u32) << imm8) as i32 })
+}
+
+#[inline(always)]
+pub unsafe fn _mm256_srli_epi32(a: __m256i, imm8: i32) -> __m256i {
+    a.map(|v| if imm8 > 31 { 0 } else { ((v as u32) >> imm8) as i32 })
+}
+
+#[inline(always)]
+pub unsafe fn _mm256_srai_epi32(a: __m256i, imm8: i32) -> __m256i {
+    a.map(|v| v >> imm8.min(31))
+}
+
+#[inline(always)]
+pub unsafe fn _mm256_cmpeq_epi32(a: __m256i, b: __m256i) -> __m256i {
+    let (a, b) = (a.lanes(), b.lanes());
+    __m256i::from_lanes(array::from_fn(|i| if a[i] == b[i] { -1 } else { 0 }))
+}
+
+// top bit of each of the 32 bytes
+#[inline(always)]
+pub unsafe fn _mm256_movemask_epi8(a: __m256i) -> i32 {
+    a.lanes().iter().enumerate().fold(0u32, |mask, (i, v)| {
+        let v = *v as u32;
+        let bits = ((v >> 7) & 1) | ((v >> 14) & 2) | ((v >> 21) & 4) | ((v >> 28) & 8);
+        mask | (bits << (i * 4))
+    }) as i32
+}
+
+// gather the bits of a selected by mask into the low bits of the result
+#[inline(always)]
+pub unsafe fn _pext_u32(a: u32, mask: u32) -> u32 {
+    let mut result = 0;
+    let mut mask = mask;
+    let mut bit = 0;
+    while mask != 0 {
+        let lowest = mask & mask.wrapping_neg();
+        if (a & lowest) != 0 {
+            result |= 1 << bit;
+        }
+        bit += 1;
+        mask &= mask - 1;
+    }
+    result
+}
+
+// avx512f_wrapper
+
+#[inline(always)]
+pub fn _wmm512_setzero_si512() -> __wm512i {
+    __wm512i(Simd::splat(0))
+}
+
+#[inline(always)]
+pub fn _wmm512_set1_epi64(a: i64) -> __wm512i {
+    __wm512i(Simd::splat(a))
+}
+
+#[inline(always)]
+pub fn _wmm512_srai_epi64<const IMM8: u32>(a: __wm512i) -> __wm512i {
+    a.map(|v| v >> IMM8.min(63))
+}
+
+#[inline(always)]
+pub fn _wmm512_srli_epi64<const IMM8: u32>(a: __wm512i) -> __wm512i {
+    a.map(|v| if IMM8 > 63 { 0 } else { ((v as u64) >> IMM8) as i64 })
+}
+
+#[inline(always)]
+pub fn _wmm512_slli_epi64<const IMM8: u32>(a: __wm512i) -> __wm512i {
+    a.map(|v| if IMM8 > 63 { 0 } else { ((v as u64) << IMM8) as i64 })
+}
+
+#[inline(always)]
+pub fn _wmm512_add_epi64(a: __wm512i, b: __wm512i) -> __wm512i {
+    __wm512i(a.0 + b.0)
+}
+
+#[inline(always)]
+pub fn _wmm512_and_si256(a: __wm512i, b: __wm512i) -> __wm512i {
+    __wm512i(a.0 & b.0)
+}
+
+#[inline(always)]
+pub fn _wmm512_and_epi64(a: __wm512i, b: __wm512i) -> __wm512i {
+    __wm512i(a.0 & b.0)
+}
+
+#[inline(always)]
+pub fn _wmm512_or_epi64(a: __wm512i, b: __wm512i) -> __wm512i {
+    __wm512i(a.0 | b.0)
+}
+
+#[inline(always)]
+pub fn _wmm512_cmpge_epi64_mask(a: __wm512i, b: __wm512i) -> __mmask8 {
+    let (a, b) = (a.lanes(), b.lanes());
+    mask8(|i| a[i] >= b[i])
+}
+
+#[inline(always)]
+pub fn _wmm512_cmplt_epi64_mask(a: __wm512i, b: __wm512i) -> __mmask8 {
+    let (a, b) = (a.lanes(), b.lanes());
+    mask8(|i| a[i] < b[i])
+}
+
+#[inline(always)]
+pub fn _wmm256_cmpeq_epi32_mask(a: __m256i, b: __m256i) -> __mmask8 {
+    let (a, b) = (a.lanes(), b.lanes());
+    mask8(|i| a[i] == b[i])
+}
+
+#[inline(always)]
+pub fn _wmm256_cmpge_epi32_mask(a: __m256i, b: __m256i) -> __mmask8 {
+    let (a, b) = (a.lanes(), b.lanes());
+    mask8(|i| a[i] >= b[i])
+}
+
+#[inline(always)]
+pub fn _wmm256_cmple_epi32_mask(a: __m256i, b: __m256i) -> __mmask8 {
+    let (a, b) = (a.lanes(), b.lanes());
+    mask8(|i| a[i] <= b[i])
+}
+
+#[inline(always)]
+pub fn _wmm256_cmplt_epi32_mask(a: __m256i, b: __m256i) -> __mmask8 {
+    let (a, b) = (a.lanes(), b.lanes());
+    mask8(|i| a[i] < b[i])
+}
+
+#[inline(always)]
+pub fn _wmm_cmpge_epi16_mask(a: __m128i, b: __m128i) -> __mmask8 {
+    let (a, b) = (a.lanes(), b.lanes());
+    mask8(|i| a[i] >= b[i])
+}
+
+#[inline(always)]
+pub fn _wmm_cmpge_epu16_mask(a: __m128i, b: __m128i) -> __mmask8 {
+    let (a, b) = (a.lanes(), b.lanes());
+    mask8(|i| (a[i] as u16) >= (b[i] as u16))
+}
+
+#[inline(always)]
+pub fn _wmm_cmpgt_epi16_mask(a: __m128i, b: __m128i) -> __mmask8 {
+    let (a, b) = (a.lanes(), b.lanes());
+    mask8(|i| a[i] > b[i])
+}
+
+#[inline(always)]
+pub fn _wmm_cmple_epi16_mask(a: __m128i, b: __m128i) -> __mmask8 {
+    let (a, b) = (a.lanes(), b.lanes());
+    mask8(|i| a[i] <= b[i])
+}
+
+#[inline(always)]
+pub fn _wmm_cmplt_epi16_mask(a: __m128i, b: __m128i) -> __mmask8 {
+    let (a, b) = (a.lanes(), b.lanes());
+    mask8(|i| a[i] < b[i])
+}
+
+#[inline(always)]
+pub fn _wmm_cmpeq_epi16_mask(a: __m128i, b: __m128i) -> __mmask8 {
+    let (a, b) = (a.lanes(), b.lanes());
+    mask8(|i| a[i] == b[i])
+}
+
+#[inline(always)]
+pub fn _wmm_cmpneq_epi16_mask(a: __m128i, b: __m128i) -> __mmask8 {
+    let (a, b) = (a.lanes(), b.lanes());
+    mask8(|i| a[i] != b[i])
+}
+
+#[inline(always)]
+pub fn _wmm512_mask_blend_epi64(mask: __mmask8, a: __wm512i, b: __wm512i) -> __wm512i {
+    let (a, b) = (a.lanes(), b.lanes());
+    __wm512i::from_lanes(array::from_fn(|i| if (mask & (1 << i)) == 0 { a[i] } else { b[i] }))
+}
+
+#[inline(always)]
+pub fn _wmm256_mask_blend_epi32(mask: __mmask8, a: __m256i, b: __m256i) -> __m256i {
+    let (a, b) = (a.lanes(), b.lanes());
+    __m256i::from_lanes(array::from_fn(|i| if (mask & (1 << i)) == 0 { a[i] } else { b[i] }))
+}
+
+#[inline(always)]
+pub fn _wmm_mask_blend_epi16(mask: __mmask8, a: __m128i, b: __m128i) -> __m128i {
+    let (a, b) = (a.lanes(), b.lanes());
+    __m128i::from_lanes(array::from_fn(|i| if (mask & (1 << i)) == 0 { a[i] } else { b[i] }))
+}
+
+#[inline(always)]
+pub fn _wmm512_cvtepi32_epi64(v: __m256i) -> __wm512i {
+    __wm512i::from_lanes(v.lanes().map(|v| v as i64))
+}
+
+#[inline(always)]
+pub fn _wmm512_cvtepi64_epi16(v: __wm512i) -> __m128i {
+    __m128i::from_lanes(v.lanes().map(|v| v as i16))
+}
+
+#[inline(always)]
+pub fn _wmm512_cvtepi64_epi32(v: __wm512i) -> __m256i {
+    __m256i::from_lanes(v.lanes().map(|v| v as i32))
+}
+
+#[inline(always)]
+pub fn _wmm512_cvtepu16_epi64(v: __m128i) -> __wm512i {
+    __wm512i::from_lanes(v.lanes().map(|v| v as u16 as i64))
+}
+
+#[inline(always)]
+pub fn _wmm512_cvtepu32_epi64(v: __m256i) -> __wm512i {
+    __wm512i::from_lanes(v.lanes().map(|v| v as u32 as i64))
+}
+
+#[inline(always)]
+pub fn _wmm256_cvtepi32_epi16(v: __m256i) -> __m128i {
+    __m128i::from_lanes(v.lanes().map(|v| v as i16))
+}
+
+#[inline(always)]
+pub fn _wmm256_cvtsepi32_epi16(v: __m256i) -> __m128i {
+    __m128i::from_lanes(v.lanes().map(|v| v.clamp(i16::MIN as i32, i16::MAX as i32) as i16))
+}
+
+// The vector unit's sequences of intrinsics, built once from core::arch::x86_64 and the AVX-512
+// wrapper and once from this module, and run on the same inputs
+#[cfg(all(test, target_arch="x86_64"))]
+mod tests {
+    macro_rules! vector_ops {
+        () => {
+            use std::mem::transmute;
+
+            pub type V = [i16; 8];
+            pub type Acc = [i64; 8];
+
+            unsafe fn v(lanes: V) -> __m128i { transmute(lanes) }
+            unsafe fn lanes(v: __m128i) -> V { transmute(v) }
+            unsafe fn acc(acc: Acc) -> __wm512i { transmute(acc) }
+            unsafe fn acc_lanes(acc: __wm512i) -> Acc { transmute(acc) }
+
+            unsafe fn highmid(acc: __wm512i) -> __m256i {
+                _wmm512_cvtepi64_epi32(_wmm512_srli_epi64::<16>(acc))
+            }
+
+            // v_math_elements
+            pub unsafe fn elements(src: V, e: u8) -> V {
+                let src = v(src);
+                lanes(if e == 0 || e == 1 {
+                    src
+                } else if (e & 0x0E) == 0x02 {
+                    if (e & 0x01) == 0 {
+                        _mm_shuffle_epi8(src, _mm_set_epi8(15, 14, 15, 14, 11, 10, 11, 10, 7, 6, 7, 6, 3, 2, 3, 2))
+                    } else {
+                        _mm_shuffle_epi8(src, _mm_set_epi8(13, 12, 13, 12, 9, 8, 9, 8, 5, 4, 5, 4, 1, 0, 1, 0))
+                    }
+                } else if (e & 0x0C) == 0x04 {
+                    let i = 6 - ((e & 0x03) as i8) * 2;
+                    _mm_shuffle_epi8(src, _mm_set_epi8(i + 9, i + 8, i + 9, i + 8, i + 9, i + 8, i + 9, i + 8,
+                                                       i + 1, i, i + 1, i, i + 1, i, i + 1, i))
+                } else {
+                    let i = 14 - (((e & 0x07) as i16) << 1);
+                    _mm_shuffle_epi8(src, _mm_set1_epi16(((i + 1) << 8) | i))
+                })
+            }
+
+            pub unsafe fn vmulf(left: V, right: V) -> (Acc, V) {
+                let product = _mm256_mullo_epi32(_mm256_cvtepi16_epi32(v(left)), _mm256_cvtepi16_epi32(v(right)));
+                let vacc = _wmm512_add_epi64(_wmm512_slli_epi64::<1>(_wmm512_cvtepi32_epi64(product)), _wmm512_set1_epi64(0x8000));
+                (acc_lanes(vacc), lanes(_wmm256_cvtsepi32_epi16(highmid(vacc))))
+            }
+
+            pub unsafe fn vmacf(vacc: Acc, left: V, right: V) -> (Acc, V) {
+                let product = _mm256_mullo_epi32(_mm256_cvtepi16_epi32(v(left)), _mm256_cvtepi16_epi32(v(right)));
+                let vacc = _wmm512_add_epi64(acc(vacc), _wmm512_slli_epi64::<1>(_wmm512_cvtepi32_epi64(product)));
+                (acc_lanes(vacc), lanes(_wmm256_cvtsepi32_epi16(highmid(vacc))))
+            }
+
+            pub unsafe fn vmulu(left: V, right: V) -> (Acc, V) {
+                let product = _mm256_mullo_epi32(_mm256_cvtepi16_epi32(v(left)), _mm256_cvtepi16_epi32(v(right)));
+                let vacc = _wmm512_add_epi64(_wmm512_slli_epi64::<1>(_wmm512_cvtepi32_epi64(product)), _wmm512_set1_epi64(0x8000));
+                let highmid = highmid(vacc);
+                let high = _wmm256_cvtepi32_epi16(_mm256_srli_epi32(highmid, 16));
+                let mid  = _wmm256_cvtepi32_epi16(highmid);
+                let uppermask = _wmm_cmplt_epi16_mask(high, _mm_setzero_si128());
+                let halfmask  = _wmm_cmplt_epi16_mask(_mm_xor_si128(high, mid), _mm_setzero_si128());
+                let result = _wmm_mask_blend_epi16(!uppermask, _mm_setzero_si128(),
+                                                   _wmm_mask_blend_epi16(!halfmask, _mm_set1_epi16(0xFFFFu16 as i16), mid));
+                (acc_lanes(vacc), lanes(result))
+            }
+
+            pub unsafe fn vaddc(left: V, right: V) -> (V, u32) {
+                let result256 = _mm256_add_epi32(_mm256_cvtepu16_epi32(v(left)), _mm256_cvtepu16_epi32(v(right)));
+                let carry = _mm256_srli_epi32(_mm256_and_si256(result256, _mm256_set1_epi32(0x0001_0000)), 1);
+                (lanes(_wmm256_cvtepi32_epi16(result256)), _pext_u32(_mm256_movemask_epi8(carry) as u32, 0x2222_2222) & 0xFF)
+            }
+
+            pub unsafe fn vsubc(left: V, right: V) -> (V, u32, u32) {
+                let result256 = _mm256_sub_epi32(_mm256_cvtepu16_epi32(v(left)), _mm256_cvtepu16_epi32(v(right)));
+                let carry_bits = _pext_u32(_mm256_movemask_epi8(result256) as u32, 0x8888_8888) & 0xFF;
+                let zero_cmp = _mm256_cmpeq_epi32(result256, _mm256_setzero_si256());
+                let zero_bits = _pext_u32(!(_mm256_movemask_epi8(zero_cmp) as u32), 0x8888_8888) & 0xFF;
+                (lanes(_wmm256_cvtepi32_epi16(result256)), carry_bits, zero_bits)
+            }
+
+            pub unsafe fn vsub(left: V, right: V, vco: u8) -> (V, V) {
+                let carry_in = _wmm256_mask_blend_epi32(vco, _mm256_setzero_si256(), _mm256_set1_epi32(1));
+                let result256 = _mm256_sub_epi32(_mm256_sub_epi32(_mm256_cvtepi16_epi32(v(left)), _mm256_cvtepi16_epi32(v(right))), carry_in);
+                (lanes(_wmm256_cvtsepi32_epi16(result256)), lanes(_wmm256_cvtepi32_epi16(result256)))
+            }
+
+            pub unsafe fn vabs(left: V, right: V) -> V {
+                let (left, right) = (v(left), v(right));
+                let result = _mm_sign_epi16(right, left);
+                let left_cmp = _mm_and_si128(left, _mm_set1_epi16(0x8000u16 as i16));
+                let right_cmp = _mm_cmpeq_epi16(right, _mm_set1_epi16(0x8000u16 as i16));
+                let cmp = _mm_and_si128(left_cmp, right_cmp);
+                let cmp = _mm_or_si128(cmp, _mm_srli_epi16(cmp, 8));
+                lanes(_mm_blendv_epi8(result, _mm_set1_epi16(0x7FFF), cmp))
+            }
+
+            pub unsafe fn vcl(left: V, right: V, vcc: u16, vco: u16, vce: u8) -> (V, u16) {
+                let (left, right) = (v(left), v(right));
+                let (vcc_low, vcc_high) = (vcc as u8, (vcc >> 8) as u8);
+                let (vco_low, vco_high) = (vco as u8, (vco >> 8) as u8);
+
+                let result256 = _mm256_add_epi32(_mm256_cvtepu16_epi32(left), _mm256_cvtepu16_epi32(right));
+                let sum       = _wmm256_cvtepi32_epi16(result256);
+                let zero_sum  = (_wmm_cmpeq_epi16_mask(sum, _mm_setzero_si128()) as u8).reverse_bits();
+                let carry     = (_wmm256_cmpge_epi32_mask(result256, _mm256_set1_epi32(0x10000)) as u8).reverse_bits();
+
+                let mut new_vcc_low = (zero_sum & !carry) | (vce & (zero_sum | !carry));
+                new_vcc_low = (new_vcc_low & !vco_high) | (vcc_low & vco_high);
+                let vco_low_set_result = _wmm_mask_blend_epi16(new_vcc_low.reverse_bits(), left, _mm_sub_epi16(_mm_setzero_si128(), right));
+
+                let mut new_vcc_high = (_wmm_cmpge_epu16_mask(left, right) as u8).reverse_bits();
+                new_vcc_high = (new_vcc_high & !vco_high) | (vcc_high & vco_high);
+                let vco_low_clear_result = _wmm_mask_blend_epi16(new_vcc_high.reverse_bits(), left, right);
+
+                let result = _wmm_mask_blend_epi16(vco_low, vco_low_clear_result, vco_low_set_result);
+                new_vcc_low  = (new_vcc_low  &  vco_low) | (vcc_low  & !vco_low);
+                new_vcc_high = (new_vcc_high & !vco_low) | (vcc_high &  vco_low);
+                (lanes(result), ((new_vcc_high as u16) << 8) | (new_vcc_low as u16))
+            }
+
+            pub unsafe fn vch(left: V, right: V) -> (V, [u8; 8]) {
+                let (left, right) = (v(left), v(right));
+                let new_vco_low  = (_wmm_cmplt_epi16_mask(_mm_xor_si128(left, right), _mm_setzero_si128()) as u8).reverse_bits();
+                let vt_lt_zero   = (_wmm_cmplt_epi16_mask(right, _mm_setzero_si128()) as u8).reverse_bits();
+                let vt_ne_not_vs = (_wmm_cmpneq_epi16_mask(right, _mm_xor_si128(left, _mm_set1_epi8(0xFFu8 as i8))) as u8).reverse_bits();
+
+                let sum  = _mm_add_epi16(left, right);
+                let diff = _mm_sub_epi16(left, right);
+                let sum_le_zero  = (_wmm_cmple_epi16_mask(sum, _mm_setzero_si128()) as u8).reverse_bits();
+                let diff_ge_zero = (_wmm_cmpge_epi16_mask(diff, _mm_setzero_si128()) as u8).reverse_bits();
+                let sum_ne_zero  = (_wmm_cmpneq_epi16_mask(sum, _mm_setzero_si128()) as u8).reverse_bits();
+                let diff_ne_zero = (_wmm_cmpneq_epi16_mask(diff, _mm_setzero_si128()) as u8).reverse_bits();
+                let sum_eq_neg1  = (_wmm_cmpeq_epi16_mask(sum, _mm_set1_epi16(-1i16)) as u8).reverse_bits();
+
+                let vco_low_set_result   = _wmm_mask_blend_epi16(sum_le_zero.reverse_bits(), left, _mm_sub_epi16(_mm_setzero_si128(), right));
+                let vco_low_clear_result = _wmm_mask_blend_epi16(diff_ge_zero.reverse_bits(), left, right);
+                let result = _wmm_mask_blend_epi16(new_vco_low.reverse_bits(), vco_low_clear_result, vco_low_set_result);
+                (lanes(result), [new_vco_low, vt_lt_zero, vt_ne_not_vs, sum_le_zero, diff_ge_zero, sum_ne_zero, diff_ne_zero, sum_eq_neg1])
+            }
+        };
+    }
+
+    mod x86 {
+        use core::arch::x86_64::*;
+        use crate::avx512f_wrapper::*;
+        vector_ops!();
+    }
+
+    mod portable {
+        use crate::portable_intrinsics::*;
+        vector_ops!();
+    }
+
+    // xorshift, with the values most likely to go wrong mixed in
+    struct Inputs(u64);
+
+    impl Inputs {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn vector(&mut self) -> [i16; 8] {
+            const EDGES: [i16; 8] = [0, 1, -1, 0x7FFF, -0x8000, 0x4000, -0x4000, 0x7FFE];
+            std::array::from_fn(|_| {
+                let r = self.next();
+                if (r & 1) == 0 { EDGES[((r >> 1) & 7) as usize] } else { (r >> 16) as i16 }
+            })
+        }
+    }
+
+    const ROUNDS: usize = 10_000;
+
+    #[test]
+    fn element_selection_matches() {
+        let mut inputs = Inputs(0x1234_5678_9ABC_DEF1);
+        for _ in 0..ROUNDS {
+            let src = inputs.vector();
+            for e in 0..16 {
+                assert_eq!(unsafe { portable::elements(src, e) }, unsafe { x86::elements(src, e) }, "{:?}[{}]", src, e);
+            }
+        }
+    }
+
+    #[test]
+    fn multiplies_match() {
+        let mut inputs = Inputs(0x0F1E_2D3C_4B5A_6978);
+        for _ in 0..ROUNDS {
+            let (left, right) = (inputs.vector(), inputs.vector());
+            let vmulf = unsafe { x86::vmulf(left, right) };
+            assert_eq!(unsafe { portable::vmulf(left, right) }, vmulf, "vmulf {:?} {:?}", left, right);
+            assert_eq!(unsafe { portable::vmulu(left, right) }, unsafe { x86::vmulu(left, right) }, "vmulu {:?} {:?}", left, right);
+
+            // keep accumulating so the result saturates both ways
+            let mut vacc = vmulf.0;
+            for _ in 0..4 {
+                let (left, right) = (inputs.vector(), inputs.vector());
+                let vmacf = unsafe { x86::vmacf(vacc, left, right) };
+                assert_eq!(unsafe { portable::vmacf(vacc, left, right) }, vmacf, "vmacf {:?} {:?} {:?}", vacc, left, right);
+                vacc = vmacf.0;
+            }
+        }
+    }
+
+    #[test]
+    fn carries_and_saturation_match() {
+        let mut inputs = Inputs(0x7766_5544_3322_1100 | 1);
+        for _ in 0..ROUNDS {
+            let (left, right, vco) = (inputs.vector(), inputs.vector(), inputs.next() as u8);
+            assert_eq!(unsafe { portable::vaddc(left, right) }, unsafe { x86::vaddc(left, right) }, "vaddc {:?} {:?}", left, right);
+            assert_eq!(unsafe { portable::vsubc(left, right) }, unsafe { x86::vsubc(left, right) }, "vsubc {:?} {:?}", left, right);
+            assert_eq!(unsafe { portable::vsub(left, right, vco) }, unsafe { x86::vsub(left, right, vco) }, "vsub {:?} {:?} {:02X}", left, right, vco);
+            assert_eq!(unsafe { portable::vabs(left, right) }, unsafe { x86::vabs(left, right) }, "vabs {:?} {:?}", left, right);
+        }
+    }
+
+    #[test]
+    fn clip_compares_match() {
+        let mut inputs = Inputs(0x0123_4567_89AB_CDEF);
+        for _ in 0..ROUNDS {
+            let (left, right) = (inputs.vector(), inputs.vector());
+            let flags = inputs.next();
+            let (vcc, vco, vce) = (flags as u16, (flags >> 16) as u16, (flags >> 32) as u8);
+            assert_eq!(unsafe { portable::vcl(left, right, vcc, vco, vce) }, unsafe { x86::vcl(left, right, vcc, vco, vce) },
+                       "vcl {:?} {:?} {:04X} {:04X} {:02X}", left, right, vcc, vco, vce);
+            assert_eq!(unsafe { portable::vch(left, right) }, unsafe { x86::vch(left, right) }, "vch {:?} {:?}", left, right);
+        }
+    }
+}
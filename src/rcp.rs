@@ -3,7 +3,7 @@ use std::mem;
 use std::sync::{mpsc, Arc, Mutex};
 
 #[allow(unused_imports)]
-use tracing::{debug, error, trace, info};
+use tracing::{debug, error, trace, warn, info};
 
 use crate::*;
 
@@ -60,6 +60,36 @@ impl fmt::Debug for DmaInfo {
     }
 }
 
+// kind of access being decoded by match_addressable
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum BusAccess {
+    Read64, Read32, Read16, Read8, ReadBlock,
+    Write64, Write32, Write16, Write8, WriteBlock,
+}
+
+impl BusAccess {
+    fn is_write(&self) -> bool {
+        matches!(self, BusAccess::Write64 | BusAccess::Write32 | BusAccess::Write16 | BusAccess::Write8 | BusAccess::WriteBlock)
+    }
+}
+
+impl fmt::Display for BusAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            BusAccess::Read64     => "read64",
+            BusAccess::Read32     => "read32",
+            BusAccess::Read16     => "read16",
+            BusAccess::Read8      => "read8",
+            BusAccess::ReadBlock  => "read_block",
+            BusAccess::Write64    => "write64",
+            BusAccess::Write32    => "write32",
+            BusAccess::Write16    => "write16",
+            BusAccess::Write8     => "write8",
+            BusAccess::WriteBlock => "write_block",
+        })
+    }
+}
+
 /// N64 Reality Control Processor
 /// Contains the on-board RSP, RDP and manages the system bus
 pub struct Rcp {
@@ -68,7 +98,7 @@ pub struct Rcp {
     pub mi: MipsInterface,
     pi: PeripheralInterface,
     pub ri: RdramInterface,
-    rsp: Rsp,
+    pub rsp: Rsp,
     si: SerialInterface,
     pub vi: VideoInterface,
 
@@ -147,6 +177,7 @@ impl Rcp {
         // return the min cycles available to all the modules
         let mut cycles = u64::MAX;
         cycles = std::cmp::min(cycles, self.vi.calculate_free_cycles());
+        cycles = std::cmp::min(cycles, self.pi.calculate_free_cycles());
         cycles
     }
 
@@ -158,8 +189,8 @@ impl Rcp {
                 trace!(target: "DMA", "performing dma: DmaInfo = {:?}", dma_info);
             }
 
-            if let Err(_) = self.do_dma(&mut dma_info) {
-                todo!("handle dma error");
+            if let Err(fault) = self.do_dma(&mut dma_info) {
+                error!(target: "DMA", "dma failed with {:?}: DmaInfo = {:?}", fault, dma_info);
             }
 
             let cb_maybe = mem::replace(&mut dma_info.completed, None);
@@ -195,14 +226,15 @@ impl Rcp {
 
     // given an RCP bus address, return an addressable object on the bus with the given offset
     // adjusted relative to the addressable
-    fn match_addressable(&mut self, physical_address: usize, mode: &str) -> (Option<&mut dyn Addressable>, usize) {
-        let physical_address = physical_address & !0x8000_0000;
-
-        match physical_address & 0xFC00_0000 {
+    // returns None for addresses that read as open bus, and a BusError for addresses nothing responds to
+    fn match_addressable(&mut self, physical_address: usize, access: BusAccess) -> Result<(Option<&mut dyn Addressable>, usize), ReadWriteFault> {
+        Ok(match physical_address & 0xFC00_0000 {
             // RDRAM 0x00000000-0x03FFFFFF
             0x0000_0000 => {
-                let repeat_count = self.mi.get_repeat_count(); // set the repeat count on RDRAM writes
-                self.ri.set_repeat_count(repeat_count);
+                if access.is_write() {
+                    let repeat_count = self.mi.get_repeat_count(); // set the repeat count on RDRAM writes
+                    self.ri.set_repeat_count(repeat_count);
+                }
                 (Some(&mut self.ri), physical_address & 0x03FF_FFFF)
             },
 
@@ -231,16 +263,21 @@ impl Rcp {
                     // RDRAM 0x0470_0000-0x047F_FFFF
                     // we pass bit 26 along to indicate the RdramInterface vs RDRAM access
                     7 => {
-                        let repeat_count = self.mi.get_repeat_count();
-                        self.ri.set_repeat_count(repeat_count);
+                        if access.is_write() {
+                            let repeat_count = self.mi.get_repeat_count();
+                            self.ri.set_repeat_count(repeat_count);
+                        }
                         (Some(&mut self.ri), 0x0400_0000 | (offset & 0x000F_FFFF))
                     },
 
                     // SI 0x0480_0000-0x048F_FFFF
                     8 => (Some(&mut self.si), offset & 0x000F_FFFF),
 
-                    // 0x0409_0000-0x04FF_FFFF unmapped
-                    _ => panic!("invalid RCP {mode}"),
+                    // 0x0490_0000-0x04FF_FFFF unmapped, reads return open bus and writes are ignored
+                    _ => {
+                        warn!(target: "RCP", "{access} to unmapped RCP address ${:08X}", physical_address);
+                        (None, 0)
+                    },
                 }
             },
 
@@ -256,8 +293,11 @@ impl Rcp {
             },
 
             // 0x8000_0000 and up not mapped
-            _ => panic!("can't happen")
-        }
+            _ => {
+                warn!(target: "RCP", "{access} bus error at ${:08X}", physical_address);
+                return Err(ReadWriteFault::BusError);
+            },
+        })
     }
 
     // Slow, maybe at some point we can do more of a direct memory copy
@@ -290,47 +330,47 @@ impl Addressable for Rcp {
     fn read_u64(&mut self, address: usize) -> Result<u64, ReadWriteFault> {
         trace!(target: "RCP", "read64 address=${:08X}", address);
 
-        if let (Some(addressable), offset) = self.match_addressable(address, "read64") {
+        if let (Some(addressable), offset) = self.match_addressable(address, BusAccess::Read64)? {
             addressable.read_u64(offset)
         } else {
-            Ok(0)
+            Ok(((open_bus(address) as u64) << 32) | (open_bus(address + 4) as u64))
         }
     }
 
     fn read_u32(&mut self, address: usize) -> Result<u32, ReadWriteFault> {
         trace!(target: "RCP", "read32 address=${:08X}", address);
 
-        if let (Some(addressable), offset) = self.match_addressable(address, "read32") {
+        if let (Some(addressable), offset) = self.match_addressable(address, BusAccess::Read32)? {
             addressable.read_u32(offset)
         } else {
-            Ok(0)
+            Ok(open_bus(address))
         }
     }
 
     fn read_u16(&mut self, address: usize) -> Result<u16, ReadWriteFault> {
         trace!(target: "RCP", "read16 address=${:08X}", address);
 
-        if let (Some(addressable), offset) = self.match_addressable(address, "read16") {
+        if let (Some(addressable), offset) = self.match_addressable(address, BusAccess::Read16)? {
             addressable.read_u16(offset)
         } else {
-            Ok(0)
+            Ok(open_bus(address) as u16)
         }
     }
 
     fn read_u8(&mut self, address: usize) -> Result<u8, ReadWriteFault> {
         trace!(target: "RCP", "read8 address=${:08X}", address);
 
-        if let (Some(addressable), offset) = self.match_addressable(address, "read8") {
+        if let (Some(addressable), offset) = self.match_addressable(address, BusAccess::Read8)? {
             addressable.read_u8(offset)
         } else {
-            Ok(0)
+            Ok((open_bus(address & !0x03) >> (24 - ((address & 0x03) << 3))) as u8)
         }
     }
 
     fn write_u64(&mut self, value: u64, address: usize) -> Result<WriteReturnSignal, ReadWriteFault> {
         trace!(target: "RCP", "write64 value=${:08X} address=${:08X}", value, address);
 
-        if let (Some(addressable), offset) = self.match_addressable(address, "write64") {
+        if let (Some(addressable), offset) = self.match_addressable(address, BusAccess::Write64)? {
             addressable.write_u64(value, offset)
         } else {
             Ok(WriteReturnSignal::None)
@@ -340,7 +380,7 @@ impl Addressable for Rcp {
     fn write_u32(&mut self, value: u32, address: usize) -> Result<WriteReturnSignal, ReadWriteFault> {
         trace!(target: "RCP", "write32 value=${:08X} address=${:08X}", value, address);
 
-        if let (Some(addressable), offset) = self.match_addressable(address, "write32") {
+        if let (Some(addressable), offset) = self.match_addressable(address, BusAccess::Write32)? {
             addressable.write_u32(value, offset)
         } else {
             Ok(WriteReturnSignal::None)
@@ -350,7 +390,7 @@ impl Addressable for Rcp {
     fn write_u16(&mut self, value: u32, address: usize) -> Result<WriteReturnSignal, ReadWriteFault> {
         trace!(target: "RCP", "write16 value=${:08X} address=${:08X}", value, address);
 
-        if let (Some(addressable), offset) = self.match_addressable(address, "write16") {
+        if let (Some(addressable), offset) = self.match_addressable(address, BusAccess::Write16)? {
             addressable.write_u16(value, offset)
         } else {
             Ok(WriteReturnSignal::None)
@@ -360,7 +400,7 @@ impl Addressable for Rcp {
     fn write_u8(&mut self, value: u32, address: usize) -> Result<WriteReturnSignal, ReadWriteFault> {
         trace!(target: "RCP", "write8 value=${:08X} address=${:08X}", value, address);
 
-        if let (Some(addressable), offset) = self.match_addressable(address, "write8") {
+        if let (Some(addressable), offset) = self.match_addressable(address, BusAccess::Write8)? {
             addressable.write_u8(value, offset)
         } else {
             Ok(WriteReturnSignal::None)
@@ -370,7 +410,7 @@ impl Addressable for Rcp {
     fn read_block(&mut self, address: usize, length: u32) -> Result<Vec<u32>, ReadWriteFault> {
         trace!(target: "RCP", "read_block length={} address=${:08X}", length, address);
 
-        if let (Some(addressable), offset) = self.match_addressable(address, "read_block") {
+        if let (Some(addressable), offset) = self.match_addressable(address, BusAccess::ReadBlock)? {
             addressable.read_block(offset, length)
         } else {
             Err(ReadWriteFault::Invalid)
@@ -380,12 +420,17 @@ impl Addressable for Rcp {
     fn write_block(&mut self, address: usize, block: &[u32], length: u32) -> Result<WriteReturnSignal, ReadWriteFault> {
         trace!(target: "RCP", "write_block length={} address=${:08X}", block.len(), address);
 
-        if let (Some(addressable), offset) = self.match_addressable(address, "write_block") {
+        if let (Some(addressable), offset) = self.match_addressable(address, BusAccess::WriteBlock)? {
             addressable.write_block(offset, block, length)
         } else {
             Err(ReadWriteFault::Invalid)
         }
     }
+
+    // only the PI external bus stalls the cpu
+    fn take_stall_cycles(&mut self) -> u64 {
+        self.pi.take_stall_cycles()
+    }
 }
 
 
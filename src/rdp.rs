@@ -46,10 +46,11 @@ impl Rdp {
     }
 
     pub fn update_clock(&mut self) {
+        // the RDP runs at 62.5MHz, 2/3 of the cpu's 93.75MHz
         let cur = self.comms.total_cpu_steps.get() as u64;
-        let delta = cur - self.last_clock_update;
+        let delta = (cur * 2 / 3) - (self.last_clock_update * 2 / 3);
         self.clock = self.clock.wrapping_add(delta as u32) & 0x00FF_FFFF;
-        self.last_clock_update = cur * 2 / 3; // 62.5MHz on the cpu's 93.75MHz TODO
+        self.last_clock_update = cur;
     }
 }
 
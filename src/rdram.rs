@@ -1,19 +1,167 @@
+#![allow(non_upper_case_globals)]
 #[allow(unused_imports)]
 use tracing::{trace, debug, error, warn, info};
 
 use crate::*;
 
+// every module on the bus is an 18Mbit (2MiB) part
+const MODULE_SIZE: usize = 2 * 1024 * 1024;
+
+// DeviceType reported by the 18Mbit (9-bit byte) modules on the N64 board and Expansion Pak. It
+// describes the part's geometry, but nothing decodes it: IPL3 sizes memory by assigning module ids
+// and probing them, so all that matters is that modules which are present answer with a non-zero type
+const DEVICE_TYPE: u32 = 0xB519_0010;
+
+// RDRAM module registers, as word indices into the 1KiB register space of each module
+const Rdram_DeviceType        : usize = 0;
+const Rdram_DeviceId          : usize = 1;
+const Rdram_Delay             : usize = 2;
+const Rdram_Mode              : usize = 3;
+const Rdram_RefInterval       : usize = 4;
+const Rdram_RefRow            : usize = 5;
+const Rdram_RasInterval       : usize = 6;
+const Rdram_MinInterval       : usize = 7;
+const Rdram_AddressSelect     : usize = 8;
+const Rdram_DeviceManufacturer: usize = 9;
+
+struct RdramModule {
+    device_id     : u32,
+    delay         : u32,
+    mode          : u32,
+    ref_interval  : u32,
+    ref_row       : u32,
+    ras_interval  : u32,
+    min_interval  : u32,
+    address_select: u32,
+}
+
+impl RdramModule {
+    fn new(id: u32) -> RdramModule {
+        RdramModule {
+            device_id     : RdramModule::encode_id(id),
+            delay         : 0x230B_0223,
+            mode          : 0xC4C0_C0C0,
+            ref_interval  : 0,
+            ref_row       : 0,
+            ras_interval  : 0,
+            min_interval  : 0x0040_C0E0,
+            address_select: 0,
+        }
+    }
+
+    // The id is the module's base address in 1MiB units, and is scattered over the DeviceId
+    // register: IdField[5:0] in bits 31:26, IdField[6] in bit 23, IdField[14:7] in bits 15:8 and
+    // IdField[15] in bit 7
+    fn id(&self) -> u32 {
+        let v = self.device_id;
+        ((v >> 26) & 0x3F) | (((v >> 23) & 0x01) << 6) | (((v >> 8) & 0xFF) << 7) | (((v >> 7) & 0x01) << 15)
+    }
+
+    fn encode_id(id: u32) -> u32 {
+        ((id & 0x3F) << 26) | (((id >> 6) & 0x01) << 23) | (((id >> 7) & 0xFF) << 8) | (((id >> 15) & 0x01) << 7)
+    }
+
+    fn read_register(&self, register: usize) -> u32 {
+        match register {
+            Rdram_DeviceType         => DEVICE_TYPE,
+            Rdram_DeviceId           => self.device_id,
+            Rdram_Delay              => self.delay,
+            Rdram_Mode               => self.mode,
+            Rdram_RefInterval        => self.ref_interval,
+            Rdram_RefRow             => self.ref_row,
+            Rdram_RasInterval        => self.ras_interval,
+            Rdram_MinInterval        => self.min_interval,
+            Rdram_AddressSelect      => self.address_select,
+            Rdram_DeviceManufacturer => 0x0000_0500,
+            _ => {
+                warn!(target: "RDRAM", "unhandled read from module register {}", register);
+                0
+            },
+        }
+    }
+
+    fn write_register(&mut self, value: u32, register: usize) {
+        match register {
+            Rdram_DeviceId      => self.device_id = value,
+            Rdram_Delay         => self.delay = value,
+            Rdram_Mode          => self.mode = value,
+            Rdram_RefInterval   => self.ref_interval = value,
+            Rdram_RefRow        => self.ref_row = value,
+            Rdram_RasInterval   => self.ras_interval = value,
+            Rdram_MinInterval   => self.min_interval = value,
+            Rdram_AddressSelect => self.address_select = value,
+            _ => warn!(target: "RDRAM", "unhandled write to module register {} value=${:08X}", register, value),
+        }
+    }
+}
+
+// Which part of RDRAM answers each 1MiB of the RDRAM address space. The RI rebuilds it whenever
+// a module is given a new id, and everything that reads RDRAM directly rather than over the bus
+// (VI, HLE, the debugger) translates physical addresses through it
+pub struct RdramMap {
+    // word offset into RDRAM, or NO_MODULE where no module answers
+    slots: [AtomicU32; 64],
+}
+
+impl RdramMap {
+    const NO_MODULE: u32 = u32::MAX;
+
+    // modules laid end to end, the way they are before IPL3 assigns ids
+    pub fn new(rdram_size: usize) -> RdramMap {
+        let map = RdramMap { slots: std::array::from_fn(|_| AtomicU32::new(RdramMap::NO_MODULE)) };
+        for slot in 0..(rdram_size >> 20).min(64) {
+            map.set(slot, Some(slot << 18));
+        }
+        map
+    }
+
+    fn set(&self, slot: usize, base: Option<usize>) {
+        self.slots[slot].store(base.map_or(RdramMap::NO_MODULE, |base| base as u32), Ordering::Relaxed);
+    }
+
+    // word index into RDRAM for a physical address, or None if there's no RDRAM there
+    pub fn word_index(&self, address: usize) -> Option<usize> {
+        if address >= 0x03F0_0000 { return None; }
+        match self.slots[address >> 20].load(Ordering::Relaxed) {
+            RdramMap::NO_MODULE => None,
+            base => Some(base as usize + ((address & 0x000F_FFFF) >> 2)),
+        }
+    }
+
+    // the word at a physical address. like the RI, addresses without a module behind them read as zero
+    pub fn read_u32(&self, ram: &[u32], address: usize) -> u32 {
+        self.word_index(address).and_then(|index| ram.get(index).copied()).unwrap_or(0)
+    }
+
+    // `count` words starting at a physical address
+    pub fn read_words(&self, ram: &[u32], address: usize, count: usize) -> Vec<u32> {
+        (0..count).map(|i| self.read_u32(ram, address.wrapping_add(i << 2))).collect()
+    }
+}
+
 pub struct RdramInterface {
     ram: Arc<RwLock<Option<Vec<u32>>>>,
     ram_len: usize,
+    code_pages: Arc<block_cache::CodePages>,
     repeat_count: Option<u32>,
 
+    // modules in the order they're chained on the bus. module n is backed by the nth 2MiB of `ram`
+    modules: Vec<RdramModule>,
+
+    // shared with direct readers of RDRAM, see RdramMap
+    memory_map: Arc<RdramMap>,
+
+    ri_mode: u32,
+    ri_config: u32,
+    ri_current_load: u32,
     ri_select: u32,
+    ri_refresh: u32,
 }
 
 impl RdramInterface {
     pub fn new(comms: SystemCommunication) -> RdramInterface {
-        let ram_len = 2*1024*1024; // 8MiB => 8*1024*1024/4 = 2MiB
+        // two modules on the board, and two more on the Expansion Pak
+        let ram_len = comms.settings.read().unwrap().rdram_size() >> 2;
         let ram = vec![0u32; ram_len];
 
         let mut rdram_ref = comms.rdram.write().unwrap();
@@ -21,12 +169,21 @@ impl RdramInterface {
         drop(rdram_ref);
 
         let ram = comms.rdram.clone();
-        RdramInterface { 
+        let mut ri = RdramInterface {
             ram: ram,
             ram_len: ram_len,
+            code_pages: comms.code_pages.clone(),
             repeat_count: None,
+            modules: Vec::new(),
+            memory_map: comms.rdram_map.clone(),
+            ri_mode: 0,
+            ri_config: 0,
+            ri_current_load: 0,
             ri_select: 0x14,
-        }
+            ri_refresh: 0,
+        };
+        ri.reset_modules();
+        ri
     }
 
     pub fn reset(&mut self) {
@@ -34,18 +191,70 @@ impl RdramInterface {
 
         // clear RAM?
         self.repeat_count = None;
+        self.reset_modules();
     }
 
+    // IPL3 assigns every module a new id before touching memory, but start them out laid end to end
+    // so RAM is usable before that
+    fn reset_modules(&mut self) {
+        let count = (self.ram_len << 2) / MODULE_SIZE;
+        self.modules = (0..count).map(|i| RdramModule::new((i * (MODULE_SIZE >> 20)) as u32)).collect();
+        self.update_memory_map();
+    }
+
+    // a module answers when the upper address bits match its id, ignoring the bits that address
+    // within the module. if more than one module has the same id, the first in the chain wins
+    fn update_memory_map(&mut self) {
+        let mask = !((MODULE_SIZE >> 20) as u32 - 1);
+        for slot in 0..64 {
+            let id = slot as u32;
+            let base = self.modules.iter().position(|module| (module.id() & mask) == (id & mask))
+                                   .map(|index| (index * MODULE_SIZE + ((id & !mask) << 20) as usize) >> 2);
+            self.memory_map.set(slot, base);
+        }
+    }
+
+    // word index into `ram` for an RDRAM address
+    fn ram_index(&self, offset: usize) -> Option<usize> {
+        self.memory_map.word_index(offset)
+    }
+
+    // Register accesses carry the module id in address bits 18:10 and the register in bits 9:2.
+    // Setting bit 19 broadcasts a write to every module, which is how IPL3 gives all of them the
+    // same id before assigning real ids one module at a time
     fn read_register(&mut self, offset: usize) -> Result<u32, ReadWriteFault> {
         debug!(target: "RDRAM", "read_register offset=${:08X}", offset);
-        Ok(0)
+
+        if (offset & 0x0008_0000) != 0 {
+            warn!(target: "RDRAM", "broadcast read from module register offset=${:08X}", offset);
+            return Ok(0);
+        }
+
+        let id = ((offset >> 10) & 0x1FF) as u32;
+        let register = (offset & 0x3FF) >> 2;
+        Ok(match self.modules.iter().find(|module| module.id() == id) {
+            Some(module) => module.read_register(register),
+            None => 0,
+        })
     }
 
-    fn write_register(&mut self, value: u32, offset: usize, broadcast: bool) -> &mut Self {
+    fn write_register(&mut self, value: u32, offset: usize) -> &mut Self {
+        let broadcast = (offset & 0x0008_0000) != 0;
         debug!(target: "RDRAM", "write_register value=${:08X} offset=${:08X} broadcast={}", value, offset, broadcast);
 
-        // TODO when writing the Delay register and repeat_count is set, refer to the note here:
-        // https://n64brew.dev/wiki/RDRAM#0x02_-_Delay
+        let id = ((offset >> 10) & 0x1FF) as u32;
+        let register = (offset & 0x3FF) >> 2;
+        if broadcast {
+            for module in self.modules.iter_mut() {
+                module.write_register(value, register);
+            }
+        } else if let Some(module) = self.modules.iter_mut().find(|module| module.id() == id) {
+            module.write_register(value, register);
+        }
+
+        if register == Rdram_DeviceId {
+            self.update_memory_map();
+        }
 
         self
     }
@@ -53,6 +262,22 @@ impl RdramInterface {
     pub fn set_repeat_count(&mut self, repeat_count: Option<u32>) {
         self.repeat_count = repeat_count;
     }
+
+    // MI repeat mode: the data of a single write is repeated over repeat_count bytes
+    fn write_repeated(&mut self, value: u32, offset: usize, repeat_count: u32) {
+        let mut access = self.ram.write().unwrap();
+        let ram = access.as_deref_mut().unwrap();
+
+        for i in (0..repeat_count as usize).step_by(4) {
+            if let Some(index) = self.ram_index(offset + i) {
+                let remaining = repeat_count as usize - i;
+                let mask = if remaining >= 4 { 0xFFFF_FFFF } else { !(0xFFFF_FFFFu32 >> (remaining << 3)) };
+                ram[index] = (value & mask) | (ram[index] & !mask);
+            }
+        }
+
+        self.code_pages.notify_write(offset, repeat_count as usize);
+    }
 }
 
 impl Addressable for RdramInterface {
@@ -62,87 +287,112 @@ impl Addressable for RdramInterface {
         match offset {
             // RDRAM memory space
             0x0000_0000..=0x03EF_FFFF => {
-                let rdram_address = ((offset & 0x03FF_FFFF) >> 2) as usize;
-                if rdram_address < self.ram_len {
+                if let Some(index) = self.ram_index(offset) {
                     let access = self.ram.read().unwrap();
                     let ram = access.as_ref().unwrap();
-                    Ok(ram[rdram_address])
+                    Ok(ram[index])
                 } else { Ok(0) }
             },
 
             // RDRAM registers
             0x03F0_0000..=0x03FF_FFFF => {
-                let register = offset & 0x0007_FFFF;
-                self.read_register(register)
+                self.read_register(offset & 0x000F_FFFF)
+            },
+
+            // RI_MODE
+            0x0400_0000 => {
+                debug!(target: "RDRAM", "read RI_MODE");
+                Ok(self.ri_mode)
+            },
+
+            // RI_CONFIG
+            0x0400_0004 => {
+                debug!(target: "RDRAM", "read RI_CONFIG");
+                Ok(self.ri_config)
             },
 
             // RI_SELECT
             0x0400_000C => {
-                // TODO
                 debug!(target: "RDRAM", "read RI_SELECT");
-                let mut access = self.ram.write().unwrap();
-                let ram = access.as_mut().unwrap();
-                ram[(0x318 >> 2) as usize] = 0x0080_0000; // HACK! set ram_size!
                 Ok(self.ri_select)
             },
 
             // RI_REFRESH
             0x0400_0010 => {
                 debug!(target: "RDRAM", "read RI_REFRESH");
-                Ok(0)
+                Ok(self.ri_refresh)
             },
 
-            _ => panic!("RDRAM: unhandled read32 ${:08X}", offset),
+            _ => {
+                warn!(target: "RDRAM", "unhandled read32 ${:08X}", offset);
+                Ok(0)
+            },
         }
     }
 
     fn write_u32(&mut self, value: u32, offset: usize) -> Result<WriteReturnSignal, ReadWriteFault> {
         trace!(target: "RDRAM", "write32 value=${:08X} offset=${:08X}", value, offset);
 
-        if let Some(_rc) = self.repeat_count {
-            if offset != 0x03F8_0008 { // TODO right now we ignore this repeat_count write. See write_register
-                todo!("repeat count write to ${:08X} value=${:08X}", offset, value);
+        if let Some(repeat_count) = self.repeat_count.take() {
+            match offset {
+                0x0000_0000..=0x03EF_FFFF => {
+                    self.write_repeated(value, offset, repeat_count);
+                    return Ok(WriteReturnSignal::None);
+                },
+
+                // IPL3 writes the Delay register in repeat mode, since until the delay is set the module
+                // may latch the data on the wrong cycle. repeating it only stretches out the one write
+                0x03F0_0000..=0x03FF_FFFF => {},
+
+                _ => warn!(target: "RDRAM", "repeat mode write outside of RDRAM offset=${:08X}", offset),
             }
-            self.repeat_count = None;
         }
 
         match offset {
             // RDRAM memory space
-            0x0000_0000..=0x007F_FFFF => {
-                let rdram_address = offset & 0x03FF_FFFF;
-                let mut access = self.ram.write().unwrap();
-                let ram = access.as_deref_mut().unwrap();
-                ram[(rdram_address >> 2) as usize] = value;
-            },
-
-            // "broken" RDRAM memory access
-            0x0080_0000..=0x03EF_FFFF => {
-                panic!("RDRAM: write32 to broken RDRAM memory access offset=${:08X}", offset);
+            0x0000_0000..=0x03EF_FFFF => {
+                if let Some(index) = self.ram_index(offset) {
+                    let mut access = self.ram.write().unwrap();
+                    let ram = access.as_deref_mut().unwrap();
+                    ram[index] = value;
+                    self.code_pages.notify_write(offset, 4);
+                } else {
+                    // there's no RDRAM here, so the write goes nowhere
+                    debug!(target: "RDRAM", "ignoring write32 to missing RDRAM offset=${:08X}", offset);
+                }
             },
 
             // RDRAM registers
             0x03F0_0000..=0x03FF_FFFF => {
-                let broadcast = (offset & 0x0008_0000) != 0;
-                let register = offset & 0x0007_FFFF;
-                self.write_register(value, register, broadcast);
+                self.write_register(value, offset & 0x000F_FFFF);
             },
 
-            // RI_MODE
+            // RI_MODE. only the values IPL3 uses are known to work, but the RDRAM timing isn't
+            // emulated so anything else just gets stored
             0x0400_0000 => {
                 debug!(target: "RDRAM", "write RI_MODE value=${:08X}", value);
-                assert!(value == 0 || value == 0x0E);
+                if value != 0 && value != 0x0E {
+                    warn!(target: "RDRAM", "unexpected RI_MODE value=${:08X}", value);
+                }
+                self.ri_mode = value;
             },
 
             // RI_CONFIG
             0x0400_0004 => {
                 debug!(target: "RDRAM", "write RI_CONFIG value=${:08X}", value);
-                assert!(value == 0x40);
+                if value != 0x40 {
+                    warn!(target: "RDRAM", "unexpected RI_CONFIG value=${:08X}", value);
+                }
+                self.ri_config = value;
             },
 
             // RI_CURRENT_LOAD
-            0x0400_0008 => { 
+            0x0400_0008 => {
                 debug!(target: "RDRAM", "write RI_CURRENT_LOAD value=${:08X}", value);
-                assert!(value == 0);
+                if value != 0 {
+                    warn!(target: "RDRAM", "unexpected RI_CURRENT_LOAD value=${:08X}", value);
+                }
+                self.ri_current_load = value;
             },
 
 
@@ -155,59 +405,53 @@ impl Addressable for RdramInterface {
             // RI_REFRESH
             0x0400_0010 => {
                 debug!(target: "RDRAM", "write RI_REFRESH value=${:08X}", value);
-                assert!(value == 0x00063634);
+                if value != 0x00063634 {
+                    warn!(target: "RDRAM", "unexpected RI_REFRESH value=${:08X}", value);
+                }
+                self.ri_refresh = value;
             },
 
-            _ => panic!("RDRAM: unhandled write32 ${:08X}", offset),
+            _ => warn!(target: "RDRAM", "unhandled write32 value=${:08X} offset=${:08X}", value, offset),
         };
 
         Ok(WriteReturnSignal::None)
     }
 
     fn read_block(&mut self, offset: usize, length: u32) -> Result<Vec<u32>, ReadWriteFault> {
-        if offset < 0x0080_0000 {
+        if offset < 0x03F0_0000 {
             let access = self.ram.read().unwrap();
             let ram = access.as_ref().unwrap();
 
-            // why doesn't std::vec have copy_into(offset, source_slice)?
-            let (_, right) = ram.split_at(offset >> 2);
-            let (left, _) = right.split_at((length >> 2) as usize);
-            Ok(left.to_owned())
-        } else if offset < 0x03FF_FFFF {
-            // some bytes in 0-8KiB every 512KiB apparently contains non-zero values, everything else is zero
-            if (offset & 0x0007_FFFF) < 0x0000_2000 {
-                warn!(target: "RI", "weird DMA out of RDRAM");
-            }
-            Ok(vec![0u32; (length >> 2) as usize])
+            // addresses without a module behind them read as zero
+            Ok((0..(length >> 2) as usize).map(|i| {
+                self.ram_index(offset + (i << 2)).map_or(0, |index| ram[index])
+            }).collect())
         } else {
             todo!("not likely");
         }
     }
 
     fn write_block(&mut self, offset: usize, block: &[u32], length: u32) -> Result<WriteReturnSignal, ReadWriteFault> {
-        if offset < 0x0080_0000 {
+        if offset < 0x03F0_0000 {
             let mut access = self.ram.write().unwrap();
             let ram = access.as_deref_mut().unwrap();
 
-            // why doesn't std::vec have copy_into(offset, source_slice)?
             let count = (length >> 2) as usize;
-            let (_, right) = ram.split_at_mut(offset >> 2);
-            let (left, _) = right.split_at_mut(count);
-            left.copy_from_slice(&block[0..count]);
+            for i in 0..count {
+                if let Some(index) = self.ram_index(offset + (i << 2)) {
+                    ram[index] = block[i];
+                }
+            }
 
             // if we're DMAing less than a multiple of 4..
-            match length & 3 {
-                1 => {
-                    ram[(offset >> 2) + count] = block[count] & 0xFF00_0000 | (ram[(offset >> 2) + count] & 0x00FF_FFFF);
+            if (length & 3) != 0 {
+                if let Some(index) = self.ram_index(offset + (count << 2)) {
+                    let mask = !(0xFFFF_FFFFu32 >> ((length & 3) << 3));
+                    ram[index] = (block[count] & mask) | (ram[index] & !mask);
                 }
-                2 => {
-                    ram[(offset >> 2) + count] = block[count] & 0xFFFF_0000 | (ram[(offset >> 2) + count] & 0x0000_FFFF);
-                }
-                3 => {
-                    ram[(offset >> 2) + count] = block[count] & 0xFFFF_FF00 | (ram[(offset >> 2) + count] & 0x0000_00FF);
-                }
-                _ => {},
             }
+
+            self.code_pages.notify_write(offset, length as usize);
             Ok(WriteReturnSignal::None)
         } else {
             todo!("DMA write to rdram offset ${:08X}: not likely", offset);
@@ -216,3 +460,117 @@ impl Addressable for RdramInterface {
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mips::MipsInterface;
+
+    // 4MiB: two modules, the first backing `ram` words 0..0x8_0000 and the second the rest
+    fn rdram() -> (RdramInterface, SystemCommunication) {
+        let comms = SystemCommunication::new(None);
+        comms.settings.write().unwrap().expansion_pak = false;
+        (RdramInterface::new(comms.clone()), comms)
+    }
+
+    // module register offset for the module with `id`, or every module with `broadcast`
+    fn register(id: u32, register: usize, broadcast: bool) -> usize {
+        0x03F0_0000 | (if broadcast { 0x0008_0000 } else { 0 }) | ((id as usize) << 10) | (register << 2)
+    }
+
+    fn ram_word(comms: &SystemCommunication, index: usize) -> u32 {
+        comms.rdram.read().unwrap().as_ref().unwrap()[index]
+    }
+
+    #[test]
+    fn module_ids_decide_the_memory_map() {
+        let (mut ri, comms) = rdram();
+        let map = comms.rdram_map.clone();
+
+        // modules start out laid end to end
+        assert_eq!(ri.read_u32(register(0, Rdram_DeviceType, false)).unwrap(), DEVICE_TYPE);
+        assert_eq!(ri.read_u32(register(2, Rdram_DeviceType, false)).unwrap(), DEVICE_TYPE);
+        assert_eq!(map.word_index(0x0020_0004), Some(0x8_0001));
+        assert_eq!(map.word_index(0x0040_0000), None);
+
+        // the way IPL3 does it: move every module out of the way, then give them ids one at a time.
+        // the first module in the chain answers when ids collide
+        ri.write_u32(RdramModule::encode_id(0x3E), register(0, Rdram_DeviceId, true)).unwrap();
+        assert_eq!(map.word_index(0x0000_0000), None);
+        assert_eq!(map.word_index(0x03E0_0000), Some(0));
+        ri.write_u32(RdramModule::encode_id(2), register(0x3E, Rdram_DeviceId, false)).unwrap();
+        ri.write_u32(RdramModule::encode_id(0), register(0x3E, Rdram_DeviceId, false)).unwrap();
+        assert_eq!(ri.read_u32(register(0, Rdram_DeviceId, false)).unwrap(), RdramModule::encode_id(0));
+        assert_eq!(ri.read_u32(register(0x3E, Rdram_DeviceType, false)).unwrap(), 0);
+
+        // the modules have swapped places, for the RI and direct readers alike
+        assert_eq!(map.word_index(0x0000_0000), Some(0x8_0000));
+        assert_eq!(map.word_index(0x0030_0000), Some(0x4_0000));
+        ri.write_u32(0x1234_5678, 0x0000_0010).unwrap();
+        ri.write_u32(0x9ABC_DEF0, 0x0020_0010).unwrap();
+        assert_eq!(ram_word(&comms, 0x8_0004), 0x1234_5678);
+        assert_eq!(ram_word(&comms, 0x0_0004), 0x9ABC_DEF0);
+        assert_eq!(ri.read_u32(0x0000_0010).unwrap(), 0x1234_5678);
+        let ram = comms.rdram.read().unwrap();
+        assert_eq!(map.read_words(ram.as_ref().unwrap(), 0x0020_000C, 2), vec![0, 0x9ABC_DEF0]);
+        drop(ram);
+
+        // nothing answers above the modules, so writes are lost and reads are zero
+        ri.write_u32(0xFFFF_FFFF, 0x0040_0000).unwrap();
+        assert_eq!(ri.read_u32(0x0040_0000).unwrap(), 0);
+        assert_eq!(ri.read_block(0x003F_FFFC, 8).unwrap(), vec![ram_word(&comms, 0x7_FFFF), 0]);
+
+        // reset restores the power on layout
+        ri.reset();
+        assert_eq!(map.word_index(0x0020_0000), Some(0x8_0000));
+    }
+
+    #[test]
+    fn mi_repeat_mode() {
+        let (mut ri, comms) = rdram();
+        let mut mi = MipsInterface::new(comms.clone());
+
+        // repeat the next write over 6 bytes, the way Rcp passes the MI repeat count to the RI
+        mi.write_u32(0x100 | 5, 0).unwrap();
+        ri.set_repeat_count(mi.get_repeat_count());
+        ri.write_u32(0xAABB_CCDD, 0x0000_0100).unwrap();
+        assert_eq!(ram_word(&comms, 0x40), 0xAABB_CCDD);
+        assert_eq!(ram_word(&comms, 0x41), 0xAABB_0000);
+        assert_eq!(ram_word(&comms, 0x42), 0);
+
+        // repeat mode only lasts for one write
+        ri.set_repeat_count(mi.get_repeat_count());
+        ri.write_u32(0x1111_1111, 0x0000_0200).unwrap();
+        assert_eq!((ram_word(&comms, 0x80), ram_word(&comms, 0x81)), (0x1111_1111, 0));
+
+        // the longest repeat is 128 bytes, and it can be cancelled before the write
+        mi.write_u32(0x100 | 0x7F, 0).unwrap();
+        ri.set_repeat_count(mi.get_repeat_count());
+        ri.write_u32(0x5555_5555, 0x0000_0400).unwrap();
+        assert!((0x100..0x120).all(|index| ram_word(&comms, index) == 0x5555_5555));
+        assert_eq!(ram_word(&comms, 0x120), 0);
+
+        mi.write_u32(0x100 | 0x0F, 0).unwrap();
+        mi.write_u32(0x200, 0).unwrap();
+        assert_eq!(mi.get_repeat_count(), None);
+
+        // a repeated register write is a single write
+        mi.write_u32(0x100 | 0x0F, 0).unwrap();
+        ri.set_repeat_count(mi.get_repeat_count());
+        ri.write_u32(0x1808_0008, register(0, Rdram_Delay, true)).unwrap();
+        assert_eq!(ri.read_u32(register(2, Rdram_Delay, false)).unwrap(), 0x1808_0008);
+        assert_eq!(ram_word(&comms, 0), 0);
+    }
+
+    #[test]
+    fn unexpected_ri_values_are_stored() {
+        let (mut ri, _comms) = rdram();
+
+        // IPL3 only ever writes these values, but anything else is kept rather than refused
+        for (offset, value) in [(0x0400_0000, 0x1234), (0x0400_0004, 0x41), (0x0400_0008, 1), (0x0400_0010, 0x0007_0000)] {
+            assert!(ri.write_u32(value, offset).is_ok());
+        }
+        assert_eq!(ri.read_u32(0x0400_0000).unwrap(), 0x1234);
+        assert_eq!(ri.read_u32(0x0400_0004).unwrap(), 0x41);
+        assert_eq!(ri.read_u32(0x0400_0010).unwrap(), 0x0007_0000);
+    }
+}
@@ -1,11 +1,15 @@
 #![allow(non_upper_case_globals)]
 use std::mem;
-use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::thread;
 
-#[cfg(all(target_arch="x86_64", target_feature="sse2"))]
+#[cfg(all(target_arch="x86_64", not(feature="portable-rsp")))]
 use core::arch::x86_64::*;
+#[cfg(all(target_arch="x86_64", not(feature="portable-rsp")))]
 use avx512f_wrapper::*;
+#[cfg(any(not(target_arch="x86_64"), feature="portable-rsp"))]
+use portable_intrinsics::*;
 
 #[allow(unused_imports)]
 use tracing::{trace, debug, error, info, warn};
@@ -36,6 +40,12 @@ const _Cop0_CmdBusy       : usize = 13;
 const _Cop0_CmdPipeBusy   : usize = 14;
 const _Cop0_CmdTMemBusy   : usize = 15;
 
+// longest run of instructions decoded into a single IMEM block
+const MAX_BLOCK_INSTRUCTIONS: usize = 64;
+
+// debug_break when the core hasn't stopped on a breakpoint
+const NO_DEBUG_BREAK: u32 = u32::MAX;
+
 const Cop2_VCO: usize = 0;
 const Cop2_VCC: usize = 1;
 const Cop2_VCE: usize = 2;
@@ -54,6 +64,15 @@ pub struct Rsp {
     // access to RspCpuCore memory
     mem: Arc<RwLock<Vec<u32>>>,
 
+    // IMEM lines written since the core last checked, see imem_lines()
+    imem_writes: Arc<AtomicU64>,
+
+    // IMEM offset of the debugger breakpoint the core stopped on, or NO_DEBUG_BREAK
+    debug_break: Arc<AtomicU32>,
+
+    // wakes the thread when the debugger lets go of the core, or it's halted
+    debug_resume: Arc<Condvar>,
+
     dma_completed_rx: mpsc::Receiver<DmaInfo>,
     dma_completed_tx: mpsc::Sender<DmaInfo>,
 
@@ -87,6 +106,21 @@ pub struct RspSharedState {
     signals: u32,
 }
 
+/// A copy of the RSP core state for the debugger
+pub struct RspDebugState {
+    pub pc          : u32,           // IMEM offset of the next instruction
+    pub next_inst   : u32,
+    pub gpr         : [u32; 32],
+    pub v           : [[u16; 8]; 32], // vector registers by element
+    pub vco         : u16,
+    pub vcc         : u16,
+    pub vce         : u8,
+    pub acc         : [u64; 8],       // 48-bit accumulators by element
+    pub halted      : bool,
+    pub broke       : bool,
+    pub debug_halted: bool,
+}
+
 struct RspCpuCore {
     comms: SystemCommunication,
 
@@ -96,7 +130,7 @@ struct RspCpuCore {
     pc: u32,
     current_instruction_pc: u32, // actual PC of the currently executing instruction
                                  // only valid inside step()
-    next_instruction: u32,       // emulates delay slot (prefetch, next instruction)
+    next_decoded: DecodedInstruction, // emulates delay slot (prefetch, next instruction)
     next_instruction_pc: u32,    // for printing correct delay slot addresses
     is_delay_slot: bool,         // true if the currently executing instruction is in a delay slot
     next_is_delay_slot: bool,    // set to true on branching instructions
@@ -121,6 +155,12 @@ struct RspCpuCore {
     regimm_table: [CpuInstruction; 32],
     cop2_table: [CpuInstruction; 64],
 
+    // pre-decoded IMEM, indexed by the word each block starts at
+    imem_blocks: Vec<Option<ImemBlock>>,
+    imem_writes: Arc<AtomicU64>,
+    block_start: usize, // block being executed and the index of the next instruction in it
+    block_index: usize,
+
     // rcp and rsq tables
     rcp_high: bool,
     rcp_input: u16,
@@ -146,20 +186,50 @@ struct RspCpuCore {
 
     // HLE
     process_task: bool,
+
+    // debugger state. While debug_halted the thread leaves the core alone and the debugger steps it
+    debug_halted: bool,
+    debug_breakpoints: Vec<u32>,
+    debug_resume_pc: Option<u32>, // breakpoint to skip when resuming from it
+    debug_break: Arc<AtomicU32>,
 }
 
 type CpuInstruction = fn(&mut RspCpuCore) -> Result<(), InstructionFault>;
 
+#[derive(Copy, Clone)]
+struct DecodedInstruction {
+    decode: InstructionDecode,
+    handler: CpuInstruction,
+}
+
+// A run of decoded IMEM instructions. Blocks end after the delay slot of a branch, at the end of
+// IMEM, or after MAX_BLOCK_INSTRUCTIONS
+struct ImemBlock {
+    instructions: Vec<DecodedInstruction>,
+    lines: u64, // the IMEM lines the block was decoded from
+}
+
+// Bit mask of the 64-byte IMEM lines covering `length` bytes at `offset`, wrapping at the end of IMEM
+fn imem_lines(offset: usize, length: usize) -> u64 {
+    if length == 0 { return 0; }
+    let first = ((offset & 0xFFF) >> 6) as u32;
+    let count = ((offset & 0x3F) + length + 0x3F) >> 6;
+    let lines = if count >= 64 { u64::MAX } else { (1u64 << count) - 1 };
+    lines.rotate_left(first)
+}
+
 impl Rsp {
     pub fn new(comms: SystemCommunication, rdp: Arc<Mutex<Rdp>>) -> Rsp {
         let mem = Arc::new(RwLock::new(vec![0u32; 2*1024]));
+        let imem_writes = Arc::new(AtomicU64::new(0));
+        let debug_break = Arc::new(AtomicU32::new(NO_DEBUG_BREAK));
 
         let shared_state = Arc::new(RwLock::new(RspSharedState::default()));
     
         // dma_completed channel is created here and sent with every DmaInfo message
         let (dma_completed_tx, dma_completed_rx) = mpsc::channel();
 
-        let core = Arc::new(Mutex::new(RspCpuCore::new(comms.clone(), mem.clone(), shared_state.clone(), rdp, dma_completed_tx.clone())));
+        let core = Arc::new(Mutex::new(RspCpuCore::new(comms.clone(), mem.clone(), imem_writes.clone(), debug_break.clone(), shared_state.clone(), rdp, dma_completed_tx.clone())));
 
         Rsp {
             comms: comms,
@@ -167,6 +237,9 @@ impl Rsp {
             core: core,
 
             mem: mem,
+            imem_writes: imem_writes,
+            debug_break: debug_break,
+            debug_resume: Arc::new(Condvar::new()),
 
             wakeup_tx: None,
             broke_rx: None,
@@ -195,6 +268,7 @@ impl Rsp {
             let mut c = core.lock().unwrap();
             c.broke_tx = Some(broke_tx);
         }
+        let debug_resume = Arc::clone(&self.debug_resume);
 
         let mut hle: Option<Hle> = if let Some(ref hle_command_buffer) = self.comms.hle_command_buffer {
             Some(Hle::new(self.comms.clone(), hle_command_buffer.clone()))
@@ -217,9 +291,10 @@ impl Rsp {
                     drop(c);
 
                     // wait forever for a signal
-                    match wakeup_rx.recv().unwrap() {
-                        0 => {}, // normal wakeup
-                        1 => {   // exit thread
+                    // the channel closes when the Rsp is dropped without being stopped
+                    match wakeup_rx.recv() {
+                        Ok(0) => {}, // normal wakeup
+                        Ok(1) | Err(_) => { // exit thread
                             break 'main_loop;
                         }
                         _ => panic!("invalid"),
@@ -305,9 +380,16 @@ impl Rsp {
                             },
                         }
                     }
+                } else if c.debug_halted {
+                    // the debugger has the core, so wait without the lock until it lets go
+                    let _c = debug_resume.wait_while(c, |c| c.debug_halted && !c.halted).unwrap();
                 } else {
                     // run for some cycles or until break
                     for _ in 0..40 {
+                        if c.debug_breakpoints.len() > 0 && c.check_debug_breakpoint() {
+                            break;
+                        }
+
                         let _ = c.step(); // TODO handle errors
 
                         if c.broke || c.halted_self { 
@@ -339,6 +421,7 @@ impl Rsp {
                 c.halted = true;
                 c.halted_self = false;
             }
+            self.debug_resume.notify_all();
 
             let shared_state = self.shared_state.read().unwrap();
             if shared_state.exited {
@@ -404,6 +487,178 @@ impl Rsp {
         self.broke
     }
 
+    pub fn debug_state(&self) -> RspDebugState {
+        self.core.lock().unwrap().debug_state()
+    }
+
+    // stop the core where it is, or let it run freely again
+    pub fn debug_halt(&mut self, halt: bool) {
+        let mut c = self.core.lock().unwrap();
+        if !halt && c.debug_halted {
+            c.debug_resume_pc = Some(c.next_instruction_pc);
+        }
+        c.debug_halted = halt;
+        self.debug_resume.notify_all();
+    }
+
+    // step the core from the debugger, which has to have halted it first
+    pub fn debug_step(&mut self) -> Result<(), String> {
+        let mut c = self.core.lock().unwrap();
+        if !c.debug_halted {
+            return Err(format!("RSP is running"));
+        }
+
+        if c.halted {
+            return Err(format!("RSP is halted (SP_STATUS)"));
+        }
+
+        let result = c.step();
+
+        // same as the thread does when the core stops itself
+        if c.broke || c.halted_self {
+            c.halted = true;
+        }
+
+        result.map_err(|err| format!("RSP fault: {:?}", err))
+    }
+
+    pub fn debug_breakpoints(&self) -> Vec<u32> {
+        self.core.lock().unwrap().debug_breakpoints.clone()
+    }
+
+    // add or remove an IMEM breakpoint, returning false if there was nothing to change
+    pub fn debug_set_breakpoint(&mut self, pc: u32, set: bool) -> bool {
+        let mut c = self.core.lock().unwrap();
+        let pc = pc & 0xFFC;
+        let index = c.debug_breakpoints.iter().position(|v| *v == pc);
+        match (index, set) {
+            (None, true) => c.debug_breakpoints.push(pc),
+            (Some(index), false) => { c.debug_breakpoints.remove(index); },
+            _ => return false,
+        }
+        true
+    }
+
+    // the breakpoint the core stopped on, returned once. The core runs on its own thread, so
+    // it's up to the debugger to report it
+    pub fn debug_break_hit(&self) -> Option<u32> {
+        match self.debug_break.swap(NO_DEBUG_BREAK, Ordering::SeqCst) {
+            NO_DEBUG_BREAK => None,
+            pc => Some(pc),
+        }
+    }
+
+    // pc is the IMEM offset of the instruction
+    pub fn disassemble(pc: u32, inst: u32) -> String {
+        const COP0_REGISTERS: [&str; 16] = [
+            "SP_DMA_SPADDR", "SP_DMA_RAMADDR", "SP_DMA_RDLEN", "SP_DMA_WRLEN", "SP_STATUS", "SP_DMA_FULL", "SP_DMA_BUSY", "SP_SEMAPHORE",
+            "DPC_START", "DPC_END", "DPC_CURRENT", "DPC_STATUS", "DPC_CLOCK", "DPC_BUFBUSY", "DPC_PIPEBUSY", "DPC_TMEM",
+        ];
+
+        const COP2_FN: [&str; 64] = [
+            "vmulf", "vmulu", "vrndp", "vmulq", "vmudl", "vmudm", "vmudn", "vmudh",
+            "vmacf", "vmacu", "vrndn", "vmacq", "vmadl", "vmadm", "vmadn", "vmadh",
+            "vadd" , "vsub" , "vsut" , "vabs" , "vaddc", "vsubc", "vaddb", "vsubb",
+            "vaccb", "vsucb", "vsad" , "vsac" , "vsum" , "vsar" , "v30"  , "v31"  ,
+            "vlt"  , "veq"  , "vne"  , "vge"  , "vcl"  , "vch"  , "vcr"  , "vmrg" ,
+            "vand" , "vnand", "vor"  , "vnor" , "vxor" , "vnxor", "v46"  , "v47"  ,
+            "vrcp" , "vrcpl", "vrcph", "vmov" , "vrsq" , "vrsql", "vrsqh", "vnop" ,
+            "vextt", "vextq", "vextn", "v59"  , "vinst", "vinsq", "vinsn", "vnull",
+        ];
+
+        // load/store name and the size the offset is scaled by
+        const LWC2_FN: [(&str, u32); 12] = [
+            ("bv", 1), ("sv", 2), ("lv", 4), ("dv", 8), ("qv", 16), ("rv", 16),
+            ("pv", 8), ("uv", 8), ("hv", 16), ("fv", 16), ("wv", 16), ("tv", 16),
+        ];
+
+        let op = inst >> 26;
+        let rs = ((inst >> 21) & 0x1F) as usize;
+        let rt = ((inst >> 16) & 0x1F) as usize;
+        let rd = ((inst >> 11) & 0x1F) as usize;
+        let func = (inst & 0x3F) as usize;
+        let rname = |r: usize| cpu::Cpu::abi_name(r);
+
+        // math instruction element suffixes
+        let element = |e: u32| -> String {
+            match e {
+                0     => String::new(),
+                1     => format!("[?]"),
+                2..=3 => format!("[{}q]", e - 2),
+                4..=7 => format!("[{}h]", e - 4),
+                _     => format!("[{}]", e - 8),
+            }
+        };
+
+        match op {
+            0b000_000 => {
+                match func {
+                    0b000_000 | 0b000_010 | 0b000_011 | 0b000_100 | 0b000_110 | 0b000_111 | 0b001_000 | 0b001_001 | 0b001_101
+                        | 0b100_000..=0b100_111 | 0b101_010 | 0b101_011 => cpu::Cpu::disassemble(pc as u64, inst, true),
+                    _ => format!("<invalid special ${:02X}>", func),
+                }
+            },
+
+            0b000_001 => {
+                match rt {
+                    0b00_000 | 0b00_001 | 0b10_000 | 0b10_001 => cpu::Cpu::disassemble(pc as u64, inst, true),
+                    _ => format!("<invalid regimm ${:02X}>", rt),
+                }
+            },
+
+            // jumps stay inside IMEM
+            0b000_010 => format!("j ${:03X}", (inst << 2) & 0xFFC),
+            0b000_011 => format!("jal ${:03X}", (inst << 2) & 0xFFC),
+
+            0b000_100..=0b001_111 | 0b100_000 | 0b100_001 | 0b100_011 | 0b100_100 | 0b100_101 | 0b100_111
+                | 0b101_000 | 0b101_001 | 0b101_011 => cpu::Cpu::disassemble(pc as u64, inst, true),
+
+            0b010_000 => {
+                match rs {
+                    0b00_000 => format!("mfc0 {}, {}", rname(rt), COP0_REGISTERS[rd & 0x0F]),
+                    0b00_100 => format!("mtc0 {}, {}", rname(rt), COP0_REGISTERS[rd & 0x0F]),
+                    _ => format!("<invalid cop0 ${:02X}>", rs),
+                }
+            },
+
+            0b010_010 => {
+                let e = (inst >> 7) & 0x0F;
+                if (inst & (1 << 25)) != 0 {
+                    let e = (inst >> 21) & 0x0F;
+                    let vd = (inst >> 6) & 0x1F;
+                    match func {
+                        // single lane instructions name the destination element in vs
+                        0b110_000..=0b110_110 => format!("{} $v{}[{}], $v{}{}", COP2_FN[func], vd, rd & 0x07, rt, element(e)),
+                        0b011_101 => format!("vsar $v{}, {}", vd, match e { 8 => "ACC_H", 9 => "ACC_M", 10 => "ACC_L", _ => "ACC_?" }),
+                        0b110_111 | 0b111_111 => format!("{}", COP2_FN[func]),
+                        _ => format!("{} $v{}, $v{}, $v{}{}", COP2_FN[func], vd, rd, rt, element(e)),
+                    }
+                } else {
+                    match rs {
+                        0b00_000 => format!("mfc2 {}, $v{}[{}]", rname(rt), rd, e),
+                        0b00_100 => format!("mtc2 {}, $v{}[{}]", rname(rt), rd, e),
+                        0b00_010 => format!("cfc2 {}, {}", rname(rt), ["vco", "vcc", "vce", "vce"][rd & 0x03]),
+                        0b00_110 => format!("ctc2 {}, {}", rname(rt), ["vco", "vcc", "vce", "vce"][rd & 0x03]),
+                        _ => format!("<invalid cop2 ${:02X}>", rs),
+                    }
+                }
+            },
+
+            0b110_010 | 0b111_010 => {
+                if rd >= LWC2_FN.len() || (rd == 10 && op == 0b110_010) {
+                    return format!("<invalid {} ${:02X}>", if op == 0b110_010 { "lwc2" } else { "swc2" }, rd);
+                }
+
+                let (name, size) = LWC2_FN[rd];
+                let e = (inst >> 7) & 0x0F;
+                let offset = (((inst & 0x7F) << 25) as i32) >> 25; // signed 7 bits
+                format!("{}{} $v{}[{}], {}({})", if op == 0b110_010 { "l" } else { "s" }, name, rt, e, offset * (size as i32), rname(rs))
+            },
+
+            _ => format!("<invalid ${:08X}>", inst),
+        }
+    }
+
     fn read_register(&mut self, offset: usize) -> Result<u32, ReadWriteFault> {
         //info!(target: "RSP", "read32 register offset=${:08X}", offset);
 
@@ -763,7 +1018,10 @@ impl Addressable for Rsp {
 
             0x0004_0000..=0x000B_FFFF => self.read_register(offset & 0x000F_FFFF),
 
-            _ => panic!("invalid RSP read"),
+            _ => {
+                warn!(target: "RSP", "read32 from unmapped offset=${:08X}", offset);
+                Ok(open_bus(offset))
+            },
         }
     }
 
@@ -773,9 +1031,13 @@ impl Addressable for Rsp {
             let mem_offset = (offset & 0x1FFF) >> 2; // 8KiB, repeated
             let mut mem = self.mem.write().unwrap();
             mem[mem_offset as usize] = (value >> 32) as u32;
+            if mem_offset >= (0x1000 >> 2) {
+                self.imem_writes.fetch_or(imem_lines(offset, 4), Ordering::Release);
+            }
             Ok(WriteReturnSignal::None)
         } else {
-            todo!("TODO");
+            // same for the registers, the lower word never makes it
+            self.write_u32((value >> 32) as u32, offset)
         }
     }
 
@@ -807,11 +1069,16 @@ impl Addressable for Rsp {
 
                 let mut mem = self.mem.write().unwrap();
                 mem[mem_offset as usize] = value;
+
+                // decoded instructions from this line are stale now
+                if mem_offset >= (0x1000 >> 2) {
+                    self.imem_writes.fetch_or(imem_lines(offset, 4), Ordering::Release);
+                }
             },
 
             0x0004_0000..=0x000B_FFFF => self.write_register(value, offset & 0x000F_FFFF),
 
-            _ => panic!("invalid RSP write"),
+            _ => warn!(target: "RSP", "write32 to unmapped offset=${:08X}", offset),
         };
 
         Ok(WriteReturnSignal::None)
@@ -872,7 +1139,8 @@ impl Addressable for Rsp {
             {
                 let mut mem = self.mem.write().unwrap();
                 let (dram, iram) = mem.split_at_mut(0x1000 >> 2);
-                let which_mem = if (offset & 0x1000) != 0 { iram } else { dram };
+                let is_imem = (offset & 0x1000) != 0;
+                let which_mem = if is_imem { iram } else { dram };
                 offset &= 0xFF8; // drop the high bit
 
                 let (_, right) = which_mem.split_at_mut(offset >> 2);
@@ -885,6 +1153,10 @@ impl Addressable for Rsp {
                     let (left, _) = which_mem.split_at_mut(leftover_words);
                     left.copy_from_slice(&block[(block.len()-leftover_words)..block.len()]);
                 }
+
+                if is_imem {
+                    self.imem_writes.fetch_or(imem_lines(offset, block.len() << 2), Ordering::Release);
+                }
             }
 
             // if the TaskType (0x0FC0) value has changed, inform the core
@@ -904,7 +1176,7 @@ impl Addressable for Rsp {
 
 use RspCpuCore as Cpu; // shorthand so I can copy code from cpu.rs :)
 impl RspCpuCore {
-    fn new(comms: SystemCommunication, mem: Arc<RwLock<Vec<u32>>>, shared_state: Arc<RwLock<RspSharedState>>, rdp: Arc<Mutex<Rdp>>, dma_completed_tx: mpsc::Sender<DmaInfo>) -> Self {
+    fn new(comms: SystemCommunication, mem: Arc<RwLock<Vec<u32>>>, imem_writes: Arc<AtomicU64>, debug_break: Arc<AtomicU32>, shared_state: Arc<RwLock<RspSharedState>>, rdp: Arc<Mutex<Rdp>>, dma_completed_tx: mpsc::Sender<DmaInfo>) -> Self {
         // initialize the reciprocal table.. the algorithm is widely available but I'll include the Ares license here as well, since it's used in n64-systemtest
         // The generation of the RCP and RSP tables was ported from Ares: https://github.com/ares-emulator/ares/blob/acd2130a4d4c9e7208f61e0ff762895f7c9b8dc6/ares/n64/rsp/rsp.cpp#L102
         // which uses the following license:
@@ -952,7 +1224,7 @@ impl RspCpuCore {
             num_steps: 0,
             pc: 0,
             current_instruction_pc: 0,
-            next_instruction: 0,
+            next_decoded: DecodedInstruction { decode: InstructionDecode::default(), handler: Cpu::inst_special },
             next_instruction_pc: 0,
             is_delay_slot: false,
             next_is_delay_slot: false,
@@ -981,6 +1253,12 @@ impl RspCpuCore {
             mem: mem,
             shared_state: shared_state,
             broke_tx: None,
+
+            imem_blocks: (0..(0x1000 >> 2)).map(|_| None).collect(),
+            imem_writes: imem_writes,
+            block_start: 0,
+            block_index: 0,
+
             dma_completed_tx: dma_completed_tx,
 
             halted: true,
@@ -990,6 +1268,11 @@ impl RspCpuCore {
             rdp: rdp,
             process_task: false,
 
+            debug_halted: false,
+            debug_breakpoints: Vec::new(),
+            debug_resume_pc: None,
+            debug_break: debug_break,
+
             instruction_table: [
                 //  _000                _001                _010                _011                _100                _101                _110                _111
    /* 000_ */   Cpu::inst_special , Cpu::inst_regimm  , Cpu::inst_j       , Cpu::inst_jal     , Cpu::inst_beq     , Cpu::inst_bne     , Cpu::inst_blez    , Cpu::inst_bgtz    ,
@@ -1059,8 +1342,59 @@ impl RspCpuCore {
         self.prefetch()
     }
 
+    // Stop for the debugger when the next instruction has a breakpoint, except the one execution
+    // just resumed from
+    fn check_debug_breakpoint(&mut self) -> bool {
+        let pc = self.next_instruction_pc;
+        if self.debug_resume_pc.take() == Some(pc) {
+            return false;
+        }
+
+        if self.debug_breakpoints.contains(&pc) {
+            self.debug_halted = true;
+            self.debug_break.store(pc, Ordering::SeqCst);
+            return true;
+        }
+
+        false
+    }
+
+    fn debug_state(&self) -> RspDebugState {
+        let mut v = [[0u16; 8]; 32];
+        for (i, reg) in self.v.iter().enumerate() {
+            for e in 0..8 {
+                v[i][e] = Self::v_short(reg, e as u8);
+            }
+        }
+
+        // split the accumulators into 16-bit lanes to read them out by element
+        let low  = _wmm512_cvtepi64_epi16(self.vacc);
+        let mid  = _wmm512_cvtepi64_epi16(_wmm512_srli_epi64::<16>(self.vacc));
+        let high = _wmm512_cvtepi64_epi16(_wmm512_srli_epi64::<32>(self.vacc));
+        let mut acc = [0u64; 8];
+        for e in 0..8 {
+            acc[e] = ((Self::v_short(&high, e as u8) as u64) << 32)
+                       | ((Self::v_short(&mid, e as u8) as u64) << 16)
+                       | (Self::v_short(&low, e as u8) as u64);
+        }
+
+        RspDebugState {
+            pc          : self.next_instruction_pc,
+            next_inst   : self.next_decoded.decode.v,
+            gpr         : self.gpr,
+            v           : v,
+            vco         : self.ccr[Cop2_VCO] as u16,
+            vcc         : self.ccr[Cop2_VCC] as u16,
+            vce         : self.ccr[Cop2_VCE] as u8,
+            acc         : acc,
+            halted      : self.halted,
+            broke       : self.broke,
+            debug_halted: self.debug_halted,
+        }
+    }
+
     pub fn prefetch(&mut self) -> Result<(), ReadWriteFault> {
-        self.next_instruction = self.read_u32(self.pc as usize | 0x1000)?; 
+        self.next_decoded = self.fetch_decoded(self.pc);
         self.next_instruction_pc = self.pc;
         self.pc = (self.pc + 4) & 0xFFC;
 
@@ -1129,22 +1463,12 @@ impl RspCpuCore {
             //return self.address_exception(self.pc, false);
         }
 
-        // current instruction
-        let inst = self.next_instruction;
-        self.inst.v = inst;
-
-        // instruction decode
-        self.inst.op         = inst >> 26;
-        self.inst.rs         = ((inst >> 21) & 0x1F) as usize;
-        self.inst.rt         = ((inst >> 16) & 0x1F) as usize;
-        self.inst.rd         = ((inst >> 11) & 0x1F) as usize;
-        self.inst.imm        = (inst & 0xFFFF) as u64;
-        self.inst.signed_imm = (self.inst.imm as i16) as u64;
-        self.inst.target     = inst & 0x3FFFFFF;
-        self.inst.sa         = (inst >> 6) & 0x1F;
+        // current instruction, already decoded
+        let current = self.next_decoded;
+        self.inst = current.decode;
 
         // next instruction prefetch
-        self.next_instruction = self.read_u32(self.pc as usize | 0x1000)?;
+        self.next_decoded = self.fetch_decoded(self.pc);
 
         // update and increment PC
         self.current_instruction_pc = self.next_instruction_pc;
@@ -1157,13 +1481,13 @@ impl RspCpuCore {
 
         //info!(target: "RSP", "${:08X}: inst ${:08X} op=0b{:06b}", self.current_instruction_pc | 0x1000, inst, self.inst.op);
 
-        let result = match self.instruction_table[self.inst.op as usize](self) {
+        let result = match (current.handler)(self) {
             // faults like Break, Unimplemented actually stop processing
             Err(msg) => {
                 // on error, restore the previous instruction since it didn't complete
                 self.pc -= 4;
                 self.next_instruction_pc = self.current_instruction_pc;
-                self.next_instruction = inst;
+                self.next_decoded = current;
                 Err(msg)
             },
 
@@ -1177,6 +1501,92 @@ impl RspCpuCore {
         result
     }
 
+    // Return the decoded instruction at pc, continuing the current block when execution is sequential
+    fn fetch_decoded(&mut self, pc: u32) -> DecodedInstruction {
+        // pairs with the Release in the writers, so a write seen here also has its IMEM data visible
+        if self.imem_writes.load(Ordering::Acquire) != 0 {
+            self.invalidate_blocks();
+        }
+
+        let word = ((pc & 0xFFC) >> 2) as usize;
+        if let Some(block) = &self.imem_blocks[self.block_start] {
+            if word == self.block_start + self.block_index && self.block_index < block.instructions.len() {
+                self.block_index += 1;
+                return block.instructions[self.block_index - 1];
+            }
+        }
+
+        if self.imem_blocks[word].is_none() {
+            self.imem_blocks[word] = Some(self.build_block(word));
+        }
+        self.block_start = word;
+        self.block_index = 1;
+        self.imem_blocks[word].as_ref().unwrap().instructions[0]
+    }
+
+    fn build_block(&self, start: usize) -> ImemBlock {
+        let mem = self.mem.read().unwrap();
+        let mut instructions = Vec::new();
+        let mut word = start;
+        let mut delay_slot = false;
+        while word < (0x1000 >> 2) && instructions.len() < MAX_BLOCK_INSTRUCTIONS {
+            let decoded = self.decode(mem[(0x1000 >> 2) + word]);
+            instructions.push(decoded);
+            word += 1;
+
+            if delay_slot { break; }
+            delay_slot = Self::is_branch(&decoded.decode);
+        }
+
+        let lines = imem_lines(start << 2, instructions.len() << 2);
+        ImemBlock { instructions, lines }
+    }
+
+    // drop every block decoded from an IMEM line that has been written
+    fn invalidate_blocks(&mut self) {
+        let written = self.imem_writes.swap(0, Ordering::Acquire);
+        for block in self.imem_blocks.iter_mut() {
+            if block.as_ref().is_some_and(|block| (block.lines & written) != 0) {
+                *block = None;
+            }
+        }
+    }
+
+    fn decode(&self, inst: u32) -> DecodedInstruction {
+        let decode = InstructionDecode {
+            v         : inst,
+            op        : inst >> 26,
+            regimm    : (inst >> 16) & 0x1F,
+            special   : inst & 0x3F,
+            rs        : ((inst >> 21) & 0x1F) as usize,
+            rt        : ((inst >> 16) & 0x1F) as usize,
+            rd        : ((inst >> 11) & 0x1F) as usize,
+            imm       : (inst & 0xFFFF) as u64,
+            signed_imm: ((inst & 0xFFFF) as i16) as u64,
+            sa        : (inst >> 6) & 0x1F,
+            target    : inst & 0x3FFFFFF,
+        };
+
+        // resolve SPECIAL and REGIMM now so execution goes straight to the handler
+        let handler = match decode.op {
+            0b000_000 => self.special_table[decode.special as usize],
+            0b000_001 => self.regimm_table[decode.regimm as usize],
+            op        => self.instruction_table[op as usize],
+        };
+
+        DecodedInstruction { decode, handler }
+    }
+
+    // true for jumps and branches, which are followed by a delay slot
+    fn is_branch(decode: &InstructionDecode) -> bool {
+        match decode.op {
+            0b000_000 => decode.special == 0b001_000 || decode.special == 0b001_001, // JR, JALR
+            0b000_001 => true,                                                       // REGIMM branches
+            0b000_010..=0b000_111 => true,                                           // J, JAL, BEQ, BNE, BLEZ, BGTZ
+            _ => false,
+        }
+    }
+
     #[inline(always)]
     fn branch(&mut self, condition: bool) {
         if condition {
@@ -4108,3 +4518,98 @@ impl Addressable for RspCpuCore {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addiu(rt: u32, rs: u32, imm: u16) -> u32 { (0b001_001 << 26) | (rs << 21) | (rt << 16) | (imm as u32) }
+    fn j(target: u32) -> u32 { (0b000_010 << 26) | (target >> 2) }
+
+    // An RSP with `program` at the start of IMEM, not running on its own thread
+    fn rsp(program: &[u32]) -> Rsp {
+        let comms = SystemCommunication::new(None);
+        let rdp = Arc::new(Mutex::new(Rdp::new(comms.clone())));
+        let mut rsp = Rsp::new(comms, rdp);
+        for (i, inst) in program.iter().enumerate() {
+            rsp.write_u32(*inst, 0x1000 + i * 4).unwrap();
+        }
+
+        let mut c = rsp.core.lock().unwrap();
+        c.pc = 0;
+        c.prefetch().unwrap();
+        c.halted = false;
+        drop(c);
+        rsp
+    }
+
+    // step the core until the instruction at pc has run
+    fn run_past(rsp: &Rsp, pc: u32) {
+        let mut c = rsp.core.lock().unwrap();
+        for _ in 0..100 {
+            let at = c.next_instruction_pc;
+            c.step().unwrap();
+            if at == pc { return; }
+        }
+        panic!("didn't reach ${:03X}", pc);
+    }
+
+    #[test]
+    fn imem_writes_replace_decoded_instructions() {
+        // loop: addiu r1, r1, 1; nop; nop; j loop; nop
+        let mut rsp = rsp(&[addiu(1, 1, 1), 0, 0, j(0), 0]);
+        run_past(&rsp, 0);
+        run_past(&rsp, 0);
+        assert_eq!(rsp.core.lock().unwrap().gpr[1], 2);
+
+        // a write from the CPU side while the loop is decoded
+        run_past(&rsp, 4);
+        rsp.write_u32(addiu(1, 1, 0x10), 0x1000).unwrap();
+        run_past(&rsp, 0);
+        assert_eq!(rsp.core.lock().unwrap().gpr[1], 0x12);
+
+        // and a DMA
+        run_past(&rsp, 4);
+        rsp.write_block(0x1000, &[addiu(1, 1, 0x100)], 4).unwrap();
+        run_past(&rsp, 0);
+        assert_eq!(rsp.core.lock().unwrap().gpr[1], 0x112);
+    }
+
+    fn wait_for_break(rsp: &Rsp) -> u32 {
+        let start = std::time::Instant::now();
+        loop {
+            if let Some(pc) = rsp.debug_break_hit() { return pc; }
+            assert!(start.elapsed() < std::time::Duration::from_secs(5), "RSP didn't stop");
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn breakpoint_then_step() {
+        let mut rsp = rsp(&[addiu(1, 1, 1), addiu(2, 2, 1), addiu(3, 3, 1), j(0), 0]);
+        rsp.core.lock().unwrap().halted = true;
+        rsp.start();
+
+        // clear SP_STATUS halt and run to the breakpoint
+        assert!(rsp.debug_set_breakpoint(0x008, true));
+        rsp.write_u32(0x01, 0x0004_0010).unwrap();
+        assert_eq!(wait_for_break(&rsp), 0x008);
+        let state = rsp.debug_state();
+        assert!(state.debug_halted);
+        assert_eq!((state.pc, state.gpr[1], state.gpr[2], state.gpr[3]), (0x008, 1, 1, 0));
+
+        // the thread leaves the core alone while the debugger steps it
+        rsp.debug_step().unwrap();
+        let state = rsp.debug_state();
+        assert_eq!((state.pc, state.gpr[3]), (0x00C, 1));
+        assert!(rsp.debug_break_hit().is_none());
+
+        // resuming wakes the thread, which goes round the loop to the breakpoint again
+        rsp.debug_halt(false);
+        assert_eq!(wait_for_break(&rsp), 0x008);
+        assert_eq!(rsp.debug_state().gpr[1], 2);
+
+        // and stopping wakes it while the debugger has the core
+        rsp.stop();
+    }
+}
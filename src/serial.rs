@@ -82,9 +82,9 @@ impl SerialInterface {
 
             // SI_PIF_AD_RD64B - DMA 64 bytes from PIF-RAM to RDRAM
             0x0_0004 => {
-                // Sanity check for now
+                // only the 64 bytes of PIF-RAM can be transferred, so the address is ignored
                 if (value & 0xFFF) != 0x7C0 {
-                    todo!("code starts PIF-RAM dma at an address not 0x7C0 in PIF-RAM");
+                    warn!(target: "SI", "PIF-RAM dma started at address ${:08X}, using PIF-RAM", value);
                 }
 
                 let dma_info = DmaInfo {
@@ -104,9 +104,9 @@ impl SerialInterface {
 
             // SI_PIF_AD_WR64B - DMA 64 bytes from RDRAM to PIF-RAM
             0x0_0010 => {
-                // Sanity check for now
+                // only the 64 bytes of PIF-RAM can be transferred, so the address is ignored
                 if (value & 0xFFF) != 0x7C0 {
-                    todo!("code starts PIF-RAM dma at an address not 0x7C0 in PIF-RAM");
+                    warn!(target: "SI", "PIF-RAM dma started at address ${:08X}, using PIF-RAM", value);
                 }
 
                 let dma_info = DmaInfo {
@@ -132,7 +132,6 @@ impl SerialInterface {
 
             _ => {
                 warn!(target: "SI", "unimplemented SI register write value=${:08X} offset=${:08X}", value, offset);
-                return Err(ReadWriteFault::Invalid);
             }
         }
         Ok(WriteReturnSignal::None)
@@ -175,7 +174,8 @@ impl Addressable for SerialInterface {
                 //self.comms.mi_interrupts_tx.as_ref().unwrap().send(InterruptUpdate(IMask_SI, InterruptUpdateMode::SetInterrupt)).unwrap();
                 self.pif.write_u16(value, offset & 0x000F_FFFF)
             },
-            _ => todo!(),
+            // the full register is on the bus, so the registers see a normal write
+            _ => self.write_register(value, offset & !0x03),
         }
     }
 
@@ -189,7 +189,7 @@ impl Addressable for SerialInterface {
                 //self.comms.mi_interrupts_tx.as_ref().unwrap().send(InterruptUpdate(IMask_SI, InterruptUpdateMode::SetInterrupt)).unwrap();
                 self.pif.write_u8(value, offset & 0x000F_FFFF)
             },
-            _ => todo!(),
+            _ => self.write_register(value, offset & !0x03),
         }
     }
 
@@ -8,12 +8,275 @@ use mips::{InterruptUpdate, InterruptUpdateMode, IMask_VI};
 
 const PAL_BURST: u32 = 0x0404233A;
 const NTSC_BURST: u32 = 0x03E52239;
+const MPAL_BURST: u32 = 0x04651E39;
+
+// VI pixel clocks for each video standard, in Hz
+const NTSC_VI_CLOCK: u64 = 48_681_812;
+const PAL_VI_CLOCK : u64 = 49_656_530;
+const MPAL_VI_CLOCK: u64 = 48_628_322;
+
+// Snapshot of the VI registers that determine the displayed image. Published by the VI whenever
+// one of them changes, so the renderer and screenshots can composite the frame from RDRAM
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct ViState {
+    pub origin: u32,
+    pub frame_buffer_width: u32,
+    pub pixel_type: u8,
+    pub aa_mode: u8,
+    pub serrate: u8,
+    pub field: u32,
+    pub dedither_filter_enable: u8,
+    pub divot_enable: u8,
+    pub gamma_enable: u8,
+    pub gamma_dither_enable: u8,
+    pub h_start: u16,
+    pub h_end: u16,
+    pub v_start: u16,
+    pub v_end: u16,
+    pub x_offset: u16,
+    pub x_scale: u16,
+    pub y_offset: u16,
+    pub y_scale: u16,
+}
+
+// Composited output of the VI, in RGBA8
+pub struct ViFrame {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl ViFrame {
+    // binary PPM, which needs no image crate and is readable by pretty much everything
+    pub fn write_ppm(&self, path: &str) -> std::io::Result<()> {
+        let mut data = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        data.reserve(self.width * self.height * 3);
+        for rgba in self.pixels.chunks_exact(4) {
+            data.extend_from_slice(&rgba[..3]);
+        }
+        std::fs::write(path, data)
+    }
+}
+
+// a framebuffer pixel expanded to 8 bits per channel, with 3-bit coverage
+#[derive(Copy, Clone, Default)]
+struct ViPixel {
+    r: u8,
+    g: u8,
+    b: u8,
+    cvg: u8,
+}
+
+impl ViState {
+    // size of the visible image. h_start..h_end are in output pixels and v_start..v_end in
+    // half-lines, so a field has half as many lines. Interlaced output weaves two fields together
+    pub fn output_size(&self) -> (usize, usize) {
+        let width = self.h_end.saturating_sub(self.h_start) as usize;
+        let lines = (self.v_end.saturating_sub(self.v_start) / 2) as usize;
+        (width, if self.serrate != 0 { lines * 2 } else { lines })
+    }
+
+    // Copy the words of `rdram` the VI reads for this frame, starting with the one holding the origin, so
+    // compose can run without holding the RDRAM lock
+    pub fn copy_framebuffer(&self, rdram: &[u32], map: &rdram::RdramMap) -> Vec<u32> {
+        let (width, height) = self.output_size();
+        let lines = if self.serrate != 0 { height / 2 } else { height };
+        if self.pixel_type < 2 || self.frame_buffer_width == 0 || width == 0 || lines == 0 { return Vec::new(); }
+
+        // the last source pixel, plus one more line and pixel each for resampling and the dedither
+        // filter, which can also read past the end of a line into the next
+        let last_x = ((self.x_offset as u64) + ((width - 1) as u64) * (self.x_scale as u64)) >> 10;
+        let last_y = ((self.y_offset as u64) + ((lines - 1) as u64) * (self.y_scale as u64)) >> 10;
+        let pixels = (last_y + 2) * (self.frame_buffer_width as u64) + last_x + 3;
+        let bytes = pixels * (if self.pixel_type == 3 { 4 } else { 2 }) + ((self.origin & 3) as u64);
+        let count = ((bytes + 3) >> 2).min(rdram.len() as u64) as usize;
+        map.read_words(rdram, (self.origin & !3) as usize, count)
+    }
+
+    // Run the VI output stage over a framebuffer from copy_framebuffer. Returns None when video is blanked
+    // (pixel type 0 or 1) or the active area is empty
+    pub fn compose(&self, framebuffer: &[u32]) -> Option<ViFrame> {
+        if self.pixel_type < 2 || self.frame_buffer_width == 0 { return None; }
+
+        let (width, height) = self.output_size();
+        if width == 0 || height == 0 { return None; }
+
+        let lines = if self.serrate != 0 { height / 2 } else { height };
+        let mut pixels = vec![0u8; width * height * 4];
+        let mut line = vec![ViPixel::default(); width];
+        let mut center_cvg = vec![0u8; width];
+
+        for y in 0..lines {
+            // scale factors are 2.10 fixed point, and the VI interpolates with 5 bits of fraction
+            let fy = (self.y_offset as u32) + (y as u32) * (self.y_scale as u32);
+            let (sy, frac_y) = (fy >> 10, (fy >> 5) & 0x1F);
+
+            for x in 0..width {
+                let fx = (self.x_offset as u32) + (x as u32) * (self.x_scale as u32);
+                let (sx, frac_x) = (fx >> 10, (fx >> 5) & 0x1F);
+
+                let p00 = self.fetch_filtered(framebuffer, sx, sy);
+                center_cvg[x] = p00.cvg;
+
+                // aa_mode 3 replicates pixels, everything else resamples
+                line[x] = if self.aa_mode == 3 {
+                    p00
+                } else {
+                    let p10 = self.fetch_filtered(framebuffer, sx + 1, sy);
+                    let p01 = self.fetch_filtered(framebuffer, sx, sy + 1);
+                    let p11 = self.fetch_filtered(framebuffer, sx + 1, sy + 1);
+                    Self::bilinear(p00, p10, p01, p11, frac_x, frac_y)
+                };
+                // TODO: edge anti-aliasing with the background (aa_mode 0/1) needs the hidden
+                // coverage bits and the previous line, and isn't emulated
+            }
+
+            // divot removes single pixel artifacts left behind by the AA filter on silhouette edges
+            if self.divot_enable != 0 && width >= 3 {
+                let source = line.clone();
+                for x in 1..(width - 1) {
+                    if center_cvg[x] == 7 { continue; }
+                    let (a, b, c) = (source[x - 1], source[x], source[x + 1]);
+                    line[x].r = Self::median3(a.r, b.r, c.r);
+                    line[x].g = Self::median3(a.g, b.g, c.g);
+                    line[x].b = Self::median3(a.b, b.b, c.b);
+                }
+            }
+
+            // interlaced fields occupy alternating output lines, filling the other line until the
+            // next field comes along
+            let rows = if self.serrate != 0 {
+                let row = y * 2 + (self.field as usize & 1);
+                [row, row ^ 1]
+            } else {
+                [y, y]
+            };
+
+            for x in 0..width {
+                let p = line[x];
+                let rgb = [self.gamma(p.r, x, y), self.gamma(p.g, x, y), self.gamma(p.b, x, y)];
+                for row in rows {
+                    if row >= height { continue; }
+                    let i = (row * width + x) * 4;
+                    pixels[i..i + 4].copy_from_slice(&[rgb[0], rgb[1], rgb[2], 0xFF]);
+                }
+            }
+        }
+
+        Some(ViFrame { width, height, pixels })
+    }
+
+    fn fetch(&self, framebuffer: &[u32], sx: u32, sy: u32) -> ViPixel {
+        // byte offset from the word holding the origin
+        let offset = (self.origin & 3) as usize;
+        let index = (sy as usize) * (self.frame_buffer_width as usize) + (sx as usize);
+        let read = |offset: usize| framebuffer.get(offset >> 2).copied().unwrap_or(0);
+        if self.pixel_type == 3 {
+            let word = read(offset + index * 4);
+            ViPixel {
+                r  : (word >> 24) as u8,
+                g  : (word >> 16) as u8,
+                b  : (word >>  8) as u8,
+                cvg: ((word >> 5) & 0x07) as u8,
+            }
+        } else {
+            let address = offset + index * 2;
+            let word = read(address);
+            let pixel = if (address & 0x02) == 0 { word >> 16 } else { word & 0xFFFF };
+            // the low bit of the coverage is the only one visible to the CPU
+            ViPixel {
+                r  : (((pixel >> 11) & 0x1F) << 3) as u8,
+                g  : (((pixel >>  6) & 0x1F) << 3) as u8,
+                b  : (((pixel >>  1) & 0x1F) << 3) as u8,
+                cvg: if (pixel & 0x01) != 0 { 7 } else { 0 },
+            }
+        }
+    }
+
+    // The dither filter recovers the low bits lost by dithering a 16-bit framebuffer, by averaging
+    // over the 3x3 neighborhood any pixels that are within one 5-bit step of the center pixel.
+    // Only fully covered pixels are filtered, since edges are handled by the AA filter
+    fn fetch_filtered(&self, framebuffer: &[u32], sx: u32, sy: u32) -> ViPixel {
+        let center = self.fetch(framebuffer, sx, sy);
+        if self.pixel_type != 2 || self.dedither_filter_enable == 0 || center.cvg != 7 {
+            return center;
+        }
+
+        let (mut r, mut g, mut b) = (0u32, 0u32, 0u32);
+        for ny in sy.saturating_sub(1)..=(sy + 1) {
+            for nx in sx.saturating_sub(1)..=(sx + 1) {
+                let n = if nx == sx && ny == sy { center } else { self.fetch(framebuffer, nx, ny) };
+                let near = |c: u8, n: u8| -> u32 { (if (c as i32 - n as i32).abs() <= 8 { n } else { c }) as u32 };
+                r += near(center.r, n.r);
+                g += near(center.g, n.g);
+                b += near(center.b, n.b);
+            }
+        }
+
+        // edge pixels have fewer neighbors, so make up the count with the center
+        let count = ((sx.min(1) + 2) * (sy.min(1) + 2)) as u32;
+        let missing = 9 - count;
+        r += missing * center.r as u32;
+        g += missing * center.g as u32;
+        b += missing * center.b as u32;
+
+        ViPixel { r: (r / 9) as u8, g: (g / 9) as u8, b: (b / 9) as u8, cvg: center.cvg }
+    }
+
+    fn bilinear(p00: ViPixel, p10: ViPixel, p01: ViPixel, p11: ViPixel, frac_x: u32, frac_y: u32) -> ViPixel {
+        let lerp = |a: u8, b: u8, f: u32| -> u32 { ((a as u32) * (32 - f) + (b as u32) * f) >> 5 };
+        let mix = |c00: u8, c10: u8, c01: u8, c11: u8| -> u8 {
+            let top    = lerp(c00, c10, frac_x) as u8;
+            let bottom = lerp(c01, c11, frac_x) as u8;
+            lerp(top, bottom, frac_y) as u8
+        };
+
+        ViPixel {
+            r  : mix(p00.r, p10.r, p01.r, p11.r),
+            g  : mix(p00.g, p10.g, p01.g, p11.g),
+            b  : mix(p00.b, p10.b, p01.b, p11.b),
+            cvg: p00.cvg,
+        }
+    }
+
+    fn median3(a: u8, b: u8, c: u8) -> u8 {
+        a.max(b).min(a.min(b).max(c))
+    }
+
+    // gamma correction is a square root. with gamma dither enabled, a pseudo-random 6-bit value
+    // is added below the 8-bit color before the square root to hide banding
+    fn gamma(&self, c: u8, x: usize, y: usize) -> u8 {
+        if self.gamma_enable == 0 { return c; }
+
+        let dither = if self.gamma_dither_enable != 0 {
+            // hash of the position, so screenshots are deterministic
+            ((x as u32).wrapping_mul(0x9E37_79B1) ^ (y as u32).wrapping_mul(0x85EB_CA77) ^ self.field) >> 26
+        } else {
+            0
+        };
+
+        let value = ((c as u32) << 6) | dither;
+        (((value as f32) / (0x3FFF as f32)).sqrt() * 255.0).round().min(255.0) as u8
+    }
+}
 
 pub struct VideoInterface {
     comms: SystemCommunication,
 
     resolution_changed: u32,
-    cycle_count: u64,
+
+    // the output state last given to the renderer
+    published_state: ViState,
+
+    // progress into the current line, in units of VI clocks * CPU_FREQ so that the
+    // VI clock and CPU clock can be compared without any rounding
+    line_clock: u64,
+
+    // odd/even field in interlaced mode
+    field: u32,
+
+    // number of fields displayed, used to select the leap pattern
+    field_count: u64,
 
     // VI_CTRL control flags
     dedither_filter_enable: u8,
@@ -37,20 +300,20 @@ pub struct VideoInterface {
     // VI_V_INTR interrupt line
     interrupt_line: u32,
 
-    // VI_V_CURRENT current line
-    current_line: u32,
+    // VI_V_CURRENT current half-line
+    current_halfline: u32,
 
     // VI_BURST won't be used for the longest time
     burst: u32,
 
-    // VI_V_SYNC vsync line start?
+    // VI_V_SYNC number of half-lines per field, minus one
     vsync: u32,
 
-    // VI_H_SYNC hsync pixel start?
+    // VI_H_SYNC duration of a line in VI clocks, minus one
     leap_pattern: u8,
     hsync: u32,
 
-    // VI_H_SYNC_LEAP leap lines
+    // VI_H_SYNC_LEAP alternate line durations used on the last line of a field
     leap_a: u16,
     leap_b: u16,
 
@@ -75,23 +338,23 @@ pub struct VideoInterface {
 }
 
 impl VideoInterface {
-    // CPU runs at 93.75MHz, so at 60fps we should render a frame every 1,562,500 cpu cycles
-    // Just assuming a 320x240 resolution, we should complete a scanline every ~6510 cycles
-    const NUM_LINES: u64 = 280;
+    // CPU runs at 93.75MHz, and the VI counts lines using its own pixel clock, which
+    // depends on the video standard. Line and field lengths come from VI_H_SYNC and VI_V_SYNC
     const CPU_FREQ : u64 = 93_750_000;
 
-    // this FPS value "simulates" the VI interrupt occuring at this framerate
-    const FPS      : u64 = 140; // some demos run really nicely with this VI at 200+
-
-    const CYC_PER_FRAME: u64 = Self::CPU_FREQ / Self::FPS;
-    const CYC_PER_SCANLINE: u64 = Self::CYC_PER_FRAME / Self::NUM_LINES;
+    // until VI_V_SYNC/VI_H_SYNC are programmed, count lines as progressive NTSC
+    const DEFAULT_VSYNC: u32 = 0x20D;
+    const DEFAULT_HSYNC: u32 = 0xC15;
 
     pub fn new(comms: SystemCommunication) -> VideoInterface {
         VideoInterface {
             comms: comms,
 
             resolution_changed: 0,
-            cycle_count: 0,
+            published_state: ViState::default(),
+            line_clock: 0,
+            field: 0,
+            field_count: 0,
 
             // VI_CTRL
             dedither_filter_enable: 0,
@@ -116,7 +379,7 @@ impl VideoInterface {
             interrupt_line: 0x3FF,
 
             // VI_V_CURRENT
-            current_line: 0,
+            current_halfline: 0,
 
             // VI_BURST
             burst: 0x01,
@@ -157,7 +420,9 @@ impl VideoInterface {
         info!(target: "VI", "reset");
 
         self.resolution_changed = 0;
-        self.cycle_count = 0;
+        self.line_clock = 0;
+        self.field = 0;
+        self.field_count = 0;
 
         // VI_CTRL
         self.dedither_filter_enable = 0;
@@ -171,7 +436,6 @@ impl VideoInterface {
         self.gamma_enable = 0;
         self.gamma_dither_enable = 0;
         self.pixel_type = 0;
-        self.comms.vi_format.store(self.pixel_type as u32, Ordering::SeqCst);
 
         // VI_ORIGIN
         self.origin = 0;
@@ -179,13 +443,12 @@ impl VideoInterface {
 
         // VI_WIDTH
         self.frame_buffer_width = 0;
-        self.comms.vi_width.store(self.frame_buffer_width, Ordering::SeqCst);
 
         // VI_V_INTR
         self.interrupt_line = 0x3FF;
 
         // VI_V_CURRENT
-        self.current_line = 0;
+        self.current_halfline = 0;
 
         // VI_BURST
         self.burst = 0x01;
@@ -219,6 +482,8 @@ impl VideoInterface {
         // VI_Y_SCALE
         self.y_offset = 0;
         self.y_scale = 0;
+
+        self.publish_state();
     }
 
     fn _is_ntsc(&self) -> bool {
@@ -233,8 +498,79 @@ impl VideoInterface {
         self.origin
     }
 
+    pub fn field_count(&self) -> u64 {
+        self.field_count
+    }
+
+    pub fn state(&self) -> ViState {
+        ViState {
+            origin                : self.origin,
+            frame_buffer_width    : self.frame_buffer_width,
+            pixel_type            : self.pixel_type,
+            aa_mode               : self.aa_mode,
+            serrate               : self.serrate,
+            field                 : self.field,
+            dedither_filter_enable: self.dedither_filter_enable,
+            divot_enable          : self.divot_enable,
+            gamma_enable          : self.gamma_enable,
+            gamma_dither_enable   : self.gamma_dither_enable,
+            h_start               : self.h_start,
+            h_end                 : self.h_end,
+            v_start               : self.v_start,
+            v_end                 : self.v_end,
+            x_offset              : self.x_offset,
+            x_scale               : self.x_scale,
+            y_offset              : self.y_offset,
+            y_scale               : self.y_scale,
+        }
+    }
+
+    // notify the renderer of any change to the output stage. Most register writes don't change
+    // it, so the lock is only taken when something did
+    fn publish_state(&mut self) {
+        let state = self.state();
+        if state != self.published_state {
+            self.published_state = state;
+            *self.comms.vi_state.write().unwrap() = state;
+        }
+    }
+
+    // the VI clock is set by the board, but the burst value a game programs tells us which
+    // board it expects to be running on
+    fn vi_clock(&self) -> u64 {
+        match self.burst {
+            PAL_BURST  => PAL_VI_CLOCK,
+            MPAL_BURST => MPAL_VI_CLOCK,
+            _          => NTSC_VI_CLOCK,
+        }
+    }
+
+    fn halflines_per_field(&self) -> u32 {
+        (if self.vsync == 0 { Self::DEFAULT_VSYNC } else { self.vsync }) + 1
+    }
+
+    // length of the current line in VI clocks. The last line of every field is replaced with
+    // one of the leap values, selected by the 5-bit leap pattern
+    fn current_line_length(&self) -> u64 {
+        let hsync = if self.hsync == 0 { Self::DEFAULT_HSYNC } else { self.hsync };
+        let is_last_line = (self.current_halfline + 2) >= self.halflines_per_field();
+        if is_last_line && self.vsync != 0 && self.leap_a != 0 && self.leap_b != 0 {
+            let leap = if ((self.leap_pattern >> (self.field_count % 5)) & 0x01) != 0 { self.leap_b } else { self.leap_a };
+            (leap as u64) + 1
+        } else {
+            (hsync as u64) + 1
+        }
+    }
+
+    // VI_V_CURRENT holds the half-line in bits 9:1 and the field in bit 0
+    fn v_current(&self) -> u32 {
+        (self.current_halfline & 0x3FE) | (if self.serrate != 0 { self.field } else { 0 })
+    }
+
     pub fn calculate_free_cycles(&self) -> u64 {
-        return Self::CYC_PER_SCANLINE - self.cycle_count;
+        let line_end = self.current_line_length() * Self::CPU_FREQ;
+        let per_cycle = self.vi_clock();
+        std::cmp::max(1, (line_end.saturating_sub(self.line_clock) + per_cycle - 1) / per_cycle)
     }
 
     pub fn step(&mut self, cpu_cycles_elapsed: u64) {
@@ -248,12 +584,25 @@ impl VideoInterface {
             debug!(target: "VI", "resolution changed to {width}x{height}x{depth}");
         }
 
-        self.cycle_count += cpu_cycles_elapsed;
-        while self.cycle_count >= Self::CYC_PER_SCANLINE { 
-            self.cycle_count -= Self::CYC_PER_SCANLINE;
-            self.current_line = (self.current_line + 1) % (Self::NUM_LINES as u32);
+        self.line_clock += cpu_cycles_elapsed * self.vi_clock();
+        loop {
+            let line_end = self.current_line_length() * Self::CPU_FREQ;
+            if self.line_clock < line_end { break; }
+            self.line_clock -= line_end;
+
+            // each line is two half-lines. at the end of the field, start the next field
+            self.current_halfline += 2;
+            if self.current_halfline >= self.halflines_per_field() {
+                self.current_halfline = 0;
+                self.field_count += 1;
+                self.field = if self.serrate != 0 { self.field ^ 1 } else { 0 };
+                if self.serrate != 0 {
+                    self.publish_state();
+                }
+            }
 
-            if self.current_line == self.interrupt_line {
+            // the field bit doesn't take part in the comparison
+            if (self.v_current() & 0x3FE) == (self.interrupt_line & 0x3FE) {
                 self.comms.mi_interrupts_tx.as_ref().unwrap().send(InterruptUpdate(IMask_VI, InterruptUpdateMode::SetInterrupt)).unwrap();
             }
         }
@@ -291,8 +640,8 @@ impl Addressable for VideoInterface {
 
             // VI_V_CURRENT
             0x0_0010 => {
-                trace!(target: "VI", "current_line = {}", self.current_line);
-                self.current_line * 2
+                trace!(target: "VI", "current_halfline = {} field = {}", self.current_halfline, self.field);
+                self.v_current()
             },
 
             // VI_BURST
@@ -340,7 +689,7 @@ impl Addressable for VideoInterface {
     fn write_u32(&mut self, value: u32, offset: usize) -> Result<WriteReturnSignal, ReadWriteFault> {
         trace!(target: "VI", "write32 value=${:08X} offset=${:08X}", value, offset);
 
-        match offset {
+        let result = match offset {
             // VI_CTRL 
             0x0_0000 => {
                 self.pixel_type             = ((value >> 0) & 0x03) as u8;
@@ -354,9 +703,11 @@ impl Addressable for VideoInterface {
                 self.kill_we                = ((value >> 11) & 0x01) as u8;
                 self.pixel_advance          = ((value >> 12) & 0x0F) as u8;
                 self.dedither_filter_enable = ((value >> 16) & 0x01) as u8;
-                assert!(self.vbus_clock_enable == 0); // crash if vbus clock is ever enabled, simulating the real thing
+                if self.vbus_clock_enable != 0 {
+                    // this can damage a real console
+                    error!(target: "VI", "VI_CTRL vbus clock enabled");
+                }
                 debug!(target: "VI", "setting pixel type to {}", self.pixel_type);
-                self.comms.vi_format.store(self.pixel_type as u32, Ordering::SeqCst);
                 Ok(WriteReturnSignal::None)
             },
 
@@ -375,10 +726,6 @@ impl Addressable for VideoInterface {
             0x0_0008 => {
                 self.frame_buffer_width = value & 0x0FFF;
                 debug!(target: "VI", "setting framebuffer width to {}", self.frame_buffer_width);
-
-                // notify renderer of the framebuffer size
-                self.comms.vi_width.store(self.frame_buffer_width, Ordering::SeqCst);
-
                 Ok(WriteReturnSignal::None)
             },
 
@@ -392,7 +739,7 @@ impl Addressable for VideoInterface {
             // VI_V_CURRENT
             0x0_0010 => {
                 self.comms.mi_interrupts_tx.as_ref().unwrap().send(InterruptUpdate(IMask_VI, InterruptUpdateMode::ClearInterrupt)).unwrap();
-                debug!(target: "VI", "setting current line to {} (int line={})", self.current_line, self.interrupt_line);
+                debug!(target: "VI", "acknowledged interrupt at half-line {} (int line={})", self.current_halfline, self.interrupt_line);
                 Ok(WriteReturnSignal::None)
             },
 
@@ -402,6 +749,7 @@ impl Addressable for VideoInterface {
                 match self.burst {
                     NTSC_BURST => debug!(target: "VI", "NTSC signal detected"),
                     PAL_BURST  => debug!(target: "VI", "PAL signal detected"),
+                    MPAL_BURST => debug!(target: "VI", "MPAL signal detected"),
                     _          => warn!(target: "VI", "non-NTSC/PAL burst signal set value=${:08X}", self.burst),
                 };
                 Ok(WriteReturnSignal::None)
@@ -410,7 +758,7 @@ impl Addressable for VideoInterface {
             // VI_V_SYNC
             0x0_0018 => {
                 self.vsync = value & 0x3FF;
-                debug!(target: "VI", "setting vsync start to {}", self.vsync);
+                debug!(target: "VI", "setting half-lines per field to {}", self.vsync + 1);
                 Ok(WriteReturnSignal::None)
             },
 
@@ -418,7 +766,7 @@ impl Addressable for VideoInterface {
             0x0_001C => {
                 self.leap_pattern = ((value >> 16) & 0x1F) as u8;
                 self.hsync = value & 0xFFF;
-                debug!(target: "VI", "setting hsync start to {}", self.hsync);
+                debug!(target: "VI", "setting line duration to {} quarter pixels", self.hsync + 1);
                 Ok(WriteReturnSignal::None)
             },
 
@@ -481,6 +829,168 @@ impl Addressable for VideoInterface {
                 error!(target: "VI", "unsupported/unimplemented write32 value=${:08X} offset=${:08X}", value, offset);
                 Ok(WriteReturnSignal::None)
             }
+        };
+
+        self.publish_state();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 263 lines of 3094 VI clocks at 48.681812MHz is a 59.83Hz field, about 1.5625M CPU cycles
+    #[test]
+    fn ntsc_field_length() {
+        let mut vi = VideoInterface::new(SystemCommunication::new(None));
+        vi.write_u32(0x20D, 0x0_0018).unwrap(); // VI_V_SYNC
+        vi.write_u32(0xC15, 0x0_001C).unwrap(); // VI_H_SYNC
+
+        let mut cycles = 0;
+        while vi.field_count == 0 {
+            let step = vi.calculate_free_cycles();
+            vi.step(step);
+            cycles += step;
+        }
+
+        let expected = VideoInterface::CPU_FREQ / 60;
+        assert!(cycles.abs_diff(expected) < expected / 100, "field took {} CPU cycles", cycles);
+    }
+
+    #[test]
+    fn register_writes_reach_the_renderer() {
+        let comms = SystemCommunication::new(None);
+        let mut vi = VideoInterface::new(comms.clone());
+
+        vi.write_u32(0x0010_0000, 0x0_0004).unwrap(); // VI_ORIGIN
+        vi.write_u32(320, 0x0_0008).unwrap();         // VI_WIDTH
+        assert_eq!(comms.vi_state.read().unwrap().origin, 0x0010_0000);
+        assert_eq!(comms.vi_state.read().unwrap().frame_buffer_width, 320);
+
+        // registers outside of the output stage leave it alone
+        vi.write_u32(0x200, 0x0_000C).unwrap();       // VI_V_INTR
+        assert_eq!(*comms.vi_state.read().unwrap(), vi.state());
+    }
+
+    // a width x lines image of a framebuffer at 1:1 scale, without filters or resampling
+    fn state(pixel_type: u8, width: u16, lines: u16) -> ViState {
+        ViState {
+            origin: 0x1000,
+            frame_buffer_width: width as u32,
+            pixel_type: pixel_type,
+            aa_mode: 3,
+            h_end: width,
+            v_end: lines * 2,
+            x_scale: 0x400,
+            y_scale: 0x400,
+            ..Default::default()
+        }
+    }
+
+    fn compose(vi: &ViState, ram: &[u32], map: &rdram::RdramMap) -> Option<ViFrame> {
+        vi.compose(&vi.copy_framebuffer(ram, map))
+    }
+
+    fn rgb(frame: &ViFrame) -> Vec<[u8; 3]> {
+        frame.pixels.chunks_exact(4).map(|p| [p[0], p[1], p[2]]).collect()
+    }
+
+    #[test]
+    fn compose_pixel_formats() {
+        let mut ram = vec![0u32; 0x10_0000 >> 2];
+        let map = rdram::RdramMap::new(0x10_0000);
+
+        ram[0x400..0x404].copy_from_slice(&[0xFF00_00FF, 0x00FF_00FF, 0x0000_FFFF, 0x1020_30FF]);
+        let frame = compose(&state(3, 2, 2), &ram, &map).unwrap();
+        assert_eq!((frame.width, frame.height), (2, 2));
+        assert_eq!(rgb(&frame), vec![[0xFF, 0, 0], [0, 0xFF, 0], [0, 0, 0xFF], [0x10, 0x20, 0x30]]);
+        assert!(frame.pixels.chunks_exact(4).all(|p| p[3] == 0xFF));
+
+        // 5/5/5/1 pixels, two to a word with the first in the high half
+        ram[0x400..0x402].copy_from_slice(&[0xF800_07C1, 0x003F_0843]);
+        let frame = compose(&state(2, 4, 1), &ram, &map).unwrap();
+        assert_eq!(rgb(&frame), vec![[0xF8, 0, 0], [0, 0xF8, 0], [0, 0, 0xF8], [0x08, 0x08, 0x08]]);
+
+        // blanked or empty
+        assert!(compose(&state(0, 2, 2), &ram, &map).is_none());
+        assert!(compose(&state(1, 2, 2), &ram, &map).is_none());
+        assert!(compose(&state(3, 0, 2), &ram, &map).is_none());
+        assert!(compose(&state(3, 2, 0), &ram, &map).is_none());
+    }
+
+    #[test]
+    fn copy_only_what_the_vi_reads() {
+        let ram: Vec<u32> = (0..(0x10_0000 >> 2)).collect();
+        let map = rdram::RdramMap::new(0x10_0000);
+
+        // two lines of two pixels, and the pixels to the right and below for the filters
+        assert_eq!(state(3, 2, 2).copy_framebuffer(&ram, &map), ram[0x400..0x40A].to_vec());
+        assert!(state(0, 2, 2).copy_framebuffer(&ram, &map).is_empty());
+
+        // 16-bit framebuffers can start in the middle of a word
+        let mut ram = vec![0u32; 0x10_0000 >> 2];
+        ram[0x400..0x402].copy_from_slice(&[0x0000_F800, 0x07C0_0000]);
+        let mut vi = state(2, 2, 1);
+        vi.origin = 0x1002;
+        assert_eq!(rgb(&compose(&vi, &ram, &map).unwrap()), vec![[0xF8, 0, 0], [0, 0xF8, 0]]);
+    }
+
+    #[test]
+    fn compose_through_the_memory_map() {
+        let mut ram = vec![0u32; 0x20_0000 >> 2];
+        ram[0x10_0000 >> 2] = 0xFFFF_FFFF;
+        let mut vi = state(3, 1, 1);
+        vi.origin = 0x10_0000;
+
+        // the second megabyte only reads back when there's a module behind it
+        assert_eq!(rgb(&compose(&vi, &ram, &rdram::RdramMap::new(0x20_0000)).unwrap()), vec![[0xFF, 0xFF, 0xFF]]);
+        assert_eq!(rgb(&compose(&vi, &ram, &rdram::RdramMap::new(0x10_0000)).unwrap()), vec![[0, 0, 0]]);
+    }
+
+    #[test]
+    fn compose_scaling_and_interlace() {
+        let mut ram = vec![0u32; 0x10_0000 >> 2];
+        let map = rdram::RdramMap::new(0x10_0000);
+        ram[0x400..0x404].copy_from_slice(&[0x0000_00FF, 0x8080_80FF, 0x4040_40FF, 0xC0C0_C0FF]);
+
+        // half scale with replication doubles every pixel
+        let mut vi = state(3, 4, 2);
+        vi.frame_buffer_width = 2;
+        vi.x_scale = 0x200;
+        vi.y_scale = 0x200;
+        let frame = compose(&vi, &ram, &map).unwrap();
+        assert_eq!(rgb(&frame), vec![[0; 3], [0; 3], [0x80; 3], [0x80; 3], [0; 3], [0; 3], [0x80; 3], [0x80; 3]]);
+
+        // resampling blends halfway between pixels, and past the end of a line with the start of
+        // the next line, since the VI just reads on
+        vi.aa_mode = 2;
+        vi.v_end = 2;
+        let frame = compose(&vi, &ram, &map).unwrap();
+        assert_eq!(rgb(&frame), vec![[0x00; 3], [0x40; 3], [0x80; 3], [0x60; 3]]);
+
+        // an interlaced field fills its own lines and the ones of the other field
+        let mut vi = state(3, 2, 1);
+        vi.serrate = 1;
+        vi.field = 1;
+        vi.v_end = 4;
+        let frame = compose(&vi, &ram, &map).unwrap();
+        assert_eq!((frame.width, frame.height), (2, 4));
+        assert_eq!(rgb(&frame), vec![[0; 3], [0x80; 3], [0; 3], [0x80; 3], [0x40; 3], [0xC0; 3], [0x40; 3], [0xC0; 3]]);
+    }
+
+    #[test]
+    fn gamma_is_a_square_root() {
+        let mut vi = state(3, 1, 1);
+        assert_eq!(vi.gamma(0x40, 0, 0), 0x40);
+
+        vi.gamma_enable = 1;
+        assert_eq!((vi.gamma(0, 0, 0), vi.gamma(0x40, 0, 0), vi.gamma(0xFF, 0, 0)), (0, 0x80, 0xFF));
+
+        // dither only ever adds below the 8-bit color
+        vi.gamma_dither_enable = 1;
+        for x in 0..64 {
+            assert!((0x80..=0x81).contains(&vi.gamma(0x40, x, 0)));
         }
     }
 }